@@ -0,0 +1,175 @@
+use crate::region::Region;
+use serde::Deserialize;
+use serde::Serialize;
+
+// Timer periods in CPU cycles https://www.nesdev.org/wiki/APU_DMC
+const DMC_RATE_TABLE: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+const DMC_RATE_TABLE_PAL: [u16; 16] = [
+    398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50,
+];
+
+// Declare DMC struct
+// Delta modulation channel: plays 1-bit delta encoded samples fetched from CPU memory
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Serialize, Deserialize)]
+pub struct DMC {
+    irq_enabled: bool,
+    loop_flag: bool,
+    rate_table: [u16; 16],
+    timer_period: u16,
+    timer: u16,
+    output_level: u8, // 7-bit output, also set directly through $4011
+    sample_address: u16,
+    sample_length: u16,
+    current_address: u16,
+    bytes_remaining: u16,
+    sample_buffer: Option<u8>,
+    shift_register: u8,
+    bits_remaining: u8,
+    silence: bool,
+    pub(crate) irq: bool,
+}
+
+// Implement functionality of DMC
+impl DMC {
+    // Create new DMC object, PAL consoles have their own rates
+    pub fn new(region: Region) -> Self {
+        let rate_table = match region {
+            Region::Pal => DMC_RATE_TABLE_PAL,
+            Region::Ntsc | Region::Dendy => DMC_RATE_TABLE,
+        };
+        DMC {
+            irq_enabled: false,
+            loop_flag: false,
+            rate_table,
+            timer_period: rate_table[0],
+            timer: 0,
+            output_level: 0,
+            sample_address: 0xC000,
+            sample_length: 1,
+            current_address: 0xC000,
+            bytes_remaining: 0,
+            sample_buffer: None,
+            shift_register: 0,
+            bits_remaining: 8,
+            silence: true,
+            irq: false,
+        }
+    }
+
+    // Write to one of the channel registers ($4010-$4013)
+    pub fn write_register(&mut self, register: u16, data: u8) {
+        match register {
+            // IL-- RRRR: IRQ enable, loop, rate index
+            0 => {
+                self.irq_enabled = data & 0b1000_0000 != 0;
+                self.loop_flag = data & 0b0100_0000 != 0;
+                self.timer_period = self.rate_table[(data & 0b1111) as usize];
+                if !self.irq_enabled {
+                    self.irq = false;
+                }
+            }
+            // -DDD DDDD: direct load of the output level, used for PCM playback
+            1 => {
+                self.output_level = data & 0b0111_1111;
+            }
+            // AAAA AAAA: sample address %11AAAAAA.AA000000
+            2 => {
+                self.sample_address = 0xC000 | ((data as u16) << 6);
+            }
+            // LLLL LLLL: sample length %LLLL.LLLL0001
+            3 => {
+                self.sample_length = ((data as u16) << 4) | 1;
+            }
+            _ => unreachable!("dmc register {}", register),
+        }
+    }
+
+    // Enable bit in $4015: restarts an idle sample or stops the current one
+    // Any write to $4015 acknowledges the DMC interrupt
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.irq = false;
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    fn restart(&mut self) {
+        self.current_address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.bytes_remaining > 0
+    }
+
+    // Address the memory reader wants to fetch, if the sample buffer needs refilling
+    pub fn dma_request(&self) -> Option<u16> {
+        if self.sample_buffer.is_none() && self.bytes_remaining > 0 {
+            Some(self.current_address)
+        } else {
+            None
+        }
+    }
+
+    // Complete a DMA fetch started by dma_request
+    pub fn fill_sample_buffer(&mut self, data: u8) {
+        self.sample_buffer = Some(data);
+        // The address wraps around to $8000 after $FFFF
+        self.current_address = if self.current_address == 0xFFFF {
+            0x8000
+        } else {
+            self.current_address + 1
+        };
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.loop_flag {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq = true;
+            }
+        }
+    }
+
+    // Clocked every CPU cycle
+    pub fn clock_timer(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.timer_period - 1;
+
+        // Move the output level up or down by 2 without wrapping
+        if !self.silence {
+            if self.shift_register & 1 == 1 {
+                if self.output_level <= 125 {
+                    self.output_level += 2;
+                }
+            } else if self.output_level >= 2 {
+                self.output_level -= 2;
+            }
+        }
+        self.shift_register >>= 1;
+
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.sample_buffer.take() {
+                Some(data) => {
+                    self.silence = false;
+                    self.shift_register = data;
+                }
+                None => self.silence = true,
+            }
+        }
+    }
+
+    // Current output level (0-127)
+    pub fn output(&self) -> u8 {
+        self.output_level
+    }
+}
@@ -0,0 +1,67 @@
+use serde::Deserialize;
+use serde::Serialize;
+
+// Declare Envelope struct
+// Volume generator shared by the pulse and noise channels https://www.nesdev.org/wiki/APU_Envelope
+#[derive(Serialize, Deserialize)]
+pub struct Envelope {
+    start: bool,
+    loop_flag: bool, // same bit as the length counter halt flag
+    constant_volume: bool,
+    volume: u8, // constant volume, or the divider period when decaying
+    divider: u8,
+    decay_level: u8,
+}
+
+// Implement functionality of Envelope
+impl Envelope {
+    // Create new Envelope object
+    pub fn new() -> Self {
+        Envelope {
+            start: false,
+            loop_flag: false,
+            constant_volume: false,
+            volume: 0,
+            divider: 0,
+            decay_level: 0,
+        }
+    }
+
+    // Write to the channel control register: --LC VVVV
+    pub fn write_control(&mut self, data: u8) {
+        self.loop_flag = data & 0b0010_0000 != 0;
+        self.constant_volume = data & 0b0001_0000 != 0;
+        self.volume = data & 0b0000_1111;
+    }
+
+    // Writing the length counter load register restarts the envelope
+    pub fn restart(&mut self) {
+        self.start = true;
+    }
+
+    // Clocked by the frame counter on every quarter frame
+    pub fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay_level = 15;
+            self.divider = self.volume;
+        } else if self.divider == 0 {
+            self.divider = self.volume;
+            if self.decay_level > 0 {
+                self.decay_level -= 1;
+            } else if self.loop_flag {
+                self.decay_level = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if self.constant_volume {
+            self.volume
+        } else {
+            self.decay_level
+        }
+    }
+}
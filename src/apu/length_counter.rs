@@ -0,0 +1,60 @@
+use serde::Deserialize;
+use serde::Serialize;
+
+// Length counter load values, indexed by the upper 5 bits of the length register
+// https://www.nesdev.org/wiki/APU_Length_Counter
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14, 12, 16, 24, 18, 48, 20, 96, 22,
+    192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+// Declare LengthCounter struct
+// Silences a channel once its note duration runs out
+#[derive(Serialize, Deserialize)]
+pub struct LengthCounter {
+    enabled: bool, // channel enable bit in $4015
+    halt: bool,
+    counter: u8,
+}
+
+// Implement functionality of LengthCounter
+impl LengthCounter {
+    // Create new LengthCounter object
+    pub fn new() -> Self {
+        LengthCounter {
+            enabled: false,
+            halt: false,
+            counter: 0,
+        }
+    }
+
+    // Disabling a channel through $4015 clears its length counter immediately
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.counter = 0;
+        }
+    }
+
+    pub fn set_halt(&mut self, halt: bool) {
+        self.halt = halt;
+    }
+
+    // Load the counter from the table, ignored while the channel is disabled
+    pub fn load(&mut self, index: u8) {
+        if self.enabled {
+            self.counter = LENGTH_TABLE[(index & 0b0001_1111) as usize];
+        }
+    }
+
+    // Clocked by the frame counter on every half frame
+    pub fn clock(&mut self) {
+        if !self.halt && self.counter > 0 {
+            self.counter -= 1;
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.counter > 0
+    }
+}
@@ -0,0 +1,532 @@
+// Audio Processing Unit https://www.nesdev.org/wiki/APU
+// Registers: $4000-$4003 pulse 1, $4004-$4007 pulse 2, $4008-$400B triangle,
+// $400C-$400F noise, $4010-$4013 DMC, $4015 status, $4017 frame counter
+mod dmc;
+mod envelope;
+mod length_counter;
+mod noise;
+pub(crate) mod pulse;
+mod triangle;
+
+use dmc::DMC;
+use noise::Noise;
+use pulse::Pulse;
+use pulse::PulseChannel;
+use serde::Deserialize;
+use serde::Serialize;
+use triangle::Triangle;
+
+use crate::region::Region;
+
+// The APU outputs one sample per CPU cycle, this is the NTSC CPU clock. See
+// APU::sample_rate for the other regions
+pub const SAMPLE_RATE: f64 = 1_789_773.0;
+
+// Frame counter steps in CPU cycles https://www.nesdev.org/wiki/APU_Frame_Counter
+// The four steps of the 4-step sequence, then the last step of the 5-step one
+const FRAME_STEPS_NTSC: [u32; 5] = [7457, 14913, 22371, 29829, 37281];
+const FRAME_STEPS_PAL: [u32; 5] = [8313, 16627, 24939, 33252, 41565];
+
+// Samples are discarded if nobody collects them for about a second
+const MAX_BUFFERED_SAMPLES: usize = 1 << 21;
+
+// Nonlinear mixer lookup tables https://www.nesdev.org/wiki/APU_Mixer
+lazy_static! {
+    static ref PULSE_TABLE: [f32; 31] = {
+        let mut table = [0.0; 31];
+        for (n, entry) in table.iter_mut().enumerate().skip(1) {
+            *entry = 95.52 / (8128.0 / n as f32 + 100.0);
+        }
+        table
+    };
+    static ref TND_TABLE: [f32; 203] = {
+        let mut table = [0.0; 203];
+        for (n, entry) in table.iter_mut().enumerate().skip(1) {
+            *entry = 163.67 / (24329.0 / n as f32 + 100.0);
+        }
+        table
+    };
+}
+
+// Sound sources that can be captured on their own, e.g. to record them separately
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Channel {
+    Pulse1,
+    Pulse2,
+    Triangle,
+    Noise,
+    DMC,
+    Expansion, // cartridge sound hardware
+}
+
+impl Channel {
+    pub const ALL: [Channel; 6] = [
+        Channel::Pulse1,
+        Channel::Pulse2,
+        Channel::Triangle,
+        Channel::Noise,
+        Channel::DMC,
+        Channel::Expansion,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Channel::Pulse1 => "pulse1",
+            Channel::Pulse2 => "pulse2",
+            Channel::Triangle => "triangle",
+            Channel::Noise => "noise",
+            Channel::DMC => "dmc",
+            Channel::Expansion => "expansion",
+        }
+    }
+}
+
+// Declare APU struct
+#[derive(Serialize, Deserialize)]
+pub struct APU {
+    pulse1: Pulse,
+    pulse2: Pulse,
+    triangle: Triangle,
+    noise: Noise,
+    dmc: DMC,
+    region: Region,
+    frame_steps: [u32; 5],
+    five_step_mode: bool,
+    irq_inhibit: bool,
+    frame_irq: bool,
+    frame_cycle: u32, // CPU cycles since the frame counter sequence started
+    cycles: u64,      // CPU cycles since power on
+    #[serde(skip)]
+    samples: Vec<f32>,
+    #[serde(skip)]
+    channel_samples: Option<Vec<Vec<f32>>>, // per-channel output, indexed by Channel
+    expansion: f32, // cartridge sound, mixed in after the APU channels
+}
+
+// Implement functionality of APU
+impl APU {
+    // Create new APU object with NTSC timing
+    pub fn new() -> Self {
+        APU::with_region(Region::Ntsc)
+    }
+
+    // Create new APU object with the timing of a region. Dendy consoles use the NTSC
+    // frame counter and periods
+    pub fn with_region(region: Region) -> Self {
+        APU {
+            pulse1: Pulse::new(PulseChannel::One),
+            pulse2: Pulse::new(PulseChannel::Two),
+            triangle: Triangle::new(),
+            noise: Noise::new(region),
+            dmc: DMC::new(region),
+            region,
+            frame_steps: match region {
+                Region::Pal => FRAME_STEPS_PAL,
+                Region::Ntsc | Region::Dendy => FRAME_STEPS_NTSC,
+            },
+            five_step_mode: false,
+            irq_inhibit: false,
+            frame_irq: false,
+            frame_cycle: 0,
+            cycles: 0,
+            samples: Vec::new(),
+            channel_samples: None,
+            expansion: 0.0,
+        }
+    }
+
+    // Write to an APU register
+    pub fn write_register(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4000..=0x4003 => self.pulse1.write_register(addr - 0x4000, data),
+            0x4004..=0x4007 => self.pulse2.write_register(addr - 0x4004, data),
+            0x4008..=0x400B => self.triangle.write_register(addr - 0x4008, data),
+            0x400C..=0x400F => self.noise.write_register(addr - 0x400C, data),
+            0x4010..=0x4013 => self.dmc.write_register(addr - 0x4010, data),
+            // ---D NT21: channel enables
+            0x4015 => {
+                self.pulse1.length_counter.set_enabled(data & 0b0001 != 0);
+                self.pulse2.length_counter.set_enabled(data & 0b0010 != 0);
+                self.triangle.length_counter.set_enabled(data & 0b0100 != 0);
+                self.noise.length_counter.set_enabled(data & 0b1000 != 0);
+                self.dmc.set_enabled(data & 0b1_0000 != 0);
+            }
+            // MI-- ----: sequencer mode, IRQ inhibit
+            0x4017 => {
+                self.five_step_mode = data & 0b1000_0000 != 0;
+                self.irq_inhibit = data & 0b0100_0000 != 0;
+                if self.irq_inhibit {
+                    self.frame_irq = false;
+                }
+                self.frame_cycle = 0;
+                // The 5-step sequence clocks all units immediately when selected
+                if self.five_step_mode {
+                    self.clock_quarter_frame();
+                    self.clock_half_frame();
+                }
+            }
+            _ => { /* not an APU register */ }
+        }
+    }
+
+    // Read $4015: IF-D NT21, reading clears the frame interrupt flag
+    pub fn read_status(&mut self) -> u8 {
+        let mut status = 0;
+        if self.pulse1.length_counter.is_active() {
+            status |= 0b0000_0001;
+        }
+        if self.pulse2.length_counter.is_active() {
+            status |= 0b0000_0010;
+        }
+        if self.triangle.length_counter.is_active() {
+            status |= 0b0000_0100;
+        }
+        if self.noise.length_counter.is_active() {
+            status |= 0b0000_1000;
+        }
+        if self.dmc.is_active() {
+            status |= 0b0001_0000;
+        }
+        if self.frame_irq {
+            status |= 0b0100_0000;
+        }
+        if self.dmc.irq {
+            status |= 0b1000_0000;
+        }
+        self.frame_irq = false;
+        status
+    }
+
+    // Level of the APU interrupt line, driven by the frame counter and the DMC
+    pub fn irq(&self) -> bool {
+        self.frame_irq || self.dmc.irq
+    }
+
+    // Address the DMC wants to read, the bus must answer with dmc_dma_complete
+    pub fn dmc_dma_request(&self) -> Option<u16> {
+        self.dmc.dma_request()
+    }
+
+    pub fn dmc_dma_complete(&mut self, data: u8) {
+        self.dmc.fill_sample_buffer(data);
+    }
+
+    // Level of the cartridge sound hardware for the next samples, already scaled to
+    // the APU output (see Mapper::audio_output)
+    pub fn set_expansion_output(&mut self, level: f32) {
+        self.expansion = level;
+    }
+
+    // Advance the APU by one CPU cycle and output one sample
+    pub fn tick(&mut self) {
+        self.cycles += 1;
+
+        self.triangle.clock_timer();
+        self.noise.clock_timer();
+        self.dmc.clock_timer();
+        if self.cycles.is_multiple_of(2) {
+            self.pulse1.clock_timer();
+            self.pulse2.clock_timer();
+        }
+        self.clock_frame_counter();
+
+        if self.samples.len() >= MAX_BUFFERED_SAMPLES {
+            self.samples.clear();
+        }
+        let sample = self.mix();
+        self.samples.push(sample);
+
+        if self.channel_samples.is_some() {
+            let levels = self.channel_levels();
+            if let Some(channel_samples) = self.channel_samples.as_mut() {
+                for (samples, level) in channel_samples.iter_mut().zip(levels.iter()) {
+                    if samples.len() >= MAX_BUFFERED_SAMPLES {
+                        samples.clear();
+                    }
+                    samples.push(*level);
+                }
+            }
+        }
+    }
+
+    // Step the 4-step or 5-step frame counter sequence
+    fn clock_frame_counter(&mut self) {
+        self.frame_cycle += 1;
+        let steps = self.frame_steps;
+        match (self.frame_cycle, self.five_step_mode) {
+            (n, _) if n == steps[0] || n == steps[2] => self.clock_quarter_frame(),
+            (n, _) if n == steps[1] => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+            }
+            (n, false) if n == steps[3] => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+                self.raise_frame_irq();
+            }
+            (n, false) if n == steps[3] + 1 => {
+                self.raise_frame_irq();
+                self.frame_cycle = 0;
+            }
+            (n, true) if n == steps[4] => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+            }
+            (n, true) if n == steps[4] + 1 => self.frame_cycle = 0,
+            _ => {}
+        }
+    }
+
+    fn raise_frame_irq(&mut self) {
+        if !self.irq_inhibit {
+            self.frame_irq = true;
+        }
+    }
+
+    // Envelopes and the triangle linear counter
+    fn clock_quarter_frame(&mut self) {
+        self.pulse1.clock_envelope();
+        self.pulse2.clock_envelope();
+        self.triangle.clock_linear_counter();
+        self.noise.clock_envelope();
+    }
+
+    // Length counters and sweep units
+    fn clock_half_frame(&mut self) {
+        self.pulse1.clock_length_and_sweep();
+        self.pulse2.clock_length_and_sweep();
+        self.triangle.clock_length();
+        self.noise.clock_length();
+    }
+
+    // Combine the channels with the nonlinear mixer, output is in the range 0.0-1.0
+    fn mix(&self) -> f32 {
+        let pulse = self.pulse1.output() + self.pulse2.output();
+        let tnd = 3 * self.triangle.output() + 2 * self.noise.output() + self.dmc.output();
+        PULSE_TABLE[pulse as usize] + TND_TABLE[tnd as usize] + self.expansion
+    }
+
+    // Every channel played alone through the mixer, indexed by Channel
+    fn channel_levels(&self) -> [f32; 6] {
+        [
+            PULSE_TABLE[self.pulse1.output() as usize],
+            PULSE_TABLE[self.pulse2.output() as usize],
+            TND_TABLE[3 * self.triangle.output() as usize],
+            TND_TABLE[2 * self.noise.output() as usize],
+            TND_TABLE[self.dmc.output() as usize],
+            self.expansion,
+        ]
+    }
+
+    // Samples per second, one per CPU cycle
+    pub fn sample_rate(&self) -> f64 {
+        self.region.cpu_clock_rate()
+    }
+
+    // Go back to a saved APU, see state.rs. The samples not taken yet and the channel
+    // capture stay as they are
+    pub(crate) fn load_state(&mut self, saved: APU) {
+        let samples = std::mem::take(&mut self.samples);
+        let channel_samples = self.channel_samples.take();
+        *self = saved;
+        self.samples = samples;
+        self.channel_samples = channel_samples;
+    }
+
+    // Take the samples produced since the last call, one per CPU cycle
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.samples)
+    }
+
+    // Also produce a sample stream for every channel on its own (costs some speed)
+    pub fn set_channel_capture(&mut self, enabled: bool) {
+        self.channel_samples = if enabled {
+            Some(vec![Vec::new(); Channel::ALL.len()])
+        } else {
+            None
+        };
+    }
+
+    // Take the per-channel samples produced since the last call, indexed by Channel
+    pub fn take_channel_samples(&mut self) -> Vec<Vec<f32>> {
+        match self.channel_samples.as_mut() {
+            Some(channel_samples) => channel_samples.iter_mut().map(std::mem::take).collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+// Level of a pulse channel sum (0-30) through the APU mixer, for expansion chips whose
+// pulse channels sound like the APU ones
+pub(crate) fn pulse_mix_level(output: u8) -> f32 {
+    PULSE_TABLE[output.min(30) as usize]
+}
+
+impl Default for APU {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const STEP2: u32 = FRAME_STEPS_NTSC[1];
+    const STEP4: u32 = FRAME_STEPS_NTSC[3];
+    const STEP5: u32 = FRAME_STEPS_NTSC[4];
+
+    #[test]
+    fn test_length_counter_status() {
+        let mut apu = APU::new();
+        // Loading while disabled is ignored
+        apu.write_register(0x4003, 0b0000_1000);
+        assert_eq!(apu.read_status() & 0b0001, 0);
+
+        apu.write_register(0x4015, 0b0000_1111);
+        apu.write_register(0x4003, 0b0000_1000);
+        apu.write_register(0x400F, 0b0000_1000);
+        assert_eq!(apu.read_status() & 0b1111, 0b1001);
+
+        // Disabling a channel clears its length counter
+        apu.write_register(0x4015, 0b0000_0001);
+        assert_eq!(apu.read_status() & 0b1111, 0b0001);
+    }
+
+    #[test]
+    fn test_length_counter_runs_out() {
+        let mut apu = APU::new();
+        apu.write_register(0x4015, 0b0000_0001);
+        // Index 3 loads a length of 2: two half frames
+        apu.write_register(0x4003, 0b0001_1000);
+        for _ in 0..STEP2 {
+            apu.tick();
+        }
+        assert_eq!(apu.read_status() & 0b0001, 0b0001);
+        for _ in STEP2..STEP4 {
+            apu.tick();
+        }
+        assert_eq!(apu.read_status() & 0b0001, 0);
+    }
+
+    #[test]
+    fn test_frame_irq() {
+        let mut apu = APU::new();
+        for _ in 0..STEP4 {
+            apu.tick();
+        }
+        assert!(apu.irq());
+        assert_eq!(apu.read_status() & 0b0100_0000, 0b0100_0000);
+        assert_eq!(apu.read_status() & 0b0100_0000, 0);
+
+        // No interrupt when inhibited or in 5-step mode
+        apu.write_register(0x4017, 0b0100_0000);
+        for _ in 0..STEP4 {
+            apu.tick();
+        }
+        assert!(!apu.irq());
+        apu.write_register(0x4017, 0b1000_0000);
+        for _ in 0..2 * STEP5 {
+            apu.tick();
+        }
+        assert!(!apu.irq());
+    }
+
+    #[test]
+    fn test_pal_frame_counter() {
+        let mut apu = APU::with_region(Region::Pal);
+        for _ in 0..STEP4 + 1 {
+            apu.tick();
+        }
+        assert!(!apu.irq());
+        for _ in STEP4 + 1..FRAME_STEPS_PAL[3] {
+            apu.tick();
+        }
+        assert!(apu.irq());
+        assert!((apu.sample_rate() - 1_662_607.0).abs() < 1.0);
+        assert!((APU::with_region(Region::Dendy).sample_rate() - 1_773_448.0).abs() < 1.0);
+    }
+
+    #[test]
+    fn test_dmc_sample_playback() {
+        let mut apu = APU::new();
+        // IRQ enabled, fastest rate, one byte at $C040
+        apu.write_register(0x4010, 0b1000_1111);
+        apu.write_register(0x4011, 0x40);
+        apu.write_register(0x4012, 0x01);
+        apu.write_register(0x4013, 0x00);
+        assert_eq!(apu.dmc_dma_request(), None);
+
+        apu.write_register(0x4015, 0b0001_0000);
+        assert_eq!(apu.read_status() & 0b0001_0000, 0b0001_0000);
+        assert_eq!(apu.dmc_dma_request(), Some(0xC040));
+        apu.dmc_dma_complete(0xFF);
+        assert_eq!(apu.dmc_dma_request(), None);
+        assert!(apu.irq());
+        assert_eq!(apu.read_status() & 0b1001_0000, 0b1000_0000);
+
+        // Eight 1 bits raise the level by 2 each once the byte reaches the output unit
+        for _ in 0..54 * 16 {
+            apu.tick();
+        }
+        assert_eq!(apu.dmc.output(), 0x40 + 16);
+
+        // Writing $4015 acknowledges the interrupt
+        apu.write_register(0x4015, 0);
+        assert!(!apu.irq());
+    }
+
+    #[test]
+    fn test_dmc_loop() {
+        let mut apu = APU::new();
+        apu.write_register(0x4010, 0b0100_0000);
+        apu.write_register(0x4012, 0xFF);
+        apu.write_register(0x4013, 0x00);
+        apu.write_register(0x4015, 0b0001_0000);
+        assert_eq!(apu.dmc_dma_request(), Some(0xFFC0));
+        apu.dmc_dma_complete(0);
+        // Looping samples restart instead of raising the interrupt
+        assert!(!apu.irq());
+        assert!(apu.dmc.is_active());
+    }
+
+    #[test]
+    fn test_channel_capture() {
+        let mut apu = APU::new();
+        apu.tick();
+        assert!(apu.take_channel_samples().is_empty());
+
+        apu.set_channel_capture(true);
+        apu.write_register(0x4011, 0x20);
+        for _ in 0..10 {
+            apu.tick();
+        }
+        let channels = apu.take_channel_samples();
+        assert_eq!(channels.len(), 6);
+        assert!(channels.iter().all(|samples| samples.len() == 10));
+        assert_eq!(channels[Channel::DMC as usize][0], TND_TABLE[0x20]);
+        assert_eq!(channels[Channel::Pulse1 as usize][0], 0.0);
+    }
+
+    #[test]
+    fn test_mixer_output() {
+        let mut apu = APU::new();
+        // The silent triangle still holds its first step
+        let baseline = apu.mix();
+        assert!((baseline - TND_TABLE[45]).abs() < f32::EPSILON);
+
+        // Constant volume 15 pulse at 50% duty
+        apu.write_register(0x4015, 0b0000_0001);
+        apu.write_register(0x4000, 0b1011_1111);
+        apu.write_register(0x4002, 0xFD);
+        apu.write_register(0x4003, 0b0000_1000);
+        for _ in 0..1000 {
+            apu.tick();
+        }
+        let samples = apu.take_samples();
+        assert_eq!(samples.len(), 1000);
+        let peak = samples.iter().cloned().fold(0.0, f32::max);
+        assert!((peak - baseline - PULSE_TABLE[15]).abs() < 1e-6);
+        assert!(samples.contains(&baseline));
+    }
+}
@@ -0,0 +1,99 @@
+use crate::apu::envelope::Envelope;
+use crate::apu::length_counter::LengthCounter;
+use crate::region::Region;
+use serde::Deserialize;
+use serde::Serialize;
+
+// Timer periods in CPU cycles https://www.nesdev.org/wiki/APU_Noise
+const NOISE_PERIOD_TABLE: [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+const NOISE_PERIOD_TABLE_PAL: [u16; 16] = [
+    4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778,
+];
+
+// Declare Noise struct
+#[derive(Serialize, Deserialize)]
+pub struct Noise {
+    envelope: Envelope,
+    pub(crate) length_counter: LengthCounter,
+    mode: bool, // short mode: feedback from bit 6 instead of bit 1
+    period_table: [u16; 16],
+    timer_period: u16,
+    timer: u16,
+    shift_register: u16, // 15-bit linear feedback shift register
+}
+
+// Implement functionality of Noise
+impl Noise {
+    // Create new Noise object, PAL consoles have their own periods
+    pub fn new(region: Region) -> Self {
+        let period_table = match region {
+            Region::Pal => NOISE_PERIOD_TABLE_PAL,
+            Region::Ntsc | Region::Dendy => NOISE_PERIOD_TABLE,
+        };
+        Noise {
+            envelope: Envelope::new(),
+            length_counter: LengthCounter::new(),
+            mode: false,
+            period_table,
+            timer_period: period_table[0],
+            timer: 0,
+            shift_register: 1,
+        }
+    }
+
+    // Write to one of the channel registers ($400C-$400F, $400D is unused)
+    pub fn write_register(&mut self, register: u16, data: u8) {
+        match register {
+            // --LC VVVV: length counter halt, constant volume, volume/envelope
+            0 => {
+                self.length_counter.set_halt(data & 0b0010_0000 != 0);
+                self.envelope.write_control(data);
+            }
+            1 => { /* unused */ }
+            // M--- PPPP: mode, period index
+            2 => {
+                self.mode = data & 0b1000_0000 != 0;
+                self.timer_period = self.period_table[(data & 0b1111) as usize];
+            }
+            // LLLL L---: length counter load
+            3 => {
+                self.length_counter.load(data >> 3);
+                self.envelope.restart();
+            }
+            _ => unreachable!("noise register {}", register),
+        }
+    }
+
+    // Clocked every CPU cycle
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period - 1;
+            let tap = if self.mode { 6 } else { 1 };
+            let feedback = (self.shift_register ^ (self.shift_register >> tap)) & 1;
+            self.shift_register = (self.shift_register >> 1) | (feedback << 14);
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    // Clocked by the frame counter on every quarter frame
+    pub fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    // Clocked by the frame counter on every half frame
+    pub fn clock_length(&mut self) {
+        self.length_counter.clock();
+    }
+
+    // Current output level (0-15), silent while bit 0 of the shift register is set
+    pub fn output(&self) -> u8 {
+        if !self.length_counter.is_active() || self.shift_register & 1 == 1 {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
@@ -0,0 +1,157 @@
+use crate::apu::envelope::Envelope;
+use crate::apu::length_counter::LengthCounter;
+use serde::Deserialize;
+use serde::Serialize;
+
+// Waveforms selected by the duty bits https://www.nesdev.org/wiki/APU_Pulse
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0], // 12.5%
+    [0, 1, 1, 0, 0, 0, 0, 0], // 25%
+    [0, 1, 1, 1, 1, 0, 0, 0], // 50%
+    [1, 0, 0, 1, 1, 1, 1, 1], // 25% negated
+];
+
+// The two pulse channels differ only in how the sweep unit negates
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum PulseChannel {
+    One,  // ones' complement: subtracts an extra 1
+    Two,  // twos' complement
+    Mmc5, // MMC5 copy without sweep unit, low periods are not muted
+}
+
+// Declare Pulse struct
+#[derive(Serialize, Deserialize)]
+pub struct Pulse {
+    channel: PulseChannel,
+    duty: u8,
+    sequence_step: u8,
+    timer_period: u16, // 11-bit period, clocked every APU cycle (2 CPU cycles)
+    timer: u16,
+    envelope: Envelope,
+    pub(crate) length_counter: LengthCounter,
+    sweep_enabled: bool,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_reload: bool,
+    sweep_divider: u8,
+}
+
+// Implement functionality of Pulse
+impl Pulse {
+    // Create new Pulse object
+    pub fn new(channel: PulseChannel) -> Self {
+        Pulse {
+            channel,
+            duty: 0,
+            sequence_step: 0,
+            timer_period: 0,
+            timer: 0,
+            envelope: Envelope::new(),
+            length_counter: LengthCounter::new(),
+            sweep_enabled: false,
+            sweep_period: 0,
+            sweep_negate: false,
+            sweep_shift: 0,
+            sweep_reload: false,
+            sweep_divider: 0,
+        }
+    }
+
+    // Write to one of the four channel registers ($4000-$4003 or $4004-$4007)
+    pub fn write_register(&mut self, register: u16, data: u8) {
+        match register {
+            // DDLC VVVV: duty, length counter halt, constant volume, volume/envelope
+            0 => {
+                self.duty = data >> 6;
+                self.length_counter.set_halt(data & 0b0010_0000 != 0);
+                self.envelope.write_control(data);
+            }
+            // EPPP NSSS: sweep enable, period, negate, shift
+            1 => {
+                self.sweep_enabled = data & 0b1000_0000 != 0;
+                self.sweep_period = (data >> 4) & 0b111;
+                self.sweep_negate = data & 0b0000_1000 != 0;
+                self.sweep_shift = data & 0b111;
+                self.sweep_reload = true;
+            }
+            // TTTT TTTT: timer low
+            2 => {
+                self.timer_period = (self.timer_period & 0x0700) | data as u16;
+            }
+            // LLLL LTTT: length counter load, timer high
+            3 => {
+                self.timer_period = (self.timer_period & 0x00FF) | ((data as u16 & 0b111) << 8);
+                self.length_counter.load(data >> 3);
+                self.sequence_step = 0;
+                self.envelope.restart();
+            }
+            _ => unreachable!("pulse register {}", register),
+        }
+    }
+
+    // Clocked every APU cycle
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            self.sequence_step = (self.sequence_step + 1) & 0b111;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    // Clocked by the frame counter on every quarter frame
+    pub fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    // Clocked by the frame counter on every half frame
+    pub fn clock_length_and_sweep(&mut self) {
+        self.length_counter.clock();
+
+        if self.sweep_divider == 0 && self.sweep_enabled && self.sweep_shift > 0 && !self.is_muted()
+        {
+            self.timer_period = self.sweep_target_period();
+        }
+        if self.sweep_divider == 0 || self.sweep_reload {
+            self.sweep_divider = self.sweep_period;
+            self.sweep_reload = false;
+        } else {
+            self.sweep_divider -= 1;
+        }
+    }
+
+    // The sweep unit continuously computes the period it would change to
+    fn sweep_target_period(&self) -> u16 {
+        let change = self.timer_period >> self.sweep_shift;
+        if self.sweep_negate {
+            let change = match self.channel {
+                PulseChannel::One => change + 1,
+                PulseChannel::Two | PulseChannel::Mmc5 => change,
+            };
+            self.timer_period.saturating_sub(change)
+        } else {
+            self.timer_period + change
+        }
+    }
+
+    // Periods below 8 and sweep targets above $7FF mute the channel, even with sweep disabled
+    fn is_muted(&self) -> bool {
+        if self.channel == PulseChannel::Mmc5 {
+            return false;
+        }
+        self.timer_period < 8 || self.sweep_target_period() > 0x07FF
+    }
+
+    // Current output level (0-15)
+    pub fn output(&self) -> u8 {
+        if !self.length_counter.is_active()
+            || self.is_muted()
+            || DUTY_TABLE[self.duty as usize][self.sequence_step as usize] == 0
+        {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
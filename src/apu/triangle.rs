@@ -0,0 +1,97 @@
+use crate::apu::length_counter::LengthCounter;
+use serde::Deserialize;
+use serde::Serialize;
+
+// 32-step triangle waveform https://www.nesdev.org/wiki/APU_Triangle
+const TRIANGLE_SEQUENCE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12,
+    13, 14, 15,
+];
+
+// Declare Triangle struct
+#[derive(Serialize, Deserialize)]
+pub struct Triangle {
+    timer_period: u16, // 11-bit period, clocked every CPU cycle
+    timer: u16,
+    sequence_step: u8,
+    pub(crate) length_counter: LengthCounter,
+    control: bool, // doubles as the length counter halt flag
+    linear_counter: u8,
+    linear_counter_reload_value: u8,
+    linear_counter_reload: bool,
+}
+
+// Implement functionality of Triangle
+impl Triangle {
+    // Create new Triangle object
+    pub fn new() -> Self {
+        Triangle {
+            timer_period: 0,
+            timer: 0,
+            sequence_step: 0,
+            length_counter: LengthCounter::new(),
+            control: false,
+            linear_counter: 0,
+            linear_counter_reload_value: 0,
+            linear_counter_reload: false,
+        }
+    }
+
+    // Write to one of the channel registers ($4008-$400B, $4009 is unused)
+    pub fn write_register(&mut self, register: u16, data: u8) {
+        match register {
+            // CRRR RRRR: control/length counter halt, linear counter reload value
+            0 => {
+                self.control = data & 0b1000_0000 != 0;
+                self.length_counter.set_halt(self.control);
+                self.linear_counter_reload_value = data & 0b0111_1111;
+            }
+            1 => { /* unused */ }
+            // TTTT TTTT: timer low
+            2 => {
+                self.timer_period = (self.timer_period & 0x0700) | data as u16;
+            }
+            // LLLL LTTT: length counter load, timer high
+            3 => {
+                self.timer_period = (self.timer_period & 0x00FF) | ((data as u16 & 0b111) << 8);
+                self.length_counter.load(data >> 3);
+                self.linear_counter_reload = true;
+            }
+            _ => unreachable!("triangle register {}", register),
+        }
+    }
+
+    // Clocked every CPU cycle; the sequencer only advances while both counters are non-zero
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            if self.length_counter.is_active() && self.linear_counter > 0 {
+                self.sequence_step = (self.sequence_step + 1) & 0b1_1111;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    // Clocked by the frame counter on every quarter frame
+    pub fn clock_linear_counter(&mut self) {
+        if self.linear_counter_reload {
+            self.linear_counter = self.linear_counter_reload_value;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+        if !self.control {
+            self.linear_counter_reload = false;
+        }
+    }
+
+    // Clocked by the frame counter on every half frame
+    pub fn clock_length(&mut self) {
+        self.length_counter.clock();
+    }
+
+    // Current output level (0-15); a halted sequencer keeps outputting its last step
+    pub fn output(&self) -> u8 {
+        TRIANGLE_SEQUENCE[self.sequence_step as usize]
+    }
+}
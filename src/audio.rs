@@ -0,0 +1,89 @@
+use crate::pacer;
+use crate::resampler::rate_adjustment;
+use crate::resampler::Resampler;
+use sdl2::audio::AudioQueue;
+use sdl2::audio::AudioSpecDesired;
+use sdl2::Sdl;
+use std::time::Duration;
+use std::time::Instant;
+
+// Preferred device settings, SDL may pick something else (e.g. 44.1 kHz)
+const DEVICE_RATE: i32 = 48_000;
+const DEVICE_BUFFER: u16 = 1024;
+
+// Latency the audio queue is kept at, in seconds
+const TARGET_LATENCY: f64 = 0.05;
+
+// Declare AudioOutput struct
+// Resamples APU output to the device rate and feeds the SDL audio queue
+pub struct AudioOutput {
+    queue: AudioQueue<f32>,
+    resampler: Resampler,
+    buffer: Vec<f32>,
+    output_rate: f64,
+    target_queued_samples: usize,
+    volume: f32,
+}
+
+// Implement functionality of AudioOutput
+impl AudioOutput {
+    // Open the default audio device for samples produced at input_rate Hz
+    pub fn new(sdl_context: &Sdl, input_rate: f64) -> Result<Self, String> {
+        let audio_subsystem = sdl_context.audio()?;
+        let desired_spec = AudioSpecDesired {
+            freq: Some(DEVICE_RATE),
+            channels: Some(1),
+            samples: Some(DEVICE_BUFFER),
+        };
+        let queue = audio_subsystem.open_queue::<f32, _>(None, &desired_spec)?;
+        let output_rate = queue.spec().freq as f64;
+        queue.resume();
+
+        Ok(AudioOutput {
+            queue,
+            resampler: Resampler::new(input_rate, output_rate),
+            buffer: Vec::new(),
+            output_rate,
+            target_queued_samples: (output_rate * TARGET_LATENCY) as usize,
+            volume: 1.0,
+        })
+    }
+
+    // Scale the sound, from 0.0 (silent) to 1.0 (as the console plays it)
+    pub fn set_volume(&mut self, volume: f32) {
+        self.volume = volume;
+    }
+
+    fn queued_samples(&self) -> usize {
+        self.queue.size() as usize / std::mem::size_of::<f32>()
+    }
+
+    // Queue the APU samples of one frame
+    pub fn push_samples(&mut self, samples: &[f32]) {
+        let queued = self.queued_samples();
+
+        // Far behind (e.g. after a pause or fast-forward): drop the backlog instead of
+        // playing it back late
+        if queued > 4 * self.target_queued_samples {
+            self.queue.clear();
+        }
+
+        self.resampler
+            .set_rate_adjustment(rate_adjustment(queued, self.target_queued_samples));
+        self.buffer.clear();
+        self.resampler.process(samples, &mut self.buffer);
+        let volume = self.volume;
+        self.buffer.iter_mut().for_each(|sample| *sample *= volume);
+        self.queue.queue(&self.buffer);
+    }
+
+    // Wait until the device has played the queue down to the target latency, which
+    // paces the frontend to the audio clock
+    pub fn wait_for_queue(&self) {
+        let excess = self
+            .queued_samples()
+            .saturating_sub(self.target_queued_samples);
+        let wait = Duration::from_secs_f64(excess as f64 / self.output_rate);
+        pacer::sleep_until(Instant::now() + wait);
+    }
+}
@@ -0,0 +1,297 @@
+use rust_nes_emulator::cli::parse_address;
+use rust_nes_emulator::cli::Machine;
+use rust_nes_emulator::cli::Options;
+use rust_nes_emulator::cpu::CpuBus;
+use rust_nes_emulator::cpu::Mem;
+use rust_nes_emulator::cpu::CPU;
+use rust_nes_emulator::easy6502::Easy6502;
+use rust_nes_emulator::nes::Nes;
+use rust_nes_emulator::romdb::RomDatabase;
+use rust_nes_emulator::trace::trace;
+use std::collections::BTreeSet;
+use std::io;
+use std::io::BufRead;
+use std::io::Write;
+
+const USAGE: &str = "\
+Usage: debugger [--machine <nes|easy6502>] [--load-address <ADDR>] [--entry <ADDR>]
+                [--region <ntsc|pal|dendy>] FILE
+
+Runs FILE under a command-line debugger, without a window.";
+
+const COMMANDS: &str = "\
+Commands:
+  s, step [N]          Run N instructions (1), tracing each
+  f, frame [N]         Run N frames (1)
+  c, continue          Run until a breakpoint, BRK or an illegal opcode
+  b, break [ADDR]      Toggle a breakpoint at ADDR, or list the breakpoints
+  m, mem ADDR [LEN]    Show LEN bytes of memory from ADDR (64)
+  w, write ADDR VALUE  Write a byte of memory
+  r, regs              Show the next instruction and the registers
+  reset                Reset the CPU
+  h, help              Show this message
+  q, quit              Quit";
+
+// Declare Command enum
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Command {
+    Step(u32),
+    Frame(u32),
+    Continue,
+    Break(Option<u16>),
+    Memory(u16, u16),
+    Write(u16, u8),
+    Registers,
+    Reset,
+    Help,
+    Quit,
+}
+
+fn parse_count(arg: Option<&str>) -> Result<u32, String> {
+    match arg {
+        Some(text) => match text.parse() {
+            Ok(count) if count > 0 => Ok(count),
+            _ => Err(format!("Invalid count {}", text)),
+        },
+        None => Ok(1),
+    }
+}
+
+// Parse a line typed at the prompt, None for an empty line
+fn parse_command(line: &str) -> Result<Option<Command>, String> {
+    let mut words = line.split_whitespace();
+    let name = match words.next() {
+        Some(name) => name,
+        None => return Ok(None),
+    };
+    let arg = words.next();
+    let address = |arg: Option<&str>| match arg {
+        Some(text) => parse_address(text),
+        None => Err(format!("{} needs an address", name)),
+    };
+    let command = match name {
+        "s" | "step" => Command::Step(parse_count(arg)?),
+        "f" | "frame" => Command::Frame(parse_count(arg)?),
+        "c" | "continue" => Command::Continue,
+        "b" | "break" => Command::Break(arg.map(parse_address).transpose()?),
+        "m" | "mem" => {
+            let len = match words.next() {
+                Some(text) => parse_address(text)?,
+                None => 64,
+            };
+            Command::Memory(address(arg)?, len)
+        }
+        "w" | "write" => {
+            let value = address(words.next())?;
+            let value = u8::try_from(value).map_err(|_| format!("Invalid byte {}", value))?;
+            Command::Write(address(arg)?, value)
+        }
+        "r" | "regs" => Command::Registers,
+        "reset" => Command::Reset,
+        "h" | "help" => Command::Help,
+        "q" | "quit" => Command::Quit,
+        _ => return Err(format!("Unknown command {}, see help", name)),
+    };
+    Ok(Some(command))
+}
+
+// Print memory 16 bytes to a line
+fn print_memory<M: CpuBus>(cpu: &mut CPU<M>, start: u16, len: u16) {
+    for row in (0..len).step_by(16) {
+        let addr = start.wrapping_add(row);
+        let bytes: Vec<String> = (0..16.min(len - row))
+            .map(|offset| format!("{:02X}", cpu.mem_read(addr.wrapping_add(offset))))
+            .collect();
+        println!("{:04X}  {}", addr, bytes.join(" "));
+    }
+}
+
+// Read commands from stdin until quit or the end of input. frame_done is called
+// after every instruction and tells whether a frame has just been completed
+fn debug<M, F>(cpu: &mut CPU<M>, mut frame_done: F) -> io::Result<()>
+where
+    M: CpuBus,
+    F: FnMut(&mut CPU<M>) -> bool,
+{
+    let mut breakpoints = BTreeSet::new();
+    let mut halted = false;
+    println!("{}", trace(cpu));
+    let stdin = io::stdin();
+    loop {
+        print!("> ");
+        io::stdout().flush()?;
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        let command = match parse_command(&line) {
+            Ok(Some(command)) => command,
+            Ok(None) => continue,
+            Err(err) => {
+                println!("{}", err);
+                continue;
+            }
+        };
+
+        // Run instructions until stop tells to, a breakpoint is reached or the CPU stops
+        let mut run = |cpu: &mut CPU<M>, tracing: bool, stop: &mut dyn FnMut(bool) -> bool| {
+            if halted {
+                println!("{}, reset to go on", cpu.stop_reason());
+                return;
+            }
+            let mut first = true;
+            loop {
+                if !first && breakpoints.contains(&cpu.program_counter) {
+                    println!("Breakpoint at ${:04X}", cpu.program_counter);
+                    return;
+                }
+                first = false;
+                if tracing {
+                    println!("{}", trace(cpu));
+                }
+                if !cpu.step() {
+                    println!("{}", cpu.stop_reason());
+                    halted = true;
+                    return;
+                }
+                if stop(frame_done(cpu)) {
+                    return;
+                }
+            }
+        };
+        match command {
+            Command::Step(count) => {
+                let mut left = count;
+                run(cpu, true, &mut |_| {
+                    left -= 1;
+                    left == 0
+                });
+            }
+            Command::Frame(count) => {
+                let mut left = count;
+                run(cpu, false, &mut |frame| {
+                    if frame {
+                        left -= 1;
+                    }
+                    left == 0
+                });
+            }
+            Command::Continue => run(cpu, false, &mut |_| false),
+            Command::Break(Some(addr)) => {
+                if breakpoints.remove(&addr) {
+                    println!("Breakpoint at ${:04X} removed", addr);
+                } else {
+                    breakpoints.insert(addr);
+                    println!("Breakpoint at ${:04X}", addr);
+                }
+                continue;
+            }
+            Command::Break(None) => {
+                for addr in breakpoints.iter() {
+                    println!("${:04X}", addr);
+                }
+                continue;
+            }
+            Command::Memory(addr, len) => {
+                print_memory(cpu, addr, len);
+                continue;
+            }
+            Command::Write(addr, value) => {
+                cpu.mem_write(addr, value);
+                continue;
+            }
+            Command::Registers => {}
+            Command::Reset => {
+                cpu.reset();
+                halted = false;
+            }
+            Command::Help => {
+                println!("{}", COMMANDS);
+                continue;
+            }
+            Command::Quit => return Ok(()),
+        }
+        println!("{}", trace(cpu));
+    }
+}
+
+// Load the file given on the command line and debug it
+fn run(args: &[String]) -> Result<(), String> {
+    let options = Options::parse(args)?;
+    if options.help {
+        println!("{}\n\n{}", USAGE, COMMANDS);
+        return Ok(());
+    }
+    let path = match options.path.as_ref() {
+        Some(path) => path,
+        None => return Err(format!("No file given\n\n{}", USAGE)),
+    };
+    println!("Type help for the commands");
+    let result = match options.machine {
+        Machine::Nes => {
+            let mut builder = Nes::builder()
+                .rom_file(path)
+                .rom_database(RomDatabase::load_user()?)
+                .saves(false);
+            if let Some(region) = options.region {
+                builder = builder.region(region);
+            }
+            let mut nes = builder.build()?;
+            debug(nes.cpu_mut(), |cpu| {
+                if !cpu.bus.ppu.take_frame_complete() {
+                    return false;
+                }
+                cpu.bus.apu.take_samples();
+                true
+            })
+        }
+        Machine::Easy6502 => {
+            let program = std::fs::read(path)
+                .map_err(|err| format!("Cannot read {}: {}", path.display(), err))?;
+            let mut cpu = CPU::new();
+            cpu.load_at(&program, options.load_address, options.entry_point())?;
+            cpu.reset();
+            let mut machine = Easy6502::new();
+            debug(&mut cpu, |cpu| machine.instruction_done(cpu))
+        }
+    };
+    result.map_err(|err| err.to_string())
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Err(err) = run(&args) {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(parse_command("  "), Ok(None));
+        assert_eq!(parse_command("s"), Ok(Some(Command::Step(1))));
+        assert_eq!(parse_command("step 20"), Ok(Some(Command::Step(20))));
+        assert_eq!(parse_command("f 3"), Ok(Some(Command::Frame(3))));
+        assert_eq!(
+            parse_command("b $8000"),
+            Ok(Some(Command::Break(Some(0x8000))))
+        );
+        assert_eq!(parse_command("break"), Ok(Some(Command::Break(None))));
+        assert_eq!(
+            parse_command("m $0200 32"),
+            Ok(Some(Command::Memory(0x0200, 32)))
+        );
+        assert_eq!(
+            parse_command("w 0x10 $FF"),
+            Ok(Some(Command::Write(0x10, 0xFF)))
+        );
+        assert!(parse_command("s 0").is_err());
+        assert!(parse_command("w $10 $100").is_err());
+        assert!(parse_command("m").is_err());
+        assert!(parse_command("jump").is_err());
+    }
+}
@@ -0,0 +1,36 @@
+use rust_nes_emulator::cli::Options;
+use rust_nes_emulator::cpu::CPU;
+use rust_nes_emulator::easy6502;
+use rust_nes_emulator::frontend;
+
+const USAGE: &str = "\
+Usage: snake [--scale <N>] [--fullscreen] [--config <PATH>] [--trace <PATH>]
+
+Plays the snake game of easy6502 with the arrow keys (or controller 1).";
+
+// Play the snake game in a window
+fn run(args: &[String]) -> Result<(), String> {
+    let options = Options::parse(args)?;
+    if options.help {
+        println!("{}", USAGE);
+        return Ok(());
+    }
+    if options.path.is_some() || options.headless {
+        return Err(format!("Unexpected arguments, see --help\n\n{}", USAGE));
+    }
+    let config = options.load_config()?;
+    let options = options.with_config(&config);
+
+    let mut cpu = CPU::new();
+    cpu.load(easy6502::SNAKE_GAME.to_vec());
+    cpu.reset();
+    frontend::run_easy6502("Snake game", cpu, &options, &config)
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Err(err) = run(&args) {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,343 @@
+use crate::apu::APU;
+use crate::cpu::CpuBus;
+use crate::cpu::Mem;
+use crate::joypad::Joypad;
+use crate::mapper::Mapper;
+use crate::ppu::PPU;
+use crate::region::Region;
+use crate::state;
+
+//  _______________ $10000  _______________
+// | PRG-ROM       |       |               |
+// | Upper Bank    |       |               |
+// |_ _ _ _ _ _ _ _| $C000 | PRG-ROM       |
+// | PRG-ROM       |       |               |
+// | Lower Bank    |       |               |
+// |_______________| $8000 |_______________|
+// | SRAM          |       | SRAM          |
+// |_______________| $6000 |_______________|
+// | Expansion ROM |       | Expansion ROM |
+// |_______________| $4020 |_______________|
+// | I/O Registers |       |               |
+// |_ _ _ _ _ _ _ _| $4000 |               |
+// | Mirrors       |       | I/O Registers |
+// | $2000-$2007   |       |               |
+// |_ _ _ _ _ _ _ _| $2008 |               |
+// | I/O Registers |       |               |
+// |_______________| $2000 |_______________|
+// | Mirrors       |       |               |
+// | $0000-$07FF   |       |               |
+// |_ _ _ _ _ _ _ _| $0800 |               |
+// | RAM           |       | RAM           |
+// |_ _ _ _ _ _ _ _| $0200 |               |
+// | Stack         |       |               |
+// |_ _ _ _ _ _ _ _| $0100 |               |
+// | Zero Page     |       |               |
+// |_______________| $0000 |_______________|
+
+const RAM: u16 = 0x0000;
+const RAM_MIRRORS_END: u16 = 0x1FFF;
+const PPU_REGISTERS: u16 = 0x2000;
+const PPU_REGISTERS_MIRRORS_END: u16 = 0x3FFF;
+const APU_REGISTERS: u16 = 0x4000;
+const APU_REGISTERS_END: u16 = 0x4013;
+const OAM_DMA: u16 = 0x4014;
+const APU_STATUS: u16 = 0x4015;
+const JOYPAD1: u16 = 0x4016;
+const JOYPAD2: u16 = 0x4017;
+const CARTRIDGE_SPACE: u16 = 0x4020;
+const CARTRIDGE_SPACE_END: u16 = 0xFFFF;
+
+// What Bus::save_state writes: the RAM, PPU, APU, controllers, the bus timing and the
+// state of the mapper
+type BusState = (
+    Vec<u8>,
+    PPU,
+    APU,
+    Joypad,
+    Joypad,
+    u8,
+    usize,
+    usize,
+    u8,
+    Vec<u8>,
+);
+
+// Declare Bus struct
+// The NES CPU memory map, connecting the CPU to RAM and the memory-mapped devices
+pub struct Bus {
+    cpu_vram: [u8; 2048],
+    pub ppu: PPU,
+    pub apu: APU,
+    pub mapper: Box<dyn Mapper>,
+    pub joypad1: Joypad,
+    pub joypad2: Joypad,
+    open_bus: u8,        // last value driven on the data bus, returned by unmapped reads
+    stall_cycles: usize, // CPU cycles taken by DMA transfers, see CpuBus::take_stall_cycles
+    cycles: usize,
+    region: Region,
+    ppu_dots_per_5_cycles: u8,
+    ppu_dot_phase: u8, // fifths of a PPU dot carried over to the next CPU cycle
+}
+
+// Implement functionality of Bus
+impl Bus {
+    // Create new Bus object with the cartridge inserted, in an NTSC console
+    pub fn new(mapper: Box<dyn Mapper>) -> Self {
+        Bus::with_region(mapper, Region::Ntsc)
+    }
+
+    // Create new Bus object with the cartridge inserted, in a console of the region
+    pub fn with_region(mapper: Box<dyn Mapper>, region: Region) -> Self {
+        Bus {
+            cpu_vram: [0; 2048],
+            ppu: PPU::with_region(region),
+            apu: APU::with_region(region),
+            mapper,
+            joypad1: Joypad::new(),
+            joypad2: Joypad::new(),
+            open_bus: 0,
+            stall_cycles: 0,
+            cycles: 0,
+            region,
+            ppu_dots_per_5_cycles: region.ppu_dots_per_5_cpu_cycles(),
+            ppu_dot_phase: 0,
+        }
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    // The state of the console and the cartridge for save states, see state.rs
+    pub(crate) fn save_state(&self) -> Result<Vec<u8>, String> {
+        state::encode(&(
+            self.cpu_vram.as_slice(),
+            &self.ppu,
+            &self.apu,
+            &self.joypad1,
+            &self.joypad2,
+            self.open_bus,
+            self.stall_cycles,
+            self.cycles,
+            self.ppu_dot_phase,
+            self.mapper.save_state()?,
+        ))
+    }
+
+    // Go back to a state returned by save_state, nothing changes when it cannot be read
+    pub(crate) fn load_state(&mut self, data: &[u8]) -> Result<(), String> {
+        let (cpu_vram, ppu, apu, joypad1, joypad2, open_bus, stall_cycles, cycles, phase, mapper): BusState =
+            state::decode(data)?;
+        let cpu_vram = cpu_vram
+            .try_into()
+            .map_err(|_| "Save state is damaged: the RAM is not 2KB".to_string())?;
+        self.mapper.load_state(&mapper)?;
+        self.cpu_vram = cpu_vram;
+        self.ppu = ppu;
+        self.apu.load_state(apu);
+        self.joypad1 = joypad1;
+        self.joypad2 = joypad2;
+        self.open_bus = open_bus;
+        self.stall_cycles = stall_cycles;
+        self.cycles = cycles;
+        self.ppu_dot_phase = phase;
+        Ok(())
+    }
+
+    // Copy a 256 byte page to OAM, halting the CPU for 513 cycles (514 on an odd cycle)
+    fn oam_dma(&mut self, page: u8) {
+        let start = (page as u16) << 8;
+        for offset in 0..256 {
+            let data = self.mem_read(start + offset);
+            self.ppu.write_to_oam_data(data);
+        }
+        self.stall_cycles += 513 + self.cycles % 2;
+    }
+}
+
+// Implement functionality of Mem for Bus
+impl Mem for Bus {
+    fn mem_read(&mut self, addr: u16) -> u8 {
+        let data = match addr {
+            RAM..=RAM_MIRRORS_END => {
+                let mirror_down_addr = addr & 0b0000_0111_1111_1111;
+                self.cpu_vram[mirror_down_addr as usize]
+            }
+            PPU_REGISTERS..=PPU_REGISTERS_MIRRORS_END => {
+                self.ppu.read_register(addr, &mut *self.mapper)
+            }
+            // Bit 5 of the status register is not driven by the APU
+            APU_STATUS => (self.open_bus & 0x20) | (self.apu.read_status() & 0xDF),
+            // The controllers only drive D0; D5-D7 keep the open bus value, which is
+            // normally the high byte of the address ($40), so games see $40 or $41
+            JOYPAD1 => (self.open_bus & 0xE0) | self.joypad1.read(),
+            JOYPAD2 => (self.open_bus & 0xE0) | self.joypad2.read(),
+            CARTRIDGE_SPACE..=CARTRIDGE_SPACE_END => {
+                self.mapper.cpu_read(addr).unwrap_or(self.open_bus)
+            }
+            _ => self.open_bus,
+        };
+        self.open_bus = data;
+        data
+    }
+
+    fn mem_write(&mut self, addr: u16, data: u8) {
+        self.open_bus = data;
+        match addr {
+            RAM..=RAM_MIRRORS_END => {
+                let mirror_down_addr = addr & 0b0000_0111_1111_1111;
+                self.cpu_vram[mirror_down_addr as usize] = data;
+            }
+            PPU_REGISTERS..=PPU_REGISTERS_MIRRORS_END => {
+                self.mapper.ppu_register_write(addr, data);
+                self.ppu.write_register(addr, data, &mut *self.mapper);
+            }
+            OAM_DMA => self.oam_dma(data),
+            APU_REGISTERS..=APU_REGISTERS_END | APU_STATUS => {
+                self.apu.write_register(addr, data);
+            }
+            // The strobe line is shared by both controller ports
+            JOYPAD1 => {
+                self.joypad1.write(data);
+                self.joypad2.write(data);
+            }
+            // Writes to $4017 go to the APU frame counter, reads come from controller 2
+            JOYPAD2 => {
+                self.apu.write_register(addr, data);
+            }
+            CARTRIDGE_SPACE..=CARTRIDGE_SPACE_END => self.mapper.cpu_write(addr, data),
+            _ => { /* ignore writes to unmapped addresses */ }
+        }
+    }
+}
+
+// Implement functionality of CpuBus for Bus
+impl CpuBus for Bus {
+    fn tick(&mut self, cycles: u8) {
+        for _ in 0..cycles {
+            self.cycles += 1;
+            // 3 dots per cycle, PAL adds a fourth every 5 cycles
+            self.ppu_dot_phase += self.ppu_dots_per_5_cycles;
+            while self.ppu_dot_phase >= 5 {
+                self.ppu_dot_phase -= 5;
+                self.ppu.tick(&mut *self.mapper);
+            }
+            self.mapper.cpu_clock();
+            self.apu.set_expansion_output(self.mapper.audio_output());
+            self.apu.tick();
+
+            // The DMC reads its samples through the CPU bus, halting the CPU for 4 cycles
+            if let Some(addr) = self.apu.dmc_dma_request() {
+                let data = self.mem_read(addr);
+                self.apu.dmc_dma_complete(data);
+                self.stall_cycles += 4;
+            }
+        }
+    }
+
+    fn take_stall_cycles(&mut self) -> usize {
+        std::mem::take(&mut self.stall_cycles)
+    }
+
+    fn poll_nmi_status(&mut self) -> bool {
+        self.ppu.poll_nmi()
+    }
+
+    fn poll_irq_status(&self) -> bool {
+        self.apu.irq() || self.mapper.irq()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::test_rom;
+    use crate::cpu::CPU;
+    use crate::joypad::JoypadButton;
+    use crate::mapper::new_mapper;
+
+    fn test_bus(program: Vec<u8>) -> Bus {
+        Bus::new(new_mapper(test_rom(program)).unwrap())
+    }
+
+    #[test]
+    fn test_ram_mirroring() {
+        let mut bus = test_bus(vec![]);
+        bus.mem_write(0x0012, 0x55);
+        assert_eq!(bus.mem_read(0x0812), 0x55);
+        assert_eq!(bus.mem_read(0x1812), 0x55);
+    }
+
+    #[test]
+    fn test_joypad_read_keeps_open_bus_bits() {
+        let mut bus = test_bus(vec![]);
+        bus.joypad1.set_buttons(JoypadButton::BUTTON_A);
+        bus.mem_write(0x4016, 1);
+        bus.mem_write(0x4016, 0);
+
+        // LDA $4016 fetches the high byte of the operand ($40) just before the read
+        bus.mem_write(0x0001, 0x40);
+        bus.mem_read(0x0001);
+        assert_eq!(bus.mem_read(0x4016), 0x41);
+        bus.mem_read(0x0001);
+        assert_eq!(bus.mem_read(0x4016), 0x40);
+    }
+
+    #[test]
+    fn test_cartridge_and_ppu_are_mapped() {
+        let mut bus = test_bus(vec![0xA9, 0x05]);
+        assert_eq!(bus.mem_read(0x8001), 0x05);
+        assert_eq!(bus.mem_read_u16(0xFFFC), 0x8000);
+
+        // PPU registers are mirrored every 8 bytes
+        bus.mem_write(0x3456, 0x21);
+        bus.mem_write(0x2006, 0x00);
+        bus.mem_write(0x2007, 0x66);
+        bus.mem_write(0x2006, 0x21);
+        bus.mem_write(0x2006, 0x00);
+        bus.mem_read(0x2007);
+        assert_eq!(bus.mem_read(0x200F), 0x66);
+    }
+
+    #[test]
+    fn test_oam_dma() {
+        let mut bus = test_bus(vec![]);
+        for i in 0..256 {
+            bus.mem_write(0x0200 + i, i as u8);
+        }
+        bus.mem_write(0x2003, 0x10);
+        bus.mem_write(0x4014, 0x02);
+        assert_eq!(bus.ppu.oam_data[0x10], 0x00);
+        assert_eq!(bus.ppu.oam_data[0x0F], 0xFF);
+        assert_eq!(bus.take_stall_cycles(), 513);
+    }
+
+    #[test]
+    fn test_pal_ppu_clock() {
+        let mut bus = Bus::with_region(new_mapper(test_rom(vec![])).unwrap(), Region::Pal);
+        bus.tick(5);
+        assert_eq!(bus.ppu.dot, 16);
+        let mut bus = test_bus(vec![]);
+        bus.tick(5);
+        assert_eq!(bus.ppu.dot, 15);
+    }
+
+    #[test]
+    fn test_vblank_nmi_reaches_cpu() {
+        // Enable NMI and spin; the handler counts frames in $10 and stops on the second
+        let mut rom = test_rom(vec![0xA9, 0x80, 0x8D, 0x00, 0x20, 0x4C, 0x05, 0x80]);
+        rom.prg_rom[0x10..0x1A]
+            .copy_from_slice(&[0xE6, 0x10, 0xA5, 0x10, 0xC9, 0x02, 0xD0, 0x01, 0x00, 0x40]);
+        rom.prg_rom[0x7FFA] = 0x10;
+        rom.prg_rom[0x7FFB] = 0x80;
+        let mut cpu = CPU::with_bus(Bus::new(new_mapper(rom).unwrap()));
+        cpu.reset();
+        cpu.run();
+
+        assert_eq!(cpu.mem_read(0x10), 2);
+        // Two frames of 262 lines of 341 dots, at 3 dots per CPU cycle
+        let frame_cycles = 262 * 341 / 3;
+        assert!(cpu.cycles > frame_cycles && cpu.cycles < 2 * frame_cycles + 100);
+    }
+}
@@ -0,0 +1,360 @@
+// iNES and NES 2.0 file format https://www.nesdev.org/wiki/INES https://www.nesdev.org/wiki/NES_2.0
+use crate::region::Region;
+use serde::Deserialize;
+use serde::Serialize;
+
+const NES_TAG: [u8; 4] = [0x4E, 0x45, 0x53, 0x1A];
+const HEADER_SIZE: usize = 16;
+const TRAINER_SIZE: usize = 512;
+const PRG_ROM_PAGE_SIZE: usize = 16384;
+const CHR_ROM_PAGE_SIZE: usize = 8192;
+const PRG_RAM_PAGE_SIZE: usize = 8192;
+
+// Nametable layout, fixed by the board or controlled by the mapper
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub enum Mirroring {
+    Vertical,
+    Horizontal,
+    FourScreen,
+    SingleScreenLower,
+    SingleScreenUpper,
+    // VRAM page of each of the four nametables, for mappers that map them freely
+    Mapped([u8; 4]),
+}
+
+// Declare Rom struct
+pub struct Rom {
+    pub prg_rom: Vec<u8>,
+    pub chr_rom: Vec<u8>,         // empty when the board has CHR-RAM
+    pub trainer: Option<Vec<u8>>, // 512 bytes the mapper loads at $7000
+    pub mapper: u16,
+    pub submapper: u8,
+    pub screen_mirroring: Mirroring,
+    pub battery: bool,
+    pub prg_ram_size: usize,   // volatile PRG-RAM at $6000-$7FFF
+    pub prg_nvram_size: usize, // battery-backed PRG-RAM
+    pub chr_ram_size: usize,
+    pub chr_nvram_size: usize,
+    pub nes2: bool,
+    pub region: Option<Region>, // None when the header does not tell
+}
+
+// Implement functionality of Rom
+impl Rom {
+    // Parse the contents of a .nes file
+    pub fn new(raw: &[u8]) -> Result<Rom, String> {
+        if raw.len() < HEADER_SIZE || raw[0..4] != NES_TAG {
+            return Err("File is not in iNES file format".to_string());
+        }
+
+        let nes2 = raw[7] & 0b0000_1100 == 0b0000_1000;
+        // Headers written by old tools can have garbage ("DiskDude!") in bytes 7-15
+        let legacy_garbage = !nes2 && raw[12..16].iter().any(|&byte| byte != 0);
+
+        let mut mapper = (raw[6] >> 4) as u16;
+        if !legacy_garbage {
+            mapper |= (raw[7] & 0b1111_0000) as u16;
+        }
+
+        let four_screen = raw[6] & 0b1000 != 0;
+        let vertical_mirroring = raw[6] & 0b1 != 0;
+        let screen_mirroring = match (four_screen, vertical_mirroring) {
+            (true, _) => Mirroring::FourScreen,
+            (false, true) => Mirroring::Vertical,
+            (false, false) => Mirroring::Horizontal,
+        };
+        let battery = raw[6] & 0b10 != 0;
+        let has_trainer = raw[6] & 0b100 != 0;
+
+        let too_large = || "ROM size in header is too large".to_string();
+        let (prg_rom_size, chr_rom_size) = if nes2 {
+            (
+                nes2_rom_size(raw[4], raw[9] & 0b1111, PRG_ROM_PAGE_SIZE).ok_or_else(too_large)?,
+                nes2_rom_size(raw[5], raw[9] >> 4, CHR_ROM_PAGE_SIZE).ok_or_else(too_large)?,
+            )
+        } else {
+            (
+                raw[4] as usize * PRG_ROM_PAGE_SIZE,
+                raw[5] as usize * CHR_ROM_PAGE_SIZE,
+            )
+        };
+
+        let mut submapper = 0;
+        let (prg_ram_size, prg_nvram_size, chr_ram_size, chr_nvram_size);
+        if nes2 {
+            mapper |= ((raw[8] & 0b1111) as u16) << 8;
+            submapper = raw[8] >> 4;
+            prg_ram_size = nes2_ram_size(raw[10] & 0b1111);
+            prg_nvram_size = nes2_ram_size(raw[10] >> 4);
+            chr_ram_size = nes2_ram_size(raw[11] & 0b1111);
+            chr_nvram_size = nes2_ram_size(raw[11] >> 4);
+        } else {
+            // iNES only knows the PRG-RAM size, where 0 means 8KB for compatibility
+            let prg_ram = (raw[8].max(1) as usize) * PRG_RAM_PAGE_SIZE;
+            if battery {
+                prg_ram_size = 0;
+                prg_nvram_size = prg_ram;
+            } else {
+                prg_ram_size = prg_ram;
+                prg_nvram_size = 0;
+            }
+            chr_ram_size = if chr_rom_size == 0 {
+                CHR_ROM_PAGE_SIZE
+            } else {
+                0
+            };
+            chr_nvram_size = 0;
+        }
+
+        // NES 2.0 has a timing field, iNES a rarely set PAL flag
+        let region = if nes2 {
+            match raw[12] & 0b11 {
+                0 => Some(Region::Ntsc),
+                1 => Some(Region::Pal),
+                3 => Some(Region::Dendy),
+                _ => None, // runs on several regions
+            }
+        } else if !legacy_garbage && raw[9] & 0b1 != 0 {
+            Some(Region::Pal)
+        } else {
+            None
+        };
+
+        let trainer_start = HEADER_SIZE;
+        let prg_rom_start = trainer_start + if has_trainer { TRAINER_SIZE } else { 0 };
+        let chr_rom_start = prg_rom_start
+            .checked_add(prg_rom_size)
+            .ok_or_else(too_large)?;
+        let chr_rom_end = chr_rom_start
+            .checked_add(chr_rom_size)
+            .ok_or_else(too_large)?;
+        if raw.len() < chr_rom_end {
+            return Err(format!(
+                "File is truncated: expected {} bytes of PRG-ROM and {} bytes of CHR-ROM",
+                prg_rom_size, chr_rom_size
+            ));
+        }
+        if prg_rom_size == 0 {
+            return Err("File has no PRG-ROM".to_string());
+        }
+
+        Ok(Rom {
+            prg_rom: raw[prg_rom_start..chr_rom_start].to_vec(),
+            chr_rom: raw[chr_rom_start..chr_rom_end].to_vec(),
+            trainer: if has_trainer {
+                Some(raw[trainer_start..prg_rom_start].to_vec())
+            } else {
+                None
+            },
+            mapper,
+            submapper,
+            screen_mirroring,
+            battery,
+            prg_ram_size,
+            prg_nvram_size,
+            chr_ram_size,
+            chr_nvram_size,
+            nes2,
+            region,
+        })
+    }
+
+    // Total PRG-RAM at $6000-$7FFF, battery-backed or not
+    pub fn total_prg_ram_size(&self) -> usize {
+        self.prg_ram_size + self.prg_nvram_size
+    }
+
+    // Total CHR-RAM, used when the board has no CHR-ROM
+    pub fn total_chr_ram_size(&self) -> usize {
+        self.chr_ram_size + self.chr_nvram_size
+    }
+}
+
+// NES 2.0 ROM sizes: pages, or exponent-multiplier notation when the MSB nibble is $F.
+// None when the size does not fit in memory
+fn nes2_rom_size(lsb: u8, msb: u8, page_size: usize) -> Option<usize> {
+    if msb == 0b1111 {
+        let exponent = (lsb >> 2) as u32;
+        let multiplier = (lsb & 0b11) as usize * 2 + 1;
+        1_usize.checked_shl(exponent)?.checked_mul(multiplier)
+    } else {
+        (((msb as usize) << 8) | lsb as usize).checked_mul(page_size)
+    }
+}
+
+// NES 2.0 RAM sizes are shift counts: 64 << shift bytes, 0 for none
+fn nes2_ram_size(shift: u8) -> usize {
+    if shift == 0 {
+        0
+    } else {
+        64 << shift
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    pub struct TestRom {
+        pub header: Vec<u8>,
+        pub trainer: Option<Vec<u8>>,
+        pub prg_rom: Vec<u8>,
+        pub chr_rom: Vec<u8>,
+    }
+
+    pub fn create_rom(rom: TestRom) -> Vec<u8> {
+        let mut result = Vec::new();
+        result.extend(rom.header.iter());
+        if let Some(trainer) = rom.trainer {
+            result.extend(trainer.iter());
+        }
+        result.extend(rom.prg_rom.iter());
+        result.extend(rom.chr_rom.iter());
+        result
+    }
+
+    // NROM with 32KB of PRG-ROM holding the program at $8000 and the reset vector
+    // pointing to it, plus 8KB of CHR-ROM
+    pub fn test_rom(program: Vec<u8>) -> Rom {
+        let mut prg_rom = vec![0; 2 * PRG_ROM_PAGE_SIZE];
+        prg_rom[..program.len()].copy_from_slice(&program);
+        prg_rom[0x7FFC] = 0x00;
+        prg_rom[0x7FFD] = 0x80;
+        let raw = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x01, 00, 00, 00, 00, 00, 00, 00, 00, 00,
+            ],
+            trainer: None,
+            prg_rom,
+            chr_rom: vec![2; CHR_ROM_PAGE_SIZE],
+        });
+        Rom::new(&raw).unwrap()
+    }
+
+    // A ROM for mapper tests where every byte of the PRG-ROM and CHR-ROM holds its bank
+    // number, for banks of prg_bank and chr_bank bytes
+    pub fn banked_rom(
+        mapper: u16,
+        prg_size: usize,
+        chr_size: usize,
+        prg_bank: usize,
+        chr_bank: usize,
+    ) -> Rom {
+        let mut rom = test_rom(vec![]);
+        rom.mapper = mapper;
+        rom.prg_rom = (0..prg_size).map(|i| (i / prg_bank) as u8).collect();
+        rom.chr_rom = (0..chr_size).map(|i| (i / chr_bank) as u8).collect();
+        rom
+    }
+
+    #[test]
+    fn test_ines() {
+        let test_rom = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x31, 00, 00, 00, 00, 00, 00, 00, 00, 00,
+            ],
+            trainer: None,
+            prg_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; CHR_ROM_PAGE_SIZE],
+        });
+
+        let rom: Rom = Rom::new(&test_rom).unwrap();
+
+        assert_eq!(rom.chr_rom, vec!(2; CHR_ROM_PAGE_SIZE));
+        assert_eq!(rom.prg_rom, vec!(1; 2 * PRG_ROM_PAGE_SIZE));
+        assert_eq!(rom.mapper, 3);
+        assert_eq!(rom.screen_mirroring, Mirroring::Vertical);
+        assert_eq!(rom.prg_ram_size, PRG_RAM_PAGE_SIZE);
+        assert_eq!(rom.chr_ram_size, 0);
+        assert!(!rom.nes2);
+        assert_eq!(rom.region, None);
+    }
+
+    #[test]
+    fn test_with_trainer_and_chr_ram() {
+        let test_rom = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x02, 0x00, 0x16, 0x10, 00, 00, 00, 00, 00, 00, 00, 00,
+            ],
+            trainer: Some(vec![0; TRAINER_SIZE]),
+            prg_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![],
+        });
+
+        let rom: Rom = Rom::new(&test_rom).unwrap();
+
+        assert_eq!(rom.prg_rom, vec!(1; 2 * PRG_ROM_PAGE_SIZE));
+        assert!(rom.trainer.is_some());
+        assert_eq!(rom.mapper, 0x11);
+        assert_eq!(rom.screen_mirroring, Mirroring::Horizontal);
+        assert!(rom.battery);
+        assert_eq!(rom.prg_nvram_size, PRG_RAM_PAGE_SIZE);
+        assert_eq!(rom.chr_ram_size, CHR_ROM_PAGE_SIZE);
+    }
+
+    #[test]
+    fn test_nes2() {
+        let test_rom = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x01, 0x00, 0x18, 0x08, 0x31, 0x00, 0x07, 0x07, 0x03, 00,
+                00, 00,
+            ],
+            trainer: None,
+            prg_rom: vec![1; PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![],
+        });
+
+        let rom: Rom = Rom::new(&test_rom).unwrap();
+
+        assert!(rom.nes2);
+        assert_eq!(rom.mapper, 0x101);
+        assert_eq!(rom.submapper, 3);
+        assert_eq!(rom.screen_mirroring, Mirroring::FourScreen);
+        assert_eq!(rom.prg_ram_size, 8192);
+        assert_eq!(rom.chr_ram_size, 8192);
+        assert_eq!(rom.region, Some(Region::Dendy));
+    }
+
+    #[test]
+    fn test_nes2_exponent_size() {
+        assert_eq!(
+            nes2_rom_size(0b0000_1001, 0b1111, PRG_ROM_PAGE_SIZE),
+            Some(4 * 3)
+        );
+        assert_eq!(
+            nes2_rom_size(0x10, 0x1, PRG_ROM_PAGE_SIZE),
+            Some(0x110 * PRG_ROM_PAGE_SIZE)
+        );
+    }
+
+    #[test]
+    fn test_nes2_size_too_large() {
+        // 2^63 * 7 bytes of PRG-ROM, and 2^62 * 3 of PRG-ROM plus as much CHR-ROM
+        for (prg, chr) in [(0xFF, 0x00), (0xF9, 0xF9)] {
+            let mut raw = vec![
+                0x4E, 0x45, 0x53, 0x1A, prg, chr, 0x00, 0x08, 00, 0xFF, 00, 00, 00, 00, 00, 00,
+            ];
+            raw.extend(vec![0; PRG_ROM_PAGE_SIZE]);
+            let err = Rom::new(&raw).err().unwrap();
+            assert_eq!(err, "ROM size in header is too large");
+        }
+    }
+
+    #[test]
+    fn test_unsupported_format() {
+        let test_rom = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x00, 0x01, 0x01, 0x00, 0x00, 00, 00, 00, 00, 00, 00, 00, 00,
+            ],
+            trainer: None,
+            prg_rom: vec![1; PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; CHR_ROM_PAGE_SIZE],
+        });
+        assert!(Rom::new(&test_rom).is_err());
+
+        let mut truncated = test_rom.clone();
+        truncated[3] = 0x1A;
+        truncated.truncate(HEADER_SIZE + 100);
+        assert!(Rom::new(&truncated).is_err());
+    }
+}
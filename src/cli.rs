@@ -0,0 +1,534 @@
+use crate::config::Config;
+use crate::headless::Condition;
+use crate::pacer::SyncMode;
+use crate::region::Region;
+use crate::screenshot::ScreenshotSize;
+use crate::state;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+pub const USAGE: &str = "\
+Usage: rust-nes-emulator [OPTIONS] FILE
+
+Runs FILE: a .nes ROM, an .fds disk image, an .nsf/.nsfe tune, or a raw 6502 binary
+with --machine easy6502. The snake game is played with the snake program.
+
+Options:
+  --machine <nes|easy6502>  Machine to run FILE on [default: nes]
+  --load-address <ADDR>     Where easy6502 binaries are loaded [default: $0600]
+  --entry <ADDR>            Where easy6502 binaries start [default: the load address]
+  --scale <N>               Window size as a multiple of the picture [default: 3 for
+                            the NES, 10 for easy6502]
+  --fullscreen              Fill the screen instead of opening a window
+  --region <ntsc|pal|dendy> Console timing, instead of the one detected from the ROM
+  --headless                Run without a window or sound, needs --frames
+  --frames <N>              Stop after N frames
+  --until <ADDR>=<VALUE>    Stop a headless run once the byte at ADDR is VALUE, checked
+                            at the end of every frame. ADDR!=VALUE waits for it not
+                            to be VALUE
+  --until-pc <ADDR>         Stop a headless run when the CPU reaches ADDR
+  --exit-code <ADDR>        Exit a headless run with the byte at ADDR as the status
+  --dump-frame <PATH>       Write the last frame of a headless run to PATH (PPM)
+  --dump-ram <PATH>         Write the RAM at the end of a headless run to PATH
+  --trace <PATH>            Write every instruction the CPU runs to PATH
+  --screenshot <PATH>       Write the last frame to PATH as a PNG image
+  --screenshot-size <native|display>
+                            Screenshots at the size of the picture or of the
+                            window [default: native]
+  --wav-out <PATH>          Record the sound of a NES game to PATH (WAV) from the start
+  --split-channels          With --wav-out, also write every APU channel to its own
+                            file next to PATH
+  --record <PATH>           Record the video of a NES game to PATH (Y4M) from the
+                            start, with the sound in a .wav next to it
+  --state <SLOT>            Load the save state in SLOT (0-9) on start, the .st<SLOT>
+                            file next to PATH. The save state hotkeys use SLOT
+  --vsync                   Pace by the display refresh
+  --audio-sync              Pace by the sound card
+  --config <PATH>           Read the settings from PATH instead of the user's
+                            config.toml
+  --wav                     Render every track of an NSF to WAV files
+  -h, --help                Show this message
+
+Addresses and values are hexadecimal with a $ or 0x prefix (e.g. $0600), decimal
+otherwise. A headless run with --until exits with status 3 when the condition is not
+met within --frames.
+Settings not given here come from config.toml and from a .toml next to FILE.";
+
+// Declare Machine enum
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Machine {
+    #[default]
+    Nes,
+    Easy6502, // 64KB of flat memory with the 32x32 screen at $0200, as on easy6502
+}
+
+// Declare Options struct
+// Settings from the command line
+#[derive(Debug, Clone, PartialEq)]
+pub struct Options {
+    pub path: Option<PathBuf>,
+    pub machine: Machine,
+    pub load_address: u16,
+    pub entry: Option<u16>,
+    pub scale: Option<u32>,
+    pub fullscreen: bool,
+    pub region: Option<Region>,
+    pub headless: bool,
+    pub frames: Option<u64>,
+    pub until: Option<Condition>,
+    pub exit_code: Option<u16>,
+    pub dump_frame: Option<PathBuf>,
+    pub dump_ram: Option<PathBuf>,
+    pub trace: Option<PathBuf>,
+    pub screenshot: Option<PathBuf>,
+    pub screenshot_size: Option<ScreenshotSize>,
+    pub wav_out: Option<PathBuf>,
+    pub split_channels: bool,
+    pub record: Option<PathBuf>,
+    pub state_slot: Option<u8>,
+    pub sync: Option<SyncMode>,
+    pub config: Option<PathBuf>,
+    pub wav: bool,
+    pub help: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            path: None,
+            machine: Machine::Nes,
+            load_address: 0x0600,
+            entry: None,
+            scale: None,
+            fullscreen: false,
+            region: None,
+            headless: false,
+            frames: None,
+            until: None,
+            exit_code: None,
+            dump_frame: None,
+            dump_ram: None,
+            trace: None,
+            screenshot: None,
+            screenshot_size: None,
+            wav_out: None,
+            split_channels: false,
+            record: None,
+            state_slot: None,
+            sync: None,
+            config: None,
+            wav: false,
+            help: false,
+        }
+    }
+}
+
+// $0600, 0x0600 or 1536
+pub fn parse_address(text: &str) -> Result<u16, String> {
+    let hex = text
+        .strip_prefix('$')
+        .or_else(|| text.strip_prefix("0x"))
+        .or_else(|| text.strip_prefix("0X"));
+    let parsed = match hex {
+        Some(digits) => u16::from_str_radix(digits, 16),
+        None => text.parse(),
+    };
+    parsed.map_err(|_| format!("Invalid address {}: expected e.g. $0600", text))
+}
+
+fn parse_byte(text: &str) -> Result<u8, String> {
+    let value = parse_address(text).map_err(|_| format!("Invalid value {}", text))?;
+    u8::try_from(value).map_err(|_| format!("Invalid value {}: bytes go up to $FF", text))
+}
+
+// ADDR=VALUE or ADDR!=VALUE
+fn parse_condition(text: &str) -> Result<Condition, String> {
+    let (addr, value, equal) = match text.split_once("!=") {
+        Some((addr, value)) => (addr, value, false),
+        None => match text.split_once('=') {
+            Some((addr, value)) => (addr, value, true),
+            None => {
+                return Err(format!(
+                    "Invalid condition {}: expected e.g. $00F0=1 or $00F0!=0",
+                    text
+                ))
+            }
+        },
+    };
+    Ok(Condition::Memory {
+        addr: parse_address(addr)?,
+        value: parse_byte(value)?,
+        equal,
+    })
+}
+
+// File name stem for output files: games/zelda.nes -> zelda
+pub fn file_stem(path: &str, default: &str) -> String {
+    Path::new(path)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| default.to_string())
+}
+
+// Name for a file written while running: <stem>-<unix time>.<extension>, with a
+// number added when that file exists already
+pub fn output_path(stem: &str, extension: &str) -> PathBuf {
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default();
+    let mut path = PathBuf::from(format!("{}-{}.{}", stem, time, extension));
+    let mut count = 1;
+    while path.exists() {
+        count += 1;
+        path = PathBuf::from(format!("{}-{}-{}.{}", stem, time, count, extension));
+    }
+    path
+}
+
+fn parse_number<T: std::str::FromStr>(option: &str, text: &str) -> Result<T, String> {
+    text.parse()
+        .map_err(|_| format!("Invalid value for {}: {}", option, text))
+}
+
+// Implement functionality of Options
+impl Options {
+    // Parse the arguments after the program name
+    pub fn parse(args: &[String]) -> Result<Options, String> {
+        let mut options = Options::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let arg = arg.as_str();
+            let mut value = || {
+                args.next()
+                    .map(String::as_str)
+                    .ok_or_else(|| format!("{} needs a value", arg))
+            };
+            match arg {
+                "--machine" => {
+                    options.machine = match value()? {
+                        "nes" => Machine::Nes,
+                        "easy6502" => Machine::Easy6502,
+                        other => {
+                            return Err(format!(
+                                "Unknown machine {}: expected nes or easy6502",
+                                other
+                            ))
+                        }
+                    }
+                }
+                "--load-address" => options.load_address = parse_address(value()?)?,
+                "--entry" => options.entry = Some(parse_address(value()?)?),
+                "--scale" => {
+                    let scale = parse_number(arg, value()?)?;
+                    if !(1..=16).contains(&scale) {
+                        return Err(format!("--scale must be 1 to 16, not {}", scale));
+                    }
+                    options.scale = Some(scale);
+                }
+                "--fullscreen" => options.fullscreen = true,
+                "--region" => {
+                    let name = value()?;
+                    options.region = Some(Region::from_name(name).ok_or_else(|| {
+                        format!("Unknown region {}: expected ntsc, pal or dendy", name)
+                    })?)
+                }
+                "--headless" => options.headless = true,
+                "--frames" => options.frames = Some(parse_number(arg, value()?)?),
+                "--until" | "--until-pc" => {
+                    if options.until.is_some() {
+                        return Err("Only one of --until and --until-pc can be given".to_string());
+                    }
+                    let text = value()?;
+                    options.until = Some(match arg {
+                        "--until" => parse_condition(text)?,
+                        _ => Condition::ProgramCounter(parse_address(text)?),
+                    });
+                }
+                "--exit-code" => options.exit_code = Some(parse_address(value()?)?),
+                "--dump-frame" => options.dump_frame = Some(PathBuf::from(value()?)),
+                "--dump-ram" => options.dump_ram = Some(PathBuf::from(value()?)),
+                "--trace" => options.trace = Some(PathBuf::from(value()?)),
+                "--screenshot" => options.screenshot = Some(PathBuf::from(value()?)),
+                "--screenshot-size" => {
+                    options.screenshot_size = Some(match value()? {
+                        "native" => ScreenshotSize::Native,
+                        "display" => ScreenshotSize::Display,
+                        other => {
+                            return Err(format!(
+                                "Unknown screenshot size {}: expected native or display",
+                                other
+                            ))
+                        }
+                    })
+                }
+                "--wav-out" => options.wav_out = Some(PathBuf::from(value()?)),
+                "--split-channels" => options.split_channels = true,
+                "--record" => options.record = Some(PathBuf::from(value()?)),
+                "--state" => {
+                    let slot = parse_number(arg, value()?)?;
+                    if slot >= state::SLOT_COUNT {
+                        return Err(format!(
+                            "--state must be a slot from 0 to {}, not {}",
+                            state::SLOT_COUNT - 1,
+                            slot
+                        ));
+                    }
+                    options.state_slot = Some(slot);
+                }
+                "--vsync" => options.sync = Some(SyncMode::Vsync),
+                "--audio-sync" => options.sync = Some(SyncMode::Audio),
+                "--config" => options.config = Some(PathBuf::from(value()?)),
+                "--wav" => options.wav = true,
+                "-h" | "--help" => options.help = true,
+                _ if arg.starts_with('-') => {
+                    return Err(format!("Unknown option {}, see --help", arg));
+                }
+                _ if options.path.is_some() => {
+                    return Err(format!("Unexpected argument {}: only one file is run", arg));
+                }
+                _ => options.path = Some(PathBuf::from(arg)),
+            }
+        }
+
+        if options.headless && options.frames.is_none() {
+            return Err("--headless needs --frames to know when to stop".to_string());
+        }
+        let headless_only = options.until.is_some()
+            || options.exit_code.is_some()
+            || options.dump_frame.is_some()
+            || options.dump_ram.is_some();
+        if headless_only && !options.headless {
+            return Err(
+                "--until, --until-pc, --exit-code, --dump-frame and --dump-ram need --headless"
+                    .to_string(),
+            );
+        }
+        if options.split_channels && options.wav_out.is_none() {
+            return Err("--split-channels needs --wav-out".to_string());
+        }
+        let recording = options.wav_out.is_some() || options.record.is_some();
+        if recording && options.machine == Machine::Easy6502 {
+            return Err("--wav-out and --record only record NES games".to_string());
+        }
+        let raw_binary = options.machine == Machine::Easy6502 && options.path.is_some();
+        let default_load_address = Options::default().load_address;
+        if !raw_binary && (options.entry.is_some() || options.load_address != default_load_address)
+        {
+            return Err(
+                "--load-address and --entry are for binaries run with --machine easy6502"
+                    .to_string(),
+            );
+        }
+        Ok(options)
+    }
+
+    // Settings not given on the command line come from the config files
+    pub fn with_config(&self, config: &Config) -> Options {
+        Options {
+            scale: self.scale.or(config.video.scale),
+            fullscreen: self.fullscreen || config.video.fullscreen,
+            sync: self.sync.or(Some(config.video.sync)),
+            screenshot_size: self.screenshot_size.or(Some(config.video.screenshot_size)),
+            ..self.clone()
+        }
+    }
+
+    // Read the user's config.toml, or the one given with --config, then the .toml next
+    // to the file being run
+    pub fn load_config(&self) -> Result<Config, String> {
+        let mut paths = Vec::new();
+        match self.config.as_ref() {
+            Some(path) if !path.exists() => {
+                return Err(format!("Config file {} does not exist", path.display()));
+            }
+            Some(path) => paths.push(path.clone()),
+            None => paths.extend(Config::user_path()),
+        }
+        if let Some(path) = self.path.as_ref() {
+            paths.push(Config::rom_path(path));
+        }
+        Config::load(&paths)
+    }
+
+    // How many pixels of a screenshot a pixel of the picture becomes, default_scale
+    // being the window's default
+    pub fn screenshot_scale(&self, default_scale: u32) -> usize {
+        match self.screenshot_size.unwrap_or_default() {
+            ScreenshotSize::Native => 1,
+            ScreenshotSize::Display => self.scale.unwrap_or(default_scale) as usize,
+        }
+    }
+
+    pub fn sync_mode(&self) -> SyncMode {
+        self.sync.unwrap_or_default()
+    }
+
+    // Start of an easy6502 binary
+    pub fn entry_point(&self) -> u16 {
+        self.entry.unwrap_or(self.load_address)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Options, String> {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        Options::parse(&args)
+    }
+
+    #[test]
+    fn test_parse_options() {
+        assert_eq!(parse(&[]).unwrap(), Options::default());
+
+        let options = parse(&[
+            "game.bin",
+            "--machine",
+            "easy6502",
+            "--load-address",
+            "$8000",
+            "--entry",
+            "0x8010",
+            "--scale",
+            "4",
+            "--region",
+            "PAL",
+            "--headless",
+            "--frames",
+            "600",
+            "--trace",
+            "cpu.log",
+            "--state",
+            "2",
+            "--audio-sync",
+            "--screenshot",
+            "last.png",
+            "--screenshot-size",
+            "display",
+        ])
+        .unwrap();
+        assert_eq!(options.path, Some(PathBuf::from("game.bin")));
+        assert_eq!(options.machine, Machine::Easy6502);
+        assert_eq!(options.load_address, 0x8000);
+        assert_eq!(options.entry_point(), 0x8010);
+        assert_eq!(options.scale, Some(4));
+        assert_eq!(options.region, Some(Region::Pal));
+        assert!(options.headless);
+        assert_eq!(options.frames, Some(600));
+        assert_eq!(options.trace, Some(PathBuf::from("cpu.log")));
+        assert_eq!(options.state_slot, Some(2));
+        assert_eq!(options.sync_mode(), SyncMode::Audio);
+        assert_eq!(options.until, None);
+        assert_eq!(options.screenshot, Some(PathBuf::from("last.png")));
+        assert_eq!(options.screenshot_scale(10), 4);
+
+        // The command line wins over the config
+        let mut config = Config::default();
+        config.video.scale = Some(2);
+        config.video.fullscreen = true;
+        config.video.sync = SyncMode::Vsync;
+        let options = options.with_config(&config);
+        assert_eq!(options.scale, Some(4));
+        assert!(options.fullscreen);
+        assert_eq!(options.sync_mode(), SyncMode::Audio);
+        let options = parse(&[]).unwrap().with_config(&config);
+        assert_eq!(options.scale, Some(2));
+        assert_eq!(options.sync_mode(), SyncMode::Vsync);
+        assert_eq!(options.screenshot_scale(3), 1);
+        let options = parse(&["a.bin", "--machine", "easy6502", "--load-address", "1536"]);
+        assert_eq!(options.unwrap().entry_point(), 0x0600);
+    }
+
+    #[test]
+    fn test_parse_headless_options() {
+        let options = parse(&[
+            "test.nes",
+            "--headless",
+            "--frames",
+            "600",
+            "--until",
+            "$6000!=$80",
+            "--exit-code",
+            "0x6000",
+            "--dump-frame",
+            "last.ppm",
+            "--dump-ram",
+            "ram.bin",
+            "--record",
+            "bug.y4m",
+            "--wav-out",
+            "music.wav",
+            "--split-channels",
+        ])
+        .unwrap();
+        assert_eq!(
+            options.until,
+            Some(Condition::Memory {
+                addr: 0x6000,
+                value: 0x80,
+                equal: false
+            })
+        );
+        assert_eq!(options.exit_code, Some(0x6000));
+        assert_eq!(options.dump_frame, Some(PathBuf::from("last.ppm")));
+        assert_eq!(options.dump_ram, Some(PathBuf::from("ram.bin")));
+        assert_eq!(options.record, Some(PathBuf::from("bug.y4m")));
+        assert_eq!(options.wav_out, Some(PathBuf::from("music.wav")));
+        assert!(options.split_channels);
+
+        let args = ["--headless", "--frames", "1", "--until", "16=1"];
+        let options = parse(&args).unwrap();
+        assert_eq!(
+            options.until,
+            Some(Condition::Memory {
+                addr: 0x10,
+                value: 1,
+                equal: true
+            })
+        );
+        let args = ["--headless", "--frames", "1", "--until-pc", "$8000"];
+        let options = parse(&args).unwrap();
+        assert_eq!(options.until, Some(Condition::ProgramCounter(0x8000)));
+
+        let args = ["--headless", "--frames", "1", "--until", "$10=$100"];
+        assert!(parse(&args).is_err());
+        let args = ["--headless", "--frames", "1", "--until", "$10"];
+        assert!(parse(&args).unwrap_err().contains("Invalid condition"));
+        let args = [
+            "--headless",
+            "--frames",
+            "1",
+            "--until",
+            "$10=1",
+            "--until-pc",
+            "0",
+        ];
+        assert!(parse(&args).is_err());
+        assert!(parse(&["--dump-ram", "ram.bin"])
+            .unwrap_err()
+            .contains("--headless"));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(parse(&["--frames"]).unwrap_err().contains("needs a value"));
+        assert!(parse(&["--frames", "ten"]).is_err());
+        assert!(parse(&["--scale", "0"]).is_err());
+        assert!(parse(&["--machine", "c64"]).is_err());
+        assert!(parse(&["--region", "secam"]).is_err());
+        assert!(parse(&["--screenshot-size", "huge"]).is_err());
+        assert!(parse(&["--state", "10"]).is_err());
+        assert!(parse(&["a.bin", "--machine", "easy6502", "--load-address", "$10000"]).is_err());
+        assert!(parse(&["a.nes", "--entry", "$8000"]).is_err());
+        assert!(parse(&["--turbo"]).unwrap_err().contains("Unknown option"));
+        assert!(parse(&["a.nes", "b.nes"]).is_err());
+        assert!(parse(&["a.nes", "--headless"]).is_err());
+        assert!(parse(&["--machine", "easy6502", "--record", "a.y4m"]).is_err());
+        assert!(parse(&["--machine", "easy6502", "--wav-out", "a.wav"]).is_err());
+        assert!(parse(&["a.nes", "--split-channels"]).is_err());
+    }
+}
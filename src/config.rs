@@ -0,0 +1,508 @@
+use crate::joypad::JoypadButton;
+use crate::pacer::SyncMode;
+use crate::screenshot::ScreenshotSize;
+use serde::de;
+use serde::Deserialize;
+use serde::Deserializer;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::path::PathBuf;
+
+const CONFIG_DIR: &str = "rust-nes-emulator";
+const CONFIG_FILE: &str = "config.toml";
+
+// Declare Binding struct
+// The keys or buttons bound to one action, written as a name or a list of names:
+// a = "A" or a = ["A", "Z"]
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize)]
+pub struct Binding(pub Vec<String>);
+
+struct BindingVisitor;
+
+impl<'de> de::Visitor<'de> for BindingVisitor {
+    type Value = Binding;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a name or a list of names")
+    }
+
+    fn visit_str<E: de::Error>(self, name: &str) -> Result<Binding, E> {
+        Ok(Binding(vec![name.to_string()]))
+    }
+
+    fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Binding, A::Error> {
+        let mut names = Vec::new();
+        while let Some(name) = seq.next_element()? {
+            names.push(name);
+        }
+        Ok(Binding(names))
+    }
+}
+
+impl<'de> Deserialize<'de> for Binding {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(BindingVisitor)
+    }
+}
+
+fn bind(names: &[&str]) -> Binding {
+    Binding(names.iter().map(|name| name.to_string()).collect())
+}
+
+// Declare ControllerBindings struct
+// The buttons of a controller, the names are SDL key names for the keyboard
+// (https://wiki.libsdl.org/SDL2/SDL_Keycode) and SDL game controller button names
+// (a, b, x, y, back, start, dpup, leftshoulder...) for gamepads
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ControllerBindings {
+    pub up: Binding,
+    pub down: Binding,
+    pub left: Binding,
+    pub right: Binding,
+    pub select: Binding,
+    pub start: Binding,
+    pub a: Binding,
+    pub b: Binding,
+}
+
+// Implement functionality of ControllerBindings
+impl ControllerBindings {
+    pub fn buttons(&self) -> [(JoypadButton, &'static str, &Binding); 8] {
+        [
+            (JoypadButton::UP, "up", &self.up),
+            (JoypadButton::DOWN, "down", &self.down),
+            (JoypadButton::LEFT, "left", &self.left),
+            (JoypadButton::RIGHT, "right", &self.right),
+            (JoypadButton::SELECT, "select", &self.select),
+            (JoypadButton::START, "start", &self.start),
+            (JoypadButton::BUTTON_A, "a", &self.a),
+            (JoypadButton::BUTTON_B, "b", &self.b),
+        ]
+    }
+}
+
+// Declare Hotkey enum
+// Emulator actions that can be put on a key or button
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Hotkey {
+    Quit,
+    Pause,
+    Reset,
+    SaveState,
+    LoadState,
+    Rewind,
+    SlowMotion,
+    FastForward,
+    Uncapped,
+    Screenshot,
+    SwitchDiskSide,
+    RecordAudio,
+    RecordChannels,
+    RecordVideo,
+}
+
+// Declare HotkeyBindings struct
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HotkeyBindings {
+    pub quit: Binding,
+    pub pause: Binding,
+    pub reset: Binding,
+    pub save_state: Binding,
+    pub load_state: Binding,
+    pub rewind: Binding,
+    pub slow_motion: Binding,
+    pub fast_forward: Binding,
+    pub uncapped: Binding,
+    pub screenshot: Binding,
+    pub switch_disk_side: Binding,
+    pub record_audio: Binding,
+    pub record_channels: Binding,
+    pub record_video: Binding,
+}
+
+// Implement functionality of HotkeyBindings
+impl HotkeyBindings {
+    pub fn hotkeys(&self) -> [(Hotkey, &'static str, &Binding); 14] {
+        [
+            (Hotkey::Quit, "quit", &self.quit),
+            (Hotkey::Pause, "pause", &self.pause),
+            (Hotkey::Reset, "reset", &self.reset),
+            (Hotkey::SaveState, "save_state", &self.save_state),
+            (Hotkey::LoadState, "load_state", &self.load_state),
+            (Hotkey::Rewind, "rewind", &self.rewind),
+            (Hotkey::SlowMotion, "slow_motion", &self.slow_motion),
+            (Hotkey::FastForward, "fast_forward", &self.fast_forward),
+            (Hotkey::Uncapped, "uncapped", &self.uncapped),
+            (Hotkey::Screenshot, "screenshot", &self.screenshot),
+            (
+                Hotkey::SwitchDiskSide,
+                "switch_disk_side",
+                &self.switch_disk_side,
+            ),
+            (Hotkey::RecordAudio, "record_audio", &self.record_audio),
+            (
+                Hotkey::RecordChannels,
+                "record_channels",
+                &self.record_channels,
+            ),
+            (Hotkey::RecordVideo, "record_video", &self.record_video),
+        ]
+    }
+}
+
+// Every name bound must be unique within the keyboard or the gamepad bindings, so a
+// key or button only does one thing. names pairs a name with where it is bound
+fn check_duplicates(section: &str, names: Vec<(&str, String)>) -> Result<(), String> {
+    let mut seen: HashMap<String, String> = HashMap::new();
+    for (name, location) in names {
+        if name.trim().is_empty() {
+            return Err(format!("{}.{} has an empty name", section, location));
+        }
+        if let Some(other) = seen.insert(name.to_ascii_lowercase(), location.clone()) {
+            return Err(format!(
+                "\"{}\" is bound to both {}.{} and {}.{}",
+                name, section, other, section, location
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn controller_names<'a>(table: &str, controller: &'a ControllerBindings) -> Vec<(&'a str, String)> {
+    let mut names = Vec::new();
+    for (_, button, binding) in controller.buttons() {
+        for name in binding.0.iter() {
+            names.push((name.as_str(), format!("{}.{}", table, button)));
+        }
+    }
+    names
+}
+
+fn hotkey_names(hotkeys: &HotkeyBindings) -> Vec<(&str, String)> {
+    let mut names = Vec::new();
+    for (_, hotkey, binding) in hotkeys.hotkeys() {
+        for name in binding.0.iter() {
+            names.push((name.as_str(), format!("hotkeys.{}", hotkey)));
+        }
+    }
+    names
+}
+
+// Declare KeyBindings struct
+// The [keys] table: the keyboard drives both controller ports
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeyBindings {
+    pub controller1: ControllerBindings,
+    pub controller2: ControllerBindings,
+    pub hotkeys: HotkeyBindings,
+}
+
+// Declare GamepadBindings struct
+// The [gamepad] table: every game controller uses the same buttons, the first one
+// plugged in drives controller 1, the second controller 2
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GamepadBindings {
+    pub left_stick: bool, // the left stick works as the D-pad
+    pub deadzone: u8,     // percent of the stick's travel that is ignored
+    pub buttons: ControllerBindings,
+    pub hotkeys: HotkeyBindings,
+}
+
+impl Default for GamepadBindings {
+    fn default() -> Self {
+        GamepadBindings {
+            left_stick: true,
+            deadzone: 40,
+            // The NES A and B buttons sit where B and A are on most pads
+            buttons: ControllerBindings {
+                up: bind(&["dpup"]),
+                down: bind(&["dpdown"]),
+                left: bind(&["dpleft"]),
+                right: bind(&["dpright"]),
+                select: bind(&["back"]),
+                start: bind(&["start"]),
+                a: bind(&["b"]),
+                b: bind(&["a"]),
+            },
+            hotkeys: HotkeyBindings {
+                rewind: bind(&["leftshoulder"]),
+                fast_forward: bind(&["rightshoulder"]),
+                ..HotkeyBindings::default()
+            },
+        }
+    }
+}
+
+// Declare VideoConfig struct
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VideoConfig {
+    pub scale: Option<u32>,
+    pub fullscreen: bool,
+    pub sync: SyncMode,
+    pub screenshot_size: ScreenshotSize,
+}
+
+// Declare AudioConfig struct
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AudioConfig {
+    pub enabled: bool,
+    pub volume: u8, // percent
+}
+
+impl Default for AudioConfig {
+    fn default() -> Self {
+        AudioConfig {
+            enabled: true,
+            volume: 100,
+        }
+    }
+}
+
+// Declare Config struct
+// Settings from config.toml in the user's config directory, overridden by a .toml
+// next to the ROM (e.g. zelda.toml for zelda.nes). Anything not set keeps its default
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub video: VideoConfig,
+    pub audio: AudioConfig,
+    pub keys: KeyBindings,
+    pub gamepad: GamepadBindings,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let keys = KeyBindings {
+            controller1: ControllerBindings {
+                up: bind(&["Up"]),
+                down: bind(&["Down"]),
+                left: bind(&["Left"]),
+                right: bind(&["Right"]),
+                select: bind(&["Space"]),
+                start: bind(&["Return"]),
+                a: bind(&["A"]),
+                b: bind(&["S"]),
+            },
+            controller2: ControllerBindings::default(),
+            hotkeys: HotkeyBindings {
+                quit: bind(&["Escape"]),
+                pause: bind(&["P"]),
+                reset: bind(&["R"]),
+                save_state: bind(&["F5"]),
+                load_state: bind(&["F7"]),
+                rewind: bind(&["Backspace"]),
+                slow_motion: bind(&["F2"]),
+                fast_forward: bind(&["F3"]),
+                uncapped: bind(&["F4"]),
+                screenshot: bind(&["F12"]),
+                switch_disk_side: bind(&["F6"]),
+                record_audio: bind(&["F9"]),
+                record_channels: bind(&["F10"]),
+                record_video: bind(&["F11"]),
+            },
+        };
+        Config {
+            video: VideoConfig::default(),
+            audio: AudioConfig::default(),
+            keys,
+            gamepad: GamepadBindings::default(),
+        }
+    }
+}
+
+// Lay the tables of overlay over base, key by key
+fn merge(base: &mut toml::Value, overlay: toml::Value) {
+    match (base, overlay) {
+        (toml::Value::Table(base), toml::Value::Table(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+// Check a file on its own, so errors point at the right line
+fn parse_value(text: &str) -> Result<toml::Value, String> {
+    toml::from_str::<Config>(text).map_err(|err| err.to_string())?;
+    toml::from_str(text).map_err(|err| err.to_string())
+}
+
+// Implement functionality of Config
+impl Config {
+    // Where config.toml is looked for: $XDG_CONFIG_HOME or ~/.config on Unix, %APPDATA%
+    // on Windows
+    pub fn user_path() -> Option<PathBuf> {
+        let dir = std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("APPDATA").map(PathBuf::from))
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+        Some(dir.join(CONFIG_DIR).join(CONFIG_FILE))
+    }
+
+    // Settings just for one ROM
+    pub fn rom_path(rom: &Path) -> PathBuf {
+        rom.with_extension("toml")
+    }
+
+    pub fn parse(text: &str) -> Result<Config, String> {
+        Config::from_values(vec![parse_value(text)?])
+    }
+
+    // Read the config files that exist, later files overriding earlier ones
+    pub fn load(paths: &[PathBuf]) -> Result<Config, String> {
+        let mut values = Vec::new();
+        for path in paths {
+            let text = match std::fs::read_to_string(path) {
+                Ok(text) => text,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(format!("Cannot read {}: {}", path.display(), err)),
+            };
+            values.push(parse_value(&text).map_err(|err| format!("{}: {}", path.display(), err))?);
+        }
+        Config::from_values(values)
+    }
+
+    // Lay the files over the defaults entry by entry, so a file only needs what it
+    // changes. An empty list unbinds a default: a = []
+    fn from_values(values: Vec<toml::Value>) -> Result<Config, String> {
+        let mut merged = toml::Value::try_from(Config::default()).map_err(|err| err.to_string())?;
+        for value in values {
+            merge(&mut merged, value);
+        }
+        let config: Config = merged.try_into().map_err(|err| err.to_string())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if let Some(scale) = self.video.scale {
+            if !(1..=16).contains(&scale) {
+                return Err(format!("video.scale must be 1 to 16, not {}", scale));
+            }
+        }
+        if self.audio.volume > 100 {
+            return Err(format!(
+                "audio.volume is a percentage from 0 to 100, not {}",
+                self.audio.volume
+            ));
+        }
+
+        if self.gamepad.deadzone >= 100 {
+            return Err(format!(
+                "gamepad.deadzone is a percentage below 100, not {}",
+                self.gamepad.deadzone
+            ));
+        }
+
+        let keys = &self.keys;
+        let mut names = controller_names("controller1", &keys.controller1);
+        names.extend(controller_names("controller2", &keys.controller2));
+        names.extend(hotkey_names(&keys.hotkeys));
+        check_duplicates("keys", names)?;
+
+        let mut names = controller_names("buttons", &self.gamepad.buttons);
+        names.extend(hotkey_names(&self.gamepad.hotkeys));
+        check_duplicates("gamepad", names)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        assert_eq!(Config::parse("").unwrap(), Config::default());
+
+        let config = Config::parse(
+            r#"
+            [video]
+            scale = 4
+            sync = "audio"
+
+            [audio]
+            volume = 50
+
+            [keys.controller1]
+            a = ["X", "Z"]
+            b = "C"
+
+            [keys.controller2]
+            start = "Keypad Enter"
+
+            [gamepad.hotkeys]
+            pause = "guide"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.video.scale, Some(4));
+        assert_eq!(config.video.sync, SyncMode::Audio);
+        assert_eq!(config.audio.volume, 50);
+        assert!(config.audio.enabled);
+        assert_eq!(config.keys.controller1.a, bind(&["X", "Z"]));
+        assert_eq!(config.keys.controller1.b, bind(&["C"]));
+        // Entries that are not set keep their defaults
+        assert_eq!(config.keys.controller1.up, bind(&["Up"]));
+        assert_eq!(config.keys.controller2.start, bind(&["Keypad Enter"]));
+        assert_eq!(config.gamepad.hotkeys.pause, bind(&["guide"]));
+        assert_eq!(config.keys.hotkeys, Config::default().keys.hotkeys);
+        assert_eq!(
+            config.gamepad.hotkeys.fast_forward,
+            bind(&["rightshoulder"])
+        );
+
+        let config = Config::parse("[keys.controller1]\nselect = []").unwrap();
+        assert_eq!(config.keys.controller1.select, Binding::default());
+    }
+
+    #[test]
+    fn test_validation_errors() {
+        let error = |text: &str| Config::parse(text).unwrap_err();
+        assert!(error("[video]\nscale = 40").contains("video.scale"));
+        assert!(error("[audio]\nvolume = 150").contains("audio.volume"));
+        assert!(error("[gamepad]\ndeadzone = 100").contains("gamepad.deadzone"));
+        assert!(error("[video]\nsync = \"gsync\"").contains("unknown variant"));
+        assert!(error("[keys.controller1]\njump = \"Z\"").contains("unknown field `jump`"));
+        let wrong_type = error("[keys.controller1]\na = 3");
+        assert!(wrong_type.contains("expected a name or a list of names"));
+        assert!(wrong_type.contains("line 2"));
+        assert!(error("[audio]\nvolume = \"x\"").contains("audio.volume"));
+        let duplicate = error("[keys.hotkeys]\npause = \"a\"");
+        assert!(duplicate.contains("keys.controller1.a"));
+        assert!(duplicate.contains("keys.hotkeys.pause"));
+    }
+
+    #[test]
+    fn test_load_overrides() {
+        let dir = std::env::temp_dir().join(format!("nes-config-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let user = dir.join("config.toml");
+        let rom = dir.join("game.toml");
+        std::fs::write(&user, "[video]\nscale = 2\nfullscreen = true\n").unwrap();
+        std::fs::write(&rom, "[video]\nscale = 5\n").unwrap();
+
+        let config = Config::load(&[user.clone(), rom.clone(), dir.join("missing.toml")]);
+        let config = config.unwrap();
+        assert_eq!(config.video.scale, Some(5));
+        assert!(config.video.fullscreen);
+
+        std::fs::write(&rom, "[video]\nscale = \"big\"\n").unwrap();
+        let err = Config::load(&[user, rom]).unwrap_err();
+        assert!(err.contains("game.toml"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,4 +1,6 @@
 use crate::opcodes;
+use serde::Deserialize;
+use serde::Serialize;
 use std::collections::HashMap;
 
 // Flags
@@ -17,6 +19,7 @@ bitflags! {
     ///
 
     // Declare CPUFlags
+    #[derive(Serialize, Deserialize)]
     pub struct CPUFlags: u8 {
         const CARRY             = 0b00000001;
         const ZERO              = 0b00000010;
@@ -33,15 +36,24 @@ bitflags! {
 const STACK: u16 = 0x0100;
 const STACK_RESET: u8 = 0xfd;
 
+// Interrupt vectors
+const NMI_VECTOR: u16 = 0xFFFA;
+const IRQ_VECTOR: u16 = 0xFFFE;
+
 // Declare CPU struct
-pub struct CPU {
+// The CPU is generic over the memory it is attached to: a flat 64KB address space for
+// easy6502 programs (the default) or the NES bus (see bus.rs)
+pub struct CPU<M: CpuBus = FlatMemory> {
     pub register_a: u8,       // register_a address is unsigned 8-bit
     pub register_x: u8,       // register_x address is unsigned 8-bit
     pub register_y: u8,       // register_y address is unsigned 8-bit
     pub status: CPUFlags,     // status is unsigned 8-bit
     pub program_counter: u16, // pc is unsigned 16-bit
     pub stack_pointer: u8,    // stack pointer is unsigned 8-bit
-    memory: [u8; 0xFFFF],
+    pub cycles: usize,        // CPU cycles executed since power on
+    // The opcode the CPU stopped on when it does not know it
+    pub illegal_opcode: Option<u8>,
+    pub bus: M,
 }
 
 // Addressing modes
@@ -61,27 +73,69 @@ pub enum AddressingMode {
 }
 
 // Declare Mem (memory) trait
+// Reads take &mut self because memory-mapped devices (e.g. the joypads) change state when read
 pub trait Mem {
-    fn mem_read(&self, addr: u16) -> u8;
+    fn mem_read(&mut self, addr: u16) -> u8;
     fn mem_write(&mut self, addr: u16, data: u8);
 
-    fn mem_read_u16(&self, pos: u16) -> u16 {
+    fn mem_read_u16(&mut self, pos: u16) -> u16 {
         let lo = self.mem_read(pos) as u16;
-        let hi = self.mem_read(pos + 1) as u16;
-        (hi << 8) | (lo as u16)
+        let hi = self.mem_read(pos.wrapping_add(1)) as u16;
+        (hi << 8) | lo
     }
 
     fn mem_write_u16(&mut self, pos: u16, data: u16) {
         let hi = (data >> 8) as u8;
         let lo = (data & 0xff) as u8;
         self.mem_write(pos, lo);
-        self.mem_write(pos + 1, hi);
+        self.mem_write(pos.wrapping_add(1), hi);
     }
 }
 
-// Implement functionality of Mem for CPU
-impl Mem for CPU {
-    fn mem_read(&self, addr: u16) -> u8 {
+// Declare CpuBus trait
+// Hooks for the devices that run alongside the CPU (APU, PPU, cartridge hardware)
+pub trait CpuBus: Mem {
+    // Advance the devices on the bus by the given number of CPU cycles
+    fn tick(&mut self, _cycles: u8) {}
+
+    // Edge on the NMI line since the last call
+    fn poll_nmi_status(&mut self) -> bool {
+        false
+    }
+
+    // Level of the shared IRQ line
+    fn poll_irq_status(&self) -> bool {
+        false
+    }
+
+    // CPU cycles lost to DMA since the last call, the CPU is halted while they run
+    fn take_stall_cycles(&mut self) -> usize {
+        0
+    }
+}
+
+// Flat 64KB memory with no mapped devices, as used by easy6502 programs (e.g. the snake game)
+pub struct FlatMemory {
+    memory: [u8; 0x10000],
+}
+
+impl FlatMemory {
+    pub fn new() -> Self {
+        FlatMemory {
+            memory: [0; 0x10000],
+        }
+    }
+}
+
+impl Default for FlatMemory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Implement functionality of Mem for FlatMemory
+impl Mem for FlatMemory {
+    fn mem_read(&mut self, addr: u16) -> u8 {
         self.memory[addr as usize]
     }
 
@@ -90,6 +144,20 @@ impl Mem for CPU {
     }
 }
 
+// FlatMemory has no devices, so the default hooks do nothing
+impl CpuBus for FlatMemory {}
+
+// Implement functionality of Mem for CPU by forwarding to the attached memory
+impl<M: CpuBus> Mem for CPU<M> {
+    fn mem_read(&mut self, addr: u16) -> u8 {
+        self.bus.mem_read(addr)
+    }
+
+    fn mem_write(&mut self, addr: u16, data: u8) {
+        self.bus.mem_write(addr, data);
+    }
+}
+
 // Declare Stack trait
 trait Stack {
     fn stack_pop(&mut self) -> u8;
@@ -110,22 +178,63 @@ trait Stack {
 }
 
 // Implement functionality of stack for CPU
-impl Stack for CPU {
+impl<M: CpuBus> Stack for CPU<M> {
     fn stack_pop(&mut self) -> u8 {
         self.stack_pointer = self.stack_pointer.wrapping_add(1);
-        self.mem_read((STACK as u16) + self.stack_pointer as u16)
+        self.mem_read(STACK + self.stack_pointer as u16)
     }
 
     fn stack_push(&mut self, data: u8) {
-        self.mem_write((STACK as u16) + self.stack_pointer as u16, data);
+        self.mem_write(STACK + self.stack_pointer as u16, data);
         self.stack_pointer = self.stack_pointer.wrapping_sub(1);
     }
 }
 
-// Implement functionality of CPU
+// Implement functionality of CPU attached to flat memory
 impl CPU {
-    // Create new CPU object
+    // Create new CPU object with 64KB of flat memory
     pub fn new() -> Self {
+        CPU::with_bus(FlatMemory::new())
+    }
+
+    // Load program into PRG ROM space and save reference to 0xFFFC
+    pub fn load(&mut self, program: Vec<u8>) {
+        self.load_at(&program, 0x0600, 0x0600).unwrap();
+    }
+
+    // Load a program at any address, starting it at entry on reset
+    pub fn load_at(&mut self, program: &[u8], load_address: u16, entry: u16) -> Result<(), String> {
+        let start = load_address as usize;
+        if start + program.len() > self.bus.memory.len() {
+            return Err(format!(
+                "A program of {} bytes does not fit in memory at ${:04X}",
+                program.len(),
+                load_address
+            ));
+        }
+        self.bus.memory[start..start + program.len()].copy_from_slice(program);
+        self.mem_write_u16(0xFFFC, entry);
+        Ok(())
+    }
+
+    // Load program and run
+    pub fn load_and_run(&mut self, program: Vec<u8>) {
+        self.load(program);
+        self.reset();
+        self.run()
+    }
+}
+
+impl Default for CPU {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Implement functionality of CPU
+impl<M: CpuBus> CPU<M> {
+    // Create new CPU object attached to the given memory
+    pub fn with_bus(bus: M) -> Self {
         CPU {
             register_a: 0,
             register_x: 0,
@@ -133,14 +242,34 @@ impl CPU {
             status: CPUFlags::from_bits_truncate(0b100100),
             program_counter: 0,
             stack_pointer: STACK_RESET,
-            memory: [0; 0xFFFF],
+            cycles: 0,
+            illegal_opcode: None,
+            bus,
+        }
+    }
+
+    // Advance the cycle counter and let the devices on the bus catch up
+    fn tick(&mut self, cycles: u8) {
+        self.cycles += cycles as usize;
+        self.bus.tick(cycles);
+
+        // The rest of the system keeps running while DMA halts the CPU
+        loop {
+            let stall = self.bus.take_stall_cycles();
+            if stall == 0 {
+                break;
+            }
+            for _ in 0..stall {
+                self.cycles += 1;
+                self.bus.tick(1);
+            }
         }
     }
 
     // CPU INSTRUCTION HELPER FUNCTIONS
 
     // Addressing mode interpretation for CPU instructions
-    fn get_operand_address(&self, mode: &AddressingMode) -> u16 {
+    fn get_operand_address(&mut self, mode: &AddressingMode) -> u16 {
         match mode {
             AddressingMode::Immediate => self.program_counter,
 
@@ -148,33 +277,29 @@ impl CPU {
 
             AddressingMode::ZeroPage_X => {
                 let pos = self.mem_read(self.program_counter);
-                let addr = pos.wrapping_add(self.register_x) as u16;
-                addr
+                pos.wrapping_add(self.register_x) as u16
             }
 
             AddressingMode::ZeroPage_Y => {
                 let pos = self.mem_read(self.program_counter);
-                let addr = pos.wrapping_add(self.register_y) as u16;
-                addr
+                pos.wrapping_add(self.register_y) as u16
             }
 
             AddressingMode::Absolute => self.mem_read_u16(self.program_counter),
 
             AddressingMode::Absolute_X => {
                 let base = self.mem_read_u16(self.program_counter);
-                let addr = base.wrapping_add(self.register_x as u16);
-                addr
+                base.wrapping_add(self.register_x as u16)
             }
 
             AddressingMode::Absolute_Y => {
                 let base = self.mem_read_u16(self.program_counter);
-                let addr = base.wrapping_add(self.register_y as u16);
-                addr
+                base.wrapping_add(self.register_y as u16)
             }
 
             AddressingMode::Indirect_X => {
                 let base = self.mem_read(self.program_counter);
-                let ptr: u8 = (base as u8).wrapping_add(self.register_x);
+                let ptr: u8 = base.wrapping_add(self.register_x);
                 let lo = self.mem_read(ptr as u16);
                 let hi = self.mem_read(ptr.wrapping_add(1) as u16);
                 (hi as u16) << 8 | (lo as u16)
@@ -183,10 +308,9 @@ impl CPU {
             AddressingMode::Indirect_Y => {
                 let base = self.mem_read(self.program_counter);
                 let lo = self.mem_read(base as u16);
-                let hi = self.mem_read((base as u8).wrapping_add(1) as u16);
+                let hi = self.mem_read(base.wrapping_add(1) as u16);
                 let deref_base = (hi as u16) << 8 | (lo as u16);
-                let deref = deref_base.wrapping_add(self.register_y as u16);
-                deref
+                deref_base.wrapping_add(self.register_y as u16)
             }
 
             AddressingMode::NoneAddressing => {
@@ -195,6 +319,47 @@ impl CPU {
         }
     }
 
+    // Indexed addressing takes an extra cycle on read instructions when it crosses a page
+    fn page_crossed(&self, mode: &AddressingMode, addr: u16) -> bool {
+        let index = match mode {
+            AddressingMode::Absolute_X => self.register_x,
+            AddressingMode::Absolute_Y | AddressingMode::Indirect_Y => self.register_y,
+            _ => return false,
+        };
+        addr.wrapping_sub(index as u16) & 0xFF00 != addr & 0xFF00
+    }
+
+    // Read the operand of a read instruction, adding the page crossing cycle
+    fn read_operand(&mut self, mode: &AddressingMode) -> u8 {
+        let addr = self.get_operand_address(mode);
+        if self.page_crossed(mode, addr) {
+            self.tick(1);
+        }
+        self.mem_read(addr)
+    }
+
+    // Read the operand of a read-modify-write instruction. The 6502 writes the unmodified
+    // value back before the result, which hardware such as the MMC1 can see
+    fn read_for_modify(&mut self, mode: &AddressingMode) -> (u16, u8) {
+        let addr = self.get_operand_address(mode);
+        let data = self.mem_read(addr);
+        self.mem_write(addr, data);
+        (addr, data)
+    }
+
+    // Push the return address and status, then jump through the interrupt vector
+    fn interrupt(&mut self, vector: u16) {
+        self.stack_push_u16(self.program_counter);
+        let mut flags = self.status;
+        flags.remove(CPUFlags::BREAK);
+        flags.insert(CPUFlags::BREAK2);
+        self.stack_push(flags.bits());
+        self.status.insert(CPUFlags::INTERRUPT_DISABLE);
+
+        self.tick(7);
+        self.program_counter = self.mem_read_u16(vector);
+    }
+
     // Update zero and negative flags using binary arithmetic
     fn update_zero_and_negative_flags(&mut self, result: u8) {
         if result == 0 {
@@ -264,16 +429,14 @@ impl CPU {
     // ADC - Add with Carry: Adds the contents of a memory location to the accumulator together with the carry bit
     // A,Z,C,N = A+M+C
     fn adc(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
-        let data = self.mem_read(addr);
+        let data = self.read_operand(mode);
         self.add_to_register_a(data);
     }
 
     // AND - Logical AND: Performed bit by bit on the accumulator contents using the contents of a byte of memory
     // A,Z,N = A&M
     fn and(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
-        let data = self.mem_read(addr);
+        let data = self.read_operand(mode);
         self.set_register_a(data & self.register_a);
     }
 
@@ -286,18 +449,17 @@ impl CPU {
         } else {
             self.clc();
         }
-        data = data << 1;
+        data <<= 1;
         self.set_register_a(data)
     }
     fn asl(&mut self, mode: &AddressingMode) -> u8 {
-        let addr = self.get_operand_address(mode);
-        let mut data = self.mem_read(addr);
+        let (addr, mut data) = self.read_for_modify(mode);
         if data >> 7 == 1 {
             self.sec();
         } else {
             self.clc();
         }
-        data = data << 1;
+        data <<= 1;
         self.mem_write(addr, data);
         self.update_zero_and_negative_flags(data);
         data
@@ -311,13 +473,17 @@ impl CPU {
     // BPL - Branch if Positive: If the negative flag is clear then add the relative displacement to the program counter to cause a branch to a new location
     // BVC - Branch if Overflow Clear: If the overflow flag is clear then add the relative displacement to the program counter to cause a branch to a new location
     // BVS - Branch if Overflow Set: If the overflow flag is set then add the relative displacement to the program counter to cause a branch to a new location
+    // A taken branch costs one extra cycle, or two when it lands on another page
     fn branch(&mut self, condition: bool) {
         if condition {
             let jump: i8 = self.mem_read(self.program_counter) as i8;
-            let jump_addr = self
-                .program_counter
-                .wrapping_add(1)
-                .wrapping_add(jump as u16);
+            let next_addr = self.program_counter.wrapping_add(1);
+            let jump_addr = next_addr.wrapping_add(jump as u16);
+            if next_addr & 0xFF00 != jump_addr & 0xFF00 {
+                self.tick(2);
+            } else {
+                self.tick(1);
+            }
             self.program_counter = jump_addr;
         }
     }
@@ -371,8 +537,7 @@ impl CPU {
     // CPY - Compare Y Register: Compares the contents of the Y register with another memory held value and sets the zero and carry flags as appropriate
     // Z,C,N = Y-M
     fn compare(&mut self, mode: &AddressingMode, compare_with: u8) {
-        let addr = self.get_operand_address(mode);
-        let data = self.mem_read(addr);
+        let data = self.read_operand(mode);
         if data <= compare_with {
             self.sec()
         } else {
@@ -384,8 +549,7 @@ impl CPU {
     // DEC - Decrement Memory: Subtracts one from the value held at a specified memory location setting the zero and negative flags as appropriate
     // M,Z,N = M-1
     fn dec(&mut self, mode: &AddressingMode) -> u8 {
-        let addr = self.get_operand_address(mode);
-        let mut data = self.mem_read(addr);
+        let (addr, mut data) = self.read_for_modify(mode);
         data = data.wrapping_sub(1);
         self.mem_write(addr, data);
         self.update_zero_and_negative_flags(data);
@@ -409,16 +573,14 @@ impl CPU {
     // EOR - Exclusive OR: Performed bit by bit on the accumulator contents using the contents of a byte of memory
     // A,Z,N = A^M
     fn eor(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
-        let data = self.mem_read(addr);
+        let data = self.read_operand(mode);
         self.set_register_a(data ^ self.register_a);
     }
 
     // INC - Increment Memory: Adds one to the value held at a specified memory location setting the zero and negative flags as appropriate
     // M,Z,N = M+1
     fn inc(&mut self, mode: &AddressingMode) -> u8 {
-        let addr = self.get_operand_address(mode);
-        let mut data = self.mem_read(addr);
+        let (addr, mut data) = self.read_for_modify(mode);
         data = data.wrapping_add(1);
         self.mem_write(addr, data);
         self.update_zero_and_negative_flags(data);
@@ -452,24 +614,21 @@ impl CPU {
     // LDA - Load Accumulator: Loads a byte of memory into the accumulator setting the zero and negative flags as appropriate
     // A,Z,N = M
     fn lda(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
-        let data = self.mem_read(addr);
+        let data = self.read_operand(mode);
         self.set_register_a(data);
     }
 
     // LDX - Load X Register: Loads a byte of memory into the X register setting the zero and negative flags as appropriate
     // X,Z,N = M
     fn ldx(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
-        let data = self.mem_read(addr);
+        let data = self.read_operand(mode);
         self.set_register_x(data);
     }
 
     // LDY - Load Y Register: Loads a byte of memory into the Y register setting the zero and negative flags as appropriate
     // Y,Z,N = M
     fn ldy(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
-        let data = self.mem_read(addr);
+        let data = self.read_operand(mode);
         self.set_register_y(data);
     }
 
@@ -482,18 +641,17 @@ impl CPU {
         } else {
             self.clc();
         }
-        data = data >> 1;
+        data >>= 1;
         self.set_register_a(data);
     }
     fn lsr(&mut self, mode: &AddressingMode) -> u8 {
-        let addr = self.get_operand_address(mode);
-        let mut data = self.mem_read(addr);
+        let (addr, mut data) = self.read_for_modify(mode);
         if data & 1 == 1 {
             self.sec();
         } else {
             self.clc();
         }
-        data = data >> 1;
+        data >>= 1;
         self.mem_write(addr, data);
         self.update_zero_and_negative_flags(data);
         data
@@ -505,8 +663,7 @@ impl CPU {
     // ORA - Logical Inclusive OR: Performed bit by bit on the accumulator contents using the contents of a byte of memory
     // A,Z,N = A|M
     fn ora(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
-        let data = self.mem_read(addr);
+        let data = self.read_operand(mode);
         self.set_register_a(data | self.register_a);
     }
 
@@ -518,7 +675,7 @@ impl CPU {
     // PHP - Push Processor Status: Pushes a copy of the status flags on to the stack
     fn php(&mut self) {
         // http://wiki.nesdev.com/w/index.php/CPU_status_flag_behavior
-        let mut flags = self.status.clone();
+        let mut flags = self.status;
         flags.insert(CPUFlags::BREAK);
         flags.insert(CPUFlags::BREAK2);
         self.stack_push(flags.bits());
@@ -539,17 +696,16 @@ impl CPU {
 
     // ROL - Rotate Left: Move each of the bits in either A or M one place to the left
     fn rol(&mut self, mode: &AddressingMode) -> u8 {
-        let addr = self.get_operand_address(mode);
-        let mut data = self.mem_read(addr);
+        let (addr, mut data) = self.read_for_modify(mode);
         let old_carry = self.status.contains(CPUFlags::CARRY);
         if data >> 7 == 1 {
             self.sec();
         } else {
             self.clc();
         }
-        data = data << 1;
+        data <<= 1;
         if old_carry {
-            data = data | 1;
+            data |= 1;
         }
         self.mem_write(addr, data);
         self.update_zero_and_negative_flags(data);
@@ -563,26 +719,25 @@ impl CPU {
         } else {
             self.clc();
         }
-        data = data << 1;
+        data <<= 1;
         if old_carry {
-            data = data | 1;
+            data |= 1;
         }
         self.set_register_a(data);
     }
 
     // ROR - Rotate Right: Move each of the bits in either A or M one place to the right
     fn ror(&mut self, mode: &AddressingMode) -> u8 {
-        let addr = self.get_operand_address(mode);
-        let mut data = self.mem_read(addr);
+        let (addr, mut data) = self.read_for_modify(mode);
         let old_carry = self.status.contains(CPUFlags::CARRY);
         if data & 1 == 1 {
             self.sec();
         } else {
             self.clc();
         }
-        data = data >> 1;
+        data >>= 1;
         if old_carry {
-            data = data | 0b10000000;
+            data |= 0b10000000;
         }
         self.mem_write(addr, data);
         self.update_zero_and_negative_flags(data);
@@ -596,9 +751,9 @@ impl CPU {
         } else {
             self.clc();
         }
-        data = data >> 1;
+        data >>= 1;
         if old_carry {
-            data = data | 0b10000000;
+            data |= 0b10000000;
         }
         self.set_register_a(data);
     }
@@ -619,8 +774,7 @@ impl CPU {
     // SBC - Subtract with Carry: Subtracts the contents of a memory location to the accumulator together with the not of the carry bit
     // A,Z,C,N = A-M-(1-C)
     fn sbc(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
-        let data = self.mem_read(addr);
+        let data = self.read_operand(mode);
         self.add_to_register_a(((data as i8).wrapping_neg().wrapping_sub(1)) as u8);
     }
 
@@ -710,282 +864,319 @@ impl CPU {
     }
     pub fn run_with_callback<F>(&mut self, mut callback: F)
     where
-        F: FnMut(&mut CPU),
+        F: FnMut(&mut CPU<M>),
     {
-        let ref opcodes: HashMap<u8, &'static opcodes::OpCode> = *opcodes::OPCODES_MAP; // HashMap of opcodes
-
         // CPU fetch-execute cycle
         loop {
             callback(self);
-            let code = self.mem_read(self.program_counter);
-            self.program_counter += 1;
-            let program_counter_state = self.program_counter;
-
-            // Error-check opcode
-            let opcode = opcodes
-                .get(&code)
-                .expect(&format!("OpCode {:x} is not recognised", code));
-
-            match code {
-                // ADC - Add with Carry
-                0x69 | 0x65 | 0x75 | 0x6D | 0x7D | 0x79 | 0x61 | 0x71 => {
-                    self.adc(&opcode.mode);
-                }
-                // AND - Logical AND
-                0x29 | 0x25 | 0x35 | 0x2D | 0x3D | 0x39 | 0x21 | 0x31 => {
-                    self.and(&opcode.mode);
-                }
-                // ASL - Arithmetic Shift Left
-                0x0A => {
-                    self.asl_accumulator();
-                }
-                0x06 | 0x16 | 0x0E | 0x1E => {
-                    self.asl(&opcode.mode);
-                }
-                // BCC - Branch if Carry Clear
-                0x90 => {
-                    self.branch(!self.status.contains(CPUFlags::CARRY));
-                }
-                // BCS - Branch if Carry Set
-                0xB0 => {
-                    self.branch(self.status.contains(CPUFlags::CARRY));
-                }
-                // BEQ - Branch if Equal
-                0xF0 => {
-                    self.branch(self.status.contains(CPUFlags::ZERO));
-                }
-                // BIT - Bit Test
-                0x24 | 0x2C => {
-                    self.bit(&opcode.mode);
-                }
-                // BMI - Branch if Minus
-                0x30 => {
-                    self.branch(self.status.contains(CPUFlags::NEGATIVE));
-                }
-                // BNE - Branch if Not Equal
-                0xD0 => {
-                    self.branch(!self.status.contains(CPUFlags::ZERO));
-                }
-                // BPL - Branch if Positive
-                0x10 => {
-                    self.branch(!self.status.contains(CPUFlags::NEGATIVE));
-                }
-                // BRK - Force Interrupt
-                0x00 => {
-                    return;
-                }
-                // BVC - Branch if Overflow Clear
-                0x50 => {
-                    self.branch(!self.status.contains(CPUFlags::OVERFLOW));
-                }
-                // BVS - Branch if Overflow Set
-                0x70 => {
-                    self.branch(self.status.contains(CPUFlags::OVERFLOW));
-                }
-                // CLC - Clear Carry Flag
-                0x18 => {
-                    self.clc();
-                }
-                // CLD - Clear Decimal Mode
-                0xD8 => {
-                    self.cld();
-                }
-                // CLI - Clear Interrupt Disable
-                0x58 => {
-                    self.cli();
-                }
-                // CLV - Clear Overflow Flag
-                0xB8 => {
-                    self.clv();
-                }
-                // CMP - Compare
-                0xC9 | 0xC5 | 0xD5 | 0xCD | 0xDD | 0xD9 | 0xC1 | 0xD1 => {
-                    self.compare(&opcode.mode, self.register_a);
-                }
-                // CPX - Compare X Register
-                0xE0 | 0xE4 | 0xEC => {
-                    self.compare(&opcode.mode, self.register_x);
-                }
-                // CPY - Compare Y Register
-                0xC0 | 0xC4 | 0xCC => {
-                    self.compare(&opcode.mode, self.register_y);
-                }
-                // DEC - Decrement Memory
-                0xC6 | 0xD6 | 0xCE | 0xDE => {
-                    self.dec(&opcode.mode);
-                }
-                // DEX - Decrement X Register
-                0xCA => {
-                    self.dex();
-                }
-                // DEY - Decrement Y Register
-                0x88 => {
-                    self.dey();
-                }
-                // EOR - Exclusive OR
-                0x49 | 0x45 | 0x55 | 0x4D | 0x5D | 0x59 | 0x41 | 0x51 => {
-                    self.eor(&opcode.mode);
-                }
-                // INC - Increment Memory
-                0xE6 | 0xF6 | 0xEE | 0xFE => {
-                    self.inc(&opcode.mode);
-                }
-                // INX - Increment X Register
-                0xE8 => {
-                    self.inx();
-                }
-                // INY - Increment Y Register
-                0xC8 => {
-                    self.iny();
-                }
-                // JMP - Jump
-                // Absolute
-                0x4C => {
-                    let mem_address = self.mem_read_u16(self.program_counter);
-                    self.program_counter = mem_address;
-                }
-                // Indirect
-                0x6C => {
-                    let mem_address = self.mem_read_u16(self.program_counter);
-                    let indirect_ref = if (mem_address & 0x00FF) == 0x00FF {
-                        let lo = self.mem_read(mem_address);
-                        let hi = self.mem_read(mem_address & 0x00FF);
-                        (hi as u16) << 8 | (lo as u16)
-                    } else {
-                        self.mem_read_u16(mem_address)
-                    };
-                    self.program_counter = indirect_ref;
-                }
-                // JSR - Jump to Subroutine
-                0x20 => {
-                    self.jsr();
-                }
-                // LDA - Load Accumulator
-                0xA9 | 0xA5 | 0xB5 | 0xAD | 0xBD | 0xB9 | 0xA1 | 0xB1 => {
-                    self.lda(&opcode.mode);
-                }
-                // LDX - Load X Register
-                0xA2 | 0xA6 | 0xB6 | 0xAE | 0xBE => {
-                    self.ldx(&opcode.mode);
-                }
-                // LDY - Load Y Register
-                0xA0 | 0xA4 | 0xB4 | 0xAC | 0xBC => {
-                    self.ldy(&opcode.mode);
-                }
-                // LSR - Logical Shift Right
-                0x4A => {
-                    self.lsr_accumulator();
-                }
-                0x46 | 0x56 | 0x4E | 0x5E => {
-                    self.lsr(&opcode.mode);
-                }
-                // NOP - No Operation
-                0xEA => {
-                    // Do nothing
-                }
-                // ORA - Logical Inclusive OR
-                0x09 | 0x05 | 0x15 | 0x0D | 0x1D | 0x19 | 0x01 | 0x11 => {
-                    self.ora(&opcode.mode);
-                }
-                // PHA - Push Accumulator
-                0x48 => {
-                    self.pha();
-                }
-                // PHP - Push Processor Status
-                0x08 => {
-                    self.php();
-                }
-                // PLA - Pull Accumulator
-                0x68 => {
-                    self.pla();
-                }
-                // PLP - Pull Processor Status
-                0x28 => {
-                    self.plp();
-                }
-                // ROL - Rotate Left
-                0x2A => {
-                    self.rol_accumulator();
-                }
-                0x26 | 0x36 | 0x2E | 0x3E => {
-                    self.rol(&opcode.mode);
-                }
-                // ROR - Rotate Right
-                0x6A => {
-                    self.ror_accumulator();
-                }
-                0x66 | 0x76 | 0x6E | 0x7E => {
-                    self.ror(&opcode.mode);
-                }
-                // RTI - Return from Interrupt
-                0x40 => {
-                    self.rti();
-                }
-                // RTS - Return from Subroutine
-                0x60 => {
-                    self.rts();
-                }
-                // SBC - Subtract with Carry
-                0xE9 | 0xE5 | 0xF5 | 0xED | 0xFD | 0xF9 | 0xE1 | 0xF1 => {
-                    self.sbc(&opcode.mode);
-                }
-                // SEC - Set Carry Flag
-                0x38 => {
-                    self.sec();
-                }
-                // SED - Set Decimal Flag
-                0xF8 => {
-                    self.sed();
-                }
-                // SEI - Set Interrupt Disable
-                0x78 => {
-                    self.sei();
-                }
-                // STA - Store Accumulator
-                0x85 | 0x95 | 0x8D | 0x9D | 0x99 | 0x81 | 0x91 => {
-                    self.sta(&opcode.mode);
-                }
-                // STX - Store X Register
-                0x86 | 0x96 | 0x8E => {
-                    self.stx(&opcode.mode);
-                }
-                // STY - Store Y Register
-                0x84 | 0x94 | 0x8C => {
-                    self.sty(&opcode.mode);
-                }
-                // TAX - Transfer Accumulator to X
-                0xAA => {
-                    self.tax();
-                }
-                // TAY - Transfer Accumulator to Y
-                0xA8 => {
-                    self.tay();
-                }
-                // TSX - Transfer Stack Pointer to X
-                0xBA => {
-                    self.tsx();
-                }
-                // TXA - Transfer X to Accumulator
-                0x8A => {
-                    self.txa();
-                }
-                // TXS - Transfer X to Stack Pointer
-                0x9A => {
-                    self.txs();
-                }
-                // TYA - Transfer Y to Accumulator
-                0x98 => {
-                    self.tya();
-                }
-                _ => todo!(),
-            }
-
-            if program_counter_state == self.program_counter {
-                self.program_counter += (opcode.len - 1) as u16;
+            if !self.step() {
+                return;
             }
         }
     }
 
+    // Run one instruction, servicing a pending interrupt first. Returns false on BRK, and
+    // on an opcode the CPU does not know, which stop the CPU. An unknown opcode is left
+    // in illegal_opcode, with the program counter on it
+    pub fn step(&mut self) -> bool {
+        let opcodes: &HashMap<u8, &'static opcodes::OpCode> = &opcodes::OPCODES_MAP; // HashMap of opcodes
+
+        // Interrupts are serviced between instructions, NMI before IRQ
+        if self.bus.poll_nmi_status() {
+            self.interrupt(NMI_VECTOR);
+        } else if self.bus.poll_irq_status() && !self.status.contains(CPUFlags::INTERRUPT_DISABLE) {
+            self.interrupt(IRQ_VECTOR);
+        }
+
+        let code = self.mem_read(self.program_counter);
+        self.program_counter += 1;
+        let program_counter_state = self.program_counter;
+
+        // Unofficial opcodes are not emulated
+        let opcode = match opcodes.get(&code) {
+            Some(opcode) => opcode,
+            None => return self.stop_on_illegal_opcode(code),
+        };
+
+        match code {
+            // ADC - Add with Carry
+            0x69 | 0x65 | 0x75 | 0x6D | 0x7D | 0x79 | 0x61 | 0x71 => {
+                self.adc(&opcode.mode);
+            }
+            // AND - Logical AND
+            0x29 | 0x25 | 0x35 | 0x2D | 0x3D | 0x39 | 0x21 | 0x31 => {
+                self.and(&opcode.mode);
+            }
+            // ASL - Arithmetic Shift Left
+            0x0A => {
+                self.asl_accumulator();
+            }
+            0x06 | 0x16 | 0x0E | 0x1E => {
+                self.asl(&opcode.mode);
+            }
+            // BCC - Branch if Carry Clear
+            0x90 => {
+                self.branch(!self.status.contains(CPUFlags::CARRY));
+            }
+            // BCS - Branch if Carry Set
+            0xB0 => {
+                self.branch(self.status.contains(CPUFlags::CARRY));
+            }
+            // BEQ - Branch if Equal
+            0xF0 => {
+                self.branch(self.status.contains(CPUFlags::ZERO));
+            }
+            // BIT - Bit Test
+            0x24 | 0x2C => {
+                self.bit(&opcode.mode);
+            }
+            // BMI - Branch if Minus
+            0x30 => {
+                self.branch(self.status.contains(CPUFlags::NEGATIVE));
+            }
+            // BNE - Branch if Not Equal
+            0xD0 => {
+                self.branch(!self.status.contains(CPUFlags::ZERO));
+            }
+            // BPL - Branch if Positive
+            0x10 => {
+                self.branch(!self.status.contains(CPUFlags::NEGATIVE));
+            }
+            // BRK - Force Interrupt
+            0x00 => {
+                return false;
+            }
+            // BVC - Branch if Overflow Clear
+            0x50 => {
+                self.branch(!self.status.contains(CPUFlags::OVERFLOW));
+            }
+            // BVS - Branch if Overflow Set
+            0x70 => {
+                self.branch(self.status.contains(CPUFlags::OVERFLOW));
+            }
+            // CLC - Clear Carry Flag
+            0x18 => {
+                self.clc();
+            }
+            // CLD - Clear Decimal Mode
+            0xD8 => {
+                self.cld();
+            }
+            // CLI - Clear Interrupt Disable
+            0x58 => {
+                self.cli();
+            }
+            // CLV - Clear Overflow Flag
+            0xB8 => {
+                self.clv();
+            }
+            // CMP - Compare
+            0xC9 | 0xC5 | 0xD5 | 0xCD | 0xDD | 0xD9 | 0xC1 | 0xD1 => {
+                self.compare(&opcode.mode, self.register_a);
+            }
+            // CPX - Compare X Register
+            0xE0 | 0xE4 | 0xEC => {
+                self.compare(&opcode.mode, self.register_x);
+            }
+            // CPY - Compare Y Register
+            0xC0 | 0xC4 | 0xCC => {
+                self.compare(&opcode.mode, self.register_y);
+            }
+            // DEC - Decrement Memory
+            0xC6 | 0xD6 | 0xCE | 0xDE => {
+                self.dec(&opcode.mode);
+            }
+            // DEX - Decrement X Register
+            0xCA => {
+                self.dex();
+            }
+            // DEY - Decrement Y Register
+            0x88 => {
+                self.dey();
+            }
+            // EOR - Exclusive OR
+            0x49 | 0x45 | 0x55 | 0x4D | 0x5D | 0x59 | 0x41 | 0x51 => {
+                self.eor(&opcode.mode);
+            }
+            // INC - Increment Memory
+            0xE6 | 0xF6 | 0xEE | 0xFE => {
+                self.inc(&opcode.mode);
+            }
+            // INX - Increment X Register
+            0xE8 => {
+                self.inx();
+            }
+            // INY - Increment Y Register
+            0xC8 => {
+                self.iny();
+            }
+            // JMP - Jump
+            // Absolute
+            0x4C => {
+                let mem_address = self.mem_read_u16(self.program_counter);
+                self.program_counter = mem_address;
+            }
+            // Indirect
+            0x6C => {
+                let mem_address = self.mem_read_u16(self.program_counter);
+                let indirect_ref = if (mem_address & 0x00FF) == 0x00FF {
+                    let lo = self.mem_read(mem_address);
+                    let hi = self.mem_read(mem_address & 0x00FF);
+                    (hi as u16) << 8 | (lo as u16)
+                } else {
+                    self.mem_read_u16(mem_address)
+                };
+                self.program_counter = indirect_ref;
+            }
+            // JSR - Jump to Subroutine
+            0x20 => {
+                self.jsr();
+            }
+            // LDA - Load Accumulator
+            0xA9 | 0xA5 | 0xB5 | 0xAD | 0xBD | 0xB9 | 0xA1 | 0xB1 => {
+                self.lda(&opcode.mode);
+            }
+            // LDX - Load X Register
+            0xA2 | 0xA6 | 0xB6 | 0xAE | 0xBE => {
+                self.ldx(&opcode.mode);
+            }
+            // LDY - Load Y Register
+            0xA0 | 0xA4 | 0xB4 | 0xAC | 0xBC => {
+                self.ldy(&opcode.mode);
+            }
+            // LSR - Logical Shift Right
+            0x4A => {
+                self.lsr_accumulator();
+            }
+            0x46 | 0x56 | 0x4E | 0x5E => {
+                self.lsr(&opcode.mode);
+            }
+            // NOP - No Operation
+            0xEA => {
+                // Do nothing
+            }
+            // ORA - Logical Inclusive OR
+            0x09 | 0x05 | 0x15 | 0x0D | 0x1D | 0x19 | 0x01 | 0x11 => {
+                self.ora(&opcode.mode);
+            }
+            // PHA - Push Accumulator
+            0x48 => {
+                self.pha();
+            }
+            // PHP - Push Processor Status
+            0x08 => {
+                self.php();
+            }
+            // PLA - Pull Accumulator
+            0x68 => {
+                self.pla();
+            }
+            // PLP - Pull Processor Status
+            0x28 => {
+                self.plp();
+            }
+            // ROL - Rotate Left
+            0x2A => {
+                self.rol_accumulator();
+            }
+            0x26 | 0x36 | 0x2E | 0x3E => {
+                self.rol(&opcode.mode);
+            }
+            // ROR - Rotate Right
+            0x6A => {
+                self.ror_accumulator();
+            }
+            0x66 | 0x76 | 0x6E | 0x7E => {
+                self.ror(&opcode.mode);
+            }
+            // RTI - Return from Interrupt
+            0x40 => {
+                self.rti();
+            }
+            // RTS - Return from Subroutine
+            0x60 => {
+                self.rts();
+            }
+            // SBC - Subtract with Carry
+            0xE9 | 0xE5 | 0xF5 | 0xED | 0xFD | 0xF9 | 0xE1 | 0xF1 => {
+                self.sbc(&opcode.mode);
+            }
+            // SEC - Set Carry Flag
+            0x38 => {
+                self.sec();
+            }
+            // SED - Set Decimal Flag
+            0xF8 => {
+                self.sed();
+            }
+            // SEI - Set Interrupt Disable
+            0x78 => {
+                self.sei();
+            }
+            // STA - Store Accumulator
+            0x85 | 0x95 | 0x8D | 0x9D | 0x99 | 0x81 | 0x91 => {
+                self.sta(&opcode.mode);
+            }
+            // STX - Store X Register
+            0x86 | 0x96 | 0x8E => {
+                self.stx(&opcode.mode);
+            }
+            // STY - Store Y Register
+            0x84 | 0x94 | 0x8C => {
+                self.sty(&opcode.mode);
+            }
+            // TAX - Transfer Accumulator to X
+            0xAA => {
+                self.tax();
+            }
+            // TAY - Transfer Accumulator to Y
+            0xA8 => {
+                self.tay();
+            }
+            // TSX - Transfer Stack Pointer to X
+            0xBA => {
+                self.tsx();
+            }
+            // TXA - Transfer X to Accumulator
+            0x8A => {
+                self.txa();
+            }
+            // TXS - Transfer X to Stack Pointer
+            0x9A => {
+                self.txs();
+            }
+            // TYA - Transfer Y to Accumulator
+            0x98 => {
+                self.tya();
+            }
+            _ => return self.stop_on_illegal_opcode(code),
+        }
+
+        self.tick(opcode.cycles);
+
+        if program_counter_state == self.program_counter {
+            self.program_counter += (opcode.len - 1) as u16;
+        }
+        true
+    }
+
+    // Why step() returned false
+    pub fn stop_reason(&self) -> String {
+        match self.illegal_opcode {
+            Some(code) => format!(
+                "Stopped on illegal opcode ${:02X} at ${:04X}",
+                code, self.program_counter
+            ),
+            None => "Stopped on BRK".to_string(),
+        }
+    }
+
+    fn stop_on_illegal_opcode(&mut self, code: u8) -> bool {
+        self.program_counter = self.program_counter.wrapping_sub(1);
+        self.illegal_opcode = Some(code);
+        false
+    }
+
     // Restore all register states and initialise program_counter
     pub fn reset(&mut self) {
         self.register_a = 0;
@@ -994,19 +1185,14 @@ impl CPU {
         self.status = CPUFlags::from_bits_truncate(0b100100);
         self.program_counter = self.mem_read_u16(0xFFFC);
         self.stack_pointer = STACK_RESET;
+        self.illegal_opcode = None;
     }
 
-    // Load program into PRG ROM space and save reference to 0xFFFC
-    pub fn load(&mut self, program: Vec<u8>) {
-        self.memory[0x0600..(0x0600 + program.len())].copy_from_slice(&program[..]);
-        self.mem_write_u16(0xFFFC, 0x0600);
-    }
-
-    // Load program and run
-    pub fn load_and_run(&mut self, program: Vec<u8>) {
-        self.load(program);
-        self.reset();
-        self.run()
+    // Jump to a subroutine as if a JSR just before return_address had called it, so its
+    // RTS continues at return_address. Used to call into code from outside the program
+    pub fn call_subroutine(&mut self, addr: u16, return_address: u16) {
+        self.stack_push_u16(return_address.wrapping_sub(1));
+        self.program_counter = addr;
     }
 }
 
@@ -1023,6 +1209,19 @@ mod test {
         assert!(cpu.status.bits() & 0b1000_0000 == 0);
     }
 
+    #[test]
+    fn test_load_at_address() {
+        let mut cpu = CPU::new();
+        // LDA #$07 at $8000, started from its second instruction: INX at $8002
+        cpu.load_at(&[0xa9, 0x07, 0xe8, 0x00], 0x8000, 0x8002)
+            .unwrap();
+        cpu.reset();
+        cpu.run();
+        assert_eq!(cpu.register_a, 0);
+        assert_eq!(cpu.register_x, 1);
+        assert!(cpu.load_at(&[0xea; 4], 0xFFFE, 0xFFFE).is_err());
+    }
+
     #[test]
     fn test_0xa9_lda_zero_flag() {
         let mut cpu = CPU::new();
@@ -1062,6 +1261,16 @@ mod test {
         assert_eq!(cpu.register_x, 0xc1)
     }
 
+    #[test]
+    fn test_cycle_counting() {
+        let mut cpu = CPU::new();
+        // LDX #$01 (2), LDA $02FF,X (4+1), STA $02FF,X (5), BEQ +0 (2+1), BRK
+        cpu.load_and_run(vec![
+            0xa2, 0x01, 0xbd, 0xff, 0x02, 0x9d, 0xff, 0x02, 0xf0, 0x00, 0x00,
+        ]);
+        assert_eq!(cpu.cycles, 15);
+    }
+
     #[test]
     fn test_inx_overflow() {
         let mut cpu = CPU::new();
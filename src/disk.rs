@@ -0,0 +1,212 @@
+use serde::Deserialize;
+use serde::Serialize;
+
+// Famicom Disk System images https://www.nesdev.org/wiki/FDS_file_format
+const FDS_TAG: [u8; 4] = [0x46, 0x44, 0x53, 0x1A];
+const HEADER_SIZE: usize = 16;
+pub const SIDE_SIZE: usize = 65500;
+const DISK_VERIFICATION: &[u8] = b"*NINTENDO-HVC*";
+
+// The drive sees the blocks with gaps, start marks and CRCs around them
+// https://www.nesdev.org/wiki/FDS_disk_format
+const LEAD_IN_SIZE: usize = 28300 / 8;
+const GAP_SIZE: usize = 976 / 8;
+const BLOCK_START: u8 = 0x80;
+const CRC_SIZE: usize = 2;
+// Sides are padded so games have room to write files after the last block
+const MIN_RAW_SIDE_SIZE: usize = LEAD_IN_SIZE + SIDE_SIZE + 0x1000;
+
+// Declare DiskImage struct
+#[derive(Clone, Serialize, Deserialize)]
+pub struct DiskImage {
+    pub sides: Vec<Vec<u8>>, // SIDE_SIZE bytes each, blocks without gaps or CRCs
+    header: Option<Vec<u8>>, // fwNES header, kept so to_bytes() matches the file
+}
+
+// .fds files start with the fwNES header or directly with the disk info block
+pub fn is_disk_image(raw: &[u8]) -> bool {
+    raw.starts_with(&FDS_TAG) || is_disk_side(raw)
+}
+
+fn is_disk_side(side: &[u8]) -> bool {
+    side.len() > DISK_VERIFICATION.len() && side[0] == 1 && side[1..15] == *DISK_VERIFICATION
+}
+
+// Implement functionality of DiskImage
+impl DiskImage {
+    // Parse the contents of a .fds file
+    pub fn new(raw: &[u8]) -> Result<DiskImage, String> {
+        let (header, body) = if raw.starts_with(&FDS_TAG) {
+            if raw.len() < HEADER_SIZE {
+                return Err("File is truncated: no room for the fwNES header".to_string());
+            }
+            (Some(raw[..HEADER_SIZE].to_vec()), &raw[HEADER_SIZE..])
+        } else {
+            (None, raw)
+        };
+
+        let sides: Vec<Vec<u8>> = body
+            .chunks_exact(SIDE_SIZE)
+            .map(|side| side.to_vec())
+            .collect();
+        if sides.is_empty() {
+            return Err(format!(
+                "File is truncated: a disk side is {} bytes",
+                SIDE_SIZE
+            ));
+        }
+        if let Some(index) = sides.iter().position(|side| !is_disk_side(side)) {
+            return Err(format!("Disk side {} has no disk info block", index + 1));
+        }
+        Ok(DiskImage { sides, header })
+    }
+
+    // The image in the layout of the file it came from
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.header.clone().unwrap_or_default();
+        for side in self.sides.iter() {
+            bytes.extend_from_slice(side);
+        }
+        bytes
+    }
+
+    // Disk 1 side A, disk 1 side B, disk 2 side A...
+    pub fn side_name(side: usize) -> String {
+        format!(
+            "disk {} side {}",
+            side / 2 + 1,
+            if side.is_multiple_of(2) { 'A' } else { 'B' }
+        )
+    }
+}
+
+// Size of a block from its type, file data blocks take the size from the file header
+// block before them
+fn block_size(block_type: u8, file_size: usize) -> Option<usize> {
+    match block_type {
+        1 => Some(56), // disk info
+        2 => Some(2),  // file amount
+        3 => Some(16), // file header
+        4 => Some(1 + file_size),
+        _ => None,
+    }
+}
+
+// File size field of a file header block
+fn file_size(header: &[u8]) -> usize {
+    header[13] as usize | (header[14] as usize) << 8
+}
+
+// Lay the blocks of a side out the way the drive reads them. The CRCs are left zero,
+// the drive never reports CRC errors
+pub fn side_to_raw(side: &[u8]) -> Vec<u8> {
+    let mut raw = vec![0; LEAD_IN_SIZE];
+    let mut pos = 0;
+    let mut last_file_size = 0;
+    while pos < side.len() {
+        let size = match block_size(side[pos], last_file_size) {
+            Some(size) if pos + size <= side.len() => size,
+            _ => break,
+        };
+        let block = &side[pos..pos + size];
+        if block[0] == 3 {
+            last_file_size = file_size(block);
+        }
+        raw.push(BLOCK_START);
+        raw.extend_from_slice(block);
+        raw.extend_from_slice(&[0; CRC_SIZE]);
+        raw.extend_from_slice(&[0; GAP_SIZE]);
+        pos += size;
+    }
+    raw.resize(raw.len().max(MIN_RAW_SIDE_SIZE), 0);
+    raw
+}
+
+// Collect the blocks of a side back from what the drive sees
+pub fn raw_to_side(raw: &[u8]) -> Vec<u8> {
+    let mut side = Vec::with_capacity(SIDE_SIZE);
+    let mut pos = 0;
+    let mut last_file_size = 0;
+    loop {
+        while pos < raw.len() && raw[pos] == 0 {
+            pos += 1;
+        }
+        if pos + 1 >= raw.len() || raw[pos] != BLOCK_START {
+            break;
+        }
+        let start = pos + 1;
+        let size = match block_size(raw[start], last_file_size) {
+            Some(size) if start + size <= raw.len() => size,
+            _ => break,
+        };
+        let block = &raw[start..start + size];
+        if block[0] == 3 {
+            last_file_size = file_size(block);
+        }
+        side.extend_from_slice(block);
+        pos = start + size + CRC_SIZE;
+    }
+    side.resize(SIDE_SIZE, 0);
+    side
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    // A side with the disk info block, one file of the given contents and padding
+    pub fn test_side(file: &[u8]) -> Vec<u8> {
+        let mut side = vec![0; SIDE_SIZE];
+        side[0] = 1;
+        side[1..15].copy_from_slice(DISK_VERIFICATION);
+        let mut pos = 56;
+        side[pos..pos + 2].copy_from_slice(&[2, 1]);
+        pos += 2;
+        side[pos] = 3;
+        side[pos + 13] = file.len() as u8;
+        side[pos + 14] = (file.len() >> 8) as u8;
+        pos += 16;
+        side[pos] = 4;
+        side[pos + 1..pos + 1 + file.len()].copy_from_slice(file);
+        side
+    }
+
+    #[test]
+    fn test_parse_with_and_without_header() {
+        let mut raw = test_side(&[1, 2, 3]);
+        raw.extend(test_side(&[4, 5]));
+        let image = DiskImage::new(&raw).unwrap();
+        assert_eq!(image.sides.len(), 2);
+        assert_eq!(image.to_bytes(), raw);
+
+        let mut with_header = vec![0x46, 0x44, 0x53, 0x1A, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        with_header.extend_from_slice(&raw);
+        assert!(is_disk_image(&with_header));
+        let image = DiskImage::new(&with_header).unwrap();
+        assert_eq!(image.sides.len(), 2);
+        assert_eq!(image.to_bytes(), with_header);
+
+        assert!(!is_disk_image(&[0x4E, 0x45, 0x53, 0x1A]));
+        assert!(DiskImage::new(&raw[..1000]).is_err());
+        assert!(DiskImage::new(&vec![0; SIDE_SIZE]).is_err());
+    }
+
+    #[test]
+    fn test_raw_layout() {
+        let side = test_side(&[0xAA, 0xBB]);
+        let raw = side_to_raw(&side);
+        assert!(raw[..LEAD_IN_SIZE].iter().all(|&byte| byte == 0));
+        assert_eq!(raw[LEAD_IN_SIZE], BLOCK_START);
+        assert_eq!(&raw[LEAD_IN_SIZE + 1..LEAD_IN_SIZE + 3], &[1, b'*']);
+        // The file amount block follows after the CRC and the gap
+        let second = LEAD_IN_SIZE + 1 + 56 + CRC_SIZE + GAP_SIZE;
+        assert_eq!(&raw[second..second + 3], &[BLOCK_START, 2, 1]);
+        assert_eq!(raw_to_side(&raw), side);
+    }
+
+    #[test]
+    fn test_side_names() {
+        assert_eq!(DiskImage::side_name(0), "disk 1 side A");
+        assert_eq!(DiskImage::side_name(3), "disk 2 side B");
+    }
+}
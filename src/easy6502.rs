@@ -0,0 +1,140 @@
+use crate::cpu::Mem;
+use crate::cpu::CPU;
+use crate::frame::Frame;
+use crate::joypad::Joypad;
+use crate::joypad::JoypadButton;
+use rand::rngs::ThreadRng;
+use rand::Rng;
+
+// The easy6502 machine: 64KB of flat memory with a 32x32 screen at $0200, one byte per
+// pixel, a random number at $FE and the last key pressed at $FF
+pub const SCREEN_SIZE: usize = 32;
+pub const SCREEN_ADDR: u16 = 0x0200;
+pub const RANDOM_ADDR: u16 = 0xFE;
+pub const KEY_ADDR: u16 = 0xFF;
+
+// Snake game code
+pub const SNAKE_GAME: &[u8] = &[
+    0x20, 0x06, 0x06, 0x20, 0x38, 0x06, 0x20, 0x0d, 0x06, 0x20, 0x2a, 0x06, 0x60, 0xa9, 0x02, 0x85,
+    0x02, 0xa9, 0x04, 0x85, 0x03, 0xa9, 0x11, 0x85, 0x10, 0xa9, 0x10, 0x85, 0x12, 0xa9, 0x0f, 0x85,
+    0x14, 0xa9, 0x04, 0x85, 0x11, 0x85, 0x13, 0x85, 0x15, 0x60, 0xa5, 0xfe, 0x85, 0x00, 0xa5, 0xfe,
+    0x29, 0x03, 0x18, 0x69, 0x02, 0x85, 0x01, 0x60, 0x20, 0x4d, 0x06, 0x20, 0x8d, 0x06, 0x20, 0xc3,
+    0x06, 0x20, 0x19, 0x07, 0x20, 0x20, 0x07, 0x20, 0x2d, 0x07, 0x4c, 0x38, 0x06, 0xa5, 0xff, 0xc9,
+    0x77, 0xf0, 0x0d, 0xc9, 0x64, 0xf0, 0x14, 0xc9, 0x73, 0xf0, 0x1b, 0xc9, 0x61, 0xf0, 0x22, 0x60,
+    0xa9, 0x04, 0x24, 0x02, 0xd0, 0x26, 0xa9, 0x01, 0x85, 0x02, 0x60, 0xa9, 0x08, 0x24, 0x02, 0xd0,
+    0x1b, 0xa9, 0x02, 0x85, 0x02, 0x60, 0xa9, 0x01, 0x24, 0x02, 0xd0, 0x10, 0xa9, 0x04, 0x85, 0x02,
+    0x60, 0xa9, 0x02, 0x24, 0x02, 0xd0, 0x05, 0xa9, 0x08, 0x85, 0x02, 0x60, 0x60, 0x20, 0x94, 0x06,
+    0x20, 0xa8, 0x06, 0x60, 0xa5, 0x00, 0xc5, 0x10, 0xd0, 0x0d, 0xa5, 0x01, 0xc5, 0x11, 0xd0, 0x07,
+    0xe6, 0x03, 0xe6, 0x03, 0x20, 0x2a, 0x06, 0x60, 0xa2, 0x02, 0xb5, 0x10, 0xc5, 0x10, 0xd0, 0x06,
+    0xb5, 0x11, 0xc5, 0x11, 0xf0, 0x09, 0xe8, 0xe8, 0xe4, 0x03, 0xf0, 0x06, 0x4c, 0xaa, 0x06, 0x4c,
+    0x35, 0x07, 0x60, 0xa6, 0x03, 0xca, 0x8a, 0xb5, 0x10, 0x95, 0x12, 0xca, 0x10, 0xf9, 0xa5, 0x02,
+    0x4a, 0xb0, 0x09, 0x4a, 0xb0, 0x19, 0x4a, 0xb0, 0x1f, 0x4a, 0xb0, 0x2f, 0xa5, 0x10, 0x38, 0xe9,
+    0x20, 0x85, 0x10, 0x90, 0x01, 0x60, 0xc6, 0x11, 0xa9, 0x01, 0xc5, 0x11, 0xf0, 0x28, 0x60, 0xe6,
+    0x10, 0xa9, 0x1f, 0x24, 0x10, 0xf0, 0x1f, 0x60, 0xa5, 0x10, 0x18, 0x69, 0x20, 0x85, 0x10, 0xb0,
+    0x01, 0x60, 0xe6, 0x11, 0xa9, 0x06, 0xc5, 0x11, 0xf0, 0x0c, 0x60, 0xc6, 0x10, 0xa5, 0x10, 0x29,
+    0x1f, 0xc9, 0x1f, 0xf0, 0x01, 0x60, 0x4c, 0x35, 0x07, 0xa0, 0x00, 0xa5, 0xfe, 0x91, 0x00, 0x60,
+    0xa6, 0x03, 0xa9, 0x00, 0x81, 0x10, 0xa2, 0x00, 0xa9, 0x01, 0x81, 0x10, 0x60, 0xa6, 0xff, 0xea,
+    0xea, 0xca, 0xd0, 0xfb, 0x60,
+];
+
+// The snake game was written for easy6502, which runs far slower than the NES. It
+// gets 24000 cycles a second, shown at 60 frames a second
+pub const FRAME_RATE: f64 = 60.0;
+pub const CYCLES_PER_FRAME: usize = 400;
+
+// Mapping colours
+pub fn color(byte: u8) -> (u8, u8, u8) {
+    match byte {
+        0 => (0, 0, 0),           // black
+        1 => (255, 255, 255),     // white
+        2 | 9 => (128, 128, 128), // grey
+        3 | 10 => (255, 0, 0),    // red
+        4 | 11 => (0, 255, 0),    // green
+        5 | 12 => (0, 0, 255),    // blue
+        6 | 13 => (255, 0, 255),  // magenta
+        7 | 14 => (255, 255, 0),  // yellow
+        _ => (0, 255, 255),       // cyan
+    }
+}
+
+// Draw the screen memory into a 32x32 frame. Returns whether any pixel changed
+pub fn read_screen(cpu: &mut CPU, frame: &mut Frame) -> bool {
+    let mut update = false;
+    for i in 0..SCREEN_SIZE * SCREEN_SIZE {
+        let rgb = color(cpu.mem_read(SCREEN_ADDR + i as u16));
+        let (x, y) = (i % SCREEN_SIZE, i / SCREEN_SIZE);
+        if frame.pixel(x, y) != rgb {
+            frame.set_pixel(x, y, rgb);
+            update = true;
+        }
+    }
+    update
+}
+
+// The snake program reads its direction from 0xFF as the ASCII code of W, A, S or D
+pub fn snake_direction(joypad: &Joypad) -> Option<u8> {
+    let buttons = joypad.buttons();
+    if buttons.contains(JoypadButton::UP) {
+        Some(0x77)
+    } else if buttons.contains(JoypadButton::DOWN) {
+        Some(0x73)
+    } else if buttons.contains(JoypadButton::LEFT) {
+        Some(0x61)
+    } else if buttons.contains(JoypadButton::RIGHT) {
+        Some(0x64)
+    } else {
+        None
+    }
+}
+
+// Declare Easy6502 struct
+// Keeps the random number fresh and splits the run into frames
+pub struct Easy6502 {
+    rng: ThreadRng,
+    frame_end: usize, // cycle count the current frame ends at
+}
+
+impl Default for Easy6502 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Implement functionality of Easy6502
+impl Easy6502 {
+    // Create new Easy6502 object
+    pub fn new() -> Self {
+        Easy6502 {
+            rng: rand::thread_rng(),
+            frame_end: CYCLES_PER_FRAME,
+        }
+    }
+
+    // Call after every instruction. Returns true when a frame's worth of cycles has run
+    pub fn instruction_done(&mut self, cpu: &mut CPU) -> bool {
+        cpu.mem_write(RANDOM_ADDR, self.rng.gen_range(1, 16));
+        if cpu.cycles < self.frame_end {
+            return false;
+        }
+        self.frame_end += CYCLES_PER_FRAME;
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_read_screen() {
+        let mut cpu = CPU::new();
+        let mut frame = Frame::new(SCREEN_SIZE, SCREEN_SIZE);
+        assert!(!read_screen(&mut cpu, &mut frame));
+        cpu.mem_write(SCREEN_ADDR + 33, 1);
+        cpu.mem_write(SCREEN_ADDR + 1023, 3);
+        assert!(read_screen(&mut cpu, &mut frame));
+        assert_eq!(frame.pixel(1, 1), (255, 255, 255));
+        assert_eq!(frame.pixel(31, 31), (255, 0, 0));
+        assert!(!read_screen(&mut cpu, &mut frame));
+    }
+}
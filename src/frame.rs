@@ -0,0 +1,84 @@
+use serde::Deserialize;
+use serde::Serialize;
+use std::io;
+use std::io::Write;
+
+// Declare Frame struct
+// An RGB24 image, as uploaded to the SDL texture
+#[derive(Serialize, Deserialize)]
+pub struct Frame {
+    pub width: usize,
+    pub height: usize,
+    pub data: Vec<u8>,
+}
+
+// Implement functionality of Frame
+impl Frame {
+    // Size of the picture produced by the PPU
+    pub const NES_WIDTH: usize = 256;
+    pub const NES_HEIGHT: usize = 240;
+
+    // Create new Frame object filled with black
+    pub fn new(width: usize, height: usize) -> Self {
+        Frame {
+            width,
+            height,
+            data: vec![0; width * height * 3],
+        }
+    }
+
+    pub fn set_pixel(&mut self, x: usize, y: usize, rgb: (u8, u8, u8)) {
+        let base = (y * self.width + x) * 3;
+        if base + 2 < self.data.len() {
+            self.data[base] = rgb.0;
+            self.data[base + 1] = rgb.1;
+            self.data[base + 2] = rgb.2;
+        }
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> (u8, u8, u8) {
+        let base = (y * self.width + x) * 3;
+        (self.data[base], self.data[base + 1], self.data[base + 2])
+    }
+
+    // Bytes per row, as expected by Texture::update
+    pub fn pitch(&self) -> usize {
+        self.width * 3
+    }
+
+    // The picture with every pixel made scale x scale pixels
+    pub fn scaled(&self, scale: usize) -> Frame {
+        let mut scaled = Frame::new(self.width * scale, self.height * scale);
+        for y in 0..scaled.height {
+            for x in 0..scaled.width {
+                scaled.set_pixel(x, y, self.pixel(x / scale, y / scale));
+            }
+        }
+        scaled
+    }
+
+    // Write the picture as a binary PPM image
+    pub fn write_ppm<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        write!(out, "P6\n{} {}\n255\n", self.width, self.height)?;
+        out.write_all(&self.data)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_write_ppm() {
+        let mut frame = Frame::new(2, 1);
+        frame.set_pixel(1, 0, (1, 2, 3));
+        let mut out = Vec::new();
+        frame.write_ppm(&mut out).unwrap();
+        assert_eq!(out, b"P6\n2 1\n255\n\0\0\0\x01\x02\x03");
+
+        let scaled = frame.scaled(2);
+        assert_eq!((scaled.width, scaled.height), (4, 2));
+        assert_eq!(scaled.pixel(1, 1), (0, 0, 0));
+        assert_eq!(scaled.pixel(2, 1), (1, 2, 3));
+    }
+}
@@ -0,0 +1,540 @@
+use crate::audio::AudioOutput;
+use crate::cli::file_stem;
+use crate::cli::output_path;
+use crate::cli::Options;
+use crate::config::Config;
+use crate::config::Hotkey;
+use crate::cpu::Mem;
+use crate::cpu::CPU;
+use crate::disk::DiskImage;
+use crate::easy6502;
+use crate::easy6502::Easy6502;
+use crate::frame::Frame;
+use crate::input::Input;
+use crate::joypad::Joypad;
+use crate::joypad::JoypadButton;
+use crate::nes::Nes;
+use crate::nsf::player::NsfPlayer;
+use crate::nsf::NsfFile;
+use crate::pacer::FramePacer;
+use crate::pacer::Speed;
+use crate::pacer::SyncMode;
+use crate::save;
+use crate::screenshot;
+use crate::state;
+use crate::state::Rewind;
+use crate::trace::finish_trace;
+use crate::trace::open_trace;
+use crate::trace::write_trace;
+use crate::video::VideoRecorder;
+use crate::wav::WavRecorder;
+use sdl2::pixels::Color;
+use sdl2::pixels::PixelFormatEnum;
+use sdl2::rect::Point;
+use sdl2::render::Canvas;
+use sdl2::video::Window;
+use sdl2::Sdl;
+use std::path::Path;
+use std::time::Duration;
+use std::time::Instant;
+
+// Start or stop recording the audio to <rom>-<time>.wav, optionally with every channel
+// in its own file
+fn toggle_recording(recorder: &mut Option<WavRecorder>, nes: &mut Nes, stem: &str, split: bool) {
+    if let Some(recording) = recorder.take() {
+        if let Err(err) = recording.finish() {
+            eprintln!("Failed to finish recording: {}", err);
+        }
+        nes.cpu_mut().bus.apu.set_channel_capture(false);
+        println!("Recording stopped");
+        return;
+    }
+
+    let path = output_path(stem, "wav");
+    match nes.start_wav(&path, split) {
+        Ok(recording) => {
+            println!("Recording to {}", path.display());
+            *recorder = Some(recording);
+        }
+        Err(err) => eprintln!("{}", err),
+    }
+}
+
+// Start recording the video to <rom>-<time>.y4m, and the sound to the .wav beside it
+fn start_video(nes: &Nes, stem: &str) -> Option<VideoRecorder> {
+    let path = output_path(stem, "y4m");
+    match nes.start_video(&path) {
+        Ok(recording) => {
+            println!("Recording video to {}", path.display());
+            Some(recording)
+        }
+        Err(err) => {
+            eprintln!("{}", err);
+            None
+        }
+    }
+}
+
+// Finalise a video recording and tell how long it is
+fn finish_video(recording: VideoRecorder) {
+    let path = recording.path().to_path_buf();
+    let frames = recording.frames();
+    match recording.finish() {
+        Ok(()) => println!("Video stopped after {} frames", frames),
+        Err(err) => eprintln!("Failed to finish {}: {}", path.display(), err),
+    }
+}
+
+// Save the frame to <stem>-<time>.png
+fn take_screenshot(frame: &Frame, stem: &str, scale: usize) {
+    let path = output_path(stem, "png");
+    match screenshot::save_png(frame, scale, &path) {
+        Ok(()) => println!("Screenshot saved to {}", path.display()),
+        Err(err) => eprintln!("{}", err),
+    }
+}
+
+// Open a window for a picture of width x height, scaled by --scale or default_scale,
+// or filling the screen with --fullscreen. The picture keeps its shape either way
+fn create_canvas(
+    sdl_context: &Sdl,
+    title: &str,
+    (width, height): (u32, u32),
+    default_scale: u32,
+    options: &Options,
+) -> Result<Canvas<Window>, String> {
+    let scale = options.scale.unwrap_or(default_scale);
+    let video_subsystem = sdl_context.video()?;
+    let mut window_builder = video_subsystem.window(title, width * scale, height * scale);
+    window_builder.position_centered();
+    if options.fullscreen {
+        window_builder.fullscreen_desktop();
+    }
+    let window = window_builder.build().map_err(|err| err.to_string())?;
+    let mut canvas_builder = window.into_canvas();
+    if options.sync_mode() == SyncMode::Vsync {
+        canvas_builder = canvas_builder.present_vsync();
+    }
+    let mut canvas = canvas_builder.build().map_err(|err| err.to_string())?;
+    canvas
+        .set_logical_size(width, height)
+        .map_err(|err| err.to_string())?;
+    Ok(canvas)
+}
+
+// Open the sound card for samples at sample_rate Hz, unless the config turns sound
+// off. Games keep running without sound when there is no audio device
+fn open_audio(sdl_context: &Sdl, sample_rate: f64, config: &Config) -> Option<AudioOutput> {
+    if !config.audio.enabled {
+        return None;
+    }
+    match AudioOutput::new(sdl_context, sample_rate) {
+        Ok(mut audio) => {
+            audio.set_volume(config.audio.volume as f32 / 100.0);
+            Some(audio)
+        }
+        Err(err) => {
+            eprintln!("Audio disabled: {}", err);
+            None
+        }
+    }
+}
+
+// How often a paused game checks for input
+const PAUSE_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+// Run a console in a window, with the hotkeys of the config. path is the file of the
+// cartridge, recordings are named after it
+pub fn run_nes(mut nes: Nes, path: &str, options: &Options, config: &Config) -> Result<(), String> {
+    let mut frames_since_flush = 0;
+    let mut trace_log = open_trace(options.trace.as_deref())?;
+    let mut frames = 0;
+
+    // Initialising sdl2, with the picture scaled by 3
+    let sdl_context = sdl2::init()?;
+    let size = (Frame::NES_WIDTH as u32, Frame::NES_HEIGHT as u32);
+    let mut canvas = create_canvas(&sdl_context, "NES", size, 3, options)?;
+    let mut event_pump = sdl_context.event_pump()?;
+    let mut input = Input::new(&sdl_context, config)?;
+
+    let creator = canvas.texture_creator();
+    let mut texture = creator
+        .create_texture_target(
+            PixelFormatEnum::RGB24,
+            Frame::NES_WIDTH as u32,
+            Frame::NES_HEIGHT as u32,
+        )
+        .map_err(|err| err.to_string())?;
+
+    let mut audio = open_audio(&sdl_context, nes.cpu().bus.apu.sample_rate(), config);
+    let stem = file_stem(path, "recording");
+    let mut recorder = match options.wav_out.as_deref() {
+        Some(path) => Some(nes.start_wav(path, options.split_channels)?),
+        None => None,
+    };
+    let mut video = match options.record.as_deref() {
+        Some(path) => Some(nes.start_video(path)?),
+        None => None,
+    };
+    let mut paused = false;
+
+    // The save state hotkeys use the slot picked with --state, slot 0 otherwise
+    let state_path = state::slot_path(Path::new(path), options.state_slot.unwrap_or(0));
+    let mut rewind = Rewind::default();
+    let mut rewinding = false;
+
+    // Frames are paced to the refresh rate of the region rather than the display's
+    let mut pacer = FramePacer::new(nes.region().frame_rate());
+
+    // Run the game a frame at a time, presenting the completed frames. While rewinding
+    // the snapshots are shown instead, latest first
+    let sync = options.sync_mode();
+    'running: while options.frames != Some(frames) {
+        if rewinding {
+            match rewind.pop() {
+                Some(snapshot) => {
+                    if let Err(err) = nes.load_state(&snapshot) {
+                        eprintln!("Cannot rewind: {}", err);
+                        rewinding = false;
+                    }
+                }
+                None => {
+                    println!("Rewound as far as possible");
+                    rewinding = false;
+                }
+            }
+        } else {
+            if !nes.run_frame_with_callback(|cpu| write_trace(&mut trace_log, cpu)) {
+                if nes.cpu().illegal_opcode.is_some() {
+                    eprintln!("{}", nes.cpu().stop_reason());
+                }
+                break;
+            }
+            frames += 1;
+            if rewind.frame_done() {
+                match nes.save_state() {
+                    Ok(snapshot) => rewind.push(snapshot),
+                    Err(err) => eprintln!("{}", err),
+                }
+            }
+        }
+
+        if pacer.present_due(Instant::now()) {
+            let frame = nes.frame();
+            texture.update(None, &frame.data, frame.pitch()).unwrap();
+            canvas.copy(&texture, None, None).unwrap();
+            canvas.present();
+        }
+
+        // While paused only the input is checked, until the game is resumed
+        loop {
+            let hotkeys = match input.handle_user_input(&mut nes.joypads_mut(), &mut event_pump) {
+                Some(hotkeys) => hotkeys,
+                None => break 'running,
+            };
+            for hotkey in hotkeys {
+                match hotkey {
+                    Hotkey::Pause => {
+                        paused = !paused;
+                        println!("{}", if paused { "Paused" } else { "Resumed" });
+                    }
+                    Hotkey::Reset => {
+                        nes.reset();
+                        println!("Reset");
+                    }
+                    Hotkey::SaveState => match nes.save_state_file(&state_path) {
+                        Ok(()) => println!("Saved the state to {}", state_path.display()),
+                        Err(err) => eprintln!("{}", err),
+                    },
+                    Hotkey::LoadState => match nes.load_state_file(&state_path) {
+                        Ok(()) => println!("Loaded the state from {}", state_path.display()),
+                        Err(err) => eprintln!("{}", err),
+                    },
+                    Hotkey::Rewind => {
+                        rewinding = !rewinding;
+                        println!("{}", if rewinding { "Rewinding" } else { "Playing" });
+                    }
+                    Hotkey::SlowMotion | Hotkey::FastForward | Hotkey::Uncapped => {
+                        let speed = match hotkey {
+                            Hotkey::SlowMotion => Speed::SlowMotion,
+                            Hotkey::FastForward => Speed::FastForward,
+                            _ => Speed::Uncapped,
+                        };
+                        pacer.toggle_speed(speed);
+                        println!("Running at {}", pacer.speed().name());
+                    }
+                    Hotkey::SwitchDiskSide => {
+                        if let Some(side) = nes.cpu_mut().bus.mapper.switch_disk_side() {
+                            println!("Inserting {}", DiskImage::side_name(side));
+                        }
+                    }
+                    Hotkey::RecordAudio | Hotkey::RecordChannels => {
+                        let split = hotkey == Hotkey::RecordChannels;
+                        toggle_recording(&mut recorder, &mut nes, &stem, split);
+                    }
+                    Hotkey::RecordVideo => {
+                        video = match video.take() {
+                            Some(recording) => {
+                                finish_video(recording);
+                                None
+                            }
+                            None => start_video(&nes, &stem),
+                        };
+                    }
+                    Hotkey::Screenshot => {
+                        take_screenshot(nes.frame(), &stem, options.screenshot_scale(3))
+                    }
+                    Hotkey::Quit => { /* handled with the input */ }
+                }
+            }
+            if !paused {
+                break;
+            }
+            std::thread::sleep(PAUSE_POLL_INTERVAL);
+        }
+
+        // Sound is only played at normal speed, recordings get every emulated frame
+        let normal_speed = pacer.speed() == Speed::Normal;
+        let samples = nes.take_samples();
+        if let Some(recording) = video.as_mut() {
+            if let Err(err) = recording.record(nes.frame(), &samples) {
+                eprintln!("Video stopped: {}", err);
+                video = None;
+            }
+        }
+        let apu = &mut nes.cpu_mut().bus.apu;
+        if let Some(audio) = audio.as_mut().filter(|_| normal_speed) {
+            audio.push_samples(&samples);
+        }
+        if let Some(recording) = recorder.as_mut() {
+            let channels = apu.take_channel_samples();
+            if let Err(err) = recording.record(&samples, &channels) {
+                eprintln!("Recording stopped: {}", err);
+                recorder = None;
+                apu.set_channel_capture(false);
+            }
+        }
+
+        frames_since_flush += 1;
+        if frames_since_flush >= save::FLUSH_INTERVAL_FRAMES {
+            frames_since_flush = 0;
+            nes.flush_save();
+        }
+
+        // Vsync and audio sync hold the frontend back at normal speed, the timer
+        // otherwise
+        match (sync, audio.as_ref()) {
+            (SyncMode::Vsync, _) if normal_speed => {}
+            (SyncMode::Audio, Some(audio)) if normal_speed => audio.wait_for_queue(),
+            _ => pacer.wait(),
+        }
+    }
+
+    nes.flush_save();
+    if let Some(path) = options.screenshot.as_ref() {
+        nes.save_screenshot(path, options.screenshot_scale(3))?;
+    }
+    if let Some(recording) = recorder.take() {
+        if let Err(err) = recording.finish() {
+            eprintln!("Failed to finish recording: {}", err);
+        }
+    }
+    if let Some(recording) = video.take() {
+        finish_video(recording);
+    }
+    finish_trace(&mut trace_log);
+    Ok(())
+}
+
+// Size of the NSF player window, which shows the waveform of the music
+const PLAYER_WIDTH: u32 = 512;
+const PLAYER_HEIGHT: u32 = 256;
+
+// Window title and console line of the track being played
+fn show_track(player: &NsfPlayer) -> String {
+    let text = format!(
+        "Track {}/{}: {}",
+        player.track() + 1,
+        player.track_count(),
+        player.nsf.track_name(player.track())
+    );
+    println!("{}", text);
+    text
+}
+
+// Play an .nsf or .nsfe file
+// Left and right go to the previous and next track, tracks move on by themselves
+// after their length. The tune plays on a console of the region it was made for,
+// unless --region picks another
+pub fn play_nsf(raw: &[u8], options: &Options, config: &Config) -> Result<(), String> {
+    let nsf = NsfFile::new(raw)?;
+    println!("{} - {} ({})", nsf.title, nsf.artist, nsf.copyright);
+    let region = options.region.unwrap_or_else(|| nsf.region());
+    let mut player = NsfPlayer::with_region(nsf, region);
+    // CPU cycles in a frame of the console, the player runs the tune in steps of one
+    let frame_cycles = region.cpu_clock_rate() / region.frame_rate();
+
+    let sdl_context = sdl2::init()?;
+    let size = (PLAYER_WIDTH, PLAYER_HEIGHT);
+    let mut canvas = create_canvas(&sdl_context, "NSF player", size, 1, options)?;
+    let mut event_pump = sdl_context.event_pump()?;
+    let mut input = Input::new(&sdl_context, config)?;
+    let mut pacer = FramePacer::new(region.frame_rate());
+    let mut audio = open_audio(&sdl_context, player.sample_rate(), config);
+
+    let mut joypad = Joypad::new();
+    canvas
+        .window_mut()
+        .set_title(&show_track(&player))
+        .map_err(|err| err.to_string())?;
+    let mut elapsed = 0.0; // milliseconds into the track
+    let mut cycle_budget = 0.0;
+    let mut frames = 0;
+    loop {
+        if options.frames == Some(frames) {
+            return Ok(());
+        }
+        frames += 1;
+        if input
+            .handle_user_input(&mut [&mut joypad], &mut event_pump)
+            .is_none()
+        {
+            return Ok(());
+        }
+        let (length, fade) = player.track_length(player.track());
+        let count = player.track_count();
+        // Left and right on controller 1 skip a track on every press (or key repeat)
+        let buttons = joypad.buttons();
+        let mut next_track = if buttons.contains(JoypadButton::RIGHT) {
+            Some((player.track() + 1) % count)
+        } else if buttons.contains(JoypadButton::LEFT) {
+            Some((player.track() + count - 1) % count)
+        } else {
+            None
+        };
+        joypad.set_button_pressed_status(JoypadButton::RIGHT, false);
+        joypad.set_button_pressed_status(JoypadButton::LEFT, false);
+        if elapsed >= (length + fade) as f64 {
+            next_track = Some((player.track() + 1) % count);
+        }
+        if let Some(track) = next_track {
+            player.start_track(track);
+            canvas
+                .window_mut()
+                .set_title(&show_track(&player))
+                .map_err(|err| err.to_string())?;
+            elapsed = 0.0;
+            cycle_budget = 0.0;
+        }
+
+        // Run the tune for one frame, fading out at the end of the track
+        let mut samples = Vec::new();
+        cycle_budget += frame_cycles;
+        while cycle_budget > 0.0 {
+            let frame = player.play_frame();
+            cycle_budget -= frame.len() as f64;
+            samples.extend(frame);
+        }
+        elapsed += samples.len() as f64 * 1000.0 / player.sample_rate();
+        if elapsed > length as f64 {
+            let gain = (1.0 - (elapsed - length as f64) / fade.max(1) as f64).max(0.0) as f32;
+            samples.iter_mut().for_each(|sample| *sample *= gain);
+        }
+        if let Some(audio) = audio.as_mut() {
+            audio.push_samples(&samples);
+        }
+
+        canvas.set_draw_color(Color::BLACK);
+        canvas.clear();
+        canvas.set_draw_color(Color::GREEN);
+        let step = (samples.len() / PLAYER_WIDTH as usize).max(1);
+        let points: Vec<Point> = samples
+            .iter()
+            .step_by(step)
+            .enumerate()
+            .map(|(x, sample)| {
+                let y = PLAYER_HEIGHT as f32 * (1.0 - sample.clamp(0.0, 1.0));
+                Point::new(x as i32, y as i32)
+            })
+            .collect();
+        canvas.draw_lines(points.as_slice())?;
+        canvas.present();
+        match (options.sync_mode(), audio.as_ref()) {
+            (SyncMode::Vsync, _) => {}
+            (SyncMode::Audio, Some(audio)) => audio.wait_for_queue(),
+            _ => pacer.wait(),
+        }
+    }
+}
+
+// Run a program on the easy6502 machine in a window. The arrow keys (or controller 1)
+// give the snake game its direction, screenshots are named after the title
+pub fn run_easy6502(
+    title: &str,
+    mut cpu: CPU,
+    options: &Options,
+    config: &Config,
+) -> Result<(), String> {
+    let mut machine = Easy6502::new();
+    let mut trace_log = open_trace(options.trace.as_deref())?;
+    let mut frames = 0;
+    let stem = title.to_lowercase().replace(' ', "-");
+
+    // Refer to https://docs.rs/sdl2/latest/sdl2/ for more details
+    // Initialising sdl2, with the canvas scaled by 10
+    let sdl_context = sdl2::init()?;
+    let size = (easy6502::SCREEN_SIZE as u32, easy6502::SCREEN_SIZE as u32);
+    let mut canvas = create_canvas(&sdl_context, title, size, 10, options)?;
+    let mut event_pump = sdl_context.event_pump()?;
+    let mut input = Input::new(&sdl_context, config)?;
+
+    // Creating texture for rendering
+    let creator = canvas.texture_creator();
+    let mut texture = creator
+        .create_texture_target(PixelFormatEnum::RGB24, size.0, size.1)
+        .map_err(|err| err.to_string())?;
+
+    let mut screen = Frame::new(easy6502::SCREEN_SIZE, easy6502::SCREEN_SIZE);
+    let mut joypad = Joypad::new();
+    let mut pacer = FramePacer::new(easy6502::FRAME_RATE);
+
+    // Run the game cycle, a frame's worth of instructions at a time
+    'running: while options.frames != Some(frames) {
+        loop {
+            write_trace(&mut trace_log, &mut cpu);
+            if !cpu.step() {
+                if cpu.illegal_opcode.is_some() {
+                    eprintln!("{}", cpu.stop_reason());
+                }
+                break 'running;
+            }
+            if machine.instruction_done(&mut cpu) {
+                break;
+            }
+        }
+        frames += 1;
+
+        let hotkeys = match input.handle_user_input(&mut [&mut joypad], &mut event_pump) {
+            Some(hotkeys) => hotkeys,
+            None => break,
+        };
+        if let Some(direction) = easy6502::snake_direction(&joypad) {
+            cpu.mem_write(easy6502::KEY_ADDR, direction);
+        }
+
+        if easy6502::read_screen(&mut cpu, &mut screen) {
+            texture.update(None, &screen.data, screen.pitch()).unwrap();
+            canvas.copy(&texture, None, None).unwrap();
+            canvas.present();
+        }
+        if hotkeys.contains(&Hotkey::Screenshot) {
+            take_screenshot(&screen, &stem, options.screenshot_scale(10));
+        }
+        pacer.wait();
+    }
+    finish_trace(&mut trace_log);
+    if let Some(path) = options.screenshot.as_ref() {
+        screenshot::save_png(&screen, options.screenshot_scale(10), path)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,255 @@
+use crate::cpu::CpuBus;
+use crate::cpu::Mem;
+use crate::cpu::CPU;
+use crate::frame::Frame;
+use crate::trace::write_trace;
+use crate::trace::TraceLog;
+use std::fmt;
+use std::path::Path;
+
+// Exit status of a headless run whose condition was not met within the frame limit
+pub const TIMEOUT_STATUS: i32 = 3;
+
+// Exit status of a headless run stopped by an opcode the CPU does not know
+pub const ILLEGAL_OPCODE_STATUS: i32 = 4;
+
+// Declare Condition enum
+// What a headless run waits for before it stops
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Condition {
+    // A byte of memory being (or not being) a value, checked at the end of every frame
+    Memory { addr: u16, value: u8, equal: bool },
+    // The CPU reaching an address, checked before every instruction
+    ProgramCounter(u16),
+}
+
+// Implement functionality of Condition
+impl Condition {
+    fn frame_met<M: CpuBus>(&self, cpu: &mut CPU<M>) -> bool {
+        match *self {
+            Condition::Memory { addr, value, equal } => (cpu.mem_read(addr) == value) == equal,
+            Condition::ProgramCounter(_) => false,
+        }
+    }
+
+    fn instruction_met<M: CpuBus>(&self, cpu: &CPU<M>) -> bool {
+        *self == Condition::ProgramCounter(cpu.program_counter)
+    }
+}
+
+// Declare Outcome enum
+// Why a headless run stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    ConditionMet,
+    FrameLimit, // ran all the frames asked for
+    TimedOut,   // ran all the frames without the condition being met
+    Halted,     // the CPU hit a BRK
+    IllegalOpcode { opcode: u8, addr: u16 },
+}
+
+// Declare Report struct
+// How a headless run ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Report {
+    pub outcome: Outcome,
+    pub frames: u64,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reason = match self.outcome {
+            Outcome::ConditionMet => "Condition met".to_string(),
+            Outcome::FrameLimit => "Stopped".to_string(),
+            Outcome::TimedOut => "Timed out".to_string(),
+            Outcome::Halted => "Stopped on BRK".to_string(),
+            Outcome::IllegalOpcode { opcode, addr } => {
+                format!("Stopped on illegal opcode ${:02X} at ${:04X}", opcode, addr)
+            }
+        };
+        write!(f, "{} after {} frames", reason, self.frames)
+    }
+}
+
+// Implement functionality of Report
+impl Report {
+    // 0 when the run stopped as asked, or the byte at exit_code when given.
+    // TIMEOUT_STATUS when the condition was not met in time, ILLEGAL_OPCODE_STATUS when
+    // the CPU stopped on an opcode it does not know
+    pub fn exit_status<M: CpuBus>(&self, cpu: &mut CPU<M>, exit_code: Option<u16>) -> i32 {
+        match (self.outcome, exit_code) {
+            (Outcome::TimedOut, _) => TIMEOUT_STATUS,
+            (Outcome::IllegalOpcode { .. }, _) => ILLEGAL_OPCODE_STATUS,
+            (_, Some(addr)) => cpu.mem_read(addr) as i32,
+            (_, None) => 0,
+        }
+    }
+}
+
+// Run the CPU without a window for up to frame_limit frames, or until the condition
+// is met. frame_done is called after every instruction and tells whether a frame has
+// just been completed
+pub fn run<M, F>(
+    cpu: &mut CPU<M>,
+    frame_limit: u64,
+    until: Option<Condition>,
+    trace_log: &mut Option<TraceLog>,
+    mut frame_done: F,
+) -> Report
+where
+    M: CpuBus,
+    F: FnMut(&mut CPU<M>) -> bool,
+{
+    let mut frames = 0;
+    let report = |outcome, frames| Report { outcome, frames };
+    loop {
+        if until.is_some_and(|until| until.instruction_met(cpu)) {
+            return report(Outcome::ConditionMet, frames);
+        }
+        write_trace(trace_log, cpu);
+        if !cpu.step() {
+            let outcome = match cpu.illegal_opcode {
+                Some(opcode) => Outcome::IllegalOpcode {
+                    opcode,
+                    addr: cpu.program_counter,
+                },
+                None => Outcome::Halted,
+            };
+            return report(outcome, frames);
+        }
+        if !frame_done(cpu) {
+            continue;
+        }
+        frames += 1;
+        if until.is_some_and(|until| until.frame_met(cpu)) {
+            return report(Outcome::ConditionMet, frames);
+        }
+        if frames >= frame_limit {
+            let outcome = match until {
+                Some(_) => Outcome::TimedOut,
+                None => Outcome::FrameLimit,
+            };
+            return report(outcome, frames);
+        }
+    }
+}
+
+// The first size bytes of memory, for RAM dumps
+pub fn read_ram<M: CpuBus>(cpu: &mut CPU<M>, size: usize) -> Vec<u8> {
+    (0..size).map(|addr| cpu.mem_read(addr as u16)).collect()
+}
+
+// Write the frame to path as a PPM image
+pub fn dump_frame(frame: &Frame, path: &Path) -> Result<(), String> {
+    let write_error = |err: std::io::Error| format!("Cannot write {}: {}", path.display(), err);
+    let mut out = Vec::new();
+    frame.write_ppm(&mut out).map_err(write_error)?;
+    std::fs::write(path, out).map_err(write_error)
+}
+
+// Write the RAM to path as raw bytes
+pub fn dump_ram(ram: &[u8], path: &Path) -> Result<(), String> {
+    std::fs::write(path, ram).map_err(|err| format!("Cannot write {}: {}", path.display(), err))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Count up at $10 forever, a frame every 10 cycles
+    fn counter() -> CPU {
+        let mut cpu = CPU::new();
+        // INC $10; JMP $0600
+        cpu.load(vec![0xE6, 0x10, 0x4C, 0x00, 0x06]);
+        cpu.reset();
+        cpu
+    }
+
+    fn frame_every_10_cycles() -> impl FnMut(&mut CPU) -> bool {
+        let mut frame_end = 10;
+        move |cpu: &mut CPU| {
+            if cpu.cycles < frame_end {
+                return false;
+            }
+            frame_end += 10;
+            true
+        }
+    }
+
+    #[test]
+    fn test_headless_run() {
+        let mut cpu = counter();
+        let report = run(&mut cpu, 5, None, &mut None, frame_every_10_cycles());
+        assert_eq!(
+            report,
+            Report {
+                outcome: Outcome::FrameLimit,
+                frames: 5
+            }
+        );
+        assert_eq!(report.exit_status(&mut cpu, None), 0);
+
+        let until = Condition::Memory {
+            addr: 0x10,
+            value: 3,
+            equal: false,
+        };
+        let mut cpu = counter();
+        let report = run(&mut cpu, 5, Some(until), &mut None, frame_every_10_cycles());
+        assert_eq!(report.outcome, Outcome::ConditionMet);
+        assert_eq!(report.frames, 1);
+        assert_eq!(report.exit_status(&mut cpu, Some(0x10)), 2);
+
+        let until = Condition::Memory {
+            addr: 0x10,
+            value: 0xFF,
+            equal: true,
+        };
+        let mut cpu = counter();
+        let report = run(&mut cpu, 5, Some(until), &mut None, frame_every_10_cycles());
+        assert_eq!(report.outcome, Outcome::TimedOut);
+        assert_eq!(report.exit_status(&mut cpu, Some(0x10)), TIMEOUT_STATUS);
+
+        let until = Condition::ProgramCounter(0x0602);
+        let mut cpu = counter();
+        let report = run(&mut cpu, 5, Some(until), &mut None, frame_every_10_cycles());
+        assert_eq!(report.outcome, Outcome::ConditionMet);
+        assert_eq!(cpu.mem_read(0x10), 1);
+
+        let mut cpu = CPU::new();
+        cpu.load(vec![0xE8, 0x00]);
+        cpu.reset();
+        let report = run(&mut cpu, 5, None, &mut None, frame_every_10_cycles());
+        assert_eq!(report.outcome, Outcome::Halted);
+        assert_eq!(cpu.register_x, 1);
+    }
+
+    #[test]
+    fn test_illegal_opcode() {
+        let mut cpu = CPU::new();
+        // INX; then the unofficial SLO $10
+        cpu.load(vec![0xE8, 0x07, 0x10]);
+        cpu.reset();
+        let report = run(&mut cpu, 5, None, &mut None, frame_every_10_cycles());
+        assert_eq!(
+            report.outcome,
+            Outcome::IllegalOpcode {
+                opcode: 0x07,
+                addr: 0x0601
+            }
+        );
+        assert_eq!(
+            report.to_string(),
+            "Stopped on illegal opcode $07 at $0601 after 0 frames"
+        );
+        assert_eq!(
+            report.exit_status(&mut cpu, Some(0x10)),
+            ILLEGAL_OPCODE_STATUS
+        );
+        assert_eq!(cpu.register_x, 1);
+
+        // The CPU stays stopped on it
+        assert!(!cpu.step());
+        assert_eq!(cpu.program_counter, 0x0601);
+    }
+}
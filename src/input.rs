@@ -0,0 +1,325 @@
+use crate::config::Binding;
+use crate::config::Config;
+use crate::config::Hotkey;
+use crate::joypad::Joypad;
+use crate::joypad::JoypadButton;
+use sdl2::controller::Axis;
+use sdl2::controller::Button;
+use sdl2::controller::GameController;
+use sdl2::event::Event;
+use sdl2::keyboard::Keycode;
+use sdl2::EventPump;
+use sdl2::GameControllerSubsystem;
+use sdl2::Sdl;
+use std::collections::HashMap;
+
+// Game controllers beyond the NES's two ports are ignored
+const PORTS: usize = 2;
+
+// Declare Action enum
+// What an input is bound to: a button of the controller in a port, or a hotkey
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Button(usize, JoypadButton),
+    Hotkey(Hotkey),
+}
+
+// Declare InputMap struct
+// The bindings of the config resolved to SDL keys and game controller buttons. Buttons
+// of game controllers act on the port of the controller, so their port is left at 0
+pub struct InputMap {
+    keys: HashMap<Keycode, Action>,
+    buttons: HashMap<Button, Action>,
+    left_stick: bool,
+    stick_threshold: i16, // axis value the deadzone ends at
+}
+
+// Turn the names bound to actions into SDL values, failing on names SDL does not know
+fn resolve<T: std::hash::Hash + Eq>(
+    bindings: Vec<(Action, String, &Binding)>,
+    lookup: impl Fn(&str) -> Option<T>,
+    unknown: &str,
+) -> Result<HashMap<T, Action>, String> {
+    let mut resolved = HashMap::new();
+    for (action, location, binding) in bindings {
+        for name in binding.0.iter() {
+            let value = lookup(name)
+                .ok_or_else(|| format!("Unknown {} \"{}\" for {}", unknown, name, location))?;
+            resolved.insert(value, action);
+        }
+    }
+    Ok(resolved)
+}
+
+// Implement functionality of InputMap
+impl InputMap {
+    pub fn new(config: &Config) -> Result<Self, String> {
+        let mut keys = Vec::new();
+        let controllers = [&config.keys.controller1, &config.keys.controller2];
+        for (port, controller) in controllers.iter().enumerate() {
+            for (button, name, binding) in controller.buttons() {
+                let location = format!("keys.controller{}.{}", port + 1, name);
+                keys.push((Action::Button(port, button), location, binding));
+            }
+        }
+        for (hotkey, name, binding) in config.keys.hotkeys.hotkeys() {
+            keys.push((
+                Action::Hotkey(hotkey),
+                format!("keys.hotkeys.{}", name),
+                binding,
+            ));
+        }
+
+        let gamepad = &config.gamepad;
+        let mut buttons = Vec::new();
+        for (button, name, binding) in gamepad.buttons.buttons() {
+            let location = format!("gamepad.buttons.{}", name);
+            buttons.push((Action::Button(0, button), location, binding));
+        }
+        for (hotkey, name, binding) in gamepad.hotkeys.hotkeys() {
+            let location = format!("gamepad.hotkeys.{}", name);
+            buttons.push((Action::Hotkey(hotkey), location, binding));
+        }
+
+        Ok(InputMap {
+            keys: resolve(
+                keys,
+                Keycode::from_name,
+                "key (see https://wiki.libsdl.org/SDL2/SDL_Keycode)",
+            )?,
+            buttons: resolve(buttons, Button::from_string, "game controller button")?,
+            left_stick: gamepad.left_stick,
+            stick_threshold: (i16::MAX as i32 * gamepad.deadzone as i32 / 100) as i16,
+        })
+    }
+}
+
+// Directions a stick axis is pushed in, outside the deadzone
+fn stick_directions(axis: Axis, value: i16, threshold: i16) -> JoypadButton {
+    let (negative, positive) = match axis {
+        Axis::LeftX => (JoypadButton::LEFT, JoypadButton::RIGHT),
+        Axis::LeftY => (JoypadButton::UP, JoypadButton::DOWN),
+        _ => return JoypadButton::empty(),
+    };
+    if (value as i32) < -(threshold as i32) {
+        negative
+    } else if value > threshold {
+        positive
+    } else {
+        JoypadButton::empty()
+    }
+}
+
+// Declare Pad struct
+// A game controller that is plugged in
+struct Pad {
+    controller: GameController,
+    port: usize,
+    stick: JoypadButton, // directions the left stick is pushed in
+}
+
+// Declare Input struct
+// Reads the keyboard and the game controllers, which can be plugged in and out at
+// any time
+pub struct Input {
+    map: InputMap,
+    controllers: Option<GameControllerSubsystem>,
+    pads: Vec<Pad>,
+}
+
+// Implement functionality of Input
+impl Input {
+    // Set up the bindings of the config, without game controllers when SDL cannot
+    // open them
+    pub fn new(sdl_context: &Sdl, config: &Config) -> Result<Self, String> {
+        let map = InputMap::new(config)?;
+        let controllers = match sdl_context.game_controller() {
+            Ok(controllers) => Some(controllers),
+            Err(err) => {
+                eprintln!("Game controllers disabled: {}", err);
+                None
+            }
+        };
+        Ok(Input {
+            map,
+            controllers,
+            pads: Vec::new(),
+        })
+    }
+
+    // SDL reports the controllers already plugged in as added when it starts. Each one
+    // takes the first free port
+    fn add_pad(&mut self, joystick_index: u32) {
+        let controllers = match self.controllers.as_ref() {
+            Some(controllers) => controllers,
+            None => return,
+        };
+        let controller = match controllers.open(joystick_index) {
+            Ok(controller) => controller,
+            Err(err) => {
+                eprintln!("Cannot open game controller: {}", err);
+                return;
+            }
+        };
+        let instance_id = controller.instance_id();
+        if self
+            .pads
+            .iter()
+            .any(|pad| pad.controller.instance_id() == instance_id)
+        {
+            return;
+        }
+        let port = (0..)
+            .find(|port| self.pads.iter().all(|pad| pad.port != *port))
+            .unwrap_or_default();
+        if port < PORTS {
+            println!("{} is controller {}", controller.name(), port + 1);
+        } else {
+            println!("{} connected, both ports are taken", controller.name());
+        }
+        self.pads.push(Pad {
+            controller,
+            port,
+            stick: JoypadButton::empty(),
+        });
+    }
+
+    // Let go of the buttons of an unplugged controller
+    fn remove_pad(&mut self, instance_id: u32, joypads: &mut [&mut Joypad]) {
+        if let Some(index) = self
+            .pads
+            .iter()
+            .position(|pad| pad.controller.instance_id() == instance_id)
+        {
+            let pad = self.pads.remove(index);
+            println!("{} disconnected", pad.controller.name());
+            if let Some(joypad) = joypads.get_mut(pad.port) {
+                joypad.set_buttons(JoypadButton::empty());
+            }
+        }
+    }
+
+    fn pad_mut(&mut self, instance_id: u32) -> Option<&mut Pad> {
+        self.pads
+            .iter_mut()
+            .find(|pad| pad.controller.instance_id() == instance_id)
+    }
+
+    // Handling user input: feed the keyboard and game controllers into the controller
+    // ports, joypads[0] being controller 1. Returns the hotkeys pressed, or None when
+    // the user quits
+    pub fn handle_user_input(
+        &mut self,
+        joypads: &mut [&mut Joypad],
+        event_pump: &mut EventPump,
+    ) -> Option<Vec<Hotkey>> {
+        let mut hotkeys = Vec::new();
+        for event in event_pump.poll_iter() {
+            // The action of the event, with the port it acts on and whether it is a press
+            let (action, port, pressed) = match event {
+                Event::Quit { .. } => return None,
+                Event::KeyDown {
+                    keycode: Some(keycode),
+                    repeat,
+                    ..
+                } => match self.map.keys.get(&keycode) {
+                    // Holding a hotkey down does not repeat it
+                    Some(Action::Hotkey(_)) if repeat => continue,
+                    Some(action) => (*action, None, true),
+                    None => continue,
+                },
+                Event::KeyUp {
+                    keycode: Some(keycode),
+                    ..
+                } => match self.map.keys.get(&keycode) {
+                    Some(action) => (*action, None, false),
+                    None => continue,
+                },
+                Event::ControllerDeviceAdded { which, .. } => {
+                    self.add_pad(which);
+                    continue;
+                }
+                Event::ControllerDeviceRemoved { which, .. } => {
+                    self.remove_pad(which, joypads);
+                    continue;
+                }
+                Event::ControllerButtonDown { which, button, .. }
+                | Event::ControllerButtonUp { which, button, .. } => {
+                    let pressed = matches!(event, Event::ControllerButtonDown { .. });
+                    let port = match self.pad_mut(which) {
+                        Some(pad) => pad.port,
+                        None => continue,
+                    };
+                    match self.map.buttons.get(&button) {
+                        Some(action) => (*action, Some(port), pressed),
+                        None => continue,
+                    }
+                }
+                Event::ControllerAxisMotion {
+                    which, axis, value, ..
+                } => {
+                    if !self.map.left_stick {
+                        continue;
+                    }
+                    let threshold = self.map.stick_threshold;
+                    let pad = match self.pad_mut(which) {
+                        Some(pad) => pad,
+                        None => continue,
+                    };
+                    let axis_directions =
+                        stick_directions(axis, i16::MAX, 0) | stick_directions(axis, i16::MIN, 0);
+                    let directions = stick_directions(axis, value, threshold);
+                    let released = pad.stick & axis_directions & !directions;
+                    let newly_pressed = directions & !pad.stick;
+                    pad.stick = (pad.stick & !axis_directions) | directions;
+                    if let Some(joypad) = joypads.get_mut(pad.port) {
+                        joypad.set_button_pressed_status(released, false);
+                        joypad.set_button_pressed_status(newly_pressed, true);
+                    }
+                    continue;
+                }
+                _ => continue,
+            };
+
+            match action {
+                Action::Button(key_port, button) => {
+                    if let Some(joypad) = joypads.get_mut(port.unwrap_or(key_port)) {
+                        joypad.set_button_pressed_status(button, pressed);
+                    }
+                }
+                Action::Hotkey(Hotkey::Quit) if pressed => return None,
+                Action::Hotkey(hotkey) if pressed => hotkeys.push(hotkey),
+                Action::Hotkey(_) => { /* released */ }
+            }
+        }
+        Some(hotkeys)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_stick_directions() {
+        let threshold = 13106; // 40% deadzone
+        assert_eq!(
+            stick_directions(Axis::LeftX, -20000, threshold),
+            JoypadButton::LEFT
+        );
+        assert_eq!(
+            stick_directions(Axis::LeftX, 20000, threshold),
+            JoypadButton::RIGHT
+        );
+        assert_eq!(
+            stick_directions(Axis::LeftY, i16::MIN, threshold),
+            JoypadButton::UP
+        );
+        assert_eq!(
+            stick_directions(Axis::LeftY, 32767, threshold),
+            JoypadButton::DOWN
+        );
+        assert!(stick_directions(Axis::LeftX, 10000, threshold).is_empty());
+        assert!(stick_directions(Axis::RightX, 32767, threshold).is_empty());
+    }
+}
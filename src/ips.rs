@@ -0,0 +1,118 @@
+// IPS patches: a list of records that each overwrite a run of bytes at a 24-bit offset
+// https://zerosoft.zophar.net/ips.php
+const MAGIC: &[u8] = b"PATCH";
+const EOF_MARKER: &[u8] = b"EOF";
+const MAX_RECORD_SIZE: usize = 0xFFFF;
+const MAX_OFFSET: usize = 0xFFFFFF;
+// A record at this offset would read as the end marker
+const EOF_OFFSET: usize = 0x454F46;
+
+// Create a patch turning original into modified, which must not be shorter
+pub fn create(original: &[u8], modified: &[u8]) -> Result<Vec<u8>, String> {
+    if modified.len() > MAX_OFFSET {
+        return Err("File is too large for an IPS patch".to_string());
+    }
+    let mut patch = MAGIC.to_vec();
+    let mut offset = 0;
+    while offset < modified.len() {
+        if original.get(offset) == Some(&modified[offset]) {
+            offset += 1;
+            continue;
+        }
+        let start = if offset == EOF_OFFSET {
+            offset - 1
+        } else {
+            offset
+        };
+        let mut end = offset;
+        while end < modified.len()
+            && end - start < MAX_RECORD_SIZE
+            && original.get(end) != Some(&modified[end])
+        {
+            end += 1;
+        }
+        patch.extend_from_slice(&(start as u32).to_be_bytes()[1..]);
+        patch.extend_from_slice(&((end - start) as u16).to_be_bytes());
+        patch.extend_from_slice(&modified[start..end]);
+        offset = end;
+    }
+    patch.extend_from_slice(EOF_MARKER);
+    Ok(patch)
+}
+
+// Apply a patch to a copy of original
+pub fn apply(original: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    if !patch.starts_with(MAGIC) {
+        return Err("File is not an IPS patch".to_string());
+    }
+    let truncated = || "IPS patch is truncated".to_string();
+    let mut data = original.to_vec();
+    let mut pos = MAGIC.len();
+    loop {
+        if patch.get(pos..pos + EOF_MARKER.len()) == Some(EOF_MARKER) {
+            return Ok(data);
+        }
+        let record = patch.get(pos..pos + 5).ok_or_else(truncated)?;
+        let offset = (record[0] as usize) << 16 | (record[1] as usize) << 8 | record[2] as usize;
+        let size = (record[3] as usize) << 8 | record[4] as usize;
+        pos += 5;
+
+        // Size 0 is a run of a single repeated byte
+        let bytes = if size == 0 {
+            let run = patch.get(pos..pos + 3).ok_or_else(truncated)?;
+            pos += 3;
+            vec![run[2]; (run[0] as usize) << 8 | run[1] as usize]
+        } else {
+            let bytes = patch.get(pos..pos + size).ok_or_else(truncated)?;
+            pos += size;
+            bytes.to_vec()
+        };
+        if data.len() < offset + bytes.len() {
+            data.resize(offset + bytes.len(), 0);
+        }
+        data[offset..offset + bytes.len()].copy_from_slice(&bytes);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let original = vec![0; 0x20000];
+        let mut modified = original.clone();
+        modified[5] = 1;
+        modified[6] = 2;
+        modified[0x10000..0x10100].fill(7);
+        let patch = create(&original, &modified).unwrap();
+        assert_eq!(&patch[..5], b"PATCH");
+        assert_eq!(&patch[5..12], &[0x00, 0x00, 0x05, 0x00, 0x02, 1, 2]);
+        assert_eq!(apply(&original, &patch).unwrap(), modified);
+
+        // No changes gives an empty patch
+        assert_eq!(create(&original, &original).unwrap(), b"PATCHEOF");
+    }
+
+    #[test]
+    fn test_record_at_eof_offset() {
+        let original = vec![0; EOF_OFFSET + 2];
+        let mut modified = original.clone();
+        modified[EOF_OFFSET] = 9;
+        let patch = create(&original, &modified).unwrap();
+        assert_eq!(&patch[5..8], &[0x45, 0x4F, 0x45]);
+        assert_eq!(apply(&original, &patch).unwrap(), modified);
+    }
+
+    #[test]
+    fn test_apply_run_and_errors() {
+        let patch = [b"PATCH".as_ref(), &[0, 0, 2, 0, 0, 0, 3, 0xEE], b"EOF"].concat();
+        assert_eq!(
+            apply(&[0; 4], &patch).unwrap(),
+            vec![0, 0, 0xEE, 0xEE, 0xEE]
+        );
+
+        assert!(apply(&[0; 4], b"NOPE").is_err());
+        assert!(apply(&[0; 4], b"PATCH\x00\x00\x01\x00\x05\x01").is_err());
+    }
+}
@@ -0,0 +1,134 @@
+use serde::Deserialize;
+use serde::Serialize;
+
+// Buttons of the standard NES controller
+bitflags! {
+    /// # Controller report order https://www.nesdev.org/wiki/Standard_controller
+    ///
+    ///  7 6 5 4 3 2 1 0
+    ///  R L D U T S B A
+    ///  | | | | | | | +--- A
+    ///  | | | | | | +----- B
+    ///  | | | | | +------- Select
+    ///  | | | | +--------- Start
+    ///  | | | +----------- Up
+    ///  | | +------------- Down
+    ///  | +--------------- Left
+    ///  +----------------- Right
+    ///
+    #[derive(Serialize, Deserialize)]
+    pub struct JoypadButton: u8 {
+        const BUTTON_A = 0b00000001;
+        const BUTTON_B = 0b00000010;
+        const SELECT   = 0b00000100;
+        const START    = 0b00001000;
+        const UP       = 0b00010000;
+        const DOWN     = 0b00100000;
+        const LEFT     = 0b01000000;
+        const RIGHT    = 0b10000000;
+    }
+}
+
+// Declare Joypad struct
+// Emulates the 4021 shift register inside a standard controller
+#[derive(Serialize, Deserialize)]
+pub struct Joypad {
+    strobe: bool,     // while strobe is high the shift register keeps reloading
+    button_index: u8, // next bit to be shifted out
+    button_status: JoypadButton,
+}
+
+// Implement functionality of Joypad
+impl Joypad {
+    // Create new Joypad object with no buttons pressed
+    pub fn new() -> Self {
+        Joypad {
+            strobe: false,
+            button_index: 0,
+            button_status: JoypadButton::from_bits_truncate(0),
+        }
+    }
+
+    // Write to $4016: bit 0 is the strobe line shared by both controllers
+    pub fn write(&mut self, data: u8) {
+        self.strobe = data & 1 == 1;
+        if self.strobe {
+            self.button_index = 0;
+        }
+    }
+
+    // Read from $4016/$4017: shift out the next button, A first and Right last
+    // Once all 8 buttons are read an official controller keeps returning 1
+    pub fn read(&mut self) -> u8 {
+        if self.button_index > 7 {
+            return 1;
+        }
+        let response = (self.button_status.bits() >> self.button_index) & 1;
+        if !self.strobe {
+            self.button_index += 1;
+        }
+        response
+    }
+
+    // Press or release a single button
+    pub fn set_button_pressed_status(&mut self, button: JoypadButton, pressed: bool) {
+        self.button_status.set(button, pressed);
+    }
+
+    // Replace the state of every button at once, e.g. with the keyboard state of this frame
+    pub fn set_buttons(&mut self, buttons: JoypadButton) {
+        self.button_status = buttons;
+    }
+
+    pub fn buttons(&self) -> JoypadButton {
+        self.button_status
+    }
+}
+
+impl Default for Joypad {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_strobe_mode_returns_button_a() {
+        let mut joypad = Joypad::new();
+        joypad.write(1);
+        joypad.set_button_pressed_status(JoypadButton::BUTTON_A, true);
+        for _ in 0..10 {
+            assert_eq!(joypad.read(), 1);
+        }
+    }
+
+    #[test]
+    fn test_reads_buttons_in_order() {
+        let mut joypad = Joypad::new();
+        joypad.set_buttons(JoypadButton::RIGHT | JoypadButton::SELECT | JoypadButton::BUTTON_B);
+        joypad.write(1);
+        joypad.write(0);
+        let bits: Vec<u8> = (0..8).map(|_| joypad.read()).collect();
+        assert_eq!(bits, vec![0, 1, 1, 0, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn test_returns_one_after_all_buttons_read() {
+        let mut joypad = Joypad::new();
+        joypad.write(1);
+        joypad.write(0);
+        for _ in 0..8 {
+            assert_eq!(joypad.read(), 0);
+        }
+        assert_eq!(joypad.read(), 1);
+        assert_eq!(joypad.read(), 1);
+
+        // Strobing again restarts the report
+        joypad.write(1);
+        joypad.write(0);
+        assert_eq!(joypad.read(), 0);
+    }
+}
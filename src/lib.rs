@@ -0,0 +1,63 @@
+// The emulator as a library: the 6502 CPU, the NES around it (bus, PPU, APU,
+// cartridges and mappers) and the easy6502 machine, with the Nes facade to run games.
+// The window, sound and controllers are in the frontend, behind the sdl feature
+pub mod apu;
+#[cfg(feature = "sdl")]
+pub mod audio;
+pub mod bus;
+pub mod cartridge;
+pub mod cli;
+pub mod config;
+pub mod cpu;
+pub mod disk;
+pub mod easy6502;
+pub mod frame;
+#[cfg(feature = "sdl")]
+pub mod frontend;
+pub mod headless;
+#[cfg(feature = "sdl")]
+pub mod input;
+pub mod ips;
+pub mod joypad;
+pub mod mapper;
+pub mod nes;
+pub mod nsf;
+pub mod opcodes;
+pub mod pacer;
+pub mod ppu;
+pub mod region;
+pub mod resampler;
+pub mod romdb;
+pub mod save;
+pub mod screenshot;
+pub mod state;
+pub mod trace;
+pub mod video;
+pub mod wav;
+
+pub use apu::APU;
+pub use bus::Bus;
+pub use cartridge::Mirroring;
+pub use cartridge::Rom;
+pub use cpu::AddressingMode;
+pub use cpu::CPUFlags;
+pub use cpu::CpuBus;
+pub use cpu::FlatMemory;
+pub use cpu::Mem;
+pub use cpu::CPU;
+pub use frame::Frame;
+pub use joypad::Joypad;
+pub use joypad::JoypadButton;
+pub use mapper::Mapper;
+pub use nes::Nes;
+pub use nes::NesBuilder;
+pub use opcodes::OpCode;
+pub use opcodes::OPCODES_MAP;
+pub use ppu::PPU;
+pub use region::Region;
+
+#[macro_use]
+extern crate lazy_static;
+
+#[macro_use]
+extern crate bitflags;
@@ -1,157 +1,285 @@
-pub mod cpu;
-pub mod opcodes;
-use cpu::Mem;
-use cpu::CPU;
-use rand::Rng;
-use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
-use sdl2::pixels::Color;
-use sdl2::pixels::PixelFormatEnum;
-use sdl2::EventPump;
+use rust_nes_emulator::cli;
+use rust_nes_emulator::cli::file_stem;
+use rust_nes_emulator::cli::Machine;
+use rust_nes_emulator::cli::Options;
+use rust_nes_emulator::config::Config;
+use rust_nes_emulator::cpu::CpuBus;
+use rust_nes_emulator::cpu::CPU;
+use rust_nes_emulator::easy6502;
+use rust_nes_emulator::easy6502::Easy6502;
+use rust_nes_emulator::frame::Frame;
+use rust_nes_emulator::headless;
+use rust_nes_emulator::nes::Nes;
+use rust_nes_emulator::nsf;
+use rust_nes_emulator::nsf::player::NsfPlayer;
+use rust_nes_emulator::nsf::NsfFile;
+use rust_nes_emulator::romdb::RomDatabase;
+use rust_nes_emulator::screenshot;
+use rust_nes_emulator::state;
+use rust_nes_emulator::trace::finish_trace;
+use rust_nes_emulator::trace::open_trace;
+use rust_nes_emulator::wav;
+use rust_nes_emulator::wav::WavWriter;
+use std::path::Path;
+use std::path::PathBuf;
 
-#[macro_use]
-extern crate lazy_static;
+#[cfg(feature = "sdl")]
+use rust_nes_emulator::frontend;
 
-#[macro_use]
-extern crate bitflags;
+// Without SDL there is no window to play in, only headless runs and NSF rendering
+#[cfg(not(feature = "sdl"))]
+mod frontend {
+    use rust_nes_emulator::cli::Options;
+    use rust_nes_emulator::config::Config;
+    use rust_nes_emulator::cpu::CPU;
+    use rust_nes_emulator::nes::Nes;
 
-// Mapping colours
-fn color(byte: u8) -> Color {
-    match byte {
-        0 => sdl2::pixels::Color::BLACK,
-        1 => sdl2::pixels::Color::WHITE,
-        2 | 9 => sdl2::pixels::Color::GREY,
-        3 | 10 => sdl2::pixels::Color::RED,
-        4 | 11 => sdl2::pixels::Color::GREEN,
-        5 | 12 => sdl2::pixels::Color::BLUE,
-        6 | 13 => sdl2::pixels::Color::MAGENTA,
-        7 | 14 => sdl2::pixels::Color::YELLOW,
-        _ => sdl2::pixels::Color::CYAN,
+    const NO_WINDOW: &str =
+        "This build has no window (the sdl feature is off), run with --headless or --wav";
+
+    pub fn run_nes(_: Nes, _: &str, _: &Options, _: &Config) -> Result<(), String> {
+        Err(NO_WINDOW.to_string())
+    }
+
+    pub fn play_nsf(_: &[u8], _: &Options, _: &Config) -> Result<(), String> {
+        Err(NO_WINDOW.to_string())
+    }
+
+    pub fn run_easy6502(_: &str, _: CPU, _: &Options, _: &Config) -> Result<(), String> {
+        Err(NO_WINDOW.to_string())
     }
 }
 
-// Keep track of screen state using temp buffer
-fn read_screen_state(cpu: &CPU, frame: &mut [u8; 32 * 3 * 32]) -> bool {
-    let mut frame_idx = 0;
-    let mut update = false;
-    for i in 0x0200..0x600 {
-        let color_idx = cpu.mem_read(i as u16);
-        let (b1, b2, b3) = color(color_idx).rgb();
-        if frame[frame_idx] != b1 || frame[frame_idx + 1] != b2 || frame[frame_idx + 2] != b3 {
-            frame[frame_idx] = b1;
-            frame[frame_idx + 1] = b2;
-            frame[frame_idx + 2] = b3;
-            update = true;
-        }
-        frame_idx += 3;
+// Print how a headless run ended and write the dumps and screenshot asked for. The
+// RAM is the first ram_size bytes of memory, display size screenshots are scaled like
+// a window of default_scale. Returns the exit status
+fn finish_headless<M: CpuBus>(
+    cpu: &mut CPU<M>,
+    report: &headless::Report,
+    frame: &Frame,
+    default_scale: u32,
+    ram_size: usize,
+    options: &Options,
+) -> Result<i32, String> {
+    println!("{}", report);
+    if let Some(path) = options.dump_frame.as_ref() {
+        headless::dump_frame(frame, path)?;
     }
-    update
+    if let Some(path) = options.screenshot.as_ref() {
+        screenshot::save_png(frame, options.screenshot_scale(default_scale), path)?;
+    }
+    if let Some(path) = options.dump_ram.as_ref() {
+        headless::dump_ram(&headless::read_ram(cpu, ram_size), path)?;
+    }
+    Ok(report.exit_status(cpu, options.exit_code))
 }
 
-// Handling user input
-fn handle_user_input(cpu: &mut CPU, event_pump: &mut EventPump) {
-    for event in event_pump.poll_iter() {
-        match event {
-            Event::Quit { .. }
-            | Event::KeyDown {
-                keycode: Some(Keycode::Escape),
-                ..
-            } => std::process::exit(0),
-            Event::KeyDown {
-                keycode: Some(Keycode::W),
-                ..
-            } => {
-                cpu.mem_write(0xff, 0x77);
-            }
-            Event::KeyDown {
-                keycode: Some(Keycode::S),
-                ..
-            } => {
-                cpu.mem_write(0xff, 0x73);
-            }
-            Event::KeyDown {
-                keycode: Some(Keycode::A),
-                ..
-            } => {
-                cpu.mem_write(0xff, 0x61);
+// Run a .nes or .fds file, in a window or headless. Headless runs return their exit
+// status
+fn run_nes(path: &str, options: &Options, config: &Config) -> Result<i32, String> {
+    let mut builder = Nes::builder()
+        .rom_file(path)
+        .rom_database(RomDatabase::load_user()?);
+    if let Some(region) = options.region {
+        builder = builder.region(region);
+    }
+    let mut nes = builder.build()?;
+    println!("Running with {} timing", nes.region().name());
+    if let Some(slot) = options.state_slot {
+        let state_path = state::slot_path(Path::new(path), slot);
+        nes.load_state_file(&state_path)?;
+        println!("Loaded the save state {}", state_path.display());
+    }
+    if !options.headless {
+        frontend::run_nes(nes, path, options, config)?;
+        return Ok(0);
+    }
+
+    // Only the frames are counted, and the sound is dropped unless recorded
+    let mut trace_log = open_trace(options.trace.as_deref())?;
+    let mut video = match options.record.as_ref() {
+        Some(path) => Some(nes.start_video(path)?),
+        None => None,
+    };
+    let mut wav = match options.wav_out.as_ref() {
+        Some(path) => Some(nes.start_wav(path, options.split_channels)?),
+        None => None,
+    };
+    let mut record_error = None;
+    let frame_limit = options.frames.unwrap_or(u64::MAX);
+    let cpu = nes.cpu_mut();
+    let report = headless::run(cpu, frame_limit, options.until, &mut trace_log, |cpu| {
+        if !cpu.bus.ppu.take_frame_complete() {
+            return false;
+        }
+        let samples = cpu.bus.apu.take_samples();
+        if let Some(recording) = video.as_mut() {
+            if let Err(err) = recording.record(&cpu.bus.ppu.frame, &samples) {
+                record_error = Some(err);
+                video = None;
             }
-            Event::KeyDown {
-                keycode: Some(Keycode::D),
-                ..
-            } => {
-                cpu.mem_write(0xff, 0x64);
+        }
+        if let Some(recording) = wav.as_mut() {
+            let channels = cpu.bus.apu.take_channel_samples();
+            if let Err(err) = recording.record(&samples, &channels) {
+                record_error = Some(err);
+                wav = None;
             }
-            _ => { /* do nothing */ }
         }
+        true
+    });
+    nes.flush_save();
+    finish_trace(&mut trace_log);
+    if let Some(err) = record_error {
+        return Err(format!("Recording stopped: {}", err));
+    }
+    if let Some(recording) = video {
+        let (path, frames) = (recording.path().to_path_buf(), recording.frames());
+        recording
+            .finish()
+            .map_err(|err| format!("Cannot write {}: {}", path.display(), err))?;
+        println!("Recorded {} frames to {}", frames, path.display());
+    }
+    if let (Some(recording), Some(path)) = (wav, options.wav_out.as_ref()) {
+        recording
+            .finish()
+            .map_err(|err| format!("Cannot write {}: {}", path.display(), err))?;
+        println!("Recorded the sound to {}", path.display());
     }
+    let cpu = nes.cpu_mut();
+    // The run is over, so the PPU can give its frame up
+    let frame = std::mem::replace(&mut cpu.bus.ppu.frame, Frame::new(0, 0));
+    finish_headless(cpu, &report, &frame, 3, 0x0800, options)
 }
 
-fn main() {
-    // Refer to https://docs.rs/sdl2/latest/sdl2/ for more details
-    // Initialising sdl2
-    let sdl_context = sdl2::init().unwrap();
-    let video_subsystem = sdl_context.video().unwrap();
-    let window = video_subsystem
-        .window("Snake game", (32.0 * 10.0) as u32, (32.0 * 10.0) as u32)
-        .position_centered()
-        .build()
-        .unwrap();
-
-    // Set canvas scale factor to 10
-    let mut canvas = window.into_canvas().present_vsync().build().unwrap();
-    let mut event_pump = sdl_context.event_pump().unwrap();
-    canvas.set_scale(10.0, 10.0).unwrap();
-
-    // Creating texture for rendering
-    let creator = canvas.texture_creator();
-    let mut texture = creator
-        .create_texture_target(PixelFormatEnum::RGB24, 32, 32)
-        .unwrap();
+// Render every track of an .nsf or .nsfe file to <name>-<track>.wav, without opening a
+// window. Tracks play for their NSFe length, or a default length, then fade out
+fn render_nsf(path: &str, raw: &[u8], options: &Options) -> Result<(), String> {
+    let nsf = NsfFile::new(raw)?;
+    let stem = file_stem(path, "nsf");
+    let region = options.region.unwrap_or_else(|| nsf.region());
+    let mut player = NsfPlayer::with_region(nsf, region);
+    for track in 0..player.track_count() {
+        let out_path = PathBuf::from(format!("{}-{:02}.wav", stem, track + 1));
+        let write_error =
+            |err: std::io::Error| format!("Cannot write {}: {}", out_path.display(), err);
+        let mut wav = WavWriter::create(&out_path, wav::RECORDING_RATE).map_err(write_error)?;
+        player.render_track(track, &mut wav).map_err(write_error)?;
+        wav.finish().map_err(write_error)?;
+        println!(
+            "Track {}/{}: {} -> {}",
+            track + 1,
+            player.track_count(),
+            player.nsf.track_name(track),
+            out_path.display()
+        );
+    }
+    Ok(())
+}
 
-    // Snake game code
-    let game_code = vec![
-        0x20, 0x06, 0x06, 0x20, 0x38, 0x06, 0x20, 0x0d, 0x06, 0x20, 0x2a, 0x06, 0x60, 0xa9, 0x02,
-        0x85, 0x02, 0xa9, 0x04, 0x85, 0x03, 0xa9, 0x11, 0x85, 0x10, 0xa9, 0x10, 0x85, 0x12, 0xa9,
-        0x0f, 0x85, 0x14, 0xa9, 0x04, 0x85, 0x11, 0x85, 0x13, 0x85, 0x15, 0x60, 0xa5, 0xfe, 0x85,
-        0x00, 0xa5, 0xfe, 0x29, 0x03, 0x18, 0x69, 0x02, 0x85, 0x01, 0x60, 0x20, 0x4d, 0x06, 0x20,
-        0x8d, 0x06, 0x20, 0xc3, 0x06, 0x20, 0x19, 0x07, 0x20, 0x20, 0x07, 0x20, 0x2d, 0x07, 0x4c,
-        0x38, 0x06, 0xa5, 0xff, 0xc9, 0x77, 0xf0, 0x0d, 0xc9, 0x64, 0xf0, 0x14, 0xc9, 0x73, 0xf0,
-        0x1b, 0xc9, 0x61, 0xf0, 0x22, 0x60, 0xa9, 0x04, 0x24, 0x02, 0xd0, 0x26, 0xa9, 0x01, 0x85,
-        0x02, 0x60, 0xa9, 0x08, 0x24, 0x02, 0xd0, 0x1b, 0xa9, 0x02, 0x85, 0x02, 0x60, 0xa9, 0x01,
-        0x24, 0x02, 0xd0, 0x10, 0xa9, 0x04, 0x85, 0x02, 0x60, 0xa9, 0x02, 0x24, 0x02, 0xd0, 0x05,
-        0xa9, 0x08, 0x85, 0x02, 0x60, 0x60, 0x20, 0x94, 0x06, 0x20, 0xa8, 0x06, 0x60, 0xa5, 0x00,
-        0xc5, 0x10, 0xd0, 0x0d, 0xa5, 0x01, 0xc5, 0x11, 0xd0, 0x07, 0xe6, 0x03, 0xe6, 0x03, 0x20,
-        0x2a, 0x06, 0x60, 0xa2, 0x02, 0xb5, 0x10, 0xc5, 0x10, 0xd0, 0x06, 0xb5, 0x11, 0xc5, 0x11,
-        0xf0, 0x09, 0xe8, 0xe8, 0xe4, 0x03, 0xf0, 0x06, 0x4c, 0xaa, 0x06, 0x4c, 0x35, 0x07, 0x60,
-        0xa6, 0x03, 0xca, 0x8a, 0xb5, 0x10, 0x95, 0x12, 0xca, 0x10, 0xf9, 0xa5, 0x02, 0x4a, 0xb0,
-        0x09, 0x4a, 0xb0, 0x19, 0x4a, 0xb0, 0x1f, 0x4a, 0xb0, 0x2f, 0xa5, 0x10, 0x38, 0xe9, 0x20,
-        0x85, 0x10, 0x90, 0x01, 0x60, 0xc6, 0x11, 0xa9, 0x01, 0xc5, 0x11, 0xf0, 0x28, 0x60, 0xe6,
-        0x10, 0xa9, 0x1f, 0x24, 0x10, 0xf0, 0x1f, 0x60, 0xa5, 0x10, 0x18, 0x69, 0x20, 0x85, 0x10,
-        0xb0, 0x01, 0x60, 0xe6, 0x11, 0xa9, 0x06, 0xc5, 0x11, 0xf0, 0x0c, 0x60, 0xc6, 0x10, 0xa5,
-        0x10, 0x29, 0x1f, 0xc9, 0x1f, 0xf0, 0x01, 0x60, 0x4c, 0x35, 0x07, 0xa0, 0x00, 0xa5, 0xfe,
-        0x91, 0x00, 0x60, 0xa6, 0x03, 0xa9, 0x00, 0x81, 0x10, 0xa2, 0x00, 0xa9, 0x01, 0x81, 0x10,
-        0x60, 0xa6, 0xff, 0xea, 0xea, 0xca, 0xd0, 0xfb, 0x60,
-    ];
+// Run a ROM, disk image or NSF, or render an NSF to WAV files with --wav
+fn run_file(path: &str, options: &Options, config: &Config) -> Result<i32, String> {
+    let raw = std::fs::read(path).map_err(|err| format!("Cannot read {}: {}", path, err))?;
+    if !nsf::is_nsf(&raw) {
+        return run_nes(path, options, config);
+    }
+    let recording = options.record.is_some() || options.wav_out.is_some();
+    if options.headless || options.trace.is_some() || recording {
+        return Err(
+            "NSF files cannot be traced, recorded or run headless, use --wav to render them"
+                .to_string(),
+        );
+    }
+    if options.state_slot.is_some() {
+        return Err("NSF files have no save states".to_string());
+    }
+    if options.wav {
+        render_nsf(path, &raw, options)?;
+    } else {
+        frontend::play_nsf(&raw, options, config)?;
+    }
+    Ok(0)
+}
 
+// Run a program on the easy6502 machine, in a window or headless. Headless runs
+// return their exit status
+fn run_easy6502(
+    title: &str,
+    program: &[u8],
+    options: &Options,
+    config: &Config,
+) -> Result<i32, String> {
     // Load the game
     let mut cpu = CPU::new();
-    cpu.load(game_code);
+    cpu.load_at(program, options.load_address, options.entry_point())?;
     cpu.reset();
+    if !options.headless {
+        frontend::run_easy6502(title, cpu, options, config)?;
+        return Ok(0);
+    }
 
-    let mut screen_state = [0 as u8; 32 * 3 * 32];
-    let mut rng = rand::thread_rng();
-
-    // Run the game cycle
-    cpu.run_with_callback(move |cpu| {
-        handle_user_input(cpu, &mut event_pump);
-        cpu.mem_write(0xfe, rng.gen_range(1, 16));
+    let mut machine = Easy6502::new();
+    let mut trace_log = open_trace(options.trace.as_deref())?;
+    let frame_limit = options.frames.unwrap_or(u64::MAX);
+    let report = headless::run(
+        &mut cpu,
+        frame_limit,
+        options.until,
+        &mut trace_log,
+        |cpu| machine.instruction_done(cpu),
+    );
+    finish_trace(&mut trace_log);
+    let mut frame = Frame::new(easy6502::SCREEN_SIZE, easy6502::SCREEN_SIZE);
+    easy6502::read_screen(&mut cpu, &mut frame);
+    finish_headless(&mut cpu, &report, &frame, 10, 0x10000, options)
+}
 
-        if read_screen_state(cpu, &mut screen_state) {
-            texture.update(None, &screen_state, 32 * 3).unwrap();
-            canvas.copy(&texture, None, None).unwrap();
-            canvas.present();
+// Run the file or game picked on the command line. Returns the exit status
+fn run(options: &Options) -> Result<i32, String> {
+    let config = options.load_config()?;
+    let options = &options.with_config(&config);
+    let path = match options.path.as_ref() {
+        Some(path) => path,
+        None => {
+            return Err(
+                "No file given, see --help. The snake game is played with the snake program"
+                    .to_string(),
+            )
         }
+    };
+    match options.machine {
+        Machine::Nes => run_file(&path.to_string_lossy(), options, &config),
+        Machine::Easy6502 if options.state_slot.is_some() => {
+            Err("Save states are only for NES games, not the easy6502 machine".to_string())
+        }
+        Machine::Easy6502 => {
+            let program = std::fs::read(path)
+                .map_err(|err| format!("Cannot read {}: {}", path.display(), err))?;
+            let title = file_stem(&path.to_string_lossy(), "easy6502");
+            run_easy6502(&title, &program, options, &config)
+        }
+    }
+}
 
-        ::std::thread::sleep(std::time::Duration::new(0, 70_000));
-    });
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let options = match Options::parse(&args) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(2);
+        }
+    };
+    if options.help {
+        println!("{}", cli::USAGE);
+        return;
+    }
+    match run(&options) {
+        Ok(0) => {}
+        Ok(status) => std::process::exit(status),
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    }
 }
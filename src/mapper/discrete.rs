@@ -0,0 +1,262 @@
+use super::bank_count;
+use super::bank_offset;
+use super::chr_memory;
+use super::Mapper;
+use crate::cartridge::Mirroring;
+use crate::cartridge::Rom;
+use serde::Deserialize;
+use serde::Serialize;
+
+const PRG_16K: usize = 0x4000;
+const PRG_32K: usize = 0x8000;
+const CHR_8K: usize = 0x2000;
+
+// NES 2.0 submappers of 2, 3 and 7 saying whether the board has bus conflicts
+const SUBMAPPER_NO_BUS_CONFLICTS: u8 = 1;
+const SUBMAPPER_BUS_CONFLICTS: u8 = 2;
+
+// Boards built from a single latch and plain logic chips
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub enum DiscreteBoard {
+    UxROM,       // mapper 2: 16KB PRG at $8000, last bank fixed at $C000
+    CNROM,       // mapper 3: 8KB CHR
+    AxROM,       // mapper 7: 32KB PRG, single-screen mirroring select
+    ColorDreams, // mapper 11: 32KB PRG in bits 0-1, 8KB CHR in bits 4-7
+    GxROM,       // mapper 66: 32KB PRG in bits 4-5, 8KB CHR in bits 0-1
+}
+
+// Implement functionality of DiscreteBoard
+impl DiscreteBoard {
+    pub fn from_mapper(number: u16) -> Option<Self> {
+        match number {
+            2 => Some(DiscreteBoard::UxROM),
+            3 => Some(DiscreteBoard::CNROM),
+            7 => Some(DiscreteBoard::AxROM),
+            11 => Some(DiscreteBoard::ColorDreams),
+            66 => Some(DiscreteBoard::GxROM),
+            _ => None,
+        }
+    }
+
+    // Board default when the header doesn't say, AOROM (the common AxROM) has no
+    // conflicts while ANROM and AMROM do
+    fn bus_conflicts(&self, submapper: u8) -> bool {
+        match submapper {
+            SUBMAPPER_NO_BUS_CONFLICTS => false,
+            SUBMAPPER_BUS_CONFLICTS => true,
+            _ => *self != DiscreteBoard::AxROM,
+        }
+    }
+}
+
+// Declare DiscreteLatch struct
+// The latch is loaded by any write to $8000-$FFFF. On boards with bus conflicts the ROM
+// drives the data bus at the same time, so the latch gets the AND of both values
+// https://www.nesdev.org/wiki/Bus_conflict
+#[derive(Serialize, Deserialize)]
+pub struct DiscreteLatch {
+    board: DiscreteBoard,
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    mirroring: Mirroring,
+    bus_conflicts: bool,
+    latch: u8,
+}
+
+// Implement functionality of DiscreteLatch
+impl DiscreteLatch {
+    // Create new DiscreteLatch object
+    pub fn new(rom: Rom, board: DiscreteBoard) -> Self {
+        let (chr, chr_is_ram) = chr_memory(&rom);
+        DiscreteLatch {
+            board,
+            bus_conflicts: board.bus_conflicts(rom.submapper),
+            mirroring: if board == DiscreteBoard::AxROM {
+                Mirroring::SingleScreenLower
+            } else {
+                rom.screen_mirroring
+            },
+            prg_rom: rom.prg_rom,
+            chr,
+            chr_is_ram,
+            latch: 0,
+        }
+    }
+
+    fn prg_rom_addr(&self, addr: u16) -> usize {
+        let offset = addr as usize - 0x8000;
+        let last_16k = bank_count(PRG_16K, self.prg_rom.len()) - 1;
+        let (bank, size) = match self.board {
+            DiscreteBoard::UxROM if addr < 0xC000 => (self.latch as usize, PRG_16K),
+            DiscreteBoard::UxROM => (last_16k, PRG_16K),
+            DiscreteBoard::CNROM => return offset % self.prg_rom.len(),
+            DiscreteBoard::AxROM => ((self.latch & 0b111) as usize, PRG_32K),
+            DiscreteBoard::ColorDreams => ((self.latch & 0b11) as usize, PRG_32K),
+            DiscreteBoard::GxROM => (((self.latch >> 4) & 0b11) as usize, PRG_32K),
+        };
+        // Images smaller than a bank (e.g. from a trimmed header) are mirrored within it
+        (bank_offset(bank, size, self.prg_rom.len()) + offset % size) % self.prg_rom.len()
+    }
+
+    fn chr_addr(&self, addr: u16) -> usize {
+        let bank = match self.board {
+            DiscreteBoard::CNROM => self.latch as usize,
+            DiscreteBoard::ColorDreams => (self.latch >> 4) as usize,
+            DiscreteBoard::GxROM => (self.latch & 0b11) as usize,
+            DiscreteBoard::UxROM | DiscreteBoard::AxROM => 0,
+        };
+        bank_offset(bank, CHR_8K, self.chr.len()) + (addr as usize & 0x1FFF)
+    }
+}
+
+// Implement functionality of Mapper for DiscreteLatch
+impl Mapper for DiscreteLatch {
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x8000..=0xFFFF => Some(self.prg_rom[self.prg_rom_addr(addr)]),
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        if addr < 0x8000 {
+            return;
+        }
+        self.latch = if self.bus_conflicts {
+            data & self.prg_rom[self.prg_rom_addr(addr)]
+        } else {
+            data
+        };
+        if self.board == DiscreteBoard::AxROM {
+            self.mirroring = if self.latch & 0x10 != 0 {
+                Mirroring::SingleScreenUpper
+            } else {
+                Mirroring::SingleScreenLower
+            };
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr[self.chr_addr(addr)]
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        if self.chr_is_ram {
+            let index = self.chr_addr(addr);
+            self.chr[index] = data;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::test_rom;
+
+    // 256KB PRG-ROM where every byte holds its 16KB bank number, except for a page of
+    // $FF at the start of every bank to write to without bus conflicts, and 64KB of
+    // CHR-ROM where every byte holds its 8KB bank number
+    fn board(mapper: u16, submapper: u8) -> Box<dyn Mapper> {
+        let mut rom = test_rom(vec![]);
+        rom.mapper = mapper;
+        rom.submapper = submapper;
+        rom.prg_rom = (0..0x40000)
+            .map(|i| {
+                if i % PRG_16K < 0x100 {
+                    0xFF
+                } else {
+                    (i / PRG_16K) as u8
+                }
+            })
+            .collect();
+        rom.chr_rom = (0..0x10000).map(|i| (i / CHR_8K) as u8).collect();
+        super::super::new_mapper(rom).unwrap()
+    }
+
+    #[test]
+    fn test_small_prg() {
+        // 8KB of PRG-ROM fills every window of the 16KB and 32KB boards
+        for mapper in [2, 3, 7, 11, 66] {
+            let mut rom = test_rom(vec![]);
+            rom.mapper = mapper;
+            rom.prg_rom = vec![0x42; 0x2000];
+            let mut mapper = super::super::new_mapper(rom).unwrap();
+            for addr in [0x8000, 0xBFFF, 0xC000, 0xFFFF] {
+                assert_eq!(mapper.cpu_read(addr), Some(0x42));
+            }
+        }
+    }
+
+    #[test]
+    fn test_bank_switching() {
+        struct Case {
+            mapper: u16,
+            value: u8,
+            bank_8000: u8,
+            bank_c000: u8,
+            chr_bank: u8,
+            mirroring: Mirroring,
+        }
+        #[rustfmt::skip]
+        let cases = [
+            Case { mapper: 2, value: 5, bank_8000: 5, bank_c000: 15, chr_bank: 0, mirroring: Mirroring::Vertical },
+            Case { mapper: 3, value: 3, bank_8000: 0, bank_c000: 1, chr_bank: 3, mirroring: Mirroring::Vertical },
+            Case { mapper: 7, value: 0x13, bank_8000: 6, bank_c000: 7, chr_bank: 0, mirroring: Mirroring::SingleScreenUpper },
+            Case { mapper: 7, value: 0x02, bank_8000: 4, bank_c000: 5, chr_bank: 0, mirroring: Mirroring::SingleScreenLower },
+            Case { mapper: 11, value: 0x52, bank_8000: 4, bank_c000: 5, chr_bank: 5, mirroring: Mirroring::Vertical },
+            Case { mapper: 66, value: 0x21, bank_8000: 4, bank_c000: 5, chr_bank: 1, mirroring: Mirroring::Vertical },
+        ];
+
+        for case in cases.iter() {
+            let mut mapper = board(case.mapper, 0);
+            mapper.cpu_write(0x8000, case.value);
+            assert_eq!(
+                mapper.cpu_read(0x8100),
+                Some(case.bank_8000),
+                "mapper {}",
+                case.mapper
+            );
+            assert_eq!(
+                mapper.cpu_read(0xC100),
+                Some(case.bank_c000),
+                "mapper {}",
+                case.mapper
+            );
+            assert_eq!(
+                mapper.ppu_read(0x0000),
+                case.chr_bank,
+                "mapper {}",
+                case.mapper
+            );
+            assert_eq!(mapper.mirroring(), case.mirroring, "mapper {}", case.mapper);
+        }
+    }
+
+    #[test]
+    fn test_bus_conflicts() {
+        // Writing 5 over a ROM byte holding 2 latches 5 & 2
+        let mut uxrom = board(2, 0);
+        uxrom.cpu_write(0x8000, 2);
+        uxrom.cpu_write(0x8100, 5);
+        assert_eq!(uxrom.cpu_read(0x8100), Some(0));
+
+        // Unless the header says the board has none
+        let mut uxrom = board(2, SUBMAPPER_NO_BUS_CONFLICTS);
+        uxrom.cpu_write(0x8000, 2);
+        uxrom.cpu_write(0x8100, 5);
+        assert_eq!(uxrom.cpu_read(0x8100), Some(5));
+
+        // AOROM has no conflicts by default, ANROM does
+        let mut aorom = board(7, 0);
+        aorom.cpu_write(0x8100, 0x03);
+        assert_eq!(aorom.cpu_read(0x8100), Some(6));
+        let mut anrom = board(7, SUBMAPPER_BUS_CONFLICTS);
+        anrom.cpu_write(0x8100, 0x03);
+        assert_eq!(anrom.cpu_read(0x8100), Some(0));
+    }
+}
@@ -0,0 +1,506 @@
+use super::fds_audio::FdsAudio;
+use super::ExpansionChip;
+use super::Mapper;
+use super::CHR_WINDOW_SIZE;
+use crate::cartridge::Mirroring;
+use crate::disk;
+use crate::disk::DiskImage;
+use crate::ips;
+use serde::Deserialize;
+use serde::Serialize;
+
+const BIOS_SIZE: usize = 0x2000;
+const PRG_RAM_SIZE: usize = 0x8000;
+
+// Drive timing in CPU cycles: a byte passes the head about every 150 cycles
+// (96.4 kbit/s), and the head takes a while to get back to the start of the disk
+const BYTE_CYCLES: u32 = 150;
+const REWIND_CYCLES: u32 = 50_000;
+
+// How long the drive stays empty when switching sides, long enough for games to see it
+const SIDE_SWITCH_CYCLES: u32 = 1_789_773;
+
+// Declare FDS struct
+// The Famicom Disk System RAM adapter: 32KB of PRG-RAM at $6000-$DFFF, the BIOS at
+// $E000-$FFFF, 8KB of CHR-RAM, a timer IRQ, the disk drive interface and a wavetable
+// sound channel https://www.nesdev.org/wiki/Family_Computer_Disk_System
+#[derive(Serialize, Deserialize)]
+pub struct FDS {
+    bios: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr_ram: Vec<u8>,
+
+    original: Vec<u8>, // the image file as loaded, disk writes are saved as a patch of it
+    image: DiskImage,
+    raw_sides: Vec<Vec<u8>>, // each side as the drive sees it, see disk::side_to_raw
+    written_sides: Vec<bool>,
+    side: Option<usize>, // inserted side, None while switching
+    next_side: usize,
+    switch_delay: u32,
+
+    disk_registers_enabled: bool,
+    sound_registers_enabled: bool,
+    timer_reload: u16,
+    timer_counter: u16,
+    timer_enabled: bool,
+    timer_repeat: bool,
+    timer_irq: bool,
+    control: u8, // $4025
+    write_data: u8,
+    read_data: u8,
+    transfer_complete: bool,
+    disk_irq: bool,
+    external: u8, // $4026 expansion port output
+
+    motor_on: bool,
+    head_position: usize,
+    byte_delay: u32,
+    end_of_head: bool,
+    scanning: bool,
+    gap_ended: bool,
+    audio: FdsAudio,
+}
+
+// Implement functionality of FDS
+impl FDS {
+    // Create new FDS object with the first side of the image inserted
+    pub fn new(bios: Vec<u8>, image: DiskImage) -> Result<Self, String> {
+        if bios.len() != BIOS_SIZE {
+            return Err(format!(
+                "Disk System BIOS must be {} bytes, found {}",
+                BIOS_SIZE,
+                bios.len()
+            ));
+        }
+        let sides = image.sides.len();
+        Ok(FDS {
+            bios,
+            prg_ram: vec![0; PRG_RAM_SIZE],
+            chr_ram: vec![0; CHR_WINDOW_SIZE],
+            original: image.to_bytes(),
+            raw_sides: image
+                .sides
+                .iter()
+                .map(|side| disk::side_to_raw(side))
+                .collect(),
+            image,
+            written_sides: vec![false; sides],
+            side: Some(0),
+            next_side: 0,
+            switch_delay: 0,
+            disk_registers_enabled: true,
+            sound_registers_enabled: true,
+            timer_reload: 0,
+            timer_counter: 0,
+            timer_enabled: false,
+            timer_repeat: false,
+            timer_irq: false,
+            control: 0,
+            write_data: 0,
+            read_data: 0,
+            transfer_complete: false,
+            disk_irq: false,
+            external: 0,
+            motor_on: false,
+            head_position: 0,
+            byte_delay: 0,
+            end_of_head: true,
+            scanning: false,
+            gap_ended: false,
+            audio: FdsAudio::new(),
+        })
+    }
+
+    fn transfer_reset(&self) -> bool {
+        self.control & 0x02 != 0
+    }
+
+    fn read_mode(&self) -> bool {
+        self.control & 0x04 != 0
+    }
+
+    fn crc_control(&self) -> bool {
+        self.control & 0x10 != 0
+    }
+
+    // Bit 6 starts the transfer: reads wait for the end of the gap, writes stop
+    // writing zeros
+    fn transfer_started(&self) -> bool {
+        self.control & 0x40 != 0
+    }
+
+    fn disk_irq_enabled(&self) -> bool {
+        self.control & 0x80 != 0
+    }
+
+    fn read_register(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x4030 => {
+                let mut status = 0;
+                if self.timer_irq {
+                    status |= 0x01;
+                }
+                if self.transfer_complete {
+                    status |= 0x02;
+                }
+                if self.end_of_head {
+                    status |= 0x40;
+                }
+                self.timer_irq = false;
+                self.disk_irq = false;
+                self.transfer_complete = false;
+                Some(status)
+            }
+            0x4031 => {
+                self.transfer_complete = false;
+                self.disk_irq = false;
+                Some(self.read_data)
+            }
+            0x4032 => {
+                let inserted = self.side.is_some();
+                let mut status = 0x40;
+                if !inserted {
+                    status |= 0x01 | 0x04; // no disk, so not writable either
+                }
+                if !inserted || !self.scanning {
+                    status |= 0x02;
+                }
+                Some(status)
+            }
+            // Bit 7 is the battery of the drive, always good
+            0x4033 => Some(0x80 | (self.external & 0x7F)),
+            _ => None,
+        }
+    }
+
+    fn write_register(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4020 => self.timer_reload = (self.timer_reload & 0xFF00) | data as u16,
+            0x4021 => self.timer_reload = (self.timer_reload & 0x00FF) | (data as u16) << 8,
+            0x4022 => {
+                self.timer_repeat = data & 0x01 != 0;
+                self.timer_enabled = data & 0x02 != 0 && self.disk_registers_enabled;
+                if self.timer_enabled {
+                    self.timer_counter = self.timer_reload;
+                } else {
+                    self.timer_irq = false;
+                }
+            }
+            0x4023 => {
+                self.disk_registers_enabled = data & 0x01 != 0;
+                self.sound_registers_enabled = data & 0x02 != 0;
+                if !self.disk_registers_enabled {
+                    self.timer_enabled = false;
+                    self.timer_irq = false;
+                    self.disk_irq = false;
+                }
+            }
+            _ if !self.disk_registers_enabled => {}
+            0x4024 => {
+                self.write_data = data;
+                self.transfer_complete = false;
+                self.disk_irq = false;
+            }
+            0x4025 => {
+                self.control = data;
+                self.motor_on = data & 0x01 != 0;
+                self.disk_irq = false;
+            }
+            0x4026 => self.external = data,
+            _ => {}
+        }
+    }
+
+    fn clock_timer(&mut self) {
+        if !self.timer_enabled {
+            return;
+        }
+        if self.timer_counter == 0 {
+            self.timer_irq = true;
+            self.timer_counter = self.timer_reload;
+            if !self.timer_repeat {
+                self.timer_enabled = false;
+            }
+        } else {
+            self.timer_counter -= 1;
+        }
+    }
+
+    // Move the disk under the head, a byte is transferred every BYTE_CYCLES
+    fn clock_drive(&mut self) {
+        if self.switch_delay > 0 {
+            self.switch_delay -= 1;
+            if self.switch_delay == 0 {
+                self.side = Some(self.next_side);
+            }
+        }
+        let side = match self.side {
+            Some(side) if self.motor_on => side,
+            _ => {
+                self.end_of_head = true;
+                self.scanning = false;
+                return;
+            }
+        };
+        if self.transfer_reset() && !self.scanning {
+            return;
+        }
+        if self.end_of_head {
+            self.byte_delay = REWIND_CYCLES;
+            self.end_of_head = false;
+            self.head_position = 0;
+            self.gap_ended = false;
+            return;
+        }
+        if self.byte_delay > 0 {
+            self.byte_delay -= 1;
+            return;
+        }
+
+        self.scanning = true;
+        if self.read_mode() {
+            let data = self.raw_sides[side][self.head_position];
+            let mut irq = self.disk_irq_enabled();
+            if !self.transfer_started() {
+                self.gap_ended = false;
+            } else if data != 0 && !self.gap_ended {
+                // The start mark ending the gap raises no IRQ
+                self.gap_ended = true;
+                irq = false;
+            }
+            if self.gap_ended {
+                self.read_data = data;
+                self.transfer_complete = true;
+                self.disk_irq |= irq;
+            }
+        } else {
+            // CRC bytes are written as zeros, they are never checked
+            let data = if !self.transfer_started() || self.crc_control() {
+                0
+            } else {
+                self.write_data
+            };
+            if !self.crc_control() {
+                self.transfer_complete = true;
+                self.disk_irq |= self.disk_irq_enabled();
+            }
+            self.raw_sides[side][self.head_position] = data;
+            self.written_sides[side] = true;
+            self.gap_ended = false;
+        }
+
+        self.head_position += 1;
+        if self.head_position >= self.raw_sides[side].len() {
+            self.motor_on = false;
+        } else {
+            self.byte_delay = BYTE_CYCLES;
+        }
+    }
+
+    // The image with the disk writes so far
+    fn current_image(&self) -> DiskImage {
+        let mut image = self.image.clone();
+        for (index, raw) in self.raw_sides.iter().enumerate() {
+            if self.written_sides[index] {
+                image.sides[index] = disk::raw_to_side(raw);
+            }
+        }
+        image
+    }
+}
+
+// Implement functionality of Mapper for FDS
+impl Mapper for FDS {
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x4030..=0x4033 if self.disk_registers_enabled => self.read_register(addr),
+            0x4040..=0x4097 if self.sound_registers_enabled => self.audio.read(addr),
+            0x6000..=0xDFFF => Some(self.prg_ram[addr as usize - 0x6000]),
+            0xE000..=0xFFFF => Some(self.bios[addr as usize - 0xE000]),
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4020..=0x4026 => self.write_register(addr, data),
+            0x4040..=0x408A if self.sound_registers_enabled => self.audio.write(addr, data),
+            0x6000..=0xDFFF => self.prg_ram[addr as usize - 0x6000] = data,
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr_ram[addr as usize % CHR_WINDOW_SIZE]
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        self.chr_ram[addr as usize % CHR_WINDOW_SIZE] = data;
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        if self.control & 0x08 != 0 {
+            Mirroring::Horizontal
+        } else {
+            Mirroring::Vertical
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.timer_irq || self.disk_irq
+    }
+
+    fn cpu_clock(&mut self) {
+        self.clock_timer();
+        self.clock_drive();
+        self.audio.clock();
+    }
+
+    fn audio_output(&self) -> f32 {
+        self.audio.output() * ExpansionChip::Fds.level()
+    }
+
+    // The disk writes, as an IPS patch of the image file
+    fn save_ram(&self) -> Vec<u8> {
+        ips::create(&self.original, &self.current_image().to_bytes()).unwrap_or_default()
+    }
+
+    // A patch that does not fit the image is ignored
+    fn load_save_ram(&mut self, data: &[u8]) {
+        let image = match ips::apply(&self.original, data).and_then(|bytes| DiskImage::new(&bytes))
+        {
+            Ok(image) if image.sides.len() == self.image.sides.len() => image,
+            _ => return,
+        };
+        self.raw_sides = image
+            .sides
+            .iter()
+            .map(|side| disk::side_to_raw(side))
+            .collect();
+        self.written_sides.fill(false);
+        self.image = image;
+    }
+
+    // Eject the disk and insert the next side after a moment
+    fn switch_disk_side(&mut self) -> Option<usize> {
+        let current = self.side.unwrap_or(self.next_side);
+        self.next_side = (current + 1) % self.image.sides.len();
+        self.side = None;
+        self.switch_delay = SIDE_SWITCH_CYCLES;
+        Some(self.next_side)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::disk::test::test_side;
+
+    fn fds(sides: usize) -> FDS {
+        let raw: Vec<u8> = (0..sides)
+            .flat_map(|side| test_side(&[0xA0 + side as u8, 0x55]))
+            .collect();
+        FDS::new(vec![0xEA; BIOS_SIZE], DiskImage::new(&raw).unwrap()).unwrap()
+    }
+
+    // Run until the next byte is transferred, as the BIOS does by polling $4030
+    fn next_byte(board: &mut FDS) -> u8 {
+        for _ in 0..1_000_000 {
+            board.cpu_clock();
+            if board.transfer_complete {
+                return board.cpu_read(0x4031).unwrap();
+            }
+        }
+        panic!("no byte transferred");
+    }
+
+    #[test]
+    fn test_memory_map() {
+        let mut board = fds(1);
+        assert!(FDS::new(vec![0; 100], board.image.clone()).is_err());
+        assert_eq!(board.cpu_read(0xFFFC), Some(0xEA));
+        board.cpu_write(0x6000, 0x12);
+        board.cpu_write(0xDFFF, 0x34);
+        assert_eq!(board.cpu_read(0x6000), Some(0x12));
+        assert_eq!(board.cpu_read(0xDFFF), Some(0x34));
+        board.ppu_write(0x1FFF, 0x56);
+        assert_eq!(board.ppu_read(0x1FFF), 0x56);
+
+        board.cpu_write(0x4025, 0x08);
+        assert_eq!(board.mirroring(), Mirroring::Horizontal);
+    }
+
+    #[test]
+    fn test_timer_irq() {
+        let mut board = fds(1);
+        board.cpu_write(0x4020, 2);
+        board.cpu_write(0x4021, 0);
+        board.cpu_write(0x4022, 0b11);
+        for _ in 0..2 {
+            board.cpu_clock();
+        }
+        assert!(!board.irq());
+        board.cpu_clock();
+        assert!(board.irq());
+        assert_eq!(board.cpu_read(0x4030).map(|status| status & 1), Some(1));
+        assert!(!board.irq());
+
+        // Repeats until disabled through $4023
+        for _ in 0..3 {
+            board.cpu_clock();
+        }
+        assert!(board.irq());
+        board.cpu_write(0x4023, 0x00);
+        assert!(!board.irq());
+    }
+
+    #[test]
+    fn test_read_disk() {
+        let mut board = fds(1);
+        assert_eq!(board.cpu_read(0x4032), Some(0x42));
+
+        // Motor on, read mode, transfer started
+        board.cpu_write(0x4025, 0x45);
+        let block: Vec<u8> = (0..16).map(|_| next_byte(&mut board)).collect();
+        assert_eq!(&block[..], b"\x80\x01*NINTENDO-HVC*");
+        assert_eq!(board.cpu_read(0x4032), Some(0x40));
+    }
+
+    #[test]
+    fn test_writes_are_saved_as_patch() {
+        let mut board = fds(1);
+        let unchanged = board.save_ram();
+        assert_eq!(unchanged, b"PATCHEOF");
+
+        // Overwrite the first byte of the file data block
+        let raw = &board.raw_sides[0];
+        let data = raw.iter().rposition(|&byte| byte == 0x55).unwrap() - 1;
+        board.head_position = data;
+        board.end_of_head = false;
+        board.scanning = true;
+        board.cpu_write(0x4024, 0x77);
+        board.cpu_write(0x4025, 0x41);
+        board.cpu_clock();
+        assert_eq!(board.raw_sides[0][data], 0x77);
+
+        let patch = board.save_ram();
+        assert_ne!(patch, unchanged);
+        let mut restored = fds(1);
+        restored.load_save_ram(&patch);
+        assert_eq!(restored.raw_sides[0][data], 0x77);
+        assert_eq!(restored.save_ram(), patch);
+    }
+
+    #[test]
+    fn test_side_switching() {
+        let mut board = fds(2);
+        assert_eq!(board.switch_disk_side(), Some(1));
+        assert_eq!(board.cpu_read(0x4032).map(|status| status & 1), Some(1));
+        for _ in 0..SIDE_SWITCH_CYCLES {
+            board.cpu_clock();
+        }
+        assert_eq!(board.side, Some(1));
+        assert_eq!(board.cpu_read(0x4032).map(|status| status & 1), Some(0));
+        assert_eq!(board.switch_disk_side(), Some(0));
+    }
+}
@@ -0,0 +1,385 @@
+use serde::Deserialize;
+use serde::Serialize;
+
+const WAVE_SIZE: usize = 64;
+
+// Highest gain that affects the volume, the registers hold up to 63
+const MAX_VOLUME_GAIN: u8 = 32;
+
+// $4089 master volume: 2/2, 2/3, 2/4 and 2/5
+const MASTER_VOLUMES: [f32; 4] = [1.0, 2.0 / 3.0, 0.5, 0.4];
+
+// Change of the modulation counter for each modulation table entry, None resets it
+const MODULATION_STEPS: [Option<i8>; 8] = [
+    Some(0),
+    Some(1),
+    Some(2),
+    Some(4),
+    None,
+    Some(-4),
+    Some(-2),
+    Some(-1),
+];
+
+// Declare FdsEnvelope struct
+// The volume and modulation depth envelopes. The gain moves one step every
+// 8 * (speed + 1) * master speed CPU cycles, or is set directly when disabled
+#[derive(Default, Serialize, Deserialize)]
+struct FdsEnvelope {
+    speed: u8,
+    gain: u8,
+    increase: bool,
+    disabled: bool,
+    timer: u32,
+}
+
+// Implement functionality of FdsEnvelope
+impl FdsEnvelope {
+    fn write(&mut self, data: u8, master_speed: u8) {
+        self.speed = data & 0x3F;
+        self.increase = data & 0x40 != 0;
+        self.disabled = data & 0x80 != 0;
+        if self.disabled {
+            self.gain = self.speed;
+        }
+        self.reset_timer(master_speed);
+    }
+
+    fn reset_timer(&mut self, master_speed: u8) {
+        self.timer = 8 * (self.speed as u32 + 1) * master_speed as u32;
+    }
+
+    // Returns true when the gain moved
+    fn clock(&mut self, master_speed: u8) -> bool {
+        if self.disabled || master_speed == 0 {
+            return false;
+        }
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer > 0 {
+            return false;
+        }
+        self.reset_timer(master_speed);
+        if self.increase && self.gain < MAX_VOLUME_GAIN {
+            self.gain += 1;
+        } else if !self.increase && self.gain > 0 {
+            self.gain -= 1;
+        }
+        true
+    }
+}
+
+// Declare FdsAudio struct
+// The Disk System sound channel: a 64-step wavetable of 6-bit samples whose pitch is
+// bent by a modulation unit stepping through a table of pitch changes
+// https://www.nesdev.org/wiki/FDS_audio
+#[derive(Serialize, Deserialize)]
+pub struct FdsAudio {
+    #[serde(with = "crate::state::byte_array")]
+    wave: [u8; WAVE_SIZE],
+    wave_write: bool, // $4089 bit 7: the wave can be written, output holds
+    master_volume: u8,
+    frequency: u16,
+    wave_halted: bool,
+    envelopes_halted: bool,
+    wave_accumulator: u32, // 16 fraction bits and the 6-bit wave position
+    output: f32,
+
+    master_envelope_speed: u8,
+    volume: FdsEnvelope,
+    modulation_envelope: FdsEnvelope,
+
+    #[serde(with = "crate::state::byte_array")]
+    modulation_table: [u8; WAVE_SIZE],
+    modulation_position: usize,
+    modulation_frequency: u16,
+    modulation_halted: bool,
+    modulation_accumulator: u16,
+    modulation_counter: i8, // 7-bit signed
+    pitch_bend: i32,
+}
+
+// Implement functionality of FdsAudio
+impl FdsAudio {
+    // Create new FdsAudio object
+    pub fn new() -> Self {
+        FdsAudio {
+            wave: [0; WAVE_SIZE],
+            wave_write: false,
+            master_volume: 0,
+            frequency: 0,
+            wave_halted: true,
+            envelopes_halted: true,
+            wave_accumulator: 0,
+            output: 0.0,
+            master_envelope_speed: 0xE8,
+            volume: FdsEnvelope::default(),
+            modulation_envelope: FdsEnvelope::default(),
+            modulation_table: [0; WAVE_SIZE],
+            modulation_position: 0,
+            modulation_frequency: 0,
+            modulation_halted: true,
+            modulation_accumulator: 0,
+            modulation_counter: 0,
+            pitch_bend: 0,
+        }
+    }
+
+    fn wave_position(&self) -> usize {
+        (self.wave_accumulator >> 16) as usize % WAVE_SIZE
+    }
+
+    // Reads of $4040-$4097, None for write-only registers
+    pub fn read(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x4040..=0x407F if self.wave_write => Some(self.wave[addr as usize - 0x4040] | 0x40),
+            0x4040..=0x407F => Some(self.wave[self.wave_position()] | 0x40),
+            0x4090 => Some(self.volume.gain | 0x40),
+            0x4092 => Some(self.modulation_envelope.gain | 0x40),
+            _ => None,
+        }
+    }
+
+    // Writes to $4040-$408A
+    pub fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4040..=0x407F if self.wave_write => self.wave[addr as usize - 0x4040] = data & 0x3F,
+            0x4080 => self.volume.write(data, self.master_envelope_speed),
+            0x4082 => {
+                self.frequency = (self.frequency & 0x0F00) | data as u16;
+                self.update_pitch_bend();
+            }
+            0x4083 => {
+                self.frequency = (self.frequency & 0x00FF) | ((data as u16 & 0x0F) << 8);
+                self.update_pitch_bend();
+                self.wave_halted = data & 0x80 != 0;
+                self.envelopes_halted = data & 0x40 != 0;
+                if self.wave_halted {
+                    self.wave_accumulator = 0;
+                }
+                if self.envelopes_halted {
+                    self.volume.reset_timer(self.master_envelope_speed);
+                    self.modulation_envelope
+                        .reset_timer(self.master_envelope_speed);
+                }
+            }
+            0x4084 => {
+                self.modulation_envelope
+                    .write(data, self.master_envelope_speed);
+                self.update_pitch_bend();
+            }
+            0x4085 => {
+                self.set_modulation_counter(data as i32 & 0x7F);
+                self.update_pitch_bend();
+            }
+            0x4086 => {
+                self.modulation_frequency = (self.modulation_frequency & 0x0F00) | data as u16;
+            }
+            0x4087 => {
+                self.modulation_frequency =
+                    (self.modulation_frequency & 0x00FF) | ((data as u16 & 0x0F) << 8);
+                self.modulation_halted = data & 0x80 != 0;
+                if self.modulation_halted {
+                    self.modulation_accumulator = 0;
+                }
+            }
+            // The table can only be filled while modulation is halted, each write
+            // fills two entries
+            0x4088 if self.modulation_halted => {
+                for _ in 0..2 {
+                    self.modulation_table[self.modulation_position] = data & 0x07;
+                    self.modulation_position = (self.modulation_position + 1) % WAVE_SIZE;
+                }
+            }
+            0x4089 => {
+                self.wave_write = data & 0x80 != 0;
+                self.master_volume = data & 0b11;
+            }
+            0x408A => self.master_envelope_speed = data,
+            _ => {}
+        }
+    }
+
+    // The counter wraps within -64..=63
+    fn set_modulation_counter(&mut self, value: i32) {
+        self.modulation_counter = (((value + 64) & 0x7F) - 64) as i8;
+    }
+
+    // Pitch change from the modulation counter and depth, rounded the way the chip does
+    fn update_pitch_bend(&mut self) {
+        let counter = self.modulation_counter as i32;
+        let mut bend = counter * self.modulation_envelope.gain as i32;
+        let remainder = bend & 0x0F;
+        bend >>= 4;
+        if remainder > 0 && bend & 0x80 == 0 {
+            bend += if counter < 0 { -1 } else { 2 };
+        }
+        if bend >= 192 {
+            bend -= 256;
+        } else if bend < -64 {
+            bend += 256;
+        }
+        bend *= self.frequency as i32;
+        let remainder = bend & 0x3F;
+        bend >>= 6;
+        if remainder >= 32 {
+            bend += 1;
+        }
+        self.pitch_bend = bend;
+    }
+
+    fn modulation_enabled(&self) -> bool {
+        !self.modulation_halted && self.modulation_frequency != 0
+    }
+
+    fn clock_modulation(&mut self) -> bool {
+        if !self.modulation_enabled() {
+            return false;
+        }
+        let (accumulator, overflow) = self
+            .modulation_accumulator
+            .overflowing_add(self.modulation_frequency);
+        self.modulation_accumulator = accumulator;
+        if !overflow {
+            return false;
+        }
+        let entry = self.modulation_table[self.modulation_position];
+        let counter = match MODULATION_STEPS[entry as usize] {
+            Some(step) => self.modulation_counter as i32 + step as i32,
+            None => 0,
+        };
+        self.set_modulation_counter(counter);
+        self.modulation_position = (self.modulation_position + 1) % WAVE_SIZE;
+        true
+    }
+
+    // Called once per CPU cycle
+    pub fn clock(&mut self) {
+        let master_speed = self.master_envelope_speed;
+        if !self.wave_halted && !self.envelopes_halted {
+            self.volume.clock(master_speed);
+            if self.modulation_envelope.clock(master_speed) {
+                self.update_pitch_bend();
+            }
+        }
+        if self.clock_modulation() {
+            self.update_pitch_bend();
+        }
+
+        if !self.wave_halted && !self.wave_write {
+            let bend = if self.modulation_enabled() {
+                self.pitch_bend
+            } else {
+                0
+            };
+            let pitch = self.frequency as i32 + bend;
+            if pitch > 0 {
+                self.wave_accumulator = (self.wave_accumulator + pitch as u32) & 0x3F_FFFF;
+            }
+        }
+        // The output holds while the wave is being written
+        if !self.wave_write {
+            let gain = self.volume.gain.min(MAX_VOLUME_GAIN) as f32;
+            let sample = self.wave[self.wave_position()] as f32;
+            self.output = sample / 63.0 * gain / MAX_VOLUME_GAIN as f32
+                * MASTER_VOLUMES[self.master_volume as usize];
+        }
+    }
+
+    // Channel level, 0.0 to 1.0
+    pub fn output(&self) -> f32 {
+        self.output
+    }
+}
+
+impl Default for FdsAudio {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // A square wave at full volume
+    fn square() -> FdsAudio {
+        let mut audio = FdsAudio::new();
+        audio.write(0x4089, 0x80);
+        for i in 0..WAVE_SIZE as u16 {
+            audio.write(0x4040 + i, if i < 32 { 63 } else { 0 });
+        }
+        audio.write(0x4089, 0x00);
+        audio.write(0x4080, 0x80 | 32);
+        audio
+    }
+
+    // Number of times the wave wraps around over the given CPU cycles
+    fn cycles(audio: &mut FdsAudio, cpu_cycles: usize) -> usize {
+        let mut wraps = 0;
+        let mut last = audio.wave_position();
+        for _ in 0..cpu_cycles {
+            audio.clock();
+            if audio.wave_position() < last {
+                wraps += 1;
+            }
+            last = audio.wave_position();
+        }
+        wraps
+    }
+
+    #[test]
+    fn test_wave_playback() {
+        let mut audio = square();
+        audio.write(0x4082, 0x10);
+        // Halted until $4083 bit 7 clears
+        assert_eq!(cycles(&mut audio, 10_000), 0);
+
+        // One wave cycle every 2^22 / frequency CPU cycles
+        audio.write(0x4083, 0x40);
+        assert_eq!(cycles(&mut audio, 1 << 20), 4);
+        assert_eq!(audio.read(0x4090), Some(0x40 | 32));
+
+        // Master volume
+        audio.write(0x4089, 0x03);
+        let peak = (0..1 << 18)
+            .map(|_| {
+                audio.clock();
+                audio.output()
+            })
+            .fold(0.0, f32::max);
+        assert!((peak - 0.4).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_volume_envelope() {
+        let mut audio = square();
+        audio.write(0x4083, 0x00);
+        // Decreasing with speed 0: a step every 8 * 1 * 2 cycles
+        audio.write(0x408A, 2);
+        audio.write(0x4080, 0x00);
+        audio.volume.gain = 10;
+        for _ in 0..16 * 4 {
+            audio.clock();
+        }
+        assert_eq!(audio.read(0x4090), Some(0x40 | 6));
+    }
+
+    #[test]
+    fn test_modulation_bends_pitch() {
+        let mut audio = square();
+        audio.write(0x4087, 0x80);
+        for _ in 0..32 {
+            audio.write(0x4088, 0);
+        }
+        // Counter 63 at depth 32 raises the pitch from 16 to 48
+        audio.write(0x4085, 0x3F);
+        audio.write(0x4084, 0x80 | 32);
+        audio.write(0x4082, 0x10);
+        audio.write(0x4083, 0x40);
+        assert_eq!(cycles(&mut audio, 1 << 20), 4);
+
+        audio.write(0x4086, 0x01);
+        audio.write(0x4087, 0x00);
+        assert_eq!(cycles(&mut audio, 1 << 20), 12);
+    }
+}
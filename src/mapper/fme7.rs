@@ -0,0 +1,270 @@
+use super::bank_count;
+use super::bank_offset;
+use super::chr_memory;
+use super::load_ram;
+use super::prg_ram;
+use super::sunsoft5b::Sunsoft5b;
+use super::ExpansionChip;
+use super::Mapper;
+use crate::cartridge::Mirroring;
+use crate::cartridge::Rom;
+use serde::Deserialize;
+use serde::Serialize;
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x0400;
+
+// Declare FME7 struct
+// Sunsoft FME-7 and 5B, mapper 69: four 8KB PRG banks of which the one at $6000 can be
+// RAM, eight 1KB CHR banks and a 16-bit IRQ counter clocked by the CPU. The 5B adds
+// three square wave channels https://www.nesdev.org/wiki/Sunsoft_FME-7
+#[derive(Serialize, Deserialize)]
+pub struct FME7 {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+
+    command: u8,
+    chr_banks: [u8; 8],
+    ram_bank: u8, // $6000: bit 7 RAM enable, bit 6 RAM instead of ROM, bits 0-5 bank
+    prg_banks: [u8; 3],
+    mirroring: u8,
+    irq_enabled: bool,
+    irq_counter_enabled: bool,
+    irq_counter: u16,
+    irq_pending: bool,
+    audio: Sunsoft5b,
+}
+
+// Implement functionality of FME7
+impl FME7 {
+    // Create new FME7 object
+    pub fn new(rom: Rom) -> Self {
+        let (chr, chr_is_ram) = chr_memory(&rom);
+        FME7 {
+            prg_ram: prg_ram(&rom, rom.total_prg_ram_size()),
+            prg_rom: rom.prg_rom,
+            chr,
+            chr_is_ram,
+            command: 0,
+            chr_banks: [0; 8],
+            ram_bank: 0,
+            prg_banks: [0; 3],
+            mirroring: 0,
+            irq_enabled: false,
+            irq_counter_enabled: false,
+            irq_counter: 0,
+            irq_pending: false,
+            audio: Sunsoft5b::new(),
+        }
+    }
+
+    fn prg_rom_addr(&self, addr: u16) -> usize {
+        let bank = match addr {
+            0x6000..=0x7FFF => (self.ram_bank & 0x3F) as usize,
+            0x8000..=0xDFFF => self.prg_banks[(addr as usize - 0x8000) >> 13] as usize,
+            _ => bank_count(PRG_BANK_SIZE, self.prg_rom.len()) - 1,
+        };
+        bank_offset(bank, PRG_BANK_SIZE, self.prg_rom.len()) + (addr as usize & 0x1FFF)
+    }
+
+    fn prg_ram_addr(&self, addr: u16) -> usize {
+        let bank = (self.ram_bank & 0x3F) as usize;
+        bank_offset(bank, PRG_BANK_SIZE, self.prg_ram.len()) + (addr as usize & 0x1FFF)
+    }
+
+    fn ram_selected(&self) -> bool {
+        self.ram_bank & 0x40 != 0
+    }
+
+    fn ram_enabled(&self) -> bool {
+        self.ram_selected() && self.ram_bank & 0x80 != 0 && !self.prg_ram.is_empty()
+    }
+
+    fn chr_addr(&self, addr: u16) -> usize {
+        let bank = self.chr_banks[addr as usize >> 10] as usize;
+        bank_offset(bank, CHR_BANK_SIZE, self.chr.len()) + (addr as usize & 0x03FF)
+    }
+
+    fn write_parameter(&mut self, data: u8) {
+        match self.command {
+            0x0..=0x7 => self.chr_banks[self.command as usize] = data,
+            0x8 => self.ram_bank = data,
+            0x9..=0xB => self.prg_banks[self.command as usize - 0x9] = data & 0x3F,
+            0xC => self.mirroring = data & 0b11,
+            0xD => {
+                self.irq_enabled = data & 0x01 != 0;
+                self.irq_counter_enabled = data & 0x80 != 0;
+                self.irq_pending = false;
+            }
+            0xE => self.irq_counter = (self.irq_counter & 0xFF00) | data as u16,
+            _ => self.irq_counter = (self.irq_counter & 0x00FF) | (data as u16) << 8,
+        }
+    }
+}
+
+// Implement functionality of Mapper for FME7
+impl Mapper for FME7 {
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7FFF if self.ram_selected() => {
+                if self.ram_enabled() {
+                    Some(self.prg_ram[self.prg_ram_addr(addr)])
+                } else {
+                    None
+                }
+            }
+            0x6000..=0xFFFF => Some(self.prg_rom[self.prg_rom_addr(addr)]),
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x6000..=0x7FFF if self.ram_enabled() => {
+                let index = self.prg_ram_addr(addr);
+                self.prg_ram[index] = data;
+            }
+            0x8000..=0x9FFF => self.command = data & 0x0F,
+            0xA000..=0xBFFF => self.write_parameter(data),
+            0xC000..=0xDFFF => self.audio.write_address(data),
+            0xE000..=0xFFFF => self.audio.write_data(data),
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr[self.chr_addr(addr)]
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        if self.chr_is_ram {
+            let index = self.chr_addr(addr);
+            self.chr[index] = data;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.mirroring {
+            0 => Mirroring::Vertical,
+            1 => Mirroring::Horizontal,
+            2 => Mirroring::SingleScreenLower,
+            _ => Mirroring::SingleScreenUpper,
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn cpu_clock(&mut self) {
+        if self.irq_counter_enabled {
+            self.irq_counter = self.irq_counter.wrapping_sub(1);
+            if self.irq_counter == 0xFFFF && self.irq_enabled {
+                self.irq_pending = true;
+            }
+        }
+        self.audio.clock();
+    }
+
+    fn audio_output(&self) -> f32 {
+        self.audio.output() * ExpansionChip::Sunsoft5b.level()
+    }
+
+    fn save_ram(&self) -> Vec<u8> {
+        self.prg_ram.clone()
+    }
+
+    fn load_save_ram(&mut self, data: &[u8]) {
+        load_ram(&mut self.prg_ram, data);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::banked_rom;
+
+    fn fme7() -> FME7 {
+        FME7::new(banked_rom(
+            69,
+            0x40000,
+            0x40000,
+            PRG_BANK_SIZE,
+            CHR_BANK_SIZE,
+        ))
+    }
+
+    fn command(board: &mut FME7, command: u8, data: u8) {
+        board.cpu_write(0x8000, command);
+        board.cpu_write(0xA000, data);
+    }
+
+    #[test]
+    fn test_banking() {
+        let mut board = fme7();
+        command(&mut board, 0x9, 3);
+        command(&mut board, 0xA, 5);
+        command(&mut board, 0xB, 7);
+        assert_eq!(board.cpu_read(0x8000), Some(3));
+        assert_eq!(board.cpu_read(0xA000), Some(5));
+        assert_eq!(board.cpu_read(0xC000), Some(7));
+        assert_eq!(board.cpu_read(0xE000), Some(31));
+
+        command(&mut board, 0x5, 0x42);
+        assert_eq!(board.ppu_read(0x1400), 0x42);
+        command(&mut board, 0xC, 3);
+        assert_eq!(board.mirroring(), Mirroring::SingleScreenUpper);
+    }
+
+    #[test]
+    fn test_prg_ram_or_rom_at_6000() {
+        let mut board = fme7();
+        command(&mut board, 0x8, 9);
+        assert_eq!(board.cpu_read(0x6000), Some(9));
+
+        // RAM selected but disabled reads open bus
+        command(&mut board, 0x8, 0x40);
+        board.cpu_write(0x6000, 0x42);
+        assert_eq!(board.cpu_read(0x6000), None);
+        command(&mut board, 0x8, 0xC0);
+        board.cpu_write(0x6000, 0x42);
+        assert_eq!(board.cpu_read(0x6000), Some(0x42));
+    }
+
+    #[test]
+    fn test_irq_counter() {
+        let mut board = fme7();
+        command(&mut board, 0xE, 0x01);
+        command(&mut board, 0xF, 0x00);
+        command(&mut board, 0xD, 0x81);
+        board.cpu_clock();
+        assert!(!board.irq());
+        board.cpu_clock();
+        assert!(board.irq());
+
+        // Writing the control register acknowledges
+        command(&mut board, 0xD, 0x80);
+        assert!(!board.irq());
+        board.cpu_clock();
+        assert!(!board.irq());
+    }
+
+    #[test]
+    fn test_audio_output() {
+        let mut board = fme7();
+        // Channel A tone at full volume
+        for (register, data) in [(0, 0x10), (7, 0b11_1110), (8, 0x0F)] {
+            board.cpu_write(0xC000, register);
+            board.cpu_write(0xE000, data);
+        }
+        let peak = (0..10_000)
+            .map(|_| {
+                board.cpu_clock();
+                board.audio_output()
+            })
+            .fold(0.0, f32::max);
+        assert!((peak - ExpansionChip::Sunsoft5b.level()).abs() < 1e-6);
+    }
+}
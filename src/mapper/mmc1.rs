@@ -0,0 +1,337 @@
+use super::bank_count;
+use super::bank_offset;
+use super::chr_memory;
+use super::load_ram;
+use super::prg_ram;
+use super::Mapper;
+use crate::cartridge::Mirroring;
+use crate::cartridge::Rom;
+use serde::Deserialize;
+use serde::Serialize;
+
+const PRG_BANK_SIZE: usize = 0x4000;
+const CHR_BANK_SIZE: usize = 0x1000;
+const PRG_RAM_BANK_SIZE: usize = 0x2000;
+
+// Boards with 512KB of PRG-ROM (SUROM, SXROM) use a CHR line to pick the 256KB half
+const OUTER_PRG_SIZE: usize = 0x40000;
+
+// Shift register value after a reset, the 1 reaching bit 0 marks the fifth write
+const SHIFT_RESET: u8 = 0b1_0000;
+
+// NES 2.0 submapper 5: SEROM/SHROM/SH1ROM, 32KB of PRG-ROM that is never banked
+const SUBMAPPER_FIXED_PRG: u8 = 5;
+
+// Declare MMC1 struct
+// Mapper 1, registers are loaded one bit at a time through a 5-bit shift register
+// https://www.nesdev.org/wiki/MMC1
+#[derive(Serialize, Deserialize)]
+pub struct MMC1 {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    shift_register: u8,
+    control: u8,
+    chr_bank_0: u8,
+    chr_bank_1: u8,
+    prg_bank: u8,
+    prg_ram_always_enabled: bool, // MMC1A has no PRG-RAM enable bit
+    fixed_prg: bool,
+    cycle: u64,
+    last_write_cycle: Option<u64>,
+}
+
+// Implement functionality of MMC1
+impl MMC1 {
+    // Create new MMC1 object (MMC1B and later)
+    pub fn new(rom: Rom) -> Self {
+        let (chr, chr_is_ram) = chr_memory(&rom);
+        MMC1 {
+            prg_ram: prg_ram(&rom, rom.total_prg_ram_size()),
+            fixed_prg: rom.submapper == SUBMAPPER_FIXED_PRG,
+            prg_rom: rom.prg_rom,
+            chr,
+            chr_is_ram,
+            shift_register: SHIFT_RESET,
+            control: 0x0C, // PRG mode 3: last bank fixed at $C000
+            chr_bank_0: 0,
+            chr_bank_1: 0,
+            prg_bank: 0,
+            prg_ram_always_enabled: false,
+            cycle: 0,
+            last_write_cycle: None,
+        }
+    }
+
+    // Create new MMC1 object for the MMC1A (mapper 155)
+    pub fn new_mmc1a(rom: Rom) -> Self {
+        MMC1 {
+            prg_ram_always_enabled: true,
+            ..MMC1::new(rom)
+        }
+    }
+
+    // Serial port at $8000-$FFFF
+    fn write_shift_register(&mut self, addr: u16, data: u8) {
+        // The MMC1 ignores a write on the cycle right after another one, which is what
+        // the dummy write of a read-modify-write instruction does. Writes in the same
+        // instruction happen before the bus is ticked, so they share a cycle count here
+        let consecutive = self.last_write_cycle == Some(self.cycle);
+        self.last_write_cycle = Some(self.cycle);
+        if consecutive {
+            return;
+        }
+
+        if data & 0x80 != 0 {
+            self.shift_register = SHIFT_RESET;
+            self.control |= 0x0C;
+            return;
+        }
+
+        let complete = self.shift_register & 1 == 1;
+        self.shift_register = (self.shift_register >> 1) | ((data & 1) << 4);
+        if complete {
+            let value = self.shift_register;
+            match addr {
+                0x8000..=0x9FFF => self.control = value,
+                0xA000..=0xBFFF => self.chr_bank_0 = value,
+                0xC000..=0xDFFF => self.chr_bank_1 = value,
+                _ => self.prg_bank = value,
+            }
+            self.shift_register = SHIFT_RESET;
+        }
+    }
+
+    fn prg_rom_addr(&self, addr: u16) -> usize {
+        if self.fixed_prg {
+            return (addr - 0x8000) as usize % self.prg_rom.len();
+        }
+
+        // SUROM/SXROM: CHR bank bit 4 selects the 256KB half, in both halves the
+        // "last" bank is the last bank of that half
+        let (outer, inner_size) = if self.prg_rom.len() > OUTER_PRG_SIZE {
+            ((self.chr_bank_0 as usize >> 4) & 1, OUTER_PRG_SIZE)
+        } else {
+            (0, self.prg_rom.len())
+        };
+        let last_bank = bank_count(PRG_BANK_SIZE, inner_size) - 1;
+        let bank = (self.prg_bank & 0x0F) as usize;
+
+        let bank = match ((self.control >> 2) & 0b11, addr) {
+            // 32KB mode ignores the low bit of the bank number
+            (0 | 1, 0x8000..=0xBFFF) => bank & !1,
+            (0 | 1, _) => bank | 1,
+            (2, 0x8000..=0xBFFF) => 0,
+            (2, _) => bank,
+            (_, 0x8000..=0xBFFF) => bank,
+            (_, _) => last_bank,
+        };
+        let bank = (bank % (last_bank + 1)) + outer * (OUTER_PRG_SIZE / PRG_BANK_SIZE);
+        // Images smaller than a bank are mirrored within it
+        let offset =
+            bank_offset(bank, PRG_BANK_SIZE, self.prg_rom.len()) + (addr as usize & 0x3FFF);
+        offset % self.prg_rom.len()
+    }
+
+    fn chr_addr(&self, addr: u16) -> usize {
+        let bank = if self.control & 0x10 == 0 {
+            // 8KB mode ignores the low bit of the bank number
+            (self.chr_bank_0 & 0x1E) as usize + (addr as usize >> 12)
+        } else if addr < 0x1000 {
+            self.chr_bank_0 as usize
+        } else {
+            self.chr_bank_1 as usize
+        };
+        bank_offset(bank, CHR_BANK_SIZE, self.chr.len()) + (addr as usize & 0x0FFF)
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        if self.prg_ram.is_empty() {
+            return false;
+        }
+        // SNROM: CHR bank bit 4 also disables the RAM when the board has CHR-RAM
+        let snrom_disabled =
+            self.chr_is_ram && self.prg_rom.len() <= OUTER_PRG_SIZE && self.chr_bank_0 & 0x10 != 0;
+        self.prg_ram_always_enabled || (self.prg_bank & 0x10 == 0 && !snrom_disabled)
+    }
+
+    // SOROM (16KB) and SXROM (32KB) select 8KB PRG-RAM banks with CHR bank bits 2-3
+    fn prg_ram_addr(&self, addr: u16) -> usize {
+        let bank = match self.prg_ram.len() / PRG_RAM_BANK_SIZE {
+            0 | 1 => 0,
+            2 => (self.chr_bank_0 as usize >> 3) & 1,
+            _ => (self.chr_bank_0 as usize >> 2) & 0b11,
+        };
+        bank_offset(bank, PRG_RAM_BANK_SIZE, self.prg_ram.len()) + (addr as usize & 0x1FFF)
+    }
+}
+
+// Implement functionality of Mapper for MMC1
+impl Mapper for MMC1 {
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => {
+                Some(self.prg_ram[self.prg_ram_addr(addr)])
+            }
+            0x8000..=0xFFFF => Some(self.prg_rom[self.prg_rom_addr(addr)]),
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => {
+                let index = self.prg_ram_addr(addr);
+                self.prg_ram[index] = data;
+            }
+            0x8000..=0xFFFF => self.write_shift_register(addr, data),
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr[self.chr_addr(addr)]
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        if self.chr_is_ram {
+            let index = self.chr_addr(addr);
+            self.chr[index] = data;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.control & 0b11 {
+            0 => Mirroring::SingleScreenLower,
+            1 => Mirroring::SingleScreenUpper,
+            2 => Mirroring::Vertical,
+            _ => Mirroring::Horizontal,
+        }
+    }
+
+    fn cpu_clock(&mut self) {
+        self.cycle += 1;
+    }
+
+    fn save_ram(&self) -> Vec<u8> {
+        self.prg_ram.clone()
+    }
+
+    fn load_save_ram(&mut self, data: &[u8]) {
+        load_ram(&mut self.prg_ram, data);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::banked_rom;
+    use crate::cartridge::test::test_rom;
+
+    fn mmc1(prg_banks: usize, chr_ram: bool, prg_ram_size: usize) -> MMC1 {
+        let chr_size = if chr_ram { 0 } else { 0x20000 };
+        let prg_size = prg_banks * PRG_BANK_SIZE;
+        let mut rom = banked_rom(1, prg_size, chr_size, PRG_BANK_SIZE, CHR_BANK_SIZE);
+        rom.prg_ram_size = prg_ram_size;
+        MMC1::new(rom)
+    }
+
+    // Load a register through the serial port, one instruction per bit
+    fn load(mapper: &mut MMC1, addr: u16, value: u8) {
+        for bit in 0..5 {
+            mapper.cpu_write(addr, (value >> bit) & 1);
+            mapper.cpu_clock();
+        }
+    }
+
+    #[test]
+    fn test_small_prg() {
+        // 8KB of PRG-ROM is mirrored through both halves
+        let mut rom = test_rom(vec![]);
+        rom.prg_rom = vec![0x42; 0x2000];
+        let mut mapper = MMC1::new(rom);
+        assert_eq!(mapper.cpu_read(0x8000), Some(0x42));
+        assert_eq!(mapper.cpu_read(0xFFFF), Some(0x42));
+    }
+
+    #[test]
+    fn test_prg_banking_modes() {
+        let mut mapper = mmc1(8, false, 0x2000);
+        // Power-up: mode 3, last bank fixed at $C000
+        assert_eq!(mapper.cpu_read(0xC000), Some(7));
+
+        load(&mut mapper, 0xE000, 3);
+        assert_eq!(mapper.cpu_read(0x8000), Some(3));
+        assert_eq!(mapper.cpu_read(0xFFFF), Some(7));
+
+        // Mode 2: first bank fixed at $8000
+        load(&mut mapper, 0x8000, 0b01000);
+        assert_eq!(mapper.cpu_read(0x8000), Some(0));
+        assert_eq!(mapper.cpu_read(0xC000), Some(3));
+
+        // Mode 0: 32KB
+        load(&mut mapper, 0x8000, 0b00000);
+        assert_eq!(mapper.cpu_read(0x8000), Some(2));
+        assert_eq!(mapper.cpu_read(0xC000), Some(3));
+        assert_eq!(mapper.mirroring(), Mirroring::SingleScreenLower);
+    }
+
+    #[test]
+    fn test_chr_banking_and_mirroring() {
+        let mut mapper = mmc1(2, false, 0);
+        load(&mut mapper, 0xA000, 5);
+        assert_eq!(mapper.ppu_read(0x0000), 4);
+        assert_eq!(mapper.ppu_read(0x1000), 5);
+
+        load(&mut mapper, 0x8000, 0b1_0011);
+        load(&mut mapper, 0xC000, 9);
+        assert_eq!(mapper.ppu_read(0x0000), 5);
+        assert_eq!(mapper.ppu_read(0x1000), 9);
+        assert_eq!(mapper.mirroring(), Mirroring::Horizontal);
+    }
+
+    #[test]
+    fn test_reset_and_consecutive_writes() {
+        let mut mapper = mmc1(8, false, 0);
+        mapper.cpu_write(0xE000, 1);
+        mapper.cpu_clock();
+        mapper.cpu_write(0xE000, 0x80);
+        mapper.cpu_clock();
+        load(&mut mapper, 0xE000, 2);
+        assert_eq!(mapper.cpu_read(0x8000), Some(2));
+
+        // A read-modify-write writes twice in a row, only the first write counts
+        mapper.cpu_write(0x8000, 0x80);
+        mapper.cpu_write(0x8000, 0x01);
+        mapper.cpu_clock();
+        load(&mut mapper, 0xE000, 5);
+        assert_eq!(mapper.cpu_read(0x8000), Some(5));
+    }
+
+    #[test]
+    fn test_prg_ram_enable() {
+        let mut mapper = mmc1(2, false, 0x2000);
+        mapper.cpu_write(0x6000, 0x42);
+        assert_eq!(mapper.cpu_read(0x6000), Some(0x42));
+        load(&mut mapper, 0xE000, 0x10);
+        assert_eq!(mapper.cpu_read(0x6000), None);
+    }
+
+    #[test]
+    fn test_sxrom_outer_bank_and_prg_ram_banks() {
+        let mut mapper = mmc1(32, true, 0x8000);
+        assert_eq!(mapper.cpu_read(0xC000), Some(15));
+
+        load(&mut mapper, 0xA000, 0b1_0100);
+        assert_eq!(mapper.cpu_read(0x8000), Some(16));
+        assert_eq!(mapper.cpu_read(0xC000), Some(31));
+
+        // RAM bank 1
+        mapper.cpu_write(0x6000, 0x11);
+        load(&mut mapper, 0xA000, 0b0_0000);
+        assert_eq!(mapper.cpu_read(0x6000), Some(0));
+        load(&mut mapper, 0xA000, 0b0_0100);
+        assert_eq!(mapper.cpu_read(0x6000), Some(0x11));
+    }
+}
@@ -0,0 +1,260 @@
+use super::bank_count;
+use super::bank_offset;
+use super::chr_memory;
+use super::load_ram;
+use super::prg_ram;
+use super::Mapper;
+use crate::cartridge::Mirroring;
+use crate::cartridge::Rom;
+use serde::Deserialize;
+use serde::Serialize;
+
+const PRG_8K: usize = 0x2000;
+const PRG_16K: usize = 0x4000;
+const CHR_BANK_SIZE: usize = 0x1000;
+
+// Tiles whose fetch flips a latch
+const TILE_FD: u8 = 0xFD;
+const TILE_FE: u8 = 0xFE;
+
+// Declare MMC2 struct
+// Mappers 9 (MMC2) and 10 (MMC4). Each 4KB pattern table has two CHR banks and a latch
+// choosing between them, flipped when the PPU fetches the second plane of tile $FD or $FE.
+// The fetch itself still comes from the old bank https://www.nesdev.org/wiki/MMC2
+#[derive(Serialize, Deserialize)]
+pub struct MMC2 {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr: Vec<u8>,
+    mmc4: bool, // 16KB PRG banks, and the left latch triggers on a whole tile row
+    prg_bank: u8,
+    chr_banks: [[u8; 2]; 2], // [pattern table][latch $FD, latch $FE]
+    latches: [u8; 2],
+    horizontal_mirroring: bool,
+}
+
+// Implement functionality of MMC2
+impl MMC2 {
+    // Create new MMC2 object (mapper 9)
+    pub fn new(rom: Rom) -> Self {
+        let (chr, _) = chr_memory(&rom);
+        MMC2 {
+            prg_ram: prg_ram(&rom, rom.total_prg_ram_size()),
+            prg_rom: rom.prg_rom,
+            chr,
+            mmc4: false,
+            prg_bank: 0,
+            chr_banks: [[0; 2]; 2],
+            latches: [TILE_FE; 2],
+            horizontal_mirroring: rom.screen_mirroring == Mirroring::Horizontal,
+        }
+    }
+
+    // Create new MMC2 object for the MMC4 (mapper 10)
+    pub fn new_mmc4(rom: Rom) -> Self {
+        MMC2 {
+            mmc4: true,
+            ..MMC2::new(rom)
+        }
+    }
+
+    fn prg_rom_addr(&self, addr: u16) -> usize {
+        let bank_size = if self.mmc4 { PRG_16K } else { PRG_8K };
+        let offset = addr as usize - 0x8000;
+        // The first bank is switchable, the rest of $8000-$FFFF holds the last banks.
+        // With fewer banks than windows the first windows all get bank 0
+        let bank = if offset < bank_size {
+            (self.prg_bank & 0x0F) as usize
+        } else {
+            let windows = 0x8000 / bank_size;
+            (bank_count(bank_size, self.prg_rom.len()) + offset / bank_size).saturating_sub(windows)
+        };
+        // Images smaller than a bank are mirrored within it
+        (bank_offset(bank, bank_size, self.prg_rom.len()) + offset % bank_size) % self.prg_rom.len()
+    }
+
+    fn chr_addr(&self, addr: u16) -> usize {
+        let table = (addr as usize >> 12) & 1;
+        let latch = (self.latches[table] == TILE_FE) as usize;
+        let bank = self.chr_banks[table][latch] as usize;
+        bank_offset(bank, CHR_BANK_SIZE, self.chr.len()) + (addr as usize & 0x0FFF)
+    }
+
+    // The MMC2 only watches the first row of the second plane on the left pattern table,
+    // everything else watches the whole second plane
+    fn update_latch(&mut self, addr: u16) {
+        let table = (addr as usize >> 12) & 1;
+        let tile = ((addr >> 4) & 0xFF) as u8;
+        let second_plane = addr & 0x08 != 0;
+        let row = addr & 0x07;
+        if !second_plane || (tile != TILE_FD && tile != TILE_FE) {
+            return;
+        }
+        if table == 0 && !self.mmc4 && row != 0 {
+            return;
+        }
+        self.latches[table] = tile;
+    }
+}
+
+// Implement functionality of Mapper for MMC2
+impl Mapper for MMC2 {
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7FFF if !self.prg_ram.is_empty() => {
+                Some(self.prg_ram[(addr as usize - 0x6000) % self.prg_ram.len()])
+            }
+            0x8000..=0xFFFF => Some(self.prg_rom[self.prg_rom_addr(addr)]),
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x6000..=0x7FFF if !self.prg_ram.is_empty() => {
+                let index = (addr as usize - 0x6000) % self.prg_ram.len();
+                self.prg_ram[index] = data;
+            }
+            0xA000..=0xAFFF => self.prg_bank = data,
+            0xB000..=0xBFFF => self.chr_banks[0][0] = data & 0x1F,
+            0xC000..=0xCFFF => self.chr_banks[0][1] = data & 0x1F,
+            0xD000..=0xDFFF => self.chr_banks[1][0] = data & 0x1F,
+            0xE000..=0xEFFF => self.chr_banks[1][1] = data & 0x1F,
+            0xF000..=0xFFFF => self.horizontal_mirroring = data & 1 != 0,
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        let data = self.chr[self.chr_addr(addr)];
+        self.update_latch(addr);
+        data
+    }
+
+    fn ppu_write(&mut self, _addr: u16, _data: u8) {}
+
+    fn mirroring(&self) -> Mirroring {
+        if self.horizontal_mirroring {
+            Mirroring::Horizontal
+        } else {
+            Mirroring::Vertical
+        }
+    }
+
+    fn save_ram(&self) -> Vec<u8> {
+        self.prg_ram.clone()
+    }
+
+    fn load_save_ram(&mut self, data: &[u8]) {
+        load_ram(&mut self.prg_ram, data);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::banked_rom;
+    use crate::cartridge::test::test_rom;
+    use crate::ppu::PPU;
+
+    fn board(mmc4: bool) -> MMC2 {
+        if mmc4 {
+            MMC2::new_mmc4(banked_rom(10, 0x20000, 0x20000, PRG_16K, CHR_BANK_SIZE))
+        } else {
+            MMC2::new(banked_rom(9, 0x20000, 0x20000, PRG_8K, CHR_BANK_SIZE))
+        }
+    }
+
+    fn set_chr_banks(mapper: &mut MMC2) {
+        mapper.cpu_write(0xB000, 1);
+        mapper.cpu_write(0xC000, 2);
+        mapper.cpu_write(0xD000, 3);
+        mapper.cpu_write(0xE000, 4);
+    }
+
+    #[test]
+    fn test_prg_banking() {
+        let mut mmc2 = board(false);
+        mmc2.cpu_write(0xA000, 5);
+        assert_eq!(mmc2.cpu_read(0x8000), Some(5));
+        assert_eq!(mmc2.cpu_read(0xA000), Some(13));
+        assert_eq!(mmc2.cpu_read(0xE000), Some(15));
+
+        let mut mmc4 = board(true);
+        mmc4.cpu_write(0xA000, 2);
+        assert_eq!(mmc4.cpu_read(0x8000), Some(2));
+        assert_eq!(mmc4.cpu_read(0xC000), Some(7));
+    }
+
+    #[test]
+    fn test_small_prg() {
+        // 16KB on the MMC2: the last 8KB bank stays at $E000 where the vectors are
+        let mut rom = test_rom(vec![]);
+        rom.prg_rom = (0..0x4000).map(|i| (i / PRG_8K) as u8).collect();
+        let mut mmc2 = MMC2::new(rom);
+        assert_eq!(mmc2.cpu_read(0xFFFC), Some(1));
+        assert_eq!(mmc2.cpu_read(0xA000), Some(0));
+        mmc2.cpu_write(0xA000, 1);
+        assert_eq!(mmc2.cpu_read(0x8000), Some(1));
+
+        // 8KB on the MMC4 is mirrored through both 16KB windows
+        let mut rom = test_rom(vec![]);
+        rom.prg_rom = vec![0x42; PRG_8K];
+        let mut mmc4 = MMC2::new_mmc4(rom);
+        assert_eq!(mmc4.cpu_read(0x8000), Some(0x42));
+        assert_eq!(mmc4.cpu_read(0xFFFC), Some(0x42));
+    }
+
+    #[test]
+    fn test_latches_switch_after_the_fetch() {
+        let mut mapper = board(false);
+        set_chr_banks(&mut mapper);
+        assert_eq!(mapper.ppu_read(0x0000), 2);
+        assert_eq!(mapper.ppu_read(0x1000), 4);
+
+        // The fetch that trips the latch still reads the old bank
+        assert_eq!(mapper.ppu_read(0x0FD8), 2);
+        assert_eq!(mapper.ppu_read(0x0000), 1);
+        assert_eq!(mapper.ppu_read(0x1FDB), 4);
+        assert_eq!(mapper.ppu_read(0x1000), 3);
+
+        // The MMC2 left latch only reacts to the first row
+        mapper.ppu_read(0x0FEA);
+        assert_eq!(mapper.ppu_read(0x0000), 1);
+        mapper.ppu_read(0x0FE8);
+        assert_eq!(mapper.ppu_read(0x0000), 2);
+
+        mapper.cpu_write(0xF000, 1);
+        assert_eq!(mapper.mirroring(), Mirroring::Horizontal);
+    }
+
+    #[test]
+    fn test_mmc4_left_latch_uses_whole_tile() {
+        let mut mapper = board(true);
+        set_chr_banks(&mut mapper);
+        mapper.ppu_read(0x0FDD);
+        assert_eq!(mapper.ppu_read(0x0000), 1);
+    }
+
+    #[test]
+    fn test_background_tile_fd_flips_bank_while_rendering() {
+        let mut mapper = board(false);
+        set_chr_banks(&mut mapper);
+        mapper.cpu_write(0xD000, 3);
+        let mut ppu = PPU::new();
+
+        // Tile $FD in the top left corner of a background drawn from $1000
+        ppu.write_register(0x2006, 0x20, &mut mapper);
+        ppu.write_register(0x2006, 0x00, &mut mapper);
+        ppu.write_register(0x2007, TILE_FD, &mut mapper);
+        ppu.write_register(0x2000, 0x10, &mut mapper);
+        ppu.write_register(0x2001, 0x08, &mut mapper);
+
+        ppu.scanline = 261;
+        for _ in 0..341 {
+            ppu.tick(&mut mapper);
+        }
+        // Fetched during the pre-render line for the first tiles of line 0
+        assert_eq!(mapper.ppu_read(0x1000), 3);
+    }
+}
@@ -0,0 +1,397 @@
+use super::bank_count;
+use super::bank_offset;
+use super::chr_memory;
+use super::load_ram;
+use super::prg_ram;
+use super::Mapper;
+use crate::cartridge::Mirroring;
+use crate::cartridge::Rom;
+use serde::Deserialize;
+use serde::Serialize;
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x0400;
+
+// A12 has to stay low for this many CPU cycles before a rise clocks the counter, which
+// filters out the short dips between the sprite pattern fetches
+const A12_LOW_CYCLES: u64 = 3;
+
+// NES 2.0 submapper 4: MMC3A with the NEC IRQ behaviour
+const SUBMAPPER_MMC3A: u8 = 4;
+
+// The two IRQ counter behaviours https://www.nesdev.org/wiki/MMC3#IRQ_Specifics
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub enum Mmc3Variant {
+    // MMC3B/MMC3C: every clock that leaves the counter at 0 raises the IRQ, so a latch
+    // of 0 fires on every scanline
+    Sharp,
+    // MMC3A: only a decrement to 0, or a reload requested through $C001, raises it
+    Nec,
+}
+
+// Declare MMC3 struct
+// Mapper 4, 8KB PRG and 1KB/2KB CHR banks with a scanline counter clocked by rising
+// edges of PPU A12 https://www.nesdev.org/wiki/MMC3
+#[derive(Serialize, Deserialize)]
+pub struct MMC3 {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    variant: Mmc3Variant,
+    four_screen: bool,
+
+    bank_select: u8,
+    registers: [u8; 8],
+    horizontal_mirroring: bool,
+    prg_ram_enabled: bool,
+    prg_ram_write_protect: bool,
+
+    irq_latch: u8,
+    irq_counter: u8,
+    irq_reload: bool,
+    irq_enabled: bool,
+    irq_pending: bool,
+
+    cycle: u64,
+    a12_high: bool,
+    a12_fell_at: u64,
+}
+
+// Implement functionality of MMC3
+impl MMC3 {
+    // Create new MMC3 object, the variant comes from the NES 2.0 submapper
+    pub fn new(rom: Rom) -> Self {
+        let variant = if rom.submapper == SUBMAPPER_MMC3A {
+            Mmc3Variant::Nec
+        } else {
+            Mmc3Variant::Sharp
+        };
+        MMC3::with_variant(rom, variant)
+    }
+
+    pub fn with_variant(rom: Rom, variant: Mmc3Variant) -> Self {
+        let (chr, chr_is_ram) = chr_memory(&rom);
+        MMC3 {
+            prg_ram: prg_ram(&rom, rom.total_prg_ram_size()),
+            four_screen: rom.screen_mirroring == Mirroring::FourScreen,
+            prg_rom: rom.prg_rom,
+            chr,
+            chr_is_ram,
+            variant,
+            bank_select: 0,
+            registers: [0, 2, 4, 5, 6, 7, 0, 1],
+            horizontal_mirroring: false,
+            prg_ram_enabled: true,
+            prg_ram_write_protect: false,
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_reload: false,
+            irq_enabled: false,
+            irq_pending: false,
+            cycle: 0,
+            a12_high: false,
+            a12_fell_at: 0,
+        }
+    }
+
+    fn prg_rom_addr(&self, addr: u16) -> usize {
+        let last_bank = bank_count(PRG_BANK_SIZE, self.prg_rom.len()) - 1;
+        let swap = self.bank_select & 0x40 != 0;
+        let bank = match (addr, swap) {
+            (0x8000..=0x9FFF, false) | (0xC000..=0xDFFF, true) => self.registers[6] as usize,
+            (0x8000..=0x9FFF, true) | (0xC000..=0xDFFF, false) => last_bank.saturating_sub(1),
+            (0xA000..=0xBFFF, _) => self.registers[7] as usize,
+            _ => last_bank,
+        };
+        bank_offset(bank & 0x3F, PRG_BANK_SIZE, self.prg_rom.len()) + (addr as usize & 0x1FFF)
+    }
+
+    fn chr_addr(&self, addr: u16) -> usize {
+        // CHR A12 inversion swaps the 2KB and 1KB halves
+        let addr = if self.bank_select & 0x80 != 0 {
+            addr ^ 0x1000
+        } else {
+            addr
+        };
+        let bank = match addr {
+            0x0000..=0x07FF => (self.registers[0] & 0xFE) as usize + (addr as usize >> 10 & 1),
+            0x0800..=0x0FFF => (self.registers[1] & 0xFE) as usize + (addr as usize >> 10 & 1),
+            _ => self.registers[2 + ((addr as usize - 0x1000) >> 10)] as usize,
+        };
+        bank_offset(bank, CHR_BANK_SIZE, self.chr.len()) + (addr as usize & 0x03FF)
+    }
+
+    fn clock_irq_counter(&mut self) {
+        let was_reload = self.irq_reload;
+        let previous = self.irq_counter;
+        if self.irq_counter == 0 || self.irq_reload {
+            self.irq_counter = self.irq_latch;
+            self.irq_reload = false;
+        } else {
+            self.irq_counter -= 1;
+        }
+
+        let fire = match self.variant {
+            Mmc3Variant::Sharp => self.irq_counter == 0,
+            Mmc3Variant::Nec => self.irq_counter == 0 && (previous > 0 || was_reload),
+        };
+        if fire && self.irq_enabled {
+            self.irq_pending = true;
+        }
+    }
+
+    fn write_register(&mut self, addr: u16, data: u8) {
+        let even = addr & 1 == 0;
+        match (addr, even) {
+            (0x8000..=0x9FFF, true) => self.bank_select = data,
+            (0x8000..=0x9FFF, false) => self.registers[(self.bank_select & 0b111) as usize] = data,
+            (0xA000..=0xBFFF, true) => self.horizontal_mirroring = data & 1 != 0,
+            (0xA000..=0xBFFF, false) => {
+                self.prg_ram_enabled = data & 0x80 != 0;
+                self.prg_ram_write_protect = data & 0x40 != 0;
+            }
+            (0xC000..=0xDFFF, true) => self.irq_latch = data,
+            (0xC000..=0xDFFF, false) => {
+                self.irq_counter = 0;
+                self.irq_reload = true;
+            }
+            (_, true) => {
+                self.irq_enabled = false;
+                self.irq_pending = false;
+            }
+            (_, false) => self.irq_enabled = true,
+        }
+    }
+}
+
+// Implement functionality of Mapper for MMC3
+impl Mapper for MMC3 {
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_enabled && !self.prg_ram.is_empty() => {
+                Some(self.prg_ram[(addr as usize - 0x6000) % self.prg_ram.len()])
+            }
+            0x8000..=0xFFFF => Some(self.prg_rom[self.prg_rom_addr(addr)]),
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x6000..=0x7FFF
+                if self.prg_ram_enabled
+                    && !self.prg_ram_write_protect
+                    && !self.prg_ram.is_empty() =>
+            {
+                let index = (addr as usize - 0x6000) % self.prg_ram.len();
+                self.prg_ram[index] = data;
+            }
+            0x8000..=0xFFFF => self.write_register(addr, data),
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr[self.chr_addr(addr)]
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        if self.chr_is_ram {
+            let index = self.chr_addr(addr);
+            self.chr[index] = data;
+        }
+    }
+
+    fn ppu_address(&mut self, addr: u16) {
+        let a12_high = addr & 0x1000 != 0;
+        if a12_high && !self.a12_high && self.cycle - self.a12_fell_at >= A12_LOW_CYCLES {
+            self.clock_irq_counter();
+        }
+        if !a12_high && self.a12_high {
+            self.a12_fell_at = self.cycle;
+        }
+        self.a12_high = a12_high;
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        if self.four_screen {
+            Mirroring::FourScreen
+        } else if self.horizontal_mirroring {
+            Mirroring::Horizontal
+        } else {
+            Mirroring::Vertical
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn cpu_clock(&mut self) {
+        self.cycle += 1;
+    }
+
+    fn save_ram(&self) -> Vec<u8> {
+        self.prg_ram.clone()
+    }
+
+    fn load_save_ram(&mut self, data: &[u8]) {
+        load_ram(&mut self.prg_ram, data);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::banked_rom;
+    use crate::cartridge::test::test_rom;
+    use crate::ppu::PPU;
+
+    fn mmc3(variant: Mmc3Variant) -> MMC3 {
+        let rom = banked_rom(4, 0x20000, 0x20000, PRG_BANK_SIZE, CHR_BANK_SIZE);
+        MMC3::with_variant(rom, variant)
+    }
+
+    // One scanline as seen on the bus: background at $0000, then sprites at $1000
+    fn scanline(mapper: &mut MMC3) {
+        mapper.ppu_address(0x0000);
+        for _ in 0..100 {
+            mapper.cpu_clock();
+        }
+        mapper.ppu_address(0x1000);
+        mapper.cpu_clock();
+        // Nametable fetch between two sprite fetches
+        mapper.ppu_address(0x2000);
+        mapper.cpu_clock();
+        mapper.ppu_address(0x1010);
+        for _ in 0..12 {
+            mapper.cpu_clock();
+        }
+    }
+
+    #[test]
+    fn test_prg_banking() {
+        let mut mapper = mmc3(Mmc3Variant::Sharp);
+        mapper.cpu_write(0x8000, 6);
+        mapper.cpu_write(0x8001, 3);
+        mapper.cpu_write(0x8000, 7);
+        mapper.cpu_write(0x8001, 5);
+        assert_eq!(mapper.cpu_read(0x8000), Some(3));
+        assert_eq!(mapper.cpu_read(0xA000), Some(5));
+        assert_eq!(mapper.cpu_read(0xC000), Some(14));
+        assert_eq!(mapper.cpu_read(0xE000), Some(15));
+
+        mapper.cpu_write(0x8000, 0x46);
+        assert_eq!(mapper.cpu_read(0x8000), Some(14));
+        assert_eq!(mapper.cpu_read(0xC000), Some(3));
+    }
+
+    #[test]
+    fn test_small_prg() {
+        // A single 8KB bank is mirrored in every window, fixed ones included
+        let mut rom = test_rom(vec![]);
+        rom.prg_rom = vec![0x42; PRG_BANK_SIZE];
+        let mut mapper = MMC3::new(rom);
+        mapper.cpu_write(0x8000, 6);
+        mapper.cpu_write(0x8001, 3);
+        for addr in [0x8000, 0xA000, 0xC000, 0xE000] {
+            assert_eq!(mapper.cpu_read(addr), Some(0x42));
+        }
+        mapper.cpu_write(0x8000, 0x46);
+        assert_eq!(mapper.cpu_read(0x8000), Some(0x42));
+    }
+
+    #[test]
+    fn test_chr_banking_and_inversion() {
+        let mut mapper = mmc3(Mmc3Variant::Sharp);
+        mapper.cpu_write(0x8000, 0);
+        mapper.cpu_write(0x8001, 9);
+        mapper.cpu_write(0x8000, 5);
+        mapper.cpu_write(0x8001, 20);
+        assert_eq!(mapper.ppu_read(0x0000), 8);
+        assert_eq!(mapper.ppu_read(0x0400), 9);
+        assert_eq!(mapper.ppu_read(0x1C00), 20);
+
+        mapper.cpu_write(0x8000, 0x80);
+        assert_eq!(mapper.ppu_read(0x1000), 8);
+        assert_eq!(mapper.ppu_read(0x0C00), 20);
+    }
+
+    #[test]
+    fn test_mirroring_and_prg_ram_protect() {
+        let mut mapper = mmc3(Mmc3Variant::Sharp);
+        mapper.cpu_write(0xA000, 1);
+        assert_eq!(mapper.mirroring(), Mirroring::Horizontal);
+
+        mapper.cpu_write(0xA001, 0x80);
+        mapper.cpu_write(0x6000, 0x42);
+        mapper.cpu_write(0xA001, 0xC0);
+        mapper.cpu_write(0x6000, 0x55);
+        assert_eq!(mapper.cpu_read(0x6000), Some(0x42));
+        mapper.cpu_write(0xA001, 0x00);
+        assert_eq!(mapper.cpu_read(0x6000), None);
+    }
+
+    #[test]
+    fn test_scanline_irq() {
+        let mut mapper = mmc3(Mmc3Variant::Sharp);
+        mapper.cpu_write(0xC000, 2);
+        mapper.cpu_write(0xC001, 0);
+        mapper.cpu_write(0xE001, 0);
+
+        // Reload to 2, then 1, then 0
+        scanline(&mut mapper);
+        scanline(&mut mapper);
+        assert!(!mapper.irq());
+        scanline(&mut mapper);
+        assert!(mapper.irq());
+
+        mapper.cpu_write(0xE000, 0);
+        assert!(!mapper.irq());
+    }
+
+    #[test]
+    fn test_latch_zero_differs_between_variants() {
+        for (variant, expected) in [(Mmc3Variant::Sharp, true), (Mmc3Variant::Nec, false)] {
+            let mut mapper = mmc3(variant);
+            mapper.cpu_write(0xC000, 0);
+            mapper.cpu_write(0xC001, 0);
+            mapper.cpu_write(0xE001, 0);
+
+            // A requested reload fires on both
+            scanline(&mut mapper);
+            assert!(mapper.irq());
+            mapper.cpu_write(0xE000, 0);
+            mapper.cpu_write(0xE001, 0);
+
+            // Reloading 0 from 0 only fires on the Sharp chip
+            scanline(&mut mapper);
+            assert_eq!(mapper.irq(), expected, "{:?}", variant);
+        }
+    }
+
+    #[test]
+    fn test_ppu_clocks_counter_once_per_scanline() {
+        let mut mapper = mmc3(Mmc3Variant::Sharp);
+        let mut ppu = PPU::new();
+        mapper.cpu_write(0xC000, 9);
+        mapper.cpu_write(0xC001, 0);
+        mapper.cpu_write(0xE001, 0);
+
+        // Background at $0000, 8x8 sprites at $1000, rendering enabled
+        ppu.write_register(0x2000, 0x08, &mut mapper);
+        ppu.write_register(0x2001, 0x18, &mut mapper);
+        ppu.scanline = 261;
+        for line in 0..12 {
+            for dot in 0..341 {
+                ppu.tick(&mut mapper);
+                if dot % 3 == 2 {
+                    mapper.cpu_clock();
+                }
+            }
+            // Clocked on the pre-render line and lines 0-8: reload to 9, then down to 0
+            assert_eq!(mapper.irq(), line >= 9, "line {}", line);
+        }
+    }
+}
@@ -0,0 +1,794 @@
+use super::bank_offset;
+use super::chr_memory;
+use super::load_ram;
+use super::prg_ram;
+use super::Mapper;
+use super::PpuFetch;
+use crate::apu::pulse::Pulse;
+use crate::apu::pulse::PulseChannel;
+use crate::apu::pulse_mix_level;
+use crate::cartridge::Mirroring;
+use crate::cartridge::Rom;
+use serde::Deserialize;
+use serde::Serialize;
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x0400;
+const EXTENDED_BANK_SIZE: usize = 0x1000; // CHR banks of extended attributes and the split
+const EXRAM_SIZE: usize = 0x0400;
+
+// iNES headers rarely give the PRG-RAM size, 64KB covers every MMC5 board
+const INES_PRG_RAM_SIZE: usize = 0x10000;
+
+// The PPU stops fetching for longer than this when it leaves the visible frame
+const PPU_IDLE_CYCLES: u8 = 3;
+
+// Background tiles fetched per scanline, including the two prefetched for the next one
+const TILES_PER_LINE: u16 = 34;
+
+// Envelopes and length counters of the MMC5 pulses run at a fixed 240Hz
+const AUDIO_FRAME_CYCLES: u16 = 7457;
+
+// ExRAM modes ($5104)
+const EXRAM_NAMETABLE: u8 = 0;
+const EXRAM_EXTENDED_ATTRIBUTES: u8 = 1;
+const EXRAM_READ_WRITE: u8 = 2;
+
+// Nametable sources ($5105)
+const NAMETABLE_EXRAM: u8 = 2;
+const NAMETABLE_FILL: u8 = 3;
+
+// Declare Mmc5Audio struct
+// The sound part of the MMC5: two pulse channels like the APU ones without sweep, and
+// a PCM channel written directly or fed by reads from $8000-$BFFF
+#[derive(Serialize, Deserialize)]
+pub(super) struct Mmc5Audio {
+    pulse1: Pulse,
+    pulse2: Pulse,
+    pcm: u8,
+    pcm_read_mode: bool,
+    pcm_irq_enabled: bool,
+    pcm_irq_pending: bool,
+    cycles: u16,
+}
+
+// Implement functionality of Mmc5Audio
+impl Mmc5Audio {
+    // Create new Mmc5Audio object
+    pub(super) fn new() -> Self {
+        Mmc5Audio {
+            pulse1: Pulse::new(PulseChannel::Mmc5),
+            pulse2: Pulse::new(PulseChannel::Mmc5),
+            pcm: 0,
+            pcm_read_mode: false,
+            pcm_irq_enabled: false,
+            pcm_irq_pending: false,
+            cycles: 0,
+        }
+    }
+
+    // Writes to $5000-$5015
+    pub(super) fn write_register(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x5000..=0x5003 if addr != 0x5001 => self.pulse1.write_register(addr - 0x5000, data),
+            0x5004..=0x5007 if addr != 0x5005 => self.pulse2.write_register(addr - 0x5004, data),
+            0x5010 => {
+                self.pcm_read_mode = data & 0b1 != 0;
+                self.pcm_irq_enabled = data & 0x80 != 0;
+            }
+            // Writes of 0 are ignored, 0 stops reads in read mode
+            0x5011 if !self.pcm_read_mode && data != 0 => self.pcm = data,
+            0x5015 => {
+                self.pulse1.length_counter.set_enabled(data & 0b01 != 0);
+                self.pulse2.length_counter.set_enabled(data & 0b10 != 0);
+            }
+            _ => {}
+        }
+    }
+
+    // Reads of $5010 and $5015
+    pub(super) fn read_register(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x5010 => {
+                let pending = std::mem::take(&mut self.pcm_irq_pending);
+                Some(((pending as u8) << 7) | self.pcm_read_mode as u8)
+            }
+            0x5015 => Some(
+                self.pulse1.length_counter.is_active() as u8
+                    | (self.pulse2.length_counter.is_active() as u8) << 1,
+            ),
+            _ => None,
+        }
+    }
+
+    // In read mode the PCM channel plays whatever the CPU reads from $8000-$BFFF
+    pub(super) fn snoop_read(&mut self, addr: u16, data: u8) {
+        if self.pcm_read_mode && (0x8000..=0xBFFF).contains(&addr) {
+            if data == 0 {
+                self.pcm_irq_pending = true;
+            } else {
+                self.pcm = data;
+            }
+        }
+    }
+
+    pub(super) fn irq(&self) -> bool {
+        self.pcm_irq_pending && self.pcm_irq_enabled
+    }
+
+    // Called once per CPU cycle
+    pub(super) fn clock(&mut self) {
+        self.cycles += 1;
+        if self.cycles.is_multiple_of(2) {
+            self.pulse1.clock_timer();
+            self.pulse2.clock_timer();
+        }
+        if self.cycles == AUDIO_FRAME_CYCLES {
+            self.cycles = 0;
+            for pulse in [&mut self.pulse1, &mut self.pulse2] {
+                pulse.clock_envelope();
+                pulse.clock_length_and_sweep();
+            }
+        }
+    }
+
+    // The pulses go through the same kind of DAC as the APU ones, full scale PCM is
+    // about as loud as both pulses at full volume
+    pub(super) fn output(&self) -> f32 {
+        pulse_mix_level(self.pulse1.output() + self.pulse2.output())
+            + self.pcm as f32 / 255.0 * pulse_mix_level(30)
+    }
+}
+
+// Declare MMC5 struct
+// Mapper 5, the most capable Nintendo mapper: four PRG and CHR banking modes, 1KB of
+// extra RAM (ExRAM) usable as a nametable or per-tile attributes, a vertical split
+// screen, a scanline IRQ, a multiplier and extra sound https://www.nesdev.org/wiki/MMC5
+#[derive(Serialize, Deserialize)]
+pub struct MMC5 {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    #[serde(with = "crate::state::byte_array")]
+    exram: [u8; EXRAM_SIZE],
+
+    prg_mode: u8,
+    chr_mode: u8,
+    prg_ram_protect: [u8; 2],
+    exram_mode: u8,
+    nametable_mapping: u8,
+    fill_tile: u8,
+    fill_attribute: u8,
+    prg_banks: [u8; 5], // $5113-$5117, bit 7 selects ROM in $5114-$5116
+    chr_banks_a: [u16; 8],
+    chr_banks_b: [u16; 4],
+    chr_upper: u8,
+    last_chr_set_b: bool,
+
+    split_control: u8,
+    split_scroll: u8,
+    split_bank: u8,
+
+    irq_compare: u8,
+    irq_enabled: bool,
+    irq_pending: bool,
+    multiplicand: u8,
+    multiplier: u8,
+
+    // What the PPU is doing, worked out from its fetches and register writes
+    sprite_8x16: bool,
+    in_frame: bool,
+    scanline: u16,
+    idle_cycles: u8,
+    last_nametable_fetch: Option<u16>,
+    nametable_matches: u8,
+    tile_fetches: u16,
+    tile_column: u16,
+    fetch: PpuFetch,
+    split_tile: bool,
+    split_y: u16,
+    extended_attribute: u8,
+    audio: Mmc5Audio,
+}
+
+// Implement functionality of MMC5
+impl MMC5 {
+    // Create new MMC5 object
+    pub fn new(rom: Rom) -> Self {
+        let (chr, chr_is_ram) = chr_memory(&rom);
+        let prg_ram_size = if rom.nes2 {
+            rom.total_prg_ram_size()
+        } else {
+            INES_PRG_RAM_SIZE
+        };
+        MMC5 {
+            prg_ram: prg_ram(&rom, prg_ram_size),
+            prg_rom: rom.prg_rom,
+            chr,
+            chr_is_ram,
+            exram: [0; EXRAM_SIZE],
+            prg_mode: 3,
+            chr_mode: 3,
+            prg_ram_protect: [0; 2],
+            exram_mode: EXRAM_NAMETABLE,
+            nametable_mapping: 0,
+            fill_tile: 0,
+            fill_attribute: 0,
+            prg_banks: [0, 0xFF, 0xFF, 0xFF, 0xFF],
+            chr_banks_a: [0; 8],
+            chr_banks_b: [0; 4],
+            chr_upper: 0,
+            last_chr_set_b: false,
+            split_control: 0,
+            split_scroll: 0,
+            split_bank: 0,
+            irq_compare: 0,
+            irq_enabled: false,
+            irq_pending: false,
+            multiplicand: 0xFF,
+            multiplier: 0xFF,
+            sprite_8x16: false,
+            in_frame: false,
+            scanline: 0,
+            idle_cycles: 0,
+            last_nametable_fetch: None,
+            nametable_matches: 0,
+            tile_fetches: 0,
+            tile_column: 0,
+            fetch: PpuFetch::Unused,
+            split_tile: false,
+            split_y: 0,
+            extended_attribute: 0,
+            audio: Mmc5Audio::new(),
+        }
+    }
+
+    // PRG bank register and 8KB bank number for $6000-$FFFF
+    fn prg_bank(&self, addr: u16) -> (u8, usize) {
+        if addr < 0x8000 {
+            // $5113 always selects RAM
+            return (self.prg_banks[0] & 0x7F, self.prg_banks[0] as usize & 0x0F);
+        }
+        let slot = (addr as usize - 0x8000) / PRG_BANK_SIZE;
+        let register = match (self.prg_mode, slot) {
+            (0, _) | (1, 2..=3) | (2, 3) | (3, 3) => 4,
+            (1, _) | (2, 0..=1) => 2,
+            (2, _) => 3,
+            (_, slot) => slot + 1,
+        };
+        // $5117 can only select ROM
+        let value = if register == 4 {
+            self.prg_banks[4] | 0x80
+        } else {
+            self.prg_banks[register]
+        };
+        let bank = value as usize & 0x7F;
+        let bank = match self.prg_mode {
+            0 => (bank & !0b11) | slot,
+            1 => (bank & !0b1) | (slot & 1),
+            2 if slot < 2 => (bank & !0b1) | (slot & 1),
+            _ => bank,
+        };
+        (value, bank)
+    }
+
+    // Offset in PRG-RAM, or None when the address maps to ROM
+    fn prg_ram_addr(&self, addr: u16) -> Option<usize> {
+        let (value, bank) = self.prg_bank(addr);
+        if value & 0x80 != 0 || self.prg_ram.is_empty() {
+            return None;
+        }
+        Some(bank_offset(bank, PRG_BANK_SIZE, self.prg_ram.len()) + (addr as usize & 0x1FFF))
+    }
+
+    fn prg_rom_addr(&self, addr: u16) -> usize {
+        let (_, bank) = self.prg_bank(addr);
+        bank_offset(bank, PRG_BANK_SIZE, self.prg_rom.len()) + (addr as usize & 0x1FFF)
+    }
+
+    // Both protect registers have to hold their magic values to write PRG-RAM
+    fn prg_ram_writable(&self) -> bool {
+        self.prg_ram_protect == [0b10, 0b01]
+    }
+
+    // With 8x16 sprites the sprites use set A and the background set B, otherwise the
+    // last written set is used for everything
+    fn chr_set_b(&self) -> bool {
+        if self.sprite_8x16 && self.in_frame {
+            self.fetch != PpuFetch::SpritePattern
+        } else {
+            self.last_chr_set_b
+        }
+    }
+
+    fn chr_addr(&self, addr: u16) -> usize {
+        let background = self.in_frame && self.fetch == PpuFetch::BackgroundPattern;
+        if background && self.split_tile {
+            let row = (addr as usize & 0x0FF8) | (self.split_y as usize & 0b111);
+            return bank_offset(self.split_bank as usize, EXTENDED_BANK_SIZE, self.chr.len()) + row;
+        }
+        if background && self.exram_mode == EXRAM_EXTENDED_ATTRIBUTES {
+            let bank = (self.extended_attribute as usize & 0x3F) | ((self.chr_upper as usize) << 6);
+            return bank_offset(bank, EXTENDED_BANK_SIZE, self.chr.len())
+                + (addr as usize & 0x0FFF);
+        }
+
+        // Set B only has four registers, repeated over both pattern tables
+        let registers = if self.chr_set_b() {
+            let b = self.chr_banks_b;
+            [b[0], b[1], b[2], b[3], b[0], b[1], b[2], b[3]]
+        } else {
+            self.chr_banks_a
+        };
+        let slot = addr as usize >> 10;
+        let bank = match self.chr_mode {
+            0 => registers[7] as usize * 8 + (slot & 0b111),
+            1 => registers[(slot & !0b11) + 3] as usize * 4 + (slot & 0b11),
+            2 => registers[(slot & !0b1) + 1] as usize * 2 + (slot & 0b1),
+            _ => registers[slot] as usize,
+        };
+        bank_offset(bank, CHR_BANK_SIZE, self.chr.len()) + (addr as usize & 0x03FF)
+    }
+
+    // Where the nametable at addr comes from ($5105)
+    fn nametable_source(&self, addr: u16) -> u8 {
+        let nametable = (addr >> 10) & 0b11;
+        (self.nametable_mapping >> (nametable * 2)) & 0b11
+    }
+
+    // Three fetches of the same nametable address in a row only happen at the start of
+    // a rendered scanline (the two unused fetches and the first real one)
+    fn detect_scanline(&mut self) {
+        if self.in_frame {
+            self.scanline += 1;
+            if self.scanline == self.irq_compare as u16 {
+                self.irq_pending = true;
+            }
+        } else {
+            self.in_frame = true;
+            self.scanline = 0;
+        }
+        self.tile_fetches = 0;
+    }
+
+    // Work out whether the background tile being fetched falls into the split region
+    fn start_tile(&mut self, addr: u16) {
+        let column = (self.tile_fetches + 2) % TILES_PER_LINE;
+        self.tile_fetches += 1;
+
+        let threshold = (self.split_control & 0x1F) as u16;
+        let right_side = self.split_control & 0x40 != 0;
+        self.split_tile = self.split_control & 0x80 != 0
+            && self.exram_mode <= EXRAM_EXTENDED_ATTRIBUTES
+            && if right_side {
+                column >= threshold
+            } else {
+                column < threshold
+            };
+        if self.split_tile {
+            // The last two tiles of the line are the first two of the next one
+            let line = self.scanline + if column < 2 { 1 } else { 0 };
+            self.split_y = (self.split_scroll as u16 + line) % 240;
+        }
+        self.extended_attribute = self.exram[addr as usize & 0x03FF];
+        self.tile_column = column;
+    }
+
+    fn write_register(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x5000..=0x5015 => self.audio.write_register(addr, data),
+            0x5100 => self.prg_mode = data & 0b11,
+            0x5101 => self.chr_mode = data & 0b11,
+            0x5102 => self.prg_ram_protect[0] = data & 0b11,
+            0x5103 => self.prg_ram_protect[1] = data & 0b11,
+            0x5104 => self.exram_mode = data & 0b11,
+            0x5105 => self.nametable_mapping = data,
+            0x5106 => self.fill_tile = data,
+            0x5107 => self.fill_attribute = data & 0b11,
+            0x5113..=0x5117 => self.prg_banks[addr as usize - 0x5113] = data,
+            0x5120..=0x5127 => {
+                self.chr_banks_a[addr as usize - 0x5120] =
+                    ((self.chr_upper as u16) << 8) | data as u16;
+                self.last_chr_set_b = false;
+            }
+            0x5128..=0x512B => {
+                self.chr_banks_b[addr as usize - 0x5128] =
+                    ((self.chr_upper as u16) << 8) | data as u16;
+                self.last_chr_set_b = true;
+            }
+            0x5130 => self.chr_upper = data & 0b11,
+            0x5200 => self.split_control = data,
+            0x5201 => self.split_scroll = data,
+            0x5202 => self.split_bank = data,
+            0x5203 => self.irq_compare = data,
+            0x5204 => self.irq_enabled = data & 0x80 != 0,
+            0x5205 => self.multiplicand = data,
+            0x5206 => self.multiplier = data,
+            0x5C00..=0x5FFF => match self.exram_mode {
+                // Only writable while rendering in the nametable modes, else 0 is written
+                EXRAM_NAMETABLE | EXRAM_EXTENDED_ATTRIBUTES => {
+                    self.exram[addr as usize - 0x5C00] = if self.in_frame { data } else { 0 };
+                }
+                EXRAM_READ_WRITE => self.exram[addr as usize - 0x5C00] = data,
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+
+    fn read_register(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x5010 | 0x5015 => self.audio.read_register(addr),
+            0x5204 => {
+                let pending = std::mem::take(&mut self.irq_pending);
+                Some(((pending as u8) << 7) | (self.in_frame as u8) << 6)
+            }
+            0x5205 => Some((self.multiplicand as u16 * self.multiplier as u16) as u8),
+            0x5206 => Some(((self.multiplicand as u16 * self.multiplier as u16) >> 8) as u8),
+            0x5C00..=0x5FFF if self.exram_mode >= EXRAM_READ_WRITE => {
+                Some(self.exram[addr as usize - 0x5C00])
+            }
+            _ => None,
+        }
+    }
+}
+
+// Implement functionality of Mapper for MMC5
+impl Mapper for MMC5 {
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x5000..=0x5FFF => self.read_register(addr),
+            0x6000..=0xFFFF => {
+                let data = match self.prg_ram_addr(addr) {
+                    Some(index) => self.prg_ram[index],
+                    None if addr < 0x8000 => return None,
+                    None => self.prg_rom[self.prg_rom_addr(addr)],
+                };
+                self.audio.snoop_read(addr, data);
+                Some(data)
+            }
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x5000..=0x5FFF => self.write_register(addr, data),
+            0x6000..=0xFFFF if self.prg_ram_writable() => {
+                if let Some(index) = self.prg_ram_addr(addr) {
+                    self.prg_ram[index] = data;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr[self.chr_addr(addr)]
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        if self.chr_is_ram {
+            let index = self.chr_addr(addr);
+            self.chr[index] = data;
+        }
+    }
+
+    fn ppu_fetch(&mut self, fetch: PpuFetch, addr: u16) {
+        self.idle_cycles = 0;
+        self.fetch = fetch;
+
+        if matches!(fetch, PpuFetch::Nametable | PpuFetch::Unused) {
+            if self.last_nametable_fetch == Some(addr) {
+                self.nametable_matches += 1;
+                if self.nametable_matches == 2 {
+                    self.detect_scanline();
+                }
+            } else {
+                self.nametable_matches = 0;
+            }
+            self.last_nametable_fetch = Some(addr);
+        } else {
+            self.last_nametable_fetch = None;
+            self.nametable_matches = 0;
+        }
+
+        if fetch == PpuFetch::Nametable && self.in_frame {
+            self.start_tile(addr);
+        }
+    }
+
+    fn nametable_read(&mut self, addr: u16) -> Option<u8> {
+        if self.in_frame && self.split_tile {
+            let row = self.split_y as usize / 8;
+            let column = self.tile_column as usize & 0x1F;
+            match self.fetch {
+                PpuFetch::Nametable => return Some(self.exram[row * 32 + column]),
+                PpuFetch::Attribute => {
+                    let attribute = self.exram[0x3C0 + (row / 4) * 8 + column / 4];
+                    let shift = ((row & 0b10) << 1) | (column & 0b10);
+                    return Some(((attribute >> shift) & 0b11) * 0x55);
+                }
+                _ => {}
+            }
+        }
+        if self.in_frame
+            && self.fetch == PpuFetch::Attribute
+            && self.exram_mode == EXRAM_EXTENDED_ATTRIBUTES
+        {
+            return Some((self.extended_attribute >> 6) * 0x55);
+        }
+
+        match self.nametable_source(addr) {
+            NAMETABLE_EXRAM if self.exram_mode <= EXRAM_EXTENDED_ATTRIBUTES => {
+                Some(self.exram[addr as usize & 0x03FF])
+            }
+            NAMETABLE_EXRAM => Some(0),
+            NAMETABLE_FILL if addr & 0x03FF < 0x03C0 => Some(self.fill_tile),
+            NAMETABLE_FILL => Some(self.fill_attribute * 0x55),
+            _ => None,
+        }
+    }
+
+    fn nametable_write(&mut self, addr: u16, data: u8) -> bool {
+        match self.nametable_source(addr) {
+            NAMETABLE_EXRAM => {
+                if self.exram_mode <= EXRAM_EXTENDED_ATTRIBUTES {
+                    self.exram[addr as usize & 0x03FF] = data;
+                }
+                true
+            }
+            NAMETABLE_FILL => true,
+            _ => false,
+        }
+    }
+
+    fn ppu_register_write(&mut self, addr: u16, data: u8) {
+        if addr & 0b111 == 0 {
+            self.sprite_8x16 = data & 0b0010_0000 != 0;
+        }
+    }
+
+    // Nametables mapped to ExRAM or fill mode are answered by nametable_read
+    fn mirroring(&self) -> Mirroring {
+        let page = |nametable: u8| (self.nametable_mapping >> (nametable * 2)) & 0b1;
+        Mirroring::Mapped([page(0), page(1), page(2), page(3)])
+    }
+
+    fn irq(&self) -> bool {
+        (self.irq_pending && self.irq_enabled) || self.audio.irq()
+    }
+
+    fn cpu_clock(&mut self) {
+        if self.idle_cycles < PPU_IDLE_CYCLES {
+            self.idle_cycles += 1;
+        } else {
+            self.in_frame = false;
+            self.last_nametable_fetch = None;
+        }
+        self.audio.clock();
+    }
+
+    fn audio_output(&self) -> f32 {
+        self.audio.output()
+    }
+
+    fn save_ram(&self) -> Vec<u8> {
+        self.prg_ram.clone()
+    }
+
+    fn load_save_ram(&mut self, data: &[u8]) {
+        load_ram(&mut self.prg_ram, data);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::banked_rom;
+    use crate::ppu::PPU;
+
+    fn mmc5() -> MMC5 {
+        MMC5::new(banked_rom(
+            5,
+            0x20000,
+            0x40000,
+            PRG_BANK_SIZE,
+            CHR_BANK_SIZE,
+        ))
+    }
+
+    // Run the PPU for a number of dots, with a CPU cycle every third dot
+    fn run_ppu(ppu: &mut PPU, mapper: &mut MMC5, dots: usize) {
+        for dot in 0..dots {
+            ppu.tick(mapper);
+            if dot % 3 == 2 {
+                mapper.cpu_clock();
+            }
+        }
+    }
+
+    #[test]
+    fn test_prg_banking_modes() {
+        let mut mapper = mmc5();
+        // Power-up: mode 3 with the last bank at $E000
+        assert_eq!(mapper.cpu_read(0xE000), Some(15));
+
+        mapper.cpu_write(0x5100, 0);
+        mapper.cpu_write(0x5117, 0x05);
+        assert_eq!(mapper.cpu_read(0x8000), Some(4));
+        assert_eq!(mapper.cpu_read(0xE000), Some(7));
+
+        mapper.cpu_write(0x5100, 2);
+        mapper.cpu_write(0x5115, 0x83);
+        mapper.cpu_write(0x5116, 0x89);
+        assert_eq!(mapper.cpu_read(0x8000), Some(2));
+        assert_eq!(mapper.cpu_read(0xA000), Some(3));
+        assert_eq!(mapper.cpu_read(0xC000), Some(9));
+        assert_eq!(mapper.cpu_read(0xE000), Some(5));
+    }
+
+    #[test]
+    fn test_prg_ram_banks_and_protection() {
+        let mut mapper = mmc5();
+        mapper.cpu_write(0x5113, 2);
+        mapper.cpu_write(0x6000, 0x42);
+        assert_eq!(mapper.cpu_read(0x6000), Some(0));
+
+        mapper.cpu_write(0x5102, 0b10);
+        mapper.cpu_write(0x5103, 0b01);
+        mapper.cpu_write(0x6000, 0x42);
+        assert_eq!(mapper.cpu_read(0x6000), Some(0x42));
+
+        // The same RAM bank mapped into $8000 with bit 7 clear
+        mapper.cpu_write(0x5114, 2);
+        assert_eq!(mapper.cpu_read(0x8000), Some(0x42));
+        mapper.cpu_write(0x5113, 3);
+        assert_eq!(mapper.cpu_read(0x6000), Some(0));
+    }
+
+    #[test]
+    fn test_chr_sets_and_multiplier() {
+        let mut mapper = mmc5();
+        mapper.cpu_write(0x5101, 3);
+        mapper.cpu_write(0x5130, 1);
+        mapper.cpu_write(0x5123, 0x02);
+        assert_eq!(mapper.ppu_read(0x0C00), 2); // bank $102 wraps around 256 banks
+
+        mapper.cpu_write(0x5130, 0);
+        mapper.cpu_write(0x5129, 0x21);
+        // Set B was written last and covers both pattern tables
+        assert_eq!(mapper.ppu_read(0x0400), 0x21);
+        assert_eq!(mapper.ppu_read(0x1400), 0x21);
+
+        mapper.cpu_write(0x5101, 1);
+        mapper.cpu_write(0x5127, 0x05);
+        assert_eq!(mapper.ppu_read(0x1C00), 0x17);
+
+        mapper.cpu_write(0x5205, 200);
+        mapper.cpu_write(0x5206, 100);
+        assert_eq!(mapper.cpu_read(0x5205), Some(0x20));
+        assert_eq!(mapper.cpu_read(0x5206), Some(0x4E));
+    }
+
+    #[test]
+    fn test_exram_and_fill_nametables() {
+        let mut mapper = mmc5();
+        mapper.cpu_write(0x5104, EXRAM_READ_WRITE);
+        mapper.cpu_write(0x5C10, 0x99);
+        assert_eq!(mapper.cpu_read(0x5C10), Some(0x99));
+
+        // Nametables: CIRAM A, CIRAM B, ExRAM, fill
+        mapper.cpu_write(0x5105, 0b11_10_01_00);
+        mapper.cpu_write(0x5106, 0x33);
+        mapper.cpu_write(0x5107, 0x02);
+        assert_eq!(mapper.mirroring(), Mirroring::Mapped([0, 1, 0, 1]),);
+        assert_eq!(mapper.nametable_read(0x2010), None);
+        assert_eq!(mapper.nametable_read(0x2810), Some(0)); // not a nametable in mode 2
+        assert_eq!(mapper.nametable_read(0x2C10), Some(0x33));
+        assert_eq!(mapper.nametable_read(0x2FC0), Some(0xAA));
+
+        mapper.cpu_write(0x5104, EXRAM_NAMETABLE);
+        assert_eq!(mapper.nametable_read(0x2810), Some(0x99));
+        assert!(mapper.nametable_write(0x2811, 0x77));
+        assert_eq!(mapper.nametable_read(0x2811), Some(0x77));
+        // ExRAM is not readable by the CPU in this mode, and only writable while rendering
+        assert_eq!(mapper.cpu_read(0x5C11), None);
+        mapper.cpu_write(0x5C11, 0x55);
+        assert_eq!(mapper.nametable_read(0x2811), Some(0));
+    }
+
+    #[test]
+    fn test_scanline_irq() {
+        let mut mapper = mmc5();
+        let mut ppu = PPU::new();
+        ppu.write_register(0x2001, 0b0000_1000, &mut mapper);
+        mapper.cpu_write(0x5203, 10);
+        mapper.cpu_write(0x5204, 0x80);
+
+        // The PPU starts on line 0, the first complete frame is the next one
+        run_ppu(&mut ppu, &mut mapper, 262 * 341);
+        mapper.cpu_read(0x5204);
+        run_ppu(&mut ppu, &mut mapper, 10 * 341);
+        assert_eq!((ppu.scanline, ppu.dot), (10, 0));
+        assert!(!mapper.irq());
+        assert_eq!(mapper.cpu_read(0x5204), Some(0x40));
+
+        // Line 10 is detected at its first nametable fetch
+        run_ppu(&mut ppu, &mut mapper, 2);
+        assert!(mapper.irq());
+        assert_eq!(mapper.cpu_read(0x5204), Some(0xC0));
+        assert!(!mapper.irq());
+
+        // Vblank ends the frame
+        run_ppu(&mut ppu, &mut mapper, 240 * 341);
+        assert_eq!(mapper.cpu_read(0x5204), Some(0));
+    }
+
+    #[test]
+    fn test_extended_attributes() {
+        let mut mapper = mmc5();
+        let mut ppu = PPU::new();
+        mapper.cpu_write(0x5104, EXRAM_EXTENDED_ATTRIBUTES);
+        ppu.write_register(0x2001, 0b0000_1000, &mut mapper);
+        run_ppu(&mut ppu, &mut mapper, 262 * 341 + 2);
+        // Every tile takes its 4KB CHR bank and palette from ExRAM
+        mapper.cpu_write(0x5C00, 0b10_000011);
+
+        mapper.ppu_fetch(PpuFetch::Nametable, 0x2000);
+        mapper.ppu_fetch(PpuFetch::Attribute, 0x23C0);
+        assert_eq!(mapper.nametable_read(0x23C0), Some(0xAA));
+        mapper.ppu_fetch(PpuFetch::BackgroundPattern, 0x0010);
+        assert_eq!(mapper.ppu_read(0x0010), 12);
+    }
+
+    #[test]
+    fn test_vertical_split() {
+        let mut mapper = mmc5();
+        let mut ppu = PPU::new();
+        ppu.write_register(0x2001, 0b0000_1000, &mut mapper);
+        // Stop just after the first tile of line 0 (column 2) was fetched
+        run_ppu(&mut ppu, &mut mapper, 262 * 341 + 2);
+        mapper.cpu_write(0x5200, 0x80 | 4);
+        mapper.cpu_write(0x5201, 8);
+        mapper.cpu_write(0x5202, 5);
+        mapper.cpu_write(0x5C23, 0x44);
+
+        // Column 3 is left of the threshold, so it comes from ExRAM row 1 (scroll 8)
+        mapper.ppu_fetch(PpuFetch::Nametable, 0x2003);
+        assert_eq!(mapper.nametable_read(0x2003), Some(0x44));
+        mapper.ppu_fetch(PpuFetch::BackgroundPattern, 0x0445);
+        assert_eq!(mapper.ppu_read(0x0445), 21);
+
+        // Column 4 is not
+        mapper.ppu_fetch(PpuFetch::Nametable, 0x2004);
+        assert_eq!(mapper.nametable_read(0x2004), None);
+    }
+
+    #[test]
+    fn test_pulse_and_pcm_output() {
+        let mut mapper = mmc5();
+        assert_eq!(mapper.audio_output(), 0.0);
+
+        mapper.cpu_write(0x5011, 0xFF);
+        assert!((mapper.audio_output() - pulse_mix_level(30)).abs() < 1e-6);
+        mapper.cpu_write(0x5011, 0);
+        assert!((mapper.audio_output() - pulse_mix_level(30)).abs() < 1e-6);
+
+        // Pulse 1 at constant volume 15, 50% duty
+        mapper.cpu_write(0x5015, 0b01);
+        mapper.cpu_write(0x5000, 0b1011_1111);
+        mapper.cpu_write(0x5002, 0x40);
+        mapper.cpu_write(0x5003, 0b0000_1000);
+        assert_eq!(mapper.cpu_read(0x5015), Some(0b01));
+        let mut levels = Vec::new();
+        for _ in 0..1000 {
+            mapper.cpu_clock();
+            levels.push(mapper.audio_output());
+        }
+        let peak = levels.iter().cloned().fold(0.0, f32::max);
+        let pcm = pulse_mix_level(30);
+        assert!((peak - pcm - pulse_mix_level(15)).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,328 @@
+use crate::apu::pulse_mix_level;
+use crate::cartridge::Mirroring;
+use crate::cartridge::Rom;
+use crate::state;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde::Serialize;
+
+mod discrete;
+mod fds;
+mod fds_audio;
+mod fme7;
+mod mmc1;
+mod mmc2;
+mod mmc3;
+mod mmc5;
+mod namco163;
+mod nrom;
+mod nsf;
+mod opll;
+mod sunsoft5b;
+mod vrc4;
+mod vrc6;
+mod vrc7;
+mod vrc_irq;
+
+pub use discrete::DiscreteBoard;
+pub use discrete::DiscreteLatch;
+pub use fds::FDS;
+pub use fme7::FME7;
+pub use mmc1::MMC1;
+pub use mmc2::MMC2;
+pub use mmc3::Mmc3Variant;
+pub use mmc3::MMC3;
+pub use mmc5::MMC5;
+pub use namco163::Namco163;
+pub use nrom::NROM;
+pub use nsf::NsfCartridge;
+pub use vrc4::VRC4;
+pub use vrc6::VRC6;
+pub use vrc7::VRC7;
+
+// Size of the CHR address space seen by the PPU ($0000-$1FFF)
+pub const CHR_WINDOW_SIZE: usize = 0x2000;
+
+// Declare ExpansionChip enum
+// Sound chips on cartridges, mixed with the APU output on the cartridge port
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ExpansionChip {
+    Vrc6,
+    Vrc7,
+    Fds,
+    Namco163,
+    Sunsoft5b,
+}
+
+// Implement functionality of ExpansionChip
+impl ExpansionChip {
+    // Swing of one channel at full volume compared with an APU pulse at full volume, as
+    // measured on consoles https://www.nesdev.org/wiki/Expansion_audio
+    pub fn relative_level(self) -> f32 {
+        match self {
+            ExpansionChip::Vrc6 => 1.0, // a pulse at volume 15, the sawtooth swings twice as far
+            ExpansionChip::Vrc7 => 1.4,
+            ExpansionChip::Fds => 2.4,
+            // About 12 dB above the APU, the quietest boards (NES 2.0 submapper 3)
+            ExpansionChip::Namco163 => 4.0,
+            ExpansionChip::Sunsoft5b => 2.8,
+        }
+    }
+
+    // Swing of a channel at full volume on the scale of the APU output
+    pub fn level(self) -> f32 {
+        self.relative_level() * pulse_mix_level(15)
+    }
+}
+
+// What a rendering fetch of the PPU is for, see Mapper::ppu_fetch
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub enum PpuFetch {
+    Nametable,
+    Attribute,
+    BackgroundPattern,
+    SpritePattern,
+    Unused, // garbage nametable fetches during sprite fetches and at the end of the line
+}
+
+// Declare MapperState trait
+// Save states of the cartridge: the mapper registers with all its memory. Every mapper
+// gets it by deriving Serialize and Deserialize
+pub trait MapperState {
+    fn save_state(&self) -> Result<Vec<u8>, String>;
+
+    // Go back to a state returned by save_state
+    fn load_state(&mut self, data: &[u8]) -> Result<(), String>;
+}
+
+// Implement MapperState for every serialisable mapper
+impl<T: Serialize + DeserializeOwned> MapperState for T {
+    fn save_state(&self) -> Result<Vec<u8>, String> {
+        state::encode(self)
+    }
+
+    fn load_state(&mut self, data: &[u8]) -> Result<(), String> {
+        *self = state::decode(data)?;
+        Ok(())
+    }
+}
+
+// Declare Mapper trait
+// The cartridge hardware: everything the CPU sees at $4020-$FFFF and the PPU sees at
+// $0000-$1FFF goes through the mapper, which also controls nametable mirroring and can
+// raise IRQs https://www.nesdev.org/wiki/Mapper
+pub trait Mapper: MapperState {
+    // CPU read from $4020-$FFFF, None leaves the open bus value
+    // Takes &mut self because some mapper registers change state when read
+    fn cpu_read(&mut self, addr: u16) -> Option<u8>;
+
+    // CPU write to $4020-$FFFF
+    fn cpu_write(&mut self, addr: u16, data: u8);
+
+    // PPU read from the pattern tables ($0000-$1FFF). Rendering fetches come in the
+    // same order as on hardware, so mappers can react to the tiles being drawn
+    fn ppu_read(&mut self, addr: u16) -> u8;
+
+    // PPU write to the pattern tables, only has an effect on CHR-RAM
+    fn ppu_write(&mut self, addr: u16, data: u8);
+
+    // Called with every address the PPU puts on its bus ($0000-$3FFF): rendering
+    // fetches, PPUDATA accesses and PPUADDR updates. Lets mappers watch lines like A12
+    fn ppu_address(&mut self, _addr: u16) {}
+
+    // Called before each rendering fetch with what it is for, for mappers that feed
+    // the background and sprites different data
+    fn ppu_fetch(&mut self, _fetch: PpuFetch, _addr: u16) {}
+
+    // PPU read from the nametables ($2000-$3EFF). None reads the console VRAM, mapped
+    // by mirroring(); mappers with their own nametable memory answer here
+    fn nametable_read(&mut self, _addr: u16) -> Option<u8> {
+        None
+    }
+
+    // PPU write to the nametables, returns true when the mapper took it
+    fn nametable_write(&mut self, _addr: u16, _data: u8) -> bool {
+        false
+    }
+
+    // CPU write to the PPU registers ($2000-$3FFF), which some mappers snoop
+    fn ppu_register_write(&mut self, _addr: u16, _data: u8) {}
+
+    // Current nametable layout
+    fn mirroring(&self) -> Mirroring;
+
+    // Level of the cartridge IRQ line
+    fn irq(&self) -> bool {
+        false
+    }
+
+    // Called once per CPU cycle, for mappers with cycle-based IRQ counters
+    fn cpu_clock(&mut self) {}
+
+    // Called at dot 260 of every scanline (0-239 visible, then vblank and pre-render),
+    // for mappers that count scanlines without watching the PPU address bus
+    fn ppu_scanline(&mut self, _scanline: u16, _rendering: bool) {}
+
+    // Output of the expansion audio chip, on the same scale as the APU output
+    fn audio_output(&self) -> f32 {
+        0.0
+    }
+
+    // What the cartridge keeps between runs: battery-backed PRG-RAM followed by any save
+    // RAM inside the mapper chip, only meaningful when the cartridge has a battery, or
+    // the disk writes of the Disk System
+    fn save_ram(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    // Restore data returned by save_ram
+    fn load_save_ram(&mut self, _data: &[u8]) {}
+
+    // Eject the disk and insert the next side, returns the side going in. None when
+    // there is no disk drive
+    fn switch_disk_side(&mut self) -> Option<usize> {
+        None
+    }
+}
+
+// Copy a save into RAM, a save of the wrong size fills what fits
+pub(crate) fn load_ram(ram: &mut [u8], data: &[u8]) {
+    let len = ram.len().min(data.len());
+    ram[..len].copy_from_slice(&data[..len]);
+}
+
+// Pick the mapper implementation from the number in the header
+pub fn new_mapper(rom: Rom) -> Result<Box<dyn Mapper>, String> {
+    if let Some(board) = DiscreteBoard::from_mapper(rom.mapper) {
+        return Ok(Box::new(DiscreteLatch::new(rom, board)));
+    }
+    match rom.mapper {
+        0 => Ok(Box::new(NROM::new(rom))),
+        1 => Ok(Box::new(MMC1::new(rom))),
+        4 => Ok(Box::new(MMC3::new(rom))),
+        5 => Ok(Box::new(MMC5::new(rom))),
+        9 => Ok(Box::new(MMC2::new(rom))),
+        10 => Ok(Box::new(MMC2::new_mmc4(rom))),
+        19 => Ok(Box::new(Namco163::new(rom))),
+        21 | 22 | 23 | 25 => Ok(Box::new(VRC4::new(rom))),
+        24 | 26 => Ok(Box::new(VRC6::new(rom))),
+        69 => Ok(Box::new(FME7::new(rom))),
+        85 => Ok(Box::new(VRC7::new(rom))),
+        155 => Ok(Box::new(MMC1::new_mmc1a(rom))),
+        number => Err(format!("Mapper {} is not supported", number)),
+    }
+}
+
+// Offset in memory of a bank of bank_size bytes, bank numbers wrap around the memory size
+// like the unconnected high bank lines do on the boards
+pub(crate) fn bank_offset(bank: usize, bank_size: usize, memory_size: usize) -> usize {
+    (bank % bank_count(bank_size, memory_size)) * bank_size
+}
+
+// Number of banks of bank_size bytes in the memory, at least one so that the "last bank"
+// of an image smaller than a bank (e.g. a trimmed or NES 2.0 odd-sized one) is bank 0
+pub(crate) fn bank_count(bank_size: usize, memory_size: usize) -> usize {
+    (memory_size / bank_size).max(1)
+}
+
+// Where a trainer is loaded: the copiers it was made for put it at $7000-$71FF
+const TRAINER_OFFSET: usize = 0x1000;
+
+// PRG-RAM of size bytes at $6000-$7FFF, holding the trainer of the ROM if it has one.
+// A trainer needs the RAM at $7000, so the RAM is then at least 8KB
+pub(crate) fn prg_ram(rom: &Rom, size: usize) -> Vec<u8> {
+    match &rom.trainer {
+        Some(trainer) => {
+            let mut ram = vec![0; size.max(0x2000)];
+            ram[TRAINER_OFFSET..TRAINER_OFFSET + trainer.len()].copy_from_slice(trainer);
+            ram
+        }
+        None => vec![0; size],
+    }
+}
+
+// CHR-ROM, or CHR-RAM when the board has none
+pub(crate) fn chr_memory(rom: &Rom) -> (Vec<u8>, bool) {
+    if rom.chr_rom.is_empty() {
+        let size = rom.total_chr_ram_size().max(CHR_WINDOW_SIZE);
+        (vec![0; size], true)
+    } else {
+        (rom.chr_rom.clone(), false)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::test_rom;
+
+    #[test]
+    fn test_expansion_levels() {
+        let square = pulse_mix_level(15);
+        assert_eq!(ExpansionChip::Vrc6.level(), square);
+        // The FDS and the Namco 163 are much louder than a square
+        assert!(ExpansionChip::Fds.level() > 2.0 * square);
+        assert!(ExpansionChip::Namco163.level() > 3.5 * square);
+        assert!(ExpansionChip::Sunsoft5b.level() > square);
+    }
+
+    #[test]
+    fn test_bank_offset_wraps() {
+        assert_eq!(bank_offset(1, 0x4000, 0x8000), 0x4000);
+        assert_eq!(bank_offset(3, 0x4000, 0x8000), 0x4000);
+        assert_eq!(bank_offset(7, 0x2000, 0x1000), 0);
+    }
+
+    #[test]
+    fn test_trainer_at_7000() {
+        let trainer: Vec<u8> = (0..=255).cycle().take(512).collect();
+        for number in [0, 1, 4, 5, 9, 10, 19, 21, 24, 69, 85] {
+            let mut rom = test_rom(vec![]);
+            rom.mapper = number;
+            rom.prg_ram_size = 0;
+            rom.trainer = Some(trainer.clone());
+            let mut mapper = new_mapper(rom).unwrap();
+            assert_eq!(
+                mapper.save_ram()[0x1000..0x1200],
+                trainer,
+                "mapper {}",
+                number
+            );
+            // Boards whose RAM is enabled at power-on
+            if [0, 1, 4, 9, 10].contains(&number) {
+                assert_eq!(mapper.cpu_read(0x7000), Some(0));
+                assert_eq!(mapper.cpu_read(0x7001), Some(1));
+                assert_eq!(mapper.cpu_read(0x71FF), Some(255));
+            }
+        }
+    }
+
+    #[test]
+    fn test_save_state() {
+        for number in [0, 1, 2, 3, 4, 5, 7, 9, 10, 11, 19, 21, 24, 66, 69, 85, 155] {
+            let mut rom = test_rom(vec![]);
+            rom.mapper = number;
+            let mut saved = new_mapper(rom).unwrap();
+            saved.cpu_write(0x6000, 0x42);
+            let state = saved.save_state().unwrap();
+
+            // A fresh mapper of the same kind takes the state over
+            let mut rom = test_rom(vec![]);
+            rom.mapper = number;
+            let mut loaded = new_mapper(rom).unwrap();
+            loaded.load_state(&state).unwrap();
+            assert_eq!(loaded.save_state().unwrap(), state, "mapper {}", number);
+            assert_eq!(loaded.save_ram(), saved.save_ram(), "mapper {}", number);
+            assert!(loaded.load_state(&state[1..]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_mapper_is_picked_by_number() {
+        assert!(new_mapper(test_rom(vec![])).is_ok());
+
+        let mut rom = test_rom(vec![]);
+        rom.mapper = 4095;
+        assert!(new_mapper(rom).is_err());
+    }
+}
@@ -0,0 +1,432 @@
+use super::bank_count;
+use super::bank_offset;
+use super::chr_memory;
+use super::load_ram;
+use super::prg_ram;
+use super::ExpansionChip;
+use super::Mapper;
+use crate::cartridge::Mirroring;
+use crate::cartridge::Rom;
+use serde::Deserialize;
+use serde::Serialize;
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x0400;
+
+// 128 bytes of RAM shared by the sound channels and the CPU, behind the $4800 data port
+const INTERNAL_RAM_SIZE: usize = 0x80;
+
+// Bank numbers from $E0 up select a console nametable instead of CHR-ROM
+const CIRAM_BANKS: u8 = 0xE0;
+
+// The 15-bit IRQ counter stops when it reaches this value
+const IRQ_COUNTER_MAX: u16 = 0x7FFF;
+
+// One sound channel is updated every 15 CPU cycles
+const CYCLES_PER_CHANNEL: u8 = 15;
+// Registers of channel n start at $40 + n * 8, $7F also holds the channel count
+const CHANNEL_REGISTERS: usize = 0x40;
+
+// Declare Namco163Audio struct
+// The sound part of the 163: up to 8 wavetable channels playing 4-bit samples from 128
+// bytes of internal RAM, which the CPU reaches through an address and a data port
+#[derive(Serialize, Deserialize)]
+pub(super) struct Namco163Audio {
+    #[serde(with = "crate::state::byte_array")]
+    internal_ram: [u8; INTERNAL_RAM_SIZE],
+    ram_address: u8, // bit 7 increments the address after every data port access
+    channel_timer: u8,
+    current_channel: usize,
+    output: i16, // level of the channel being played, the output is time-multiplexed
+}
+
+// Implement functionality of Namco163Audio
+impl Namco163Audio {
+    // Create new Namco163Audio object
+    pub(super) fn new() -> Self {
+        Namco163Audio {
+            internal_ram: [0; INTERNAL_RAM_SIZE],
+            ram_address: 0,
+            channel_timer: 0,
+            current_channel: 7,
+            output: 0,
+        }
+    }
+
+    // Write to the address port
+    pub(super) fn set_address(&mut self, data: u8) {
+        self.ram_address = data;
+    }
+
+    fn access_internal_ram(&mut self) -> usize {
+        let index = (self.ram_address & 0x7F) as usize;
+        if self.ram_address & 0x80 != 0 {
+            self.ram_address = 0x80 | (self.ram_address.wrapping_add(1) & 0x7F);
+        }
+        index
+    }
+
+    // Read of the data port
+    pub(super) fn read_data(&mut self) -> u8 {
+        let index = self.access_internal_ram();
+        self.internal_ram[index]
+    }
+
+    // Write to the data port
+    pub(super) fn write_data(&mut self, data: u8) {
+        let index = self.access_internal_ram();
+        self.internal_ram[index] = data;
+    }
+
+    fn enabled_channels(&self) -> usize {
+        ((self.internal_ram[0x7F] >> 4) & 0b111) as usize + 1
+    }
+
+    // Advance the phase of one channel and play its current sample
+    fn update_channel(&mut self, channel: usize) {
+        let registers = CHANNEL_REGISTERS + channel * 8;
+        let ram = &mut self.internal_ram;
+        let frequency = ram[registers] as u32
+            | (ram[registers + 2] as u32) << 8
+            | (ram[registers + 4] as u32 & 0b11) << 16;
+        let mut phase = ram[registers + 1] as u32
+            | (ram[registers + 3] as u32) << 8
+            | (ram[registers + 5] as u32) << 16;
+        let length = 256 - (ram[registers + 4] & 0xFC) as u32;
+        let wave_address = ram[registers + 6] as u32;
+        let volume = (ram[registers + 7] & 0x0F) as i16;
+
+        phase = (phase + frequency) % (length << 16);
+        ram[registers + 1] = phase as u8;
+        ram[registers + 3] = (phase >> 8) as u8;
+        ram[registers + 5] = (phase >> 16) as u8;
+
+        // Samples are 4-bit, the low nibble comes first
+        let sample_index = ((phase >> 16) + wave_address) as usize & 0xFF;
+        let sample = (ram[sample_index >> 1] >> ((sample_index & 1) * 4)) & 0x0F;
+        self.output = (sample as i16 - 8) * volume;
+    }
+
+    // Called once per CPU cycle
+    pub(super) fn clock(&mut self) {
+        self.channel_timer += 1;
+        if self.channel_timer == CYCLES_PER_CHANNEL {
+            self.channel_timer = 0;
+            // Channels are played from 7 down to 8 - the number of enabled channels
+            self.current_channel = if self.current_channel <= 8 - self.enabled_channels() {
+                7
+            } else {
+                self.current_channel - 1
+            };
+            self.update_channel(self.current_channel);
+        }
+    }
+
+    // The channel levels are output in turn, which averages out to their sum divided by
+    // the channel count. A channel at full volume has the level of the chip
+    pub(super) fn output(&self) -> f32 {
+        self.output as f32 * ExpansionChip::Namco163.level() / 225.0
+    }
+}
+
+// Declare Namco163 struct
+// Namco 163, mapper 19: 8KB PRG banks, 1KB CHR banks, nametables that can come from
+// CHR-ROM, a 15-bit cycle IRQ counter and up to 8 wavetable sound channels playing
+// 4-bit samples from the internal RAM https://www.nesdev.org/wiki/Namco_163
+#[derive(Serialize, Deserialize)]
+pub struct Namco163 {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    audio: Namco163Audio,
+
+    prg_banks: [u8; 3],
+    chr_banks: [u8; 8],
+    nametable_banks: [u8; 4],
+    prg_ram_protect: u8,
+    sound_disabled: bool,
+
+    irq_counter: u16,
+    irq_enabled: bool,
+    irq_pending: bool,
+}
+
+// Implement functionality of Namco163
+impl Namco163 {
+    // Create new Namco163 object
+    pub fn new(rom: Rom) -> Self {
+        let (chr, chr_is_ram) = chr_memory(&rom);
+        Namco163 {
+            prg_ram: prg_ram(&rom, rom.total_prg_ram_size()),
+            prg_rom: rom.prg_rom,
+            chr,
+            chr_is_ram,
+            audio: Namco163Audio::new(),
+            prg_banks: [0; 3],
+            chr_banks: [0; 8],
+            nametable_banks: [CIRAM_BANKS; 4],
+            prg_ram_protect: 0,
+            sound_disabled: false,
+            irq_counter: 0,
+            irq_enabled: false,
+            irq_pending: false,
+        }
+    }
+
+    fn prg_rom_addr(&self, addr: u16) -> usize {
+        let bank = match addr {
+            0x8000..=0xDFFF => self.prg_banks[(addr as usize - 0x8000) >> 13] as usize,
+            _ => bank_count(PRG_BANK_SIZE, self.prg_rom.len()) - 1,
+        };
+        bank_offset(bank, PRG_BANK_SIZE, self.prg_rom.len()) + (addr as usize & 0x1FFF)
+    }
+
+    // Pattern banks of $E0 and up would map CIRAM when enabled in $E800, which no
+    // game is known to use, so they always read CHR memory here
+    fn chr_addr(&self, bank: u8, addr: u16) -> usize {
+        bank_offset(bank as usize, CHR_BANK_SIZE, self.chr.len()) + (addr as usize & 0x03FF)
+    }
+
+    // $F800 has to hold $4x, with bits 0-3 protecting the four 2KB windows
+    fn prg_ram_writable(&self, addr: u16) -> bool {
+        let window = (addr as usize - 0x6000) >> 11;
+        self.prg_ram_protect & 0xF0 == 0x40 && self.prg_ram_protect & (1 << window) == 0
+    }
+
+    fn write_register(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4800..=0x4FFF => self.audio.write_data(data),
+            0x5000..=0x57FF => {
+                self.irq_counter = (self.irq_counter & 0x7F00) | data as u16;
+                self.irq_pending = false;
+            }
+            0x5800..=0x5FFF => {
+                self.irq_counter = (self.irq_counter & 0x00FF) | ((data as u16 & 0x7F) << 8);
+                self.irq_enabled = data & 0x80 != 0;
+                self.irq_pending = false;
+            }
+            0x8000..=0xBFFF => self.chr_banks[(addr as usize - 0x8000) >> 11] = data,
+            0xC000..=0xDFFF => self.nametable_banks[(addr as usize - 0xC000) >> 11] = data,
+            0xE000..=0xE7FF => {
+                self.prg_banks[0] = data & 0x3F;
+                self.sound_disabled = data & 0x40 != 0;
+            }
+            0xE800..=0xEFFF => self.prg_banks[1] = data & 0x3F,
+            0xF000..=0xF7FF => self.prg_banks[2] = data & 0x3F,
+            0xF800..=0xFFFF => {
+                self.audio.set_address(data);
+                self.prg_ram_protect = data;
+            }
+            _ => {}
+        }
+    }
+}
+
+// Implement functionality of Mapper for Namco163
+impl Mapper for Namco163 {
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x4800..=0x4FFF => Some(self.audio.read_data()),
+            0x5000..=0x57FF => Some(self.irq_counter as u8),
+            0x5800..=0x5FFF => Some((self.irq_enabled as u8) << 7 | (self.irq_counter >> 8) as u8),
+            0x6000..=0x7FFF if !self.prg_ram.is_empty() => {
+                Some(self.prg_ram[(addr as usize - 0x6000) % self.prg_ram.len()])
+            }
+            0x8000..=0xFFFF => Some(self.prg_rom[self.prg_rom_addr(addr)]),
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x6000..=0x7FFF if !self.prg_ram.is_empty() && self.prg_ram_writable(addr) => {
+                let index = (addr as usize - 0x6000) % self.prg_ram.len();
+                self.prg_ram[index] = data;
+            }
+            0x4800..=0x5FFF | 0x8000..=0xFFFF => self.write_register(addr, data),
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr[self.chr_addr(self.chr_banks[addr as usize >> 10], addr)]
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        if self.chr_is_ram {
+            let index = self.chr_addr(self.chr_banks[addr as usize >> 10], addr);
+            self.chr[index] = data;
+        }
+    }
+
+    // Nametables with a bank below $E0 come from CHR-ROM
+    fn nametable_read(&mut self, addr: u16) -> Option<u8> {
+        let bank = self.nametable_banks[(addr as usize >> 10) & 0b11];
+        if bank < CIRAM_BANKS {
+            Some(self.chr[self.chr_addr(bank, addr)])
+        } else {
+            None
+        }
+    }
+
+    fn nametable_write(&mut self, addr: u16, data: u8) -> bool {
+        let bank = self.nametable_banks[(addr as usize >> 10) & 0b11];
+        if bank >= CIRAM_BANKS {
+            return false;
+        }
+        if self.chr_is_ram {
+            let index = self.chr_addr(bank, addr);
+            self.chr[index] = data;
+        }
+        true
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        let page = |nametable: usize| self.nametable_banks[nametable] & 1;
+        Mirroring::Mapped([page(0), page(1), page(2), page(3)])
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn cpu_clock(&mut self) {
+        if self.irq_enabled && self.irq_counter < IRQ_COUNTER_MAX {
+            self.irq_counter += 1;
+            if self.irq_counter == IRQ_COUNTER_MAX {
+                self.irq_pending = true;
+            }
+        }
+
+        if !self.sound_disabled {
+            self.audio.clock();
+        }
+    }
+
+    fn audio_output(&self) -> f32 {
+        if self.sound_disabled {
+            return 0.0;
+        }
+        self.audio.output()
+    }
+
+    // The internal RAM holding the wavetables doubles as save RAM on some boards
+    fn save_ram(&self) -> Vec<u8> {
+        let mut data = self.prg_ram.clone();
+        data.extend_from_slice(&self.audio.internal_ram);
+        data
+    }
+
+    fn load_save_ram(&mut self, data: &[u8]) {
+        let split = self.prg_ram.len().min(data.len());
+        load_ram(&mut self.prg_ram, &data[..split]);
+        load_ram(&mut self.audio.internal_ram, &data[split..]);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::banked_rom;
+
+    fn namco163() -> Namco163 {
+        Namco163::new(banked_rom(
+            19,
+            0x20000,
+            0x40000,
+            PRG_BANK_SIZE,
+            CHR_BANK_SIZE,
+        ))
+    }
+
+    fn write_internal_ram(board: &mut Namco163, start: u8, data: &[u8]) {
+        board.cpu_write(0xF800, 0x80 | start);
+        for &byte in data {
+            board.cpu_write(0x4800, byte);
+        }
+    }
+
+    #[test]
+    fn test_banking_and_chr_nametables() {
+        let mut board = namco163();
+        board.cpu_write(0xE000, 3);
+        board.cpu_write(0xE800, 4);
+        board.cpu_write(0xF000, 5);
+        assert_eq!(board.cpu_read(0x8000), Some(3));
+        assert_eq!(board.cpu_read(0xA000), Some(4));
+        assert_eq!(board.cpu_read(0xC000), Some(5));
+        assert_eq!(board.cpu_read(0xE000), Some(15));
+
+        board.cpu_write(0xB800, 0x42);
+        assert_eq!(board.ppu_read(0x1C00), 0x42);
+
+        board.cpu_write(0xC800, 0x21);
+        board.cpu_write(0xD000, 0xE1);
+        assert_eq!(board.nametable_read(0x2400), Some(0x21));
+        assert_eq!(board.nametable_read(0x2800), None);
+        assert_eq!(board.mirroring(), Mirroring::Mapped([0, 1, 1, 0]));
+    }
+
+    #[test]
+    fn test_internal_ram_and_prg_ram_protect() {
+        let mut board = namco163();
+        write_internal_ram(&mut board, 0x10, &[1, 2, 3]);
+        board.cpu_write(0xF800, 0x11);
+        assert_eq!(board.cpu_read(0x4800), Some(2));
+        assert_eq!(board.cpu_read(0x4800), Some(2));
+
+        board.cpu_write(0x6000, 0x55);
+        assert_eq!(board.cpu_read(0x6000), Some(0));
+        board.cpu_write(0xF800, 0x40);
+        board.cpu_write(0x6000, 0x55);
+        assert_eq!(board.cpu_read(0x6000), Some(0x55));
+    }
+
+    #[test]
+    fn test_irq_counter() {
+        let mut board = namco163();
+        board.cpu_write(0x5000, 0xFD);
+        board.cpu_write(0x5800, 0xFF);
+        board.cpu_clock();
+        assert!(!board.irq());
+        board.cpu_clock();
+        assert!(board.irq());
+        assert_eq!(board.cpu_read(0x5800), Some(0xFF));
+
+        // The counter stays at $7FFF
+        board.cpu_clock();
+        assert_eq!(board.cpu_read(0x5000), Some(0xFF));
+        board.cpu_write(0x5000, 0);
+        assert!(!board.irq());
+    }
+
+    #[test]
+    fn test_wavetable_channel() {
+        let mut board = namco163();
+        // Sample bytes $00-$03: a 8 step square wave of 4-bit samples
+        write_internal_ram(&mut board, 0x00, &[0xFF, 0xFF, 0x00, 0x00]);
+        // Channel 7: half a sample per update, 8 sample wave at address 0, volume 15,
+        // and one enabled channel
+        write_internal_ram(
+            &mut board,
+            0x78,
+            &[0x00, 0x00, 0x80, 0x00, 0xF8, 0x00, 0x00, 0x0F],
+        );
+        let mut levels = Vec::new();
+        let (mut low, mut high) = (0.0_f32, 0.0_f32);
+        for _ in 0..CYCLES_PER_CHANNEL as usize * 16 {
+            board.cpu_clock();
+            levels.push(board.audio.output);
+            low = low.min(board.audio_output());
+            high = high.max(board.audio_output());
+        }
+        assert!(levels.contains(&(7 * 15)));
+        assert!(levels.contains(&(-8 * 15)));
+        // The channel swings through the whole level of the chip
+        assert!((high - low - ExpansionChip::Namco163.level()).abs() < 1e-6);
+
+        board.cpu_write(0xE000, 0x40);
+        assert_eq!(board.audio_output(), 0.0);
+    }
+}
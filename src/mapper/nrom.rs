@@ -0,0 +1,114 @@
+use super::chr_memory;
+use super::load_ram;
+use super::prg_ram;
+use super::Mapper;
+use crate::cartridge::Mirroring;
+use crate::cartridge::Rom;
+use serde::Deserialize;
+use serde::Serialize;
+
+// Declare NROM struct
+// Mapper 0: 16KB or 32KB of PRG-ROM with no bank switching, a 16KB PRG-ROM is
+// mirrored at $C000. Family BASIC boards add PRG-RAM at $6000
+// https://www.nesdev.org/wiki/NROM
+#[derive(Serialize, Deserialize)]
+pub struct NROM {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    mirroring: Mirroring,
+}
+
+// Implement functionality of NROM
+impl NROM {
+    // Create new NROM object
+    pub fn new(rom: Rom) -> Self {
+        let (chr, chr_is_ram) = chr_memory(&rom);
+        NROM {
+            prg_ram: prg_ram(&rom, rom.total_prg_ram_size()),
+            prg_rom: rom.prg_rom,
+            chr,
+            chr_is_ram,
+            mirroring: rom.screen_mirroring,
+        }
+    }
+}
+
+// Implement functionality of Mapper for NROM
+impl Mapper for NROM {
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7FFF if !self.prg_ram.is_empty() => {
+                let index = (addr - 0x6000) as usize % self.prg_ram.len();
+                Some(self.prg_ram[index])
+            }
+            0x8000..=0xFFFF => {
+                let index = (addr - 0x8000) as usize % self.prg_rom.len();
+                Some(self.prg_rom[index])
+            }
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        if let 0x6000..=0x7FFF = addr {
+            if !self.prg_ram.is_empty() {
+                let index = (addr - 0x6000) as usize % self.prg_ram.len();
+                self.prg_ram[index] = data;
+            }
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr[addr as usize % self.chr.len()]
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        if self.chr_is_ram {
+            let index = addr as usize % self.chr.len();
+            self.chr[index] = data;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn save_ram(&self) -> Vec<u8> {
+        self.prg_ram.clone()
+    }
+
+    fn load_save_ram(&mut self, data: &[u8]) {
+        load_ram(&mut self.prg_ram, data);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::test_rom;
+
+    #[test]
+    fn test_16k_prg_rom_is_mirrored() {
+        let mut rom = test_rom(vec![0xA9, 0x05]);
+        rom.prg_rom.truncate(0x4000);
+        let mut nrom = NROM::new(rom);
+        assert_eq!(nrom.cpu_read(0x8000), Some(0xA9));
+        assert_eq!(nrom.cpu_read(0xC001), Some(0x05));
+        assert_eq!(nrom.cpu_read(0x5000), None);
+    }
+
+    #[test]
+    fn test_chr_rom_is_read_only_and_chr_ram_is_writable() {
+        let mut nrom = NROM::new(test_rom(vec![]));
+        nrom.ppu_write(0x0010, 0x55);
+        assert_eq!(nrom.ppu_read(0x0010), 2);
+
+        let mut rom = test_rom(vec![]);
+        rom.chr_rom.clear();
+        let mut nrom = NROM::new(rom);
+        nrom.ppu_write(0x1FFF, 0x55);
+        assert_eq!(nrom.ppu_read(0x1FFF), 0x55);
+    }
+}
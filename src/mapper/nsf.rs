@@ -0,0 +1,378 @@
+use super::bank_offset;
+use super::fds_audio::FdsAudio;
+use super::mmc5::Mmc5Audio;
+use super::namco163::Namco163Audio;
+use super::opll::Opll;
+use super::sunsoft5b::Sunsoft5b;
+use super::vrc6::Vrc6Audio;
+use super::ExpansionChip;
+use super::Mapper;
+use crate::cartridge::Mirroring;
+use crate::nsf::ExpansionChips;
+use crate::nsf::NsfFile;
+use serde::Deserialize;
+use serde::Serialize;
+
+const BANK_SIZE: usize = 0x1000;
+
+// 4KB windows at $6000-$FFFF, the two at $6000-$7FFF are only banked for the FDS
+const WINDOWS: usize = 10;
+const FDS_WINDOWS: usize = 2;
+
+const PRG_RAM_SIZE: usize = 0x2000;
+const EXRAM_SIZE: usize = 0x400;
+
+// Declare NsfCartridge struct
+// The hardware an NSF player puts around the tune: 8KB of RAM at $6000, 4KB banks at
+// $8000-$FFFF switched through $5FF8-$5FFF, and the sound chips the file asks for.
+// With the FDS all of $6000-$FFFF is RAM that the bank registers copy banks into
+// https://www.nesdev.org/wiki/NSF#Bankswitching
+#[derive(Serialize, Deserialize)]
+pub struct NsfCartridge {
+    prg: Vec<u8>, // program data, shifted to start at the load address within its bank
+    bank_init: [u8; WINDOWS],
+    banks: [u8; WINDOWS],
+    ram: Vec<u8>,
+    expansion: ExpansionChips,
+
+    vrc6: Option<Vrc6Audio>,
+    vrc7: Option<Opll>,
+    fds: Option<FdsAudio>,
+    mmc5: Option<Mmc5Audio>,
+    namco163: Option<Namco163Audio>,
+    sunsoft5b: Option<Sunsoft5b>,
+    mmc5_multiplier: [u8; 2],
+    #[serde(with = "crate::state::byte_array")]
+    mmc5_exram: [u8; EXRAM_SIZE],
+}
+
+// Implement functionality of NsfCartridge
+impl NsfCartridge {
+    // Create new NsfCartridge object, ready to play the tune once reset
+    pub fn new(nsf: &NsfFile) -> Self {
+        let fds = nsf.expansion.contains(ExpansionChips::FDS);
+        let mut bank_init = [0; WINDOWS];
+        // Without banking the data is laid out from the start of the address space,
+        // which is $8000, or $6000 with the FDS
+        let padding = if nsf.is_bankswitched() {
+            bank_init[FDS_WINDOWS..].copy_from_slice(&nsf.bank_init);
+            // The FDS windows at $6000 and $7000 start with the banks of $E000 and $F000
+            bank_init[..FDS_WINDOWS].copy_from_slice(&nsf.bank_init[6..]);
+            nsf.load_addr as usize & (BANK_SIZE - 1)
+        } else if fds {
+            for (window, bank) in bank_init.iter_mut().enumerate() {
+                *bank = window as u8;
+            }
+            nsf.load_addr as usize - 0x6000
+        } else {
+            for (window, bank) in bank_init.iter_mut().enumerate().skip(FDS_WINDOWS) {
+                *bank = (window - FDS_WINDOWS) as u8;
+            }
+            (nsf.load_addr as usize).saturating_sub(0x8000)
+        };
+        let mut prg = vec![0; padding];
+        prg.extend_from_slice(&nsf.data);
+        prg.resize(prg.len().div_ceil(BANK_SIZE) * BANK_SIZE, 0);
+
+        let mut cartridge = NsfCartridge {
+            prg,
+            bank_init,
+            banks: bank_init,
+            ram: Vec::new(),
+            expansion: nsf.expansion,
+            vrc6: None,
+            vrc7: None,
+            fds: None,
+            mmc5: None,
+            namco163: None,
+            sunsoft5b: None,
+            mmc5_multiplier: [0; 2],
+            mmc5_exram: [0; EXRAM_SIZE],
+        };
+        cartridge.reset();
+        cartridge
+    }
+
+    fn has_fds(&self) -> bool {
+        self.expansion.contains(ExpansionChips::FDS)
+    }
+
+    // Back to the state the tune expects before INIT: cleared RAM, the initial banks
+    // and silent sound chips
+    pub fn reset(&mut self) {
+        let ram_size = if self.has_fds() {
+            WINDOWS * BANK_SIZE
+        } else {
+            PRG_RAM_SIZE
+        };
+        self.ram = vec![0; ram_size];
+        for window in 0..WINDOWS {
+            self.switch_bank(window, self.bank_init[window]);
+        }
+
+        let chips = self.expansion;
+        let chip = |flag: ExpansionChips| chips.contains(flag);
+        self.vrc6 = chip(ExpansionChips::VRC6).then(Vrc6Audio::default);
+        self.vrc7 = chip(ExpansionChips::VRC7).then(Opll::new);
+        self.fds = chip(ExpansionChips::FDS).then(FdsAudio::new);
+        self.mmc5 = chip(ExpansionChips::MMC5).then(Mmc5Audio::new);
+        self.namco163 = chip(ExpansionChips::NAMCO163).then(Namco163Audio::new);
+        self.sunsoft5b = chip(ExpansionChips::SUNSOFT5B).then(Sunsoft5b::new);
+        self.mmc5_multiplier = [0; 2];
+        self.mmc5_exram = [0; EXRAM_SIZE];
+    }
+
+    fn prg_addr(&self, window: usize, addr: u16) -> usize {
+        bank_offset(self.banks[window] as usize, BANK_SIZE, self.prg.len())
+            + (addr as usize & (BANK_SIZE - 1))
+    }
+
+    // With the FDS a bank switch copies the bank into RAM, where the tune can change it
+    fn switch_bank(&mut self, window: usize, bank: u8) {
+        self.banks[window] = bank;
+        if self.has_fds() {
+            let start = bank_offset(bank as usize, BANK_SIZE, self.prg.len());
+            self.ram[window * BANK_SIZE..(window + 1) * BANK_SIZE]
+                .copy_from_slice(&self.prg[start..start + BANK_SIZE]);
+        }
+    }
+
+    fn mmc5_product(&self) -> u16 {
+        self.mmc5_multiplier[0] as u16 * self.mmc5_multiplier[1] as u16
+    }
+}
+
+// Implement functionality of Mapper for NsfCartridge
+impl Mapper for NsfCartridge {
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        if let Some(fds) = self.fds.as_ref() {
+            if let Some(data) = fds.read(addr) {
+                return Some(data);
+            }
+        }
+        if let Some(mmc5) = self.mmc5.as_mut() {
+            match addr {
+                0x5010 | 0x5015 => return mmc5.read_register(addr),
+                0x5205 => return Some(self.mmc5_product() as u8),
+                0x5206 => return Some((self.mmc5_product() >> 8) as u8),
+                0x5C00..=0x5FF5 => return Some(self.mmc5_exram[addr as usize - 0x5C00]),
+                _ => {}
+            }
+        }
+        if let Some(namco163) = self.namco163.as_mut() {
+            if (0x4800..=0x4FFF).contains(&addr) {
+                return Some(namco163.read_data());
+            }
+        }
+
+        let data = match addr {
+            0x6000..=0xFFFF if self.has_fds() => self.ram[addr as usize - 0x6000],
+            0x6000..=0x7FFF => self.ram[addr as usize - 0x6000],
+            0x8000..=0xFFFF => {
+                let window = (addr as usize - 0x6000) / BANK_SIZE;
+                self.prg[self.prg_addr(window, addr)]
+            }
+            _ => return None,
+        };
+        if let Some(mmc5) = self.mmc5.as_mut() {
+            mmc5.snoop_read(addr, data);
+        }
+        Some(data)
+    }
+
+    // Writes go to every chip that listens at the address
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x5FF6..=0x5FF7 if self.has_fds() => {
+                self.switch_bank(addr as usize - 0x5FF6, data);
+            }
+            0x5FF8..=0x5FFF => self.switch_bank(addr as usize - 0x5FF8 + FDS_WINDOWS, data),
+            0x6000..=0xFFFF if self.has_fds() => self.ram[addr as usize - 0x6000] = data,
+            0x6000..=0x7FFF => self.ram[addr as usize - 0x6000] = data,
+            _ => {}
+        }
+
+        if let Some(vrc6) = self.vrc6.as_mut() {
+            vrc6.write_register(addr, data);
+        }
+        if let Some(vrc7) = self.vrc7.as_mut() {
+            match addr {
+                0x9010 => vrc7.write_address(data),
+                0x9030 => vrc7.write_data(data),
+                _ => {}
+            }
+        }
+        if let Some(fds) = self.fds.as_mut() {
+            fds.write(addr, data);
+        }
+        if let Some(mmc5) = self.mmc5.as_mut() {
+            match addr {
+                0x5000..=0x5015 => mmc5.write_register(addr, data),
+                0x5205..=0x5206 => self.mmc5_multiplier[addr as usize - 0x5205] = data,
+                0x5C00..=0x5FF5 => self.mmc5_exram[addr as usize - 0x5C00] = data,
+                _ => {}
+            }
+        }
+        if let Some(namco163) = self.namco163.as_mut() {
+            match addr {
+                0x4800..=0x4FFF => namco163.write_data(data),
+                0xF800..=0xFFFF => namco163.set_address(data),
+                _ => {}
+            }
+        }
+        if let Some(sunsoft5b) = self.sunsoft5b.as_mut() {
+            match addr {
+                0xC000..=0xDFFF => sunsoft5b.write_address(data),
+                0xE000..=0xFFFF => sunsoft5b.write_data(data),
+                _ => {}
+            }
+        }
+    }
+
+    // There is no PPU on the bus
+    fn ppu_read(&mut self, _addr: u16) -> u8 {
+        0
+    }
+
+    fn ppu_write(&mut self, _addr: u16, _data: u8) {}
+
+    fn mirroring(&self) -> Mirroring {
+        Mirroring::Horizontal
+    }
+
+    fn cpu_clock(&mut self) {
+        if let Some(vrc6) = self.vrc6.as_mut() {
+            vrc6.clock();
+        }
+        if let Some(vrc7) = self.vrc7.as_mut() {
+            vrc7.clock();
+        }
+        if let Some(fds) = self.fds.as_mut() {
+            fds.clock();
+        }
+        if let Some(mmc5) = self.mmc5.as_mut() {
+            mmc5.clock();
+        }
+        if let Some(namco163) = self.namco163.as_mut() {
+            namco163.clock();
+        }
+        if let Some(sunsoft5b) = self.sunsoft5b.as_mut() {
+            sunsoft5b.clock();
+        }
+    }
+
+    // The chips are mixed at the levels their cartridges use
+    fn audio_output(&self) -> f32 {
+        let mut output = 0.0;
+        if let Some(vrc6) = self.vrc6.as_ref() {
+            output += vrc6.output();
+        }
+        if let Some(vrc7) = self.vrc7.as_ref() {
+            output += vrc7.output() * ExpansionChip::Vrc7.level() / 2.0;
+        }
+        if let Some(fds) = self.fds.as_ref() {
+            output += fds.output() * ExpansionChip::Fds.level();
+        }
+        if let Some(mmc5) = self.mmc5.as_ref() {
+            output += mmc5.output();
+        }
+        if let Some(namco163) = self.namco163.as_ref() {
+            output += namco163.output();
+        }
+        if let Some(sunsoft5b) = self.sunsoft5b.as_ref() {
+            output += sunsoft5b.output() * ExpansionChip::Sunsoft5b.level();
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::nsf::test::test_nsf;
+
+    fn cartridge(load_addr: u16, banks: [u8; 8], expansion: u8, data: Vec<u8>) -> NsfCartridge {
+        let mut raw = test_nsf(&data);
+        raw[0x08..0x0A].copy_from_slice(&load_addr.to_le_bytes());
+        raw[0x70..0x78].copy_from_slice(&banks);
+        raw[0x7B] = expansion;
+        NsfCartridge::new(&NsfFile::new(&raw).unwrap())
+    }
+
+    // Four 4KB banks where every byte holds its bank number
+    fn bank_data() -> Vec<u8> {
+        (0..4 * BANK_SIZE).map(|i| (i / BANK_SIZE) as u8).collect()
+    }
+
+    #[test]
+    fn test_fixed_layout() {
+        let mut cartridge = cartridge(0x8010, [0; 8], 0, vec![0xAA, 0xBB]);
+        assert_eq!(cartridge.cpu_read(0x8010), Some(0xAA));
+        assert_eq!(cartridge.cpu_read(0x8011), Some(0xBB));
+        assert_eq!(cartridge.cpu_read(0x8000), Some(0));
+
+        cartridge.cpu_write(0x6000, 0x12);
+        assert_eq!(cartridge.cpu_read(0x6000), Some(0x12));
+        cartridge.cpu_write(0x8010, 0x12);
+        assert_eq!(cartridge.cpu_read(0x8010), Some(0xAA));
+        cartridge.reset();
+        assert_eq!(cartridge.cpu_read(0x6000), Some(0));
+    }
+
+    #[test]
+    fn test_bankswitching() {
+        let mut cartridge = cartridge(0x8000, [0, 1, 2, 3, 0, 0, 0, 1], 0, bank_data());
+        assert_eq!(cartridge.cpu_read(0x9000), Some(1));
+        assert_eq!(cartridge.cpu_read(0xF000), Some(1));
+        cartridge.cpu_write(0x5FFF, 3);
+        assert_eq!(cartridge.cpu_read(0xFFFF), Some(3));
+        cartridge.reset();
+        assert_eq!(cartridge.cpu_read(0xFFFF), Some(1));
+    }
+
+    #[test]
+    fn test_fds_ram_and_banks() {
+        let mut cartridge = cartridge(0x8000, [0, 1, 2, 3, 0, 0, 2, 3], 0b100, bank_data());
+        assert_eq!(cartridge.cpu_read(0x6000), Some(2));
+        assert_eq!(cartridge.cpu_read(0x7000), Some(3));
+        cartridge.cpu_write(0x9000, 0x55);
+        assert_eq!(cartridge.cpu_read(0x9000), Some(0x55));
+        // Switching copies the bank over the changes
+        cartridge.cpu_write(0x5FF9, 1);
+        assert_eq!(cartridge.cpu_read(0x9000), Some(1));
+        cartridge.cpu_write(0x5FF6, 0);
+        assert_eq!(cartridge.cpu_read(0x6000), Some(0));
+
+        // The sound registers are there too
+        cartridge.cpu_write(0x4080, 0x80 | 20);
+        assert_eq!(cartridge.cpu_read(0x4090), Some(0x40 | 20));
+    }
+
+    #[test]
+    fn test_expansion_chips() {
+        let mut plain = cartridge(0x8000, [0; 8], 0, vec![0x60]);
+        let mut chips = cartridge(0x8000, [0; 8], 0b11_1001, vec![0x60]);
+        assert!(chips.vrc6.is_some() && chips.mmc5.is_some() && chips.sunsoft5b.is_some());
+        assert!(chips.vrc7.is_none() && chips.fds.is_none());
+
+        chips.cpu_write(0x5205, 7);
+        chips.cpu_write(0x5206, 9);
+        assert_eq!(chips.cpu_read(0x5205), Some(63));
+        plain.cpu_write(0x5205, 7);
+        assert_eq!(plain.cpu_read(0x5205), None);
+
+        // 5B channel A at full volume with the tone enabled
+        chips.cpu_write(0xC000, 0x08);
+        chips.cpu_write(0xE000, 0x0F);
+        chips.cpu_write(0xC000, 0x07);
+        chips.cpu_write(0xE000, 0x3E);
+        let mut peak = 0.0_f32;
+        for _ in 0..100 {
+            chips.cpu_clock();
+            plain.cpu_clock();
+            peak = peak.max(chips.audio_output());
+            assert_eq!(plain.audio_output(), 0.0);
+        }
+        assert!((peak - ExpansionChip::Sunsoft5b.level()).abs() < 1e-6);
+    }
+}
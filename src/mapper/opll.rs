@@ -0,0 +1,491 @@
+use serde::Deserialize;
+use serde::Serialize;
+use std::f64::consts::TAU;
+
+// The VRC7 sound chip runs at 3.58MHz and produces a sample every 72 of its clocks,
+// which is every 36 NES CPU cycles (about 49.7kHz)
+const CPU_CYCLES_PER_SAMPLE: u8 = 36;
+const SAMPLE_RATE: f64 = 1_789_773.0 / CPU_CYCLES_PER_SAMPLE as f64;
+
+const CHANNELS: usize = 6;
+
+// Envelope attenuation in dB at which an operator is silent
+const ENVELOPE_MAX_DB: f64 = 48.0;
+// Time for the slowest attack (effective rate 4) and to decay through the whole range,
+// every 4 effective rate steps halve it
+const ATTACK_TIME: f64 = 2.8;
+const DECAY_TIME: f64 = 19.6;
+
+// Phase deviation of the carrier for a full scale modulator, in cycles (8π)
+const MODULATION_DEPTH: f64 = 4.0;
+
+// Tremolo (AM) and vibrato (VIB) low frequency oscillators
+const TREMOLO_RATE: f64 = 3.7;
+const TREMOLO_DEPTH_DB: f64 = 4.8;
+const VIBRATO_RATE: f64 = 6.4;
+const VIBRATO_DEPTH: f64 = 0.008;
+
+// Frequency multipliers selected by MULT
+const MULTIPLIERS: [f64; 16] = [
+    0.5, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0, 10.0, 10.0, 12.0, 12.0, 15.0, 15.0,
+];
+
+// Key scale level attenuation at block 7, indexed by the top 4 bits of the F-number
+const KEY_SCALE_DB: [f64; 16] = [
+    0.0, 18.0, 24.0, 27.75, 30.0, 32.25, 33.75, 35.25, 36.0, 37.5, 38.25, 39.0, 39.75, 40.5, 41.25,
+    42.0,
+];
+
+// KSL 0-3 is 0, 1.5, 3 and 6 dB per octave, the table above is the 6dB one
+const KEY_SCALE_FACTORS: [f64; 4] = [0.0, 0.25, 0.5, 1.0];
+
+// The 15 built-in instruments of the VRC7, instrument 0 is the custom one in $00-$07
+// https://www.nesdev.org/wiki/VRC7_audio
+const INSTRUMENTS: [[u8; 8]; 16] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+    [0x03, 0x21, 0x05, 0x06, 0xE8, 0x81, 0x42, 0x27],
+    [0x13, 0x41, 0x14, 0x0D, 0xD8, 0xF6, 0x23, 0x12],
+    [0x11, 0x11, 0x08, 0x08, 0xFA, 0xB2, 0x20, 0x12],
+    [0x31, 0x61, 0x0C, 0x07, 0xA8, 0x64, 0x61, 0x27],
+    [0x32, 0x21, 0x1E, 0x06, 0xE1, 0x76, 0x01, 0x28],
+    [0x02, 0x01, 0x06, 0x00, 0xA3, 0xE2, 0xF4, 0xF4],
+    [0x21, 0x61, 0x1D, 0x07, 0x82, 0x81, 0x11, 0x07],
+    [0x23, 0x21, 0x22, 0x17, 0xA2, 0x72, 0x01, 0x17],
+    [0x35, 0x11, 0x25, 0x00, 0x40, 0x73, 0x72, 0x01],
+    [0xB5, 0x01, 0x0F, 0x0F, 0xA8, 0xA5, 0x51, 0x02],
+    [0x17, 0xC1, 0x24, 0x07, 0xF8, 0xF8, 0x22, 0x12],
+    [0x71, 0x23, 0x11, 0x06, 0x65, 0x74, 0x18, 0x16],
+    [0x01, 0x02, 0xD3, 0x05, 0xC9, 0x95, 0x03, 0x02],
+    [0x61, 0x63, 0x0C, 0x00, 0x94, 0xC0, 0x33, 0xF6],
+    [0x21, 0x72, 0x0D, 0x00, 0xC1, 0xD5, 0x56, 0x06],
+];
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+enum EnvelopeStage {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+    Off,
+}
+
+// Parameters of one operator, decoded from an instrument
+struct OperatorPatch {
+    tremolo: bool,
+    vibrato: bool,
+    sustained: bool, // EG type: hold at the sustain level instead of fading out
+    key_scale_rate: bool,
+    multiplier: f64,
+    key_scale_level: u8,
+    rectified: bool, // negative half of the sine wave is cut off
+    attack: u8,
+    decay: u8,
+    sustain_level: u8,
+    release: u8,
+}
+
+impl OperatorPatch {
+    // Operator 0 is the modulator, 1 the carrier
+    fn new(instrument: &[u8; 8], operator: usize) -> Self {
+        let flags = instrument[operator];
+        OperatorPatch {
+            tremolo: flags & 0x80 != 0,
+            vibrato: flags & 0x40 != 0,
+            sustained: flags & 0x20 != 0,
+            key_scale_rate: flags & 0x10 != 0,
+            multiplier: MULTIPLIERS[(flags & 0x0F) as usize],
+            key_scale_level: instrument[2 + operator] >> 6,
+            rectified: instrument[3] & (0x08 << operator) != 0,
+            attack: instrument[4 + operator] >> 4,
+            decay: instrument[4 + operator] & 0x0F,
+            sustain_level: instrument[6 + operator] >> 4,
+            release: instrument[6 + operator] & 0x0F,
+        }
+    }
+}
+
+// Declare Operator struct
+// A sine oscillator with its envelope generator
+#[derive(Serialize, Deserialize)]
+struct Operator {
+    phase: f64, // in cycles
+    envelope_db: f64,
+    stage: EnvelopeStage,
+    outputs: [f64; 2], // last two outputs, for the modulator feedback
+}
+
+// Implement functionality of Operator
+impl Operator {
+    fn new() -> Self {
+        Operator {
+            phase: 0.0,
+            envelope_db: ENVELOPE_MAX_DB,
+            stage: EnvelopeStage::Off,
+            outputs: [0.0; 2],
+        }
+    }
+
+    fn key_on(&mut self) {
+        self.phase = 0.0;
+        self.stage = EnvelopeStage::Attack;
+    }
+
+    fn key_off(&mut self) {
+        if self.stage != EnvelopeStage::Off {
+            self.stage = EnvelopeStage::Release;
+        }
+    }
+
+    // Step the envelope by one sample
+    fn clock_envelope(&mut self, patch: &OperatorPatch, channel: &OpllChannel) {
+        let key_scale = channel.key_scale();
+        let key_scale = if patch.key_scale_rate {
+            key_scale
+        } else {
+            key_scale >> 2
+        };
+        // Per-sample change for a 4-bit rate, 0 stops the envelope
+        let step = |rate: u8, time: f64| {
+            if rate == 0 {
+                return 0.0;
+            }
+            let effective = (rate as u32 * 4 + key_scale as u32).min(63);
+            let seconds = time * (-(effective as f64 - 4.0) / 4.0).exp2();
+            ENVELOPE_MAX_DB / (seconds * SAMPLE_RATE)
+        };
+
+        match self.stage {
+            // A straight line in dB stands in for the exponential attack curve
+            EnvelopeStage::Attack => {
+                if patch.attack == 15 {
+                    self.envelope_db = 0.0;
+                } else {
+                    self.envelope_db -= step(patch.attack, ATTACK_TIME);
+                }
+                if self.envelope_db <= 0.0 {
+                    self.envelope_db = 0.0;
+                    self.stage = EnvelopeStage::Decay;
+                }
+            }
+            EnvelopeStage::Decay => {
+                let sustain_db = patch.sustain_level as f64 * 3.0;
+                self.envelope_db += step(patch.decay, DECAY_TIME);
+                if self.envelope_db >= sustain_db {
+                    self.envelope_db = sustain_db;
+                    self.stage = EnvelopeStage::Sustain;
+                }
+            }
+            EnvelopeStage::Sustain if patch.sustained => {}
+            EnvelopeStage::Sustain => self.envelope_db += step(patch.release, DECAY_TIME),
+            EnvelopeStage::Release => {
+                let rate = if channel.sustain {
+                    5
+                } else if patch.sustained {
+                    patch.release
+                } else {
+                    7
+                };
+                self.envelope_db += step(rate, DECAY_TIME);
+            }
+            EnvelopeStage::Off => {}
+        }
+        if self.envelope_db >= ENVELOPE_MAX_DB {
+            self.envelope_db = ENVELOPE_MAX_DB;
+            self.stage = EnvelopeStage::Off;
+        }
+    }
+
+    // Advance the phase and compute the output (-1.0 to 1.0), modulation is in cycles
+    fn output(
+        &mut self,
+        patch: &OperatorPatch,
+        channel: &OpllChannel,
+        lfo: &Lfo,
+        modulation: f64,
+        attenuation_db: f64,
+    ) -> f64 {
+        let mut increment = channel.fnum as f64 * (channel.block as f64).exp2() / (1 << 19) as f64
+            * patch.multiplier;
+        if patch.vibrato {
+            increment *= 1.0 + lfo.vibrato;
+        }
+        self.phase = (self.phase + increment).fract();
+
+        if self.stage == EnvelopeStage::Off {
+            return 0.0;
+        }
+        let mut wave = (TAU * (self.phase + modulation)).sin();
+        if patch.rectified && wave < 0.0 {
+            wave = 0.0;
+        }
+        let mut db =
+            self.envelope_db + attenuation_db + channel.key_scale_level_db(patch.key_scale_level);
+        if patch.tremolo {
+            db += lfo.tremolo_db;
+        }
+        wave * 10f64.powf(-db / 20.0)
+    }
+}
+
+// Current values of the low frequency oscillators
+struct Lfo {
+    tremolo_db: f64,
+    vibrato: f64,
+}
+
+// Declare OpllChannel struct
+// Two operators, the modulator feeding the carrier
+#[derive(Serialize, Deserialize)]
+struct OpllChannel {
+    modulator: Operator,
+    carrier: Operator,
+    fnum: u16, // 9-bit frequency number
+    block: u8, // octave
+    key_on: bool,
+    sustain: bool,
+    instrument: u8,
+    volume: u8, // attenuation in 3dB steps
+}
+
+// Implement functionality of OpllChannel
+impl OpllChannel {
+    fn new() -> Self {
+        OpllChannel {
+            modulator: Operator::new(),
+            carrier: Operator::new(),
+            fnum: 0,
+            block: 0,
+            key_on: false,
+            sustain: false,
+            instrument: 0,
+            volume: 0,
+        }
+    }
+
+    // Envelope rate offset for the pitch, 0-15
+    fn key_scale(&self) -> u8 {
+        (self.block << 1) | (self.fnum >> 8) as u8
+    }
+
+    // Higher notes are attenuated depending on KSL
+    fn key_scale_level_db(&self, key_scale_level: u8) -> f64 {
+        let db = KEY_SCALE_DB[(self.fnum >> 5) as usize] - 6.0 * (7 - self.block) as f64;
+        db.max(0.0) * KEY_SCALE_FACTORS[key_scale_level as usize]
+    }
+}
+
+// Declare Opll struct
+// The FM synthesizer of the VRC7, a cut down YM2413 (OPLL) with 6 two-operator
+// channels and its own instrument set. Rates and curves follow the YM2413
+// documentation closely enough to get the instruments' character, not bit for bit
+// https://www.nesdev.org/wiki/VRC7_audio
+#[derive(Serialize, Deserialize)]
+pub struct Opll {
+    address: u8,
+    custom_instrument: [u8; 8],
+    channels: [OpllChannel; CHANNELS],
+    sample_timer: u8,
+    samples: u64,
+    output: f32,
+}
+
+// Implement functionality of Opll
+impl Opll {
+    // Create new Opll object
+    pub fn new() -> Self {
+        Opll {
+            address: 0,
+            custom_instrument: [0; 8],
+            channels: std::array::from_fn(|_| OpllChannel::new()),
+            sample_timer: 0,
+            samples: 0,
+            output: 0.0,
+        }
+    }
+
+    // Silence every channel and clear the registers
+    pub fn reset(&mut self) {
+        *self = Opll::new();
+    }
+
+    pub fn write_address(&mut self, data: u8) {
+        self.address = data;
+    }
+
+    pub fn write_data(&mut self, data: u8) {
+        let address = self.address;
+        let index = (address & 0x0F) as usize;
+        match address {
+            0x00..=0x07 => self.custom_instrument[index] = data,
+            0x10..=0x15 => {
+                let channel = &mut self.channels[index];
+                channel.fnum = (channel.fnum & 0x100) | data as u16;
+            }
+            0x20..=0x25 => {
+                let channel = &mut self.channels[index];
+                channel.fnum = (channel.fnum & 0xFF) | ((data as u16 & 1) << 8);
+                channel.block = (data >> 1) & 0b111;
+                channel.sustain = data & 0x20 != 0;
+                let key_on = data & 0x10 != 0;
+                if key_on && !channel.key_on {
+                    channel.modulator.key_on();
+                    channel.carrier.key_on();
+                } else if !key_on && channel.key_on {
+                    channel.modulator.key_off();
+                    channel.carrier.key_off();
+                }
+                channel.key_on = key_on;
+            }
+            0x30..=0x35 => {
+                let channel = &mut self.channels[index];
+                channel.instrument = data >> 4;
+                channel.volume = data & 0x0F;
+            }
+            _ => {}
+        }
+    }
+
+    // Called once per CPU cycle
+    pub fn clock(&mut self) {
+        self.sample_timer += 1;
+        if self.sample_timer == CPU_CYCLES_PER_SAMPLE {
+            self.sample_timer = 0;
+            self.output = self.generate_sample() as f32;
+        }
+    }
+
+    // Sum of the channels, each in the range -1.0 to 1.0
+    pub fn output(&self) -> f32 {
+        self.output
+    }
+
+    fn generate_sample(&mut self) -> f64 {
+        self.samples += 1;
+        let time = self.samples as f64 / SAMPLE_RATE;
+        let lfo = Lfo {
+            tremolo_db: TREMOLO_DEPTH_DB * (1.0 - (TAU * TREMOLO_RATE * time).cos()) / 2.0,
+            vibrato: VIBRATO_DEPTH * (TAU * VIBRATO_RATE * time).sin(),
+        };
+
+        let mut sum = 0.0;
+        for channel in self.channels.iter_mut() {
+            let instrument = if channel.instrument == 0 {
+                self.custom_instrument
+            } else {
+                INSTRUMENTS[channel.instrument as usize]
+            };
+            let modulator_patch = OperatorPatch::new(&instrument, 0);
+            let carrier_patch = OperatorPatch::new(&instrument, 1);
+
+            // Take the operators out so they can look at the channel registers
+            let mut modulator = std::mem::replace(&mut channel.modulator, Operator::new());
+            let mut carrier = std::mem::replace(&mut channel.carrier, Operator::new());
+            modulator.clock_envelope(&modulator_patch, channel);
+            carrier.clock_envelope(&carrier_patch, channel);
+
+            let feedback = instrument[3] & 0b111;
+            let feedback_modulation = if feedback == 0 {
+                0.0
+            } else {
+                (modulator.outputs[0] + modulator.outputs[1]) / 2.0 * (feedback as f64 - 6.0).exp2()
+            };
+            let total_level_db = (instrument[2] & 0x3F) as f64 * 0.75;
+            let modulation = modulator.output(
+                &modulator_patch,
+                channel,
+                &lfo,
+                feedback_modulation,
+                total_level_db,
+            );
+            modulator.outputs = [modulator.outputs[1], modulation];
+
+            let volume_db = channel.volume as f64 * 3.0;
+            sum += carrier.output(
+                &carrier_patch,
+                channel,
+                &lfo,
+                modulation * MODULATION_DEPTH,
+                volume_db,
+            );
+
+            channel.modulator = modulator;
+            channel.carrier = carrier;
+        }
+        sum
+    }
+}
+
+impl Default for Opll {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn write(opll: &mut Opll, address: u8, data: u8) {
+        opll.write_address(address);
+        opll.write_data(data);
+    }
+
+    // Peak output over a number of samples
+    fn peak(opll: &mut Opll, samples: usize) -> f32 {
+        let mut peak: f32 = 0.0;
+        for _ in 0..samples * CPU_CYCLES_PER_SAMPLE as usize {
+            opll.clock();
+            peak = peak.max(opll.output().abs());
+        }
+        peak
+    }
+
+    #[test]
+    fn test_key_on_and_release() {
+        let mut opll = Opll::new();
+        assert_eq!(peak(&mut opll, 100), 0.0);
+
+        // Instrument 3 (piano) at full volume, A4 (fnum 288, block 4)
+        write(&mut opll, 0x30, 0x30);
+        write(&mut opll, 0x10, 0x20);
+        write(&mut opll, 0x20, 0x19);
+        assert!(peak(&mut opll, 2000) > 0.5);
+
+        write(&mut opll, 0x20, 0x09);
+        peak(&mut opll, 50_000);
+        assert!(peak(&mut opll, 100) < 0.01);
+    }
+
+    #[test]
+    fn test_volume_attenuates() {
+        let mut loud = Opll::new();
+        let mut quiet = Opll::new();
+        for (opll, volume) in [(&mut loud, 0x0), (&mut quiet, 0x4)] {
+            write(opll, 0x31, 0x10 | volume);
+            write(opll, 0x11, 0x20);
+            write(opll, 0x21, 0x19);
+        }
+        let ratio = peak(&mut quiet, 1000) / peak(&mut loud, 1000);
+        // 4 steps of 3dB
+        assert!((ratio - 0.25).abs() < 0.03, "ratio {}", ratio);
+    }
+
+    #[test]
+    fn test_custom_instrument_and_reset() {
+        let mut opll = Opll::new();
+        // Plain sine: carrier multiplier 1, instant attack, no decay, modulator silent
+        for (address, data) in [0x00, 0x21, 0x3F, 0x00, 0x00, 0xF0, 0x00, 0x00]
+            .iter()
+            .enumerate()
+        {
+            write(&mut opll, address as u8, *data);
+        }
+        write(&mut opll, 0x32, 0x00);
+        write(&mut opll, 0x12, 0x20);
+        write(&mut opll, 0x22, 0x19);
+        assert!((peak(&mut opll, 500) - 1.0).abs() < 0.01);
+
+        opll.reset();
+        assert_eq!(peak(&mut opll, 10), 0.0);
+    }
+}
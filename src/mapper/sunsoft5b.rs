@@ -0,0 +1,276 @@
+use serde::Deserialize;
+use serde::Serialize;
+
+// Tone, noise and envelope counters advance every 16 CPU cycles
+const CPU_CYCLES_PER_TICK: u8 = 16;
+
+const CHANNELS: usize = 3;
+
+// Declare Sunsoft5b struct
+// The sound part of the Sunsoft 5B, a YM2149F (AY-3-8910 family): three square wave
+// channels that can mix in a shared noise generator, with 4-bit logarithmic volumes or
+// a shared 32-step envelope https://www.nesdev.org/wiki/Sunsoft_5B_audio
+#[derive(Serialize, Deserialize)]
+pub struct Sunsoft5b {
+    address: u8,
+    registers: [u8; 16],
+
+    tick_timer: u8,
+    tone_counters: [u16; CHANNELS],
+    tone_outputs: [bool; CHANNELS],
+    noise_counter: u16,
+    noise_shift: u32, // 17-bit LFSR
+    envelope_counter: u16,
+    envelope_step: u8, // 0-31 within the current ramp
+    envelope_holding: bool,
+    envelope_attack: bool, // current ramp goes up
+}
+
+// Implement functionality of Sunsoft5b
+impl Sunsoft5b {
+    // Create new Sunsoft5b object
+    pub fn new() -> Self {
+        Sunsoft5b {
+            address: 0,
+            registers: [0; 16],
+            tick_timer: 0,
+            tone_counters: [0; CHANNELS],
+            tone_outputs: [false; CHANNELS],
+            noise_counter: 0,
+            noise_shift: 1,
+            envelope_counter: 0,
+            envelope_step: 0,
+            envelope_holding: false,
+            envelope_attack: false,
+        }
+    }
+
+    pub fn write_address(&mut self, data: u8) {
+        self.address = data;
+    }
+
+    pub fn write_data(&mut self, data: u8) {
+        // The upper 4 address bits have to be 0
+        if self.address > 0x0F {
+            return;
+        }
+        self.registers[self.address as usize] = data;
+        // Writing the shape restarts the envelope
+        if self.address == 0x0D {
+            self.envelope_step = 0;
+            self.envelope_counter = 0;
+            self.envelope_holding = false;
+            self.envelope_attack = data & 0b0100 != 0;
+        }
+    }
+
+    fn tone_period(&self, channel: usize) -> u16 {
+        let period = self.registers[channel * 2] as u16
+            | ((self.registers[channel * 2 + 1] as u16 & 0x0F) << 8);
+        period.max(1)
+    }
+
+    fn noise_period(&self) -> u16 {
+        (self.registers[6] as u16 & 0x1F).max(1)
+    }
+
+    fn envelope_period(&self) -> u16 {
+        (self.registers[11] as u16 | (self.registers[12] as u16) << 8).max(1)
+    }
+
+    // Called once per CPU cycle
+    pub fn clock(&mut self) {
+        self.tick_timer += 1;
+        if self.tick_timer < CPU_CYCLES_PER_TICK {
+            return;
+        }
+        self.tick_timer = 0;
+
+        for channel in 0..CHANNELS {
+            self.tone_counters[channel] += 1;
+            if self.tone_counters[channel] >= self.tone_period(channel) {
+                self.tone_counters[channel] = 0;
+                self.tone_outputs[channel] = !self.tone_outputs[channel];
+            }
+        }
+
+        // The noise runs at half the tone rate
+        self.noise_counter += 1;
+        if self.noise_counter >= self.noise_period() * 2 {
+            self.noise_counter = 0;
+            let feedback = (self.noise_shift ^ (self.noise_shift >> 3)) & 1;
+            self.noise_shift = (self.noise_shift >> 1) | (feedback << 16);
+        }
+
+        self.envelope_counter += 1;
+        if self.envelope_counter >= self.envelope_period() {
+            self.envelope_counter = 0;
+            self.clock_envelope();
+        }
+    }
+
+    // Shape bits: continue, attack, alternate, hold
+    fn clock_envelope(&mut self) {
+        if self.envelope_holding {
+            return;
+        }
+        if self.envelope_step < 31 {
+            self.envelope_step += 1;
+            return;
+        }
+        let shape = self.registers[13];
+        let continue_ = shape & 0b1000 != 0;
+        let alternate = shape & 0b0010 != 0;
+        let hold = shape & 0b0001 != 0;
+        if !continue_ {
+            // Drop to silence and stay there
+            self.envelope_holding = true;
+            self.envelope_attack = false;
+            self.envelope_step = 31;
+        } else if hold {
+            self.envelope_holding = true;
+            if alternate {
+                self.envelope_attack = !self.envelope_attack;
+            }
+        } else {
+            self.envelope_step = 0;
+            if alternate {
+                self.envelope_attack = !self.envelope_attack;
+            }
+        }
+    }
+
+    // Current envelope volume, 0-31
+    fn envelope_level(&self) -> u8 {
+        if self.envelope_holding && self.registers[13] & 0b1000 == 0 {
+            return 0;
+        }
+        if self.envelope_attack {
+            self.envelope_step
+        } else {
+            31 - self.envelope_step
+        }
+    }
+
+    // Sum of the channel levels, each 0.0 to 1.0
+    pub fn output(&self) -> f32 {
+        let mixer = self.registers[7];
+        let noise = self.noise_shift & 1 != 0;
+        let mut sum = 0.0;
+        for channel in 0..CHANNELS {
+            let tone_off = mixer & (1 << channel) != 0;
+            let noise_off = mixer & (0b1000 << channel) != 0;
+            if !((tone_off || self.tone_outputs[channel]) && (noise_off || noise)) {
+                continue;
+            }
+            let volume = self.registers[8 + channel];
+            // 4-bit volumes use every other step of the 32-step envelope scale
+            let level = if volume & 0x10 != 0 {
+                self.envelope_level()
+            } else if volume & 0x0F == 0 {
+                0
+            } else {
+                (volume & 0x0F) * 2 + 1
+            };
+            sum += level_amplitude(level);
+        }
+        sum
+    }
+}
+
+impl Default for Sunsoft5b {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// The DAC is logarithmic, 1.5dB per step with 0 silent
+fn level_amplitude(level: u8) -> f32 {
+    if level == 0 {
+        0.0
+    } else {
+        10f32.powf((level as f32 - 31.0) * 1.5 / 20.0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn write(chip: &mut Sunsoft5b, address: u8, data: u8) {
+        chip.write_address(address);
+        chip.write_data(data);
+    }
+
+    fn run(chip: &mut Sunsoft5b, ticks: usize) -> Vec<f32> {
+        (0..ticks * CPU_CYCLES_PER_TICK as usize)
+            .map(|_| {
+                chip.clock();
+                chip.output()
+            })
+            .step_by(CPU_CYCLES_PER_TICK as usize)
+            .collect()
+    }
+
+    #[test]
+    fn test_tone_and_volume() {
+        let mut chip = Sunsoft5b::new();
+        // Channel A tone only, period 4, full volume
+        write(&mut chip, 7, 0b11_1110);
+        write(&mut chip, 0, 4);
+        write(&mut chip, 8, 0x0F);
+        let levels = run(&mut chip, 16);
+        assert!(levels.contains(&1.0));
+        assert!(levels.contains(&0.0));
+        // A square wave toggling every 4 ticks
+        assert_eq!(
+            levels.windows(2).filter(|pair| pair[0] != pair[1]).count(),
+            3
+        );
+
+        write(&mut chip, 8, 0x0D);
+        let peak = run(&mut chip, 16).into_iter().fold(0.0, f32::max);
+        assert!((peak - level_amplitude(27)).abs() < 1e-6);
+        assert!((level_amplitude(27) - 0.5).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_envelope_shapes() {
+        let mut chip = Sunsoft5b::new();
+        // Channel A constant on, envelope volume, period 1
+        write(&mut chip, 7, 0b11_1111);
+        write(&mut chip, 8, 0x10);
+        write(&mut chip, 11, 1);
+
+        // Decay then silence
+        write(&mut chip, 13, 0b0000);
+        assert_eq!(chip.envelope_level(), 31);
+        run(&mut chip, 40);
+        assert_eq!(chip.output(), 0.0);
+
+        // Attack and hold at the top
+        write(&mut chip, 13, 0b1101);
+        assert_eq!(chip.envelope_level(), 0);
+        run(&mut chip, 40);
+        assert_eq!(chip.envelope_level(), 31);
+
+        // Triangle
+        write(&mut chip, 13, 0b1110);
+        run(&mut chip, 31);
+        assert_eq!(chip.envelope_level(), 31);
+        run(&mut chip, 32);
+        assert_eq!(chip.envelope_level(), 0);
+    }
+
+    #[test]
+    fn test_noise() {
+        let mut chip = Sunsoft5b::new();
+        // Channel A noise only, full volume
+        write(&mut chip, 7, 0b11_0111);
+        write(&mut chip, 6, 1);
+        write(&mut chip, 8, 0x0F);
+        let levels = run(&mut chip, 2000);
+        let on = levels.iter().filter(|&&level| level > 0.0).count();
+        assert!(on > 800 && on < 1200, "{} ticks on", on);
+    }
+}
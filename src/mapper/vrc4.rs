@@ -0,0 +1,300 @@
+use super::bank_count;
+use super::bank_offset;
+use super::chr_memory;
+use super::load_ram;
+use super::prg_ram;
+use super::vrc_irq::VrcIrq;
+use super::Mapper;
+use crate::cartridge::Mirroring;
+use crate::cartridge::Rom;
+use serde::Deserialize;
+use serde::Serialize;
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x0400;
+
+// Declare VRC4 struct
+// Konami VRC2 and VRC4, mappers 21, 22, 23 and 25: two switchable 8KB PRG banks and
+// eight 1KB CHR banks. VRC4 adds the IRQ counter, a PRG swap mode and PRG-RAM control.
+// The boards wire the register select pins to different CPU address lines, told apart
+// by the NES 2.0 submapper, or all decoded at once when it is not known
+// https://www.nesdev.org/wiki/VRC2_and_VRC4
+#[derive(Serialize, Deserialize)]
+pub struct VRC4 {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    vrc2: bool,
+    a0_lines: u16, // CPU address lines connected to the chip's A0 and A1 pins
+    a1_lines: u16,
+    chr_shift: u8, // VRC2a ignores the low bit of the CHR banks
+
+    prg_banks: [u8; 2],
+    prg_swap: bool,
+    prg_ram_enabled: bool,
+    chr_banks: [u16; 8],
+    mirroring: u8,
+    microwire_latch: u8, // VRC2 boards without PRG-RAM have a 1-bit latch at $6000
+    irq: VrcIrq,
+}
+
+// Implement functionality of VRC4
+impl VRC4 {
+    // Create new VRC4 object, the chip and its wiring come from the mapper number
+    pub fn new(rom: Rom) -> Self {
+        let (a0_lines, a1_lines, vrc2) = match (rom.mapper, rom.submapper) {
+            (21, 1) => (0x02, 0x04, false), // VRC4a
+            (21, 2) => (0x40, 0x80, false), // VRC4c
+            (21, _) => (0x42, 0x84, false),
+            (22, _) => (0x02, 0x01, true),  // VRC2a
+            (23, 1) => (0x01, 0x02, false), // VRC4f
+            (23, 2) => (0x04, 0x08, false), // VRC4e
+            (23, 3) => (0x01, 0x02, true),  // VRC2b
+            (23, _) => (0x05, 0x0A, false),
+            (25, 1) => (0x02, 0x01, false), // VRC4b
+            (25, 2) => (0x08, 0x04, false), // VRC4d
+            (25, 3) => (0x02, 0x01, true),  // VRC2c
+            _ => (0x0A, 0x05, false),
+        };
+        let (chr, chr_is_ram) = chr_memory(&rom);
+        VRC4 {
+            prg_ram: prg_ram(&rom, rom.total_prg_ram_size()),
+            chr_shift: if rom.mapper == 22 { 1 } else { 0 },
+            prg_rom: rom.prg_rom,
+            chr,
+            chr_is_ram,
+            vrc2,
+            a0_lines,
+            a1_lines,
+            prg_banks: [0; 2],
+            prg_swap: false,
+            prg_ram_enabled: vrc2,
+            chr_banks: [0; 8],
+            mirroring: 0,
+            microwire_latch: 0,
+            irq: VrcIrq::new(),
+        }
+    }
+
+    // Register address as the chip sees it: $x000-$x003
+    fn register(&self, addr: u16) -> u16 {
+        let a0 = (addr & self.a0_lines != 0) as u16;
+        let a1 = (addr & self.a1_lines != 0) as u16;
+        (addr & 0xF000) | (a1 << 1) | a0
+    }
+
+    fn prg_rom_addr(&self, addr: u16) -> usize {
+        let last_bank = bank_count(PRG_BANK_SIZE, self.prg_rom.len()) - 1;
+        let bank = match (addr, self.prg_swap) {
+            (0x8000..=0x9FFF, false) | (0xC000..=0xDFFF, true) => self.prg_banks[0] as usize,
+            (0x8000..=0x9FFF, true) | (0xC000..=0xDFFF, false) => last_bank.saturating_sub(1),
+            (0xA000..=0xBFFF, _) => self.prg_banks[1] as usize,
+            _ => last_bank,
+        };
+        bank_offset(bank, PRG_BANK_SIZE, self.prg_rom.len()) + (addr as usize & 0x1FFF)
+    }
+
+    fn chr_addr(&self, addr: u16) -> usize {
+        let bank = (self.chr_banks[addr as usize >> 10] >> self.chr_shift) as usize;
+        bank_offset(bank, CHR_BANK_SIZE, self.chr.len()) + (addr as usize & 0x03FF)
+    }
+
+    fn write_register(&mut self, addr: u16, data: u8) {
+        let register = self.register(addr);
+        match register {
+            0x8000..=0x8003 => self.prg_banks[0] = data & 0x1F,
+            0x9000..=0x9003 if self.vrc2 => self.mirroring = data & 0b01,
+            0x9000 | 0x9001 => self.mirroring = data & 0b11,
+            0x9002 => {
+                self.prg_ram_enabled = data & 0b01 != 0;
+                self.prg_swap = data & 0b10 != 0;
+            }
+            0xA000..=0xA003 => self.prg_banks[1] = data & 0x1F,
+            // Each 1KB CHR bank number is written as a low and a high nibble
+            0xB000..=0xE003 => {
+                let index =
+                    ((register as usize - 0xB000) >> 12) * 2 + ((register as usize >> 1) & 1);
+                let bank = self.chr_banks[index];
+                self.chr_banks[index] = if register & 1 == 0 {
+                    (bank & 0x1F0) | (data & 0x0F) as u16
+                } else {
+                    (bank & 0x00F) | ((data & 0x1F) as u16) << 4
+                };
+            }
+            _ if self.vrc2 => {}
+            0xF000 => self.irq.write_latch_low(data),
+            0xF001 => self.irq.write_latch_high(data),
+            0xF002 => self.irq.write_control(data),
+            0xF003 => self.irq.acknowledge(),
+            _ => {}
+        }
+    }
+}
+
+// Implement functionality of Mapper for VRC4
+impl Mapper for VRC4 {
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x6FFF if self.vrc2 && self.prg_ram.is_empty() => Some(self.microwire_latch),
+            0x6000..=0x7FFF if self.prg_ram_enabled && !self.prg_ram.is_empty() => {
+                Some(self.prg_ram[(addr as usize - 0x6000) % self.prg_ram.len()])
+            }
+            0x8000..=0xFFFF => Some(self.prg_rom[self.prg_rom_addr(addr)]),
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x6000..=0x6FFF if self.vrc2 && self.prg_ram.is_empty() => {
+                self.microwire_latch = data & 1;
+            }
+            0x6000..=0x7FFF if self.prg_ram_enabled && !self.prg_ram.is_empty() => {
+                let index = (addr as usize - 0x6000) % self.prg_ram.len();
+                self.prg_ram[index] = data;
+            }
+            0x8000..=0xFFFF => self.write_register(addr, data),
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr[self.chr_addr(addr)]
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        if self.chr_is_ram {
+            let index = self.chr_addr(addr);
+            self.chr[index] = data;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.mirroring {
+            0 => Mirroring::Vertical,
+            1 => Mirroring::Horizontal,
+            2 => Mirroring::SingleScreenLower,
+            _ => Mirroring::SingleScreenUpper,
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.irq.pending()
+    }
+
+    fn cpu_clock(&mut self) {
+        self.irq.clock();
+    }
+
+    fn save_ram(&self) -> Vec<u8> {
+        self.prg_ram.clone()
+    }
+
+    fn load_save_ram(&mut self, data: &[u8]) {
+        load_ram(&mut self.prg_ram, data);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::banked_rom;
+    use crate::cartridge::test::test_rom;
+
+    fn vrc(mapper: u16, submapper: u8) -> VRC4 {
+        let mut rom = banked_rom(mapper, 0x20000, 0x40000, PRG_BANK_SIZE, CHR_BANK_SIZE);
+        rom.submapper = submapper;
+        VRC4::new(rom)
+    }
+
+    #[test]
+    fn test_small_prg() {
+        // A single 8KB bank is mirrored in every window, fixed ones included
+        let mut rom = test_rom(vec![]);
+        rom.mapper = 21;
+        rom.prg_rom = vec![0x42; PRG_BANK_SIZE];
+        let mut mapper = VRC4::new(rom);
+        for addr in [0x8000, 0xA000, 0xC000, 0xE000] {
+            assert_eq!(mapper.cpu_read(addr), Some(0x42));
+        }
+    }
+
+    #[test]
+    fn test_address_wiring() {
+        // The high nibble register of CHR bank 1 ($B003) on each board
+        let cases = [
+            (21, 1, 0xB006),
+            (21, 2, 0xB0C0),
+            (23, 1, 0xB003),
+            (23, 2, 0xB00C),
+            (25, 1, 0xB003),
+            (25, 2, 0xB00C),
+            (23, 0, 0xB00C),
+            (25, 0, 0xB003),
+        ];
+        for (mapper, submapper, addr) in cases {
+            let mut board = vrc(mapper, submapper);
+            board.cpu_write(addr, 0x01);
+            assert_eq!(
+                board.ppu_read(0x0400),
+                0x10,
+                "mapper {} submapper {}",
+                mapper,
+                submapper
+            );
+        }
+    }
+
+    #[test]
+    fn test_prg_banks_and_swap_mode() {
+        let mut board = vrc(21, 1);
+        board.cpu_write(0x8000, 3);
+        board.cpu_write(0xA000, 5);
+        assert_eq!(board.cpu_read(0x8000), Some(3));
+        assert_eq!(board.cpu_read(0xA000), Some(5));
+        assert_eq!(board.cpu_read(0xC000), Some(14));
+        assert_eq!(board.cpu_read(0xE000), Some(15));
+
+        board.cpu_write(0x9004, 0b10);
+        assert_eq!(board.cpu_read(0x8000), Some(14));
+        assert_eq!(board.cpu_read(0xC000), Some(3));
+    }
+
+    #[test]
+    fn test_vrc2a_chr_and_mirroring() {
+        let mut board = vrc(22, 0);
+        board.cpu_write(0xD000, 0x07);
+        assert_eq!(board.ppu_read(0x1000), 3);
+
+        board.cpu_write(0x9000, 0b11);
+        assert_eq!(board.mirroring(), Mirroring::Horizontal);
+
+        // No IRQ on VRC2
+        board.cpu_write(0xF002, 0b111);
+        for _ in 0..1000 {
+            board.cpu_clock();
+        }
+        assert!(!board.irq());
+    }
+
+    #[test]
+    fn test_vrc4_irq_and_ram_enable() {
+        let mut board = vrc(25, 1);
+        board.cpu_write(0x6000, 0x42);
+        assert_eq!(board.cpu_read(0x6000), None);
+        board.cpu_write(0x9001, 0b01);
+        board.cpu_write(0x6000, 0x42);
+        assert_eq!(board.cpu_read(0x6000), Some(0x42));
+
+        board.cpu_write(0xF000, 0x0E);
+        board.cpu_write(0xF002, 0x0F);
+        board.cpu_write(0xF001, 0b110);
+        board.cpu_clock();
+        assert!(!board.irq());
+        board.cpu_clock();
+        assert!(board.irq());
+        board.cpu_write(0xF003, 0);
+        assert!(!board.irq());
+    }
+}
@@ -0,0 +1,407 @@
+use super::bank_count;
+use super::bank_offset;
+use super::chr_memory;
+use super::load_ram;
+use super::prg_ram;
+use super::vrc_irq::VrcIrq;
+use super::ExpansionChip;
+use super::Mapper;
+use crate::cartridge::Mirroring;
+use crate::cartridge::Rom;
+use serde::Deserialize;
+use serde::Serialize;
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x0400;
+
+// Declare Vrc6Pulse struct
+// Pulse channel with 16-step duty cycles and no envelope or length counter
+#[derive(Default, Serialize, Deserialize)]
+struct Vrc6Pulse {
+    volume: u8,
+    duty: u8,
+    ignore_duty: bool, // constant output at the volume level
+    period: u16,
+    timer: u16,
+    step: u8,
+    enabled: bool,
+}
+
+// Implement functionality of Vrc6Pulse
+impl Vrc6Pulse {
+    fn write_register(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.ignore_duty = data & 0x80 != 0;
+                self.duty = (data >> 4) & 0b111;
+                self.volume = data & 0x0F;
+            }
+            1 => self.period = (self.period & 0x0F00) | data as u16,
+            _ => {
+                self.period = (self.period & 0x00FF) | ((data as u16 & 0x0F) << 8);
+                self.enabled = data & 0x80 != 0;
+                if !self.enabled {
+                    self.step = 0;
+                }
+            }
+        }
+    }
+
+    fn clock(&mut self, shift: u8) {
+        if !self.enabled {
+            return;
+        }
+        if self.timer == 0 {
+            self.timer = self.period >> shift;
+            self.step = (self.step + 1) & 0x0F;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.enabled && (self.ignore_duty || self.step <= self.duty) {
+            self.volume
+        } else {
+            0
+        }
+    }
+}
+
+// Declare Vrc6Sawtooth struct
+// Adds the rate to an accumulator every other step, and resets it after 7 additions
+#[derive(Default, Serialize, Deserialize)]
+struct Vrc6Sawtooth {
+    rate: u8,
+    period: u16,
+    timer: u16,
+    step: u8,
+    accumulator: u8,
+    enabled: bool,
+}
+
+// Implement functionality of Vrc6Sawtooth
+impl Vrc6Sawtooth {
+    fn write_register(&mut self, register: u16, data: u8) {
+        match register {
+            0 => self.rate = data & 0x3F,
+            1 => self.period = (self.period & 0x0F00) | data as u16,
+            _ => {
+                self.period = (self.period & 0x00FF) | ((data as u16 & 0x0F) << 8);
+                self.enabled = data & 0x80 != 0;
+                if !self.enabled {
+                    self.step = 0;
+                    self.accumulator = 0;
+                }
+            }
+        }
+    }
+
+    fn clock(&mut self, shift: u8) {
+        if !self.enabled {
+            return;
+        }
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.period >> shift;
+        self.step += 1;
+        if self.step == 14 {
+            self.step = 0;
+            self.accumulator = 0;
+        } else if self.step.is_multiple_of(2) {
+            self.accumulator = self.accumulator.wrapping_add(self.rate);
+        }
+    }
+
+    // The top 5 bits of the accumulator
+    fn output(&self) -> u8 {
+        self.accumulator >> 3
+    }
+}
+
+// Declare Vrc6Audio struct
+// The sound part of the VRC6, registers $9000-$B002 as the chip sees them
+#[derive(Default, Serialize, Deserialize)]
+pub(super) struct Vrc6Audio {
+    pulse1: Vrc6Pulse,
+    pulse2: Vrc6Pulse,
+    sawtooth: Vrc6Sawtooth,
+    halt: bool,
+    frequency_shift: u8,
+}
+
+// Implement functionality of Vrc6Audio
+impl Vrc6Audio {
+    pub(super) fn write_register(&mut self, register: u16, data: u8) {
+        match register {
+            0x9000..=0x9002 => self.pulse1.write_register(register - 0x9000, data),
+            0x9003 => {
+                self.halt = data & 0b001 != 0;
+                self.frequency_shift = if data & 0b100 != 0 {
+                    8
+                } else if data & 0b010 != 0 {
+                    4
+                } else {
+                    0
+                };
+            }
+            0xA000..=0xA002 => self.pulse2.write_register(register - 0xA000, data),
+            0xB000..=0xB002 => self.sawtooth.write_register(register - 0xB000, data),
+            _ => {}
+        }
+    }
+
+    // Called once per CPU cycle
+    pub(super) fn clock(&mut self) {
+        if !self.halt {
+            self.pulse1.clock(self.frequency_shift);
+            self.pulse2.clock(self.frequency_shift);
+            self.sawtooth.clock(self.frequency_shift);
+        }
+    }
+
+    // The channels are mixed linearly, a pulse at volume 15 has the level of the chip
+    pub(super) fn output(&self) -> f32 {
+        let level = self.pulse1.output() + self.pulse2.output() + self.sawtooth.output();
+        level as f32 * ExpansionChip::Vrc6.level() / 15.0
+    }
+}
+
+// Declare VRC6 struct
+// Konami VRC6, mappers 24 and 26 (A0 and A1 swapped): 16KB and 8KB PRG banks, 1KB CHR
+// banks, the VRC IRQ counter and two pulse plus one sawtooth sound channels
+// https://www.nesdev.org/wiki/VRC6
+#[derive(Serialize, Deserialize)]
+pub struct VRC6 {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    swap_a0_a1: bool,
+
+    prg_bank_16k: u8,
+    prg_bank_8k: u8,
+    chr_banks: [u8; 8],
+    banking_control: u8, // $B003: PPU banking mode, mirroring and PRG-RAM enable
+    irq: VrcIrq,
+    audio: Vrc6Audio,
+}
+
+// Implement functionality of VRC6
+impl VRC6 {
+    // Create new VRC6 object
+    pub fn new(rom: Rom) -> Self {
+        let (chr, chr_is_ram) = chr_memory(&rom);
+        VRC6 {
+            prg_ram: prg_ram(&rom, rom.total_prg_ram_size()),
+            swap_a0_a1: rom.mapper == 26,
+            prg_rom: rom.prg_rom,
+            chr,
+            chr_is_ram,
+            prg_bank_16k: 0,
+            prg_bank_8k: 0,
+            chr_banks: [0; 8],
+            banking_control: 0,
+            irq: VrcIrq::new(),
+            audio: Vrc6Audio::default(),
+        }
+    }
+
+    fn prg_rom_addr(&self, addr: u16) -> usize {
+        let bank = match addr {
+            0x8000..=0xBFFF => self.prg_bank_16k as usize * 2 + ((addr as usize >> 13) & 1),
+            0xC000..=0xDFFF => self.prg_bank_8k as usize,
+            _ => bank_count(PRG_BANK_SIZE, self.prg_rom.len()) - 1,
+        };
+        bank_offset(bank, PRG_BANK_SIZE, self.prg_rom.len()) + (addr as usize & 0x1FFF)
+    }
+
+    // Modes 0-3 of $B003: eight 1KB banks, four 2KB banks, or 1KB banks followed by
+    // 2KB banks. In 2KB banks PPU A10 selects the half when bit 5 is set
+    fn chr_addr(&self, addr: u16) -> usize {
+        let slot = addr as usize >> 10;
+        let a10_from_ppu = self.banking_control & 0x20 != 0;
+        let two_kb = |register: usize| {
+            let bank = self.chr_banks[register] as usize;
+            if a10_from_ppu {
+                (bank & !1) | (slot & 1)
+            } else {
+                bank
+            }
+        };
+        let bank = match (self.banking_control & 0b11, slot) {
+            (0, _) => self.chr_banks[slot] as usize,
+            (1, _) => two_kb(slot / 2),
+            (_, 0..=3) => self.chr_banks[slot] as usize,
+            (_, _) => two_kb(4 + (slot - 4) / 2),
+        };
+        bank_offset(bank, CHR_BANK_SIZE, self.chr.len()) + (addr as usize & 0x03FF)
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        self.banking_control & 0x80 != 0 && !self.prg_ram.is_empty()
+    }
+
+    fn write_register(&mut self, addr: u16, data: u8) {
+        let register = if self.swap_a0_a1 {
+            (addr & 0xF000) | ((addr & 1) << 1) | ((addr >> 1) & 1)
+        } else {
+            addr & 0xF003
+        };
+        match register {
+            0x8000..=0x8003 => self.prg_bank_16k = data & 0x0F,
+            0x9000..=0xB002 => self.audio.write_register(register, data),
+            0xB003 => self.banking_control = data,
+            0xC000..=0xC003 => self.prg_bank_8k = data & 0x1F,
+            0xD000..=0xD003 => self.chr_banks[(register - 0xD000) as usize] = data,
+            0xE000..=0xE003 => self.chr_banks[4 + (register - 0xE000) as usize] = data,
+            0xF000 => self.irq.write_latch(data),
+            0xF001 => self.irq.write_control(data),
+            0xF002 => self.irq.acknowledge(),
+            _ => {}
+        }
+    }
+}
+
+// Implement functionality of Mapper for VRC6
+impl Mapper for VRC6 {
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => {
+                Some(self.prg_ram[(addr as usize - 0x6000) % self.prg_ram.len()])
+            }
+            0x8000..=0xFFFF => Some(self.prg_rom[self.prg_rom_addr(addr)]),
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => {
+                let index = (addr as usize - 0x6000) % self.prg_ram.len();
+                self.prg_ram[index] = data;
+            }
+            0x8000..=0xFFFF => self.write_register(addr, data),
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr[self.chr_addr(addr)]
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        if self.chr_is_ram {
+            let index = self.chr_addr(addr);
+            self.chr[index] = data;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match (self.banking_control >> 2) & 0b11 {
+            0 => Mirroring::Vertical,
+            1 => Mirroring::Horizontal,
+            2 => Mirroring::SingleScreenLower,
+            _ => Mirroring::SingleScreenUpper,
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.irq.pending()
+    }
+
+    fn cpu_clock(&mut self) {
+        self.irq.clock();
+        self.audio.clock();
+    }
+
+    fn audio_output(&self) -> f32 {
+        self.audio.output()
+    }
+
+    fn save_ram(&self) -> Vec<u8> {
+        self.prg_ram.clone()
+    }
+
+    fn load_save_ram(&mut self, data: &[u8]) {
+        load_ram(&mut self.prg_ram, data);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::banked_rom;
+
+    fn vrc6(mapper: u16) -> VRC6 {
+        VRC6::new(banked_rom(
+            mapper,
+            0x20000,
+            0x40000,
+            PRG_BANK_SIZE,
+            CHR_BANK_SIZE,
+        ))
+    }
+
+    #[test]
+    fn test_banking() {
+        let mut board = vrc6(24);
+        board.cpu_write(0x8000, 2);
+        board.cpu_write(0xC000, 9);
+        assert_eq!(board.cpu_read(0x8000), Some(4));
+        assert_eq!(board.cpu_read(0xA000), Some(5));
+        assert_eq!(board.cpu_read(0xC000), Some(9));
+        assert_eq!(board.cpu_read(0xE000), Some(15));
+
+        board.cpu_write(0xE001, 0x31);
+        assert_eq!(board.ppu_read(0x1400), 0x31);
+
+        // 2KB banks with A10 from the PPU
+        board.cpu_write(0xB003, 0b0010_0101);
+        board.cpu_write(0xD001, 0x40);
+        assert_eq!(board.ppu_read(0x0800), 0x40);
+        assert_eq!(board.ppu_read(0x0C00), 0x41);
+        assert_eq!(board.mirroring(), Mirroring::Horizontal);
+    }
+
+    #[test]
+    fn test_mapper_26_swaps_register_lines() {
+        let mut board = vrc6(26);
+        board.cpu_write(0xD002, 0x21);
+        assert_eq!(board.ppu_read(0x0400), 0x21);
+    }
+
+    #[test]
+    fn test_pulse_duty() {
+        let mut board = vrc6(24);
+        board.cpu_write(0x9000, 0b0011_1111); // duty 4/16, volume 15
+        board.cpu_write(0x9001, 0);
+        board.cpu_write(0x9002, 0x80);
+        let levels: Vec<f32> = (0..16)
+            .map(|_| {
+                board.cpu_clock();
+                board.audio_output()
+            })
+            .collect();
+        let loud = levels.iter().filter(|&&level| level > 0.0).count();
+        assert_eq!(loud, 4);
+        let peak = levels.iter().cloned().fold(0.0, f32::max);
+        assert!((peak - ExpansionChip::Vrc6.level()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_sawtooth() {
+        let mut saw = Vrc6Sawtooth::default();
+        saw.write_register(0, 0x10);
+        saw.write_register(2, 0x80);
+        let outputs: Vec<u8> = (0..14)
+            .map(|_| {
+                saw.clock(0);
+                saw.output()
+            })
+            .collect();
+        assert_eq!(outputs, vec![0, 2, 2, 4, 4, 6, 6, 8, 8, 10, 10, 12, 12, 0]);
+    }
+}
@@ -0,0 +1,250 @@
+use super::bank_count;
+use super::bank_offset;
+use super::chr_memory;
+use super::load_ram;
+use super::opll::Opll;
+use super::prg_ram;
+use super::vrc_irq::VrcIrq;
+use super::ExpansionChip;
+use super::Mapper;
+use crate::cartridge::Mirroring;
+use crate::cartridge::Rom;
+use serde::Deserialize;
+use serde::Serialize;
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x0400;
+
+// Declare VRC7 struct
+// Konami VRC7, mapper 85: three 8KB PRG banks, eight 1KB CHR banks, the VRC IRQ
+// counter and an FM synthesizer. VRC7a (Lagrange Point) selects registers with A4,
+// VRC7b (Tiny Toon Adventures 2) with A3 https://www.nesdev.org/wiki/VRC7
+#[derive(Serialize, Deserialize)]
+pub struct VRC7 {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    select_lines: u16, // CPU address lines connected to the chip's register select pin
+
+    prg_banks: [u8; 3],
+    chr_banks: [u8; 8],
+    control: u8, // $E000: mirroring, sound reset and PRG-RAM enable
+    irq: VrcIrq,
+    opll: Opll,
+}
+
+// Implement functionality of VRC7
+impl VRC7 {
+    // Create new VRC7 object, the wiring comes from the NES 2.0 submapper
+    pub fn new(rom: Rom) -> Self {
+        let select_lines = match rom.submapper {
+            1 => 0x08, // VRC7b
+            2 => 0x10, // VRC7a
+            _ => 0x18,
+        };
+        let (chr, chr_is_ram) = chr_memory(&rom);
+        VRC7 {
+            prg_ram: prg_ram(&rom, rom.total_prg_ram_size()),
+            prg_rom: rom.prg_rom,
+            chr,
+            chr_is_ram,
+            select_lines,
+            prg_banks: [0; 3],
+            chr_banks: [0; 8],
+            control: 0,
+            irq: VrcIrq::new(),
+            opll: Opll::new(),
+        }
+    }
+
+    fn prg_rom_addr(&self, addr: u16) -> usize {
+        let bank = match addr {
+            0x8000..=0xDFFF => self.prg_banks[(addr as usize - 0x8000) >> 13] as usize,
+            _ => bank_count(PRG_BANK_SIZE, self.prg_rom.len()) - 1,
+        };
+        bank_offset(bank, PRG_BANK_SIZE, self.prg_rom.len()) + (addr as usize & 0x1FFF)
+    }
+
+    fn chr_addr(&self, addr: u16) -> usize {
+        let bank = self.chr_banks[addr as usize >> 10] as usize;
+        bank_offset(bank, CHR_BANK_SIZE, self.chr.len()) + (addr as usize & 0x03FF)
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        self.control & 0x80 != 0 && !self.prg_ram.is_empty()
+    }
+
+    fn sound_reset(&self) -> bool {
+        self.control & 0x40 != 0
+    }
+
+    fn write_register(&mut self, addr: u16, data: u8) {
+        // The sound chip is always at $9010 and $9030
+        match addr & 0xF030 {
+            0x9010 => return self.opll.write_address(data),
+            0x9030 => {
+                if !self.sound_reset() {
+                    self.opll.write_data(data);
+                }
+                return;
+            }
+            _ => {}
+        }
+
+        // Register address as the chip sees it: $x000 or $x010
+        let select = if addr & self.select_lines != 0 {
+            0x10
+        } else {
+            0
+        };
+        match (addr & 0xF000) | select {
+            0x8000 => self.prg_banks[0] = data & 0x3F,
+            0x8010 => self.prg_banks[1] = data & 0x3F,
+            0x9000 => self.prg_banks[2] = data & 0x3F,
+            register @ 0xA000..=0xD010 => {
+                let index = ((register as usize - 0xA000) >> 12) * 2 + (register as usize >> 4 & 1);
+                self.chr_banks[index] = data;
+            }
+            0xE000 => {
+                self.control = data;
+                if self.sound_reset() {
+                    self.opll.reset();
+                }
+            }
+            0xE010 => self.irq.write_latch(data),
+            0xF000 => self.irq.write_control(data),
+            0xF010 => self.irq.acknowledge(),
+            _ => {}
+        }
+    }
+}
+
+// Implement functionality of Mapper for VRC7
+impl Mapper for VRC7 {
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => {
+                Some(self.prg_ram[(addr as usize - 0x6000) % self.prg_ram.len()])
+            }
+            0x8000..=0xFFFF => Some(self.prg_rom[self.prg_rom_addr(addr)]),
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => {
+                let index = (addr as usize - 0x6000) % self.prg_ram.len();
+                self.prg_ram[index] = data;
+            }
+            0x8000..=0xFFFF => self.write_register(addr, data),
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr[self.chr_addr(addr)]
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        if self.chr_is_ram {
+            let index = self.chr_addr(addr);
+            self.chr[index] = data;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.control & 0b11 {
+            0 => Mirroring::Vertical,
+            1 => Mirroring::Horizontal,
+            2 => Mirroring::SingleScreenLower,
+            _ => Mirroring::SingleScreenUpper,
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.irq.pending()
+    }
+
+    fn cpu_clock(&mut self) {
+        self.irq.clock();
+        if !self.sound_reset() {
+            self.opll.clock();
+        }
+    }
+
+    // The OPLL channels swing from -1.0 to 1.0, twice the range the level is given for
+    fn audio_output(&self) -> f32 {
+        self.opll.output() * ExpansionChip::Vrc7.level() / 2.0
+    }
+
+    fn save_ram(&self) -> Vec<u8> {
+        self.prg_ram.clone()
+    }
+
+    fn load_save_ram(&mut self, data: &[u8]) {
+        load_ram(&mut self.prg_ram, data);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::banked_rom;
+
+    fn vrc7(submapper: u8) -> VRC7 {
+        let mut rom = banked_rom(85, 0x20000, 0x40000, PRG_BANK_SIZE, CHR_BANK_SIZE);
+        rom.submapper = submapper;
+        VRC7::new(rom)
+    }
+
+    #[test]
+    fn test_banking_on_both_boards() {
+        for (submapper, select) in [(1, 0x08), (2, 0x10), (0, 0x10)] {
+            let mut board = vrc7(submapper);
+            board.cpu_write(0x8000, 3);
+            board.cpu_write(0x8000 | select, 5);
+            board.cpu_write(0x9000, 7);
+            assert_eq!(board.cpu_read(0x8000), Some(3));
+            assert_eq!(board.cpu_read(0xA000), Some(5));
+            assert_eq!(board.cpu_read(0xC000), Some(7));
+            assert_eq!(board.cpu_read(0xE000), Some(15));
+
+            board.cpu_write(0xD000 | select, 0x42);
+            assert_eq!(board.ppu_read(0x1C00), 0x42);
+            board.cpu_write(0xE000, 0x01);
+            assert_eq!(board.mirroring(), Mirroring::Horizontal);
+        }
+    }
+
+    #[test]
+    fn test_irq_and_sound() {
+        let mut board = vrc7(2);
+        board.cpu_write(0xE010, 0xFE);
+        board.cpu_write(0xF000, 0b110);
+        board.cpu_clock();
+        board.cpu_clock();
+        assert!(board.irq());
+        board.cpu_write(0xF010, 0);
+        assert!(!board.irq());
+
+        // Key on channel 0 with instrument 1
+        for (register, data) in [(0x30, 0x10), (0x10, 0x20), (0x20, 0x19)] {
+            board.cpu_write(0x9010, register);
+            board.cpu_write(0x9030, data);
+        }
+        let peak = (0..36_000)
+            .map(|_| {
+                board.cpu_clock();
+                board.audio_output().abs()
+            })
+            .fold(0.0, f32::max);
+        assert!(peak > 0.0 && peak <= ExpansionChip::Vrc7.level() / 2.0);
+
+        // The sound reset bit silences the chip
+        board.cpu_write(0xE000, 0x40);
+        board.cpu_clock();
+        assert_eq!(board.audio_output(), 0.0);
+    }
+}
@@ -0,0 +1,145 @@
+use serde::Deserialize;
+use serde::Serialize;
+
+// PPU dots per scanline, the scanline prescaler counts 3 dots per CPU cycle
+const PRESCALER_PERIOD: i16 = 341;
+const PRESCALER_STEP: i16 = 3;
+
+// Declare VrcIrq struct
+// The IRQ counter shared by VRC4, VRC6 and VRC7: an 8-bit up-counter reloaded from a
+// latch on overflow, clocked every CPU cycle or once per scanline through a prescaler
+// that approximates the line length https://www.nesdev.org/wiki/VRC_IRQ
+#[derive(Serialize, Deserialize)]
+pub struct VrcIrq {
+    latch: u8,
+    counter: u8,
+    prescaler: i16,
+    enabled: bool,
+    enabled_after_ack: bool,
+    cycle_mode: bool,
+    pending: bool,
+}
+
+// Implement functionality of VrcIrq
+impl VrcIrq {
+    // Create new VrcIrq object
+    pub fn new() -> Self {
+        VrcIrq {
+            latch: 0,
+            counter: 0,
+            prescaler: PRESCALER_PERIOD,
+            enabled: false,
+            enabled_after_ack: false,
+            cycle_mode: false,
+            pending: false,
+        }
+    }
+
+    pub fn write_latch(&mut self, data: u8) {
+        self.latch = data;
+    }
+
+    // VRC4 takes the latch one nibble at a time
+    pub fn write_latch_low(&mut self, data: u8) {
+        self.latch = (self.latch & 0xF0) | (data & 0x0F);
+    }
+
+    pub fn write_latch_high(&mut self, data: u8) {
+        self.latch = (self.latch & 0x0F) | (data << 4);
+    }
+
+    // .... .MEA: cycle mode, enable, enable again after acknowledge
+    pub fn write_control(&mut self, data: u8) {
+        self.enabled_after_ack = data & 0b001 != 0;
+        self.enabled = data & 0b010 != 0;
+        self.cycle_mode = data & 0b100 != 0;
+        self.pending = false;
+        if self.enabled {
+            self.counter = self.latch;
+            self.prescaler = PRESCALER_PERIOD;
+        }
+    }
+
+    pub fn acknowledge(&mut self) {
+        self.pending = false;
+        self.enabled = self.enabled_after_ack;
+    }
+
+    // Called once per CPU cycle
+    pub fn clock(&mut self) {
+        if !self.enabled {
+            return;
+        }
+        if self.cycle_mode {
+            self.clock_counter();
+        } else {
+            self.prescaler -= PRESCALER_STEP;
+            if self.prescaler <= 0 {
+                self.prescaler += PRESCALER_PERIOD;
+                self.clock_counter();
+            }
+        }
+    }
+
+    fn clock_counter(&mut self) {
+        if self.counter == 0xFF {
+            self.counter = self.latch;
+            self.pending = true;
+        } else {
+            self.counter += 1;
+        }
+    }
+
+    pub fn pending(&self) -> bool {
+        self.pending
+    }
+}
+
+impl Default for VrcIrq {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn cycles_until_irq(irq: &mut VrcIrq) -> usize {
+        (1..100_000)
+            .find(|_| {
+                irq.clock();
+                irq.pending()
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn test_cycle_mode() {
+        let mut irq = VrcIrq::new();
+        irq.write_latch(0xFC);
+        irq.write_control(0b111);
+        assert_eq!(cycles_until_irq(&mut irq), 4);
+
+        // Reloaded from the latch, and still enabled after the acknowledge
+        irq.acknowledge();
+        assert_eq!(cycles_until_irq(&mut irq), 4);
+    }
+
+    #[test]
+    fn test_scanline_mode() {
+        let mut irq = VrcIrq::new();
+        irq.write_latch_low(0xE);
+        irq.write_latch_high(0xF);
+        irq.write_control(0b010);
+        // Two scanlines of 113.67 CPU cycles
+        assert_eq!(cycles_until_irq(&mut irq), 228);
+
+        // Disabled after the acknowledge since bit 0 was clear
+        irq.acknowledge();
+        for _ in 0..1000 {
+            irq.clock();
+        }
+        assert!(!irq.pending());
+    }
+}
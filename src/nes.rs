@@ -0,0 +1,470 @@
+use crate::bus::Bus;
+use crate::cartridge::Rom;
+use crate::cpu::CPU;
+use crate::disk;
+use crate::disk::DiskImage;
+use crate::frame::Frame;
+use crate::joypad::Joypad;
+use crate::mapper;
+use crate::mapper::Mapper;
+use crate::mapper::FDS;
+use crate::region::Region;
+use crate::romdb;
+use crate::romdb::RomDatabase;
+use crate::save;
+use crate::save::SaveFile;
+use crate::screenshot;
+use crate::state;
+use crate::video::VideoRecorder;
+use crate::wav::WavRecorder;
+use std::path::Path;
+use std::path::PathBuf;
+
+// The Disk System BIOS is not included: disksys.rom is looked for next to the disk
+// image, then in the working directory
+fn read_bios(image_path: &Path) -> Result<Vec<u8>, String> {
+    let name = "disksys.rom";
+    let mut candidates = Vec::new();
+    if let Some(dir) = image_path.parent() {
+        candidates.push(dir.join(name));
+    }
+    candidates.push(PathBuf::from(name));
+    for candidate in candidates.iter() {
+        if let Ok(bios) = std::fs::read(candidate) {
+            return Ok(bios);
+        }
+    }
+    Err(format!(
+        "Disk System images need the BIOS: put {} next to the image or in the working directory",
+        name
+    ))
+}
+
+// Declare LoadedCartridge struct
+// A ROM or disk image ready to be inserted
+struct LoadedCartridge {
+    mapper: Box<dyn Mapper>,
+    // Where the battery-backed RAM or disk writes are kept: a .sav next to a .nes
+    // with a battery, an .ips patch next to a .fds
+    save_path: Option<PathBuf>,
+    region: Region,
+    crc: u32, // CRC-32 of the dump, which save states are checked against
+}
+
+// Load the bytes of a .nes file. The region comes from the NES 2.0 header, then from
+// the database, and is NTSC otherwise
+fn load_rom(raw: &[u8], database: &RomDatabase) -> Result<LoadedCartridge, String> {
+    let rom = Rom::new(raw)?;
+    let region = rom
+        .region
+        .or_else(|| database.lookup(&rom))
+        .unwrap_or_default();
+    Ok(LoadedCartridge {
+        crc: romdb::rom_crc32(&rom),
+        mapper: mapper::new_mapper(rom)?,
+        save_path: None,
+        region,
+    })
+}
+
+// Load a .nes or .fds file. The region comes from the NES 2.0 header, then from the
+// database, then from the tags in the file name, and is NTSC otherwise. The Disk
+// System only exists for NTSC
+fn load_cartridge(path: &Path, database: &RomDatabase) -> Result<LoadedCartridge, String> {
+    let raw =
+        std::fs::read(path).map_err(|err| format!("Cannot read {}: {}", path.display(), err))?;
+    if disk::is_disk_image(&raw) {
+        let image = DiskImage::new(&raw)?;
+        let fds = FDS::new(read_bios(path)?, image)?;
+        return Ok(LoadedCartridge {
+            mapper: Box::new(fds),
+            save_path: Some(path.with_extension("ips")),
+            region: Region::Ntsc,
+            crc: romdb::crc32(&raw),
+        });
+    }
+
+    let rom = Rom::new(&raw).map_err(|err| {
+        format!(
+            "{}: {}. Raw 6502 binaries run with --machine easy6502",
+            path.display(),
+            err
+        )
+    })?;
+    let save_path = if rom.battery {
+        Some(SaveFile::path_for(path))
+    } else {
+        None
+    };
+    let region = rom
+        .region
+        .or_else(|| database.lookup(&rom))
+        .or_else(|| Region::from_file_name(path))
+        .unwrap_or_default();
+    Ok(LoadedCartridge {
+        crc: romdb::rom_crc32(&rom),
+        mapper: mapper::new_mapper(rom)?,
+        save_path,
+        region,
+    })
+}
+
+// Declare Cartridge enum
+// Where the builder gets the cartridge from
+enum Cartridge {
+    File(PathBuf),
+    Rom(Vec<u8>),
+}
+
+// Declare NesBuilder struct
+// Settings for a new Nes, see Nes::builder
+pub struct NesBuilder {
+    cartridge: Option<Cartridge>,
+    region: Option<Region>,
+    database: RomDatabase,
+    saves: bool,
+}
+
+impl Default for NesBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Implement functionality of NesBuilder
+impl NesBuilder {
+    // Create new NesBuilder object
+    pub fn new() -> Self {
+        NesBuilder {
+            cartridge: None,
+            region: None,
+            database: RomDatabase::bundled(),
+            saves: true,
+        }
+    }
+
+    // Insert a .nes or .fds file
+    pub fn rom_file<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.cartridge = Some(Cartridge::File(path.as_ref().to_path_buf()));
+        self
+    }
+
+    // Insert a .nes file already in memory. Its battery-backed RAM is not saved
+    pub fn rom(mut self, raw: &[u8]) -> Self {
+        self.cartridge = Some(Cartridge::Rom(raw.to_vec()));
+        self
+    }
+
+    // Console timing, instead of the one of the cartridge
+    pub fn region(mut self, region: Region) -> Self {
+        self.region = Some(region);
+        self
+    }
+
+    // Regions of known dumps, for cartridges whose header does not tell. The bundled
+    // list by default, RomDatabase::load_user adds the user's own
+    pub fn rom_database(mut self, database: RomDatabase) -> Self {
+        self.database = database;
+        self
+    }
+
+    // Whether battery-backed RAM and disk writes are read from and written to the
+    // files next to the cartridge file. On by default
+    pub fn saves(mut self, saves: bool) -> Self {
+        self.saves = saves;
+        self
+    }
+
+    // Insert the cartridge and power the console on
+    pub fn build(self) -> Result<Nes, String> {
+        let cartridge = match self.cartridge {
+            Some(Cartridge::File(path)) => load_cartridge(&path, &self.database)?,
+            Some(Cartridge::Rom(raw)) => load_rom(&raw, &self.database)?,
+            None => return Err("No cartridge given to the builder".to_string()),
+        };
+        let region = self.region.unwrap_or(cartridge.region);
+        let bus = Bus::with_region(cartridge.mapper, region);
+        let mut cpu = CPU::with_bus(bus);
+        cpu.reset();
+
+        let save = match cartridge.save_path.filter(|_| self.saves) {
+            Some(save_path) => {
+                let save = SaveFile::open(save_path.clone(), &mut *cpu.bus.mapper)
+                    .map_err(|err| format!("Cannot read {}: {}", save_path.display(), err))?;
+                Some(save)
+            }
+            None => None,
+        };
+        Ok(Nes {
+            cpu,
+            save,
+            crc: cartridge.crc,
+        })
+    }
+}
+
+// Declare Nes struct
+// A console with a cartridge inserted, ready to run:
+//     let mut nes = Nes::builder().rom_file("game.nes").build()?;
+//     nes.run_frame();
+//     let picture = nes.frame();
+pub struct Nes {
+    cpu: CPU<Bus>,
+    save: Option<SaveFile>,
+    crc: u32, // CRC-32 of the cartridge
+}
+
+// Implement functionality of Nes
+impl Nes {
+    pub fn builder() -> NesBuilder {
+        NesBuilder::new()
+    }
+
+    pub fn cpu(&self) -> &CPU<Bus> {
+        &self.cpu
+    }
+
+    // The CPU, with the rest of the console on its bus
+    pub fn cpu_mut(&mut self) -> &mut CPU<Bus> {
+        &mut self.cpu
+    }
+
+    pub fn region(&self) -> Region {
+        self.cpu.bus.region()
+    }
+
+    // Press the reset button: the CPU restarts and the APU is silenced
+    pub fn reset(&mut self) {
+        self.cpu.reset();
+        self.cpu.bus.apu.write_register(0x4015, 0);
+    }
+
+    // Run one instruction. Returns false on BRK or an illegal opcode, see CPU::step
+    pub fn step(&mut self) -> bool {
+        self.cpu.step()
+    }
+
+    // Run until the PPU completes a frame. Returns false when the CPU stops on BRK or an
+    // illegal opcode first
+    pub fn run_frame(&mut self) -> bool {
+        self.run_frame_with_callback(|_| {})
+    }
+
+    // Run until the PPU completes a frame, calling back before every instruction
+    pub fn run_frame_with_callback<F>(&mut self, mut callback: F) -> bool
+    where
+        F: FnMut(&mut CPU<Bus>),
+    {
+        loop {
+            callback(&mut self.cpu);
+            if !self.cpu.step() {
+                return false;
+            }
+            if self.cpu.bus.ppu.take_frame_complete() {
+                return true;
+            }
+        }
+    }
+
+    // The picture of the last frame
+    pub fn frame(&self) -> &Frame {
+        &self.cpu.bus.ppu.frame
+    }
+
+    // Write the picture of the last frame to path as a PNG image, every pixel
+    // becoming scale x scale pixels
+    pub fn save_screenshot(&self, path: &Path, scale: usize) -> Result<(), String> {
+        screenshot::save_png(self.frame(), scale, path)
+    }
+
+    // Start recording the sound to path as WAV, with every APU channel in its own file
+    // next to it when split_channels is set. Give the recorder the samples from
+    // take_samples and take_channel_samples after every frame
+    pub fn start_wav(&mut self, path: &Path, split_channels: bool) -> Result<WavRecorder, String> {
+        let apu = &mut self.cpu.bus.apu;
+        apu.set_channel_capture(split_channels);
+        // Drop the samples of this frame so every file starts at the same point
+        apu.take_samples();
+        apu.take_channel_samples();
+        WavRecorder::start(path, apu.sample_rate(), split_channels).map_err(|err| {
+            apu.set_channel_capture(false);
+            format!("Cannot record to {}: {}", path.display(), err)
+        })
+    }
+
+    // The sound of every APU channel made since the last call, while a recording of
+    // split channels is running
+    pub fn take_channel_samples(&mut self) -> Vec<Vec<f32>> {
+        self.cpu.bus.apu.take_channel_samples()
+    }
+
+    // Start recording video to path and sound next to it, at the console's frame
+    // rate. Give the recorder every frame and the samples from take_samples
+    pub fn start_video(&self, path: &Path) -> Result<VideoRecorder, String> {
+        VideoRecorder::start(
+            path,
+            Frame::NES_WIDTH,
+            Frame::NES_HEIGHT,
+            self.region().frame_rate(),
+            self.cpu.bus.apu.sample_rate(),
+        )
+        .map_err(|err| format!("Cannot record to {}: {}", path.display(), err))
+    }
+
+    // The controllers in the two ports, joypads[0] being controller 1
+    pub fn joypads_mut(&mut self) -> [&mut Joypad; 2] {
+        [&mut self.cpu.bus.joypad1, &mut self.cpu.bus.joypad2]
+    }
+
+    // The sound made since the last call, at the APU's sample rate
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        self.cpu.bus.apu.take_samples()
+    }
+
+    // Snapshot of the console and the cartridge, which load_state goes back to
+    pub fn save_state(&self) -> Result<Vec<u8>, String> {
+        state::save(&self.cpu, self.crc)
+    }
+
+    // Go back to a snapshot taken by save_state. States of other games and damaged
+    // states are refused, leaving the console as it is
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), String> {
+        state::load(&mut self.cpu, self.crc, data)
+    }
+
+    // Write a snapshot to a file, see state::slot_path
+    pub fn save_state_file(&self, path: &Path) -> Result<(), String> {
+        save::write_atomic(path, &self.save_state()?)
+            .map_err(|err| format!("Cannot write {}: {}", path.display(), err))
+    }
+
+    // Go back to the snapshot in a file written by save_state_file
+    pub fn load_state_file(&mut self, path: &Path) -> Result<(), String> {
+        let data = std::fs::read(path)
+            .map_err(|err| format!("Cannot read {}: {}", path.display(), err))?;
+        self.load_state(&data)
+            .map_err(|err| format!("{}: {}", path.display(), err))
+    }
+
+    // Write the battery-backed RAM or disk writes out if they changed
+    pub fn flush_save(&mut self) {
+        if let Some(save) = self.save.as_mut() {
+            if let Err(err) = save.flush(&*self.cpu.bus.mapper) {
+                eprintln!("Failed to write {}: {}", save.path().display(), err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::Mem;
+
+    // An NROM cartridge whose program counts frames at $10 from the NMI handler
+    fn test_rom() -> Vec<u8> {
+        let mut raw = vec![0x4E, 0x45, 0x53, 0x1A, 1, 1, 0, 0];
+        raw.resize(16, 0);
+        let mut prg = vec![0xEA; 0x4000];
+        // Reset: LDA #$80; STA $2000 to turn on NMI, then JMP to itself
+        prg[0..8].copy_from_slice(&[0xA9, 0x80, 0x8D, 0x00, 0x20, 0x4C, 0x05, 0x80]);
+        // NMI: INC $10; RTI
+        prg[0x100..0x103].copy_from_slice(&[0xE6, 0x10, 0x40]);
+        prg[0x3FFA..0x3FFC].copy_from_slice(&[0x00, 0x81]);
+        prg[0x3FFC..0x3FFE].copy_from_slice(&[0x00, 0x80]);
+        raw.extend(prg);
+        raw.extend(vec![0; 0x2000]);
+        raw
+    }
+
+    #[test]
+    fn test_nes_builder() {
+        assert!(Nes::builder().build().is_err());
+
+        let mut nes = Nes::builder()
+            .rom(&test_rom())
+            .region(Region::Pal)
+            .build()
+            .unwrap();
+        assert_eq!(nes.region(), Region::Pal);
+        for _ in 0..3 {
+            assert!(nes.run_frame());
+        }
+        assert_eq!(nes.frame().width, Frame::NES_WIDTH);
+        assert!(!nes.take_samples().is_empty());
+        let counted = nes.cpu_mut().mem_read(0x10);
+        assert!((2..=3).contains(&counted));
+    }
+
+    #[test]
+    fn test_region_from_database() {
+        let raw = test_rom();
+        let nes = Nes::builder().rom(&raw).build().unwrap();
+        assert_eq!(nes.region(), Region::Ntsc);
+
+        let text = format!("{:08X} pal", crate::romdb::crc32(&raw[16..]));
+        let database = RomDatabase::parse(&text).unwrap();
+        let nes = Nes::builder()
+            .rom(&raw)
+            .rom_database(database.clone())
+            .build()
+            .unwrap();
+        assert_eq!(nes.region(), Region::Pal);
+
+        // The NES 2.0 header comes first
+        let mut nes2 = raw.clone();
+        nes2[7] = 0x08;
+        nes2[12] = 3;
+        let nes = Nes::builder()
+            .rom(&nes2)
+            .rom_database(database)
+            .build()
+            .unwrap();
+        assert_eq!(nes.region(), Region::Dendy);
+    }
+
+    #[test]
+    fn test_save_state() {
+        let mut nes = Nes::builder().rom(&test_rom()).build().unwrap();
+        for _ in 0..5 {
+            nes.run_frame();
+        }
+        let state = nes.save_state().unwrap();
+        let counted = nes.cpu_mut().mem_read(0x10);
+        for _ in 0..5 {
+            nes.run_frame();
+        }
+        let later = (nes.cpu().program_counter, nes.cpu_mut().mem_read(0x10));
+        assert_ne!(later.1, counted);
+
+        // Running on from the state does the same again
+        nes.load_state(&state).unwrap();
+        assert_eq!(nes.cpu_mut().mem_read(0x10), counted);
+        for _ in 0..5 {
+            nes.run_frame();
+        }
+        assert_eq!(
+            (nes.cpu().program_counter, nes.cpu_mut().mem_read(0x10)),
+            later
+        );
+
+        // Damaged states and states of other games leave the console alone
+        assert!(nes.load_state(&state[..state.len() / 2]).is_err());
+        assert!(nes.load_state(b"not a state").is_err());
+        let mut other = test_rom();
+        other[0x1000] = 0;
+        let other = Nes::builder().rom(&other).build().unwrap();
+        let err = nes.load_state(&other.save_state().unwrap()).unwrap_err();
+        assert!(err.contains("another game"));
+        let pal = Nes::builder()
+            .rom(&test_rom())
+            .region(Region::Pal)
+            .build()
+            .unwrap();
+        assert!(nes.load_state(&pal.save_state().unwrap()).is_err());
+        assert_eq!(
+            (nes.cpu().program_counter, nes.cpu_mut().mem_read(0x10)),
+            later
+        );
+    }
+}
@@ -0,0 +1,403 @@
+// NES Sound Format: the music code and data of a game with the addresses of its INIT
+// and PLAY routines https://www.nesdev.org/wiki/NSF
+// NSFe keeps the same information in chunks, along with track names and lengths
+// https://www.nesdev.org/wiki/NSFe
+pub mod player;
+
+use crate::region::Region;
+use serde::Deserialize;
+use serde::Serialize;
+
+const NSF_TAG: &[u8] = b"NESM\x1A";
+const NSFE_TAG: &[u8] = b"NSFE";
+const HEADER_SIZE: usize = 0x80;
+const NAME_SIZE: usize = 32;
+
+// PLAY is called every this many microseconds when the file does not say
+pub const DEFAULT_PLAY_SPEED_NTSC: u16 = 16639;
+pub const DEFAULT_PLAY_SPEED_PAL: u16 = 19997;
+
+bitflags! {
+    /// # Expansion sound chips https://www.nesdev.org/wiki/NSF#Header_Overview
+    ///
+    ///  7 6 5 4 3 2 1 0
+    ///  _ _ B N M F 7 6
+    ///      | | | | | +--- Konami VRC6
+    ///      | | | | +----- Konami VRC7
+    ///      | | | +------- Famicom Disk System
+    ///      | | +--------- Nintendo MMC5
+    ///      | +----------- Namco 163
+    ///      +------------- Sunsoft 5B
+    ///
+    #[derive(Serialize, Deserialize)]
+    pub struct ExpansionChips: u8 {
+        const VRC6 = 0b0000_0001;
+        const VRC7 = 0b0000_0010;
+        const FDS = 0b0000_0100;
+        const MMC5 = 0b0000_1000;
+        const NAMCO163 = 0b0001_0000;
+        const SUNSOFT5B = 0b0010_0000;
+    }
+}
+
+// Declare TrackInfo struct
+// What NSFe files tell about a track, times in milliseconds
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct TrackInfo {
+    pub name: Option<String>,
+    pub length: Option<u32>,
+    pub fade: Option<u32>,
+}
+
+// Declare NsfFile struct
+pub struct NsfFile {
+    pub total_songs: u8,
+    pub starting_song: u8, // 0-based
+    pub load_addr: u16,
+    pub init_addr: u16,
+    pub play_addr: u16,
+    pub title: String,
+    pub artist: String,
+    pub copyright: String,
+    pub play_speed_ntsc: u16, // microseconds between PLAY calls
+    pub play_speed_pal: u16,
+    pub bank_init: [u8; 8], // all zero when the tune does not switch banks
+    pub pal: bool,          // made for PAL consoles only
+    pub expansion: ExpansionChips,
+    pub data: Vec<u8>, // placed at load_addr
+    pub tracks: Vec<TrackInfo>,
+}
+
+// Checks whether the file is an NSF or NSFe file
+pub fn is_nsf(raw: &[u8]) -> bool {
+    raw.starts_with(NSF_TAG) || raw.starts_with(NSFE_TAG)
+}
+
+// Text fields are null-terminated, or fill their whole space
+fn read_string(raw: &[u8]) -> String {
+    let end = raw.iter().position(|&byte| byte == 0).unwrap_or(raw.len());
+    String::from_utf8_lossy(&raw[..end]).into_owned()
+}
+
+fn read_u16(raw: &[u8], pos: usize) -> u16 {
+    raw[pos] as u16 | (raw[pos + 1] as u16) << 8
+}
+
+// Implement functionality of NsfFile
+impl NsfFile {
+    // Parse the contents of an .nsf or .nsfe file
+    pub fn new(raw: &[u8]) -> Result<NsfFile, String> {
+        if raw.starts_with(NSFE_TAG) {
+            NsfFile::from_nsfe(&raw[NSFE_TAG.len()..])
+        } else if raw.starts_with(NSF_TAG) {
+            NsfFile::from_nsf(raw)
+        } else {
+            Err("File is not in NSF or NSFe format".to_string())
+        }
+    }
+
+    // The console the tune is played on by default: PAL for tunes made for PAL consoles
+    // only, NTSC otherwise (dual-region tunes included)
+    pub fn region(&self) -> Region {
+        if self.pal {
+            Region::Pal
+        } else {
+            Region::Ntsc
+        }
+    }
+
+    // Microseconds between PLAY calls on a console of the region. Dendy consoles
+    // refresh at 50 Hz like PAL ones, so they use the PAL speed
+    pub fn play_speed(&self, region: Region) -> u16 {
+        match region {
+            Region::Ntsc => self.play_speed_ntsc,
+            Region::Pal | Region::Dendy => self.play_speed_pal,
+        }
+    }
+
+    fn empty() -> NsfFile {
+        NsfFile {
+            total_songs: 1,
+            starting_song: 0,
+            load_addr: 0,
+            init_addr: 0,
+            play_addr: 0,
+            title: String::new(),
+            artist: String::new(),
+            copyright: String::new(),
+            play_speed_ntsc: DEFAULT_PLAY_SPEED_NTSC,
+            play_speed_pal: DEFAULT_PLAY_SPEED_PAL,
+            bank_init: [0; 8],
+            pal: false,
+            expansion: ExpansionChips::empty(),
+            data: Vec::new(),
+            tracks: Vec::new(),
+        }
+    }
+
+    fn from_nsf(raw: &[u8]) -> Result<NsfFile, String> {
+        if raw.len() <= HEADER_SIZE {
+            return Err("File is truncated: no room for the NSF header".to_string());
+        }
+        let speed = |pos| match read_u16(raw, pos) {
+            0 => None,
+            speed => Some(speed),
+        };
+        let mut nsf = NsfFile {
+            total_songs: raw[0x06],
+            starting_song: raw[0x07].saturating_sub(1),
+            load_addr: read_u16(raw, 0x08),
+            init_addr: read_u16(raw, 0x0A),
+            play_addr: read_u16(raw, 0x0C),
+            title: read_string(&raw[0x0E..0x0E + NAME_SIZE]),
+            artist: read_string(&raw[0x2E..0x2E + NAME_SIZE]),
+            copyright: read_string(&raw[0x4E..0x4E + NAME_SIZE]),
+            play_speed_ntsc: speed(0x6E).unwrap_or(DEFAULT_PLAY_SPEED_NTSC),
+            play_speed_pal: speed(0x78).unwrap_or(DEFAULT_PLAY_SPEED_PAL),
+            bank_init: raw[0x70..0x78].try_into().unwrap(),
+            pal: raw[0x7A] & 0b11 == 0b01,
+            expansion: ExpansionChips::from_bits_truncate(raw[0x7B]),
+            data: raw[HEADER_SIZE..].to_vec(),
+            tracks: Vec::new(),
+        };
+
+        // NSF2 can give the length of the program data and follow it with NSFe chunks
+        let data_length =
+            raw[0x7D] as usize | (raw[0x7E] as usize) << 8 | (raw[0x7F] as usize) << 16;
+        if raw[0x05] >= 2 && data_length > 0 && HEADER_SIZE + data_length < raw.len() {
+            nsf.data.truncate(data_length);
+            nsf.read_chunks(&raw[HEADER_SIZE + data_length..], false)?;
+        }
+        nsf.tracks
+            .resize(nsf.total_songs as usize, TrackInfo::default());
+        nsf.check()?;
+        Ok(nsf)
+    }
+
+    fn from_nsfe(chunks: &[u8]) -> Result<NsfFile, String> {
+        let mut nsf = NsfFile::empty();
+        nsf.read_chunks(chunks, true)?;
+        nsf.tracks
+            .resize(nsf.total_songs as usize, TrackInfo::default());
+        nsf.check()?;
+        Ok(nsf)
+    }
+
+    // Chunks are a 4-byte length, a 4-byte id and the data. The header fields come from
+    // the INFO, DATA, BANK and RATE chunks in NSFe files, NSF2 only uses the metadata
+    fn read_chunks(&mut self, raw: &[u8], nsfe: bool) -> Result<(), String> {
+        let mut has_info = !nsfe;
+        let mut has_data = !nsfe;
+        let mut pos = 0;
+        while pos + 8 <= raw.len() {
+            let length = u32::from_le_bytes(raw[pos..pos + 4].try_into().unwrap()) as usize;
+            let id = &raw[pos + 4..pos + 8];
+            let chunk = raw
+                .get(pos + 8..pos + 8 + length)
+                .ok_or_else(|| format!("Chunk {} is truncated", read_string(id)))?;
+            pos += 8 + length;
+
+            match id {
+                b"INFO" if nsfe => {
+                    if chunk.len() < 8 {
+                        return Err("INFO chunk is truncated".to_string());
+                    }
+                    self.load_addr = read_u16(chunk, 0);
+                    self.init_addr = read_u16(chunk, 2);
+                    self.play_addr = read_u16(chunk, 4);
+                    self.pal = chunk[6] & 0b11 == 0b01;
+                    self.expansion = ExpansionChips::from_bits_truncate(chunk[7]);
+                    self.total_songs = chunk.get(8).copied().unwrap_or(1);
+                    self.starting_song = chunk.get(9).copied().unwrap_or(0);
+                    has_info = true;
+                }
+                b"DATA" if nsfe => {
+                    self.data = chunk.to_vec();
+                    has_data = true;
+                }
+                b"BANK" if nsfe => {
+                    let len = chunk.len().min(self.bank_init.len());
+                    self.bank_init[..len].copy_from_slice(&chunk[..len]);
+                }
+                b"RATE" if nsfe => {
+                    if chunk.len() >= 2 && read_u16(chunk, 0) != 0 {
+                        self.play_speed_ntsc = read_u16(chunk, 0);
+                    }
+                    if chunk.len() >= 4 && read_u16(chunk, 2) != 0 {
+                        self.play_speed_pal = read_u16(chunk, 2);
+                    }
+                }
+                b"auth" => {
+                    let mut fields = chunk.split(|&byte| byte == 0).map(read_string);
+                    self.title = fields.next().unwrap_or_default();
+                    self.artist = fields.next().unwrap_or_default();
+                    self.copyright = fields.next().unwrap_or_default();
+                }
+                b"tlbl" => {
+                    let names: Vec<String> =
+                        chunk.split(|&byte| byte == 0).map(read_string).collect();
+                    for (track, name) in self.tracks_mut(names.len()).iter_mut().zip(names) {
+                        track.name = Some(name).filter(|name| !name.is_empty());
+                    }
+                }
+                b"time" | b"fade" => {
+                    let count = chunk.len() / 4;
+                    let times = chunk
+                        .chunks_exact(4)
+                        .map(|time| i32::from_le_bytes(time.try_into().unwrap()));
+                    for (track, time) in self.tracks_mut(count).iter_mut().zip(times) {
+                        // Negative times leave the player's default
+                        let time = u32::try_from(time).ok();
+                        if id == b"time" {
+                            track.length = time;
+                        } else {
+                            track.fade = time;
+                        }
+                    }
+                }
+                b"NEND" => break,
+                // Chunks starting with a capital letter are needed to play the file
+                _ if id[0].is_ascii_uppercase() && nsfe => {
+                    return Err(format!("Unsupported chunk {}", read_string(id)));
+                }
+                _ => { /* optional chunk, e.g. the playlist */ }
+            }
+        }
+        if !has_info || !has_data {
+            return Err("NSFe file has no INFO or DATA chunk".to_string());
+        }
+        Ok(())
+    }
+
+    // Track infos for at least count tracks, before INFO told how many there are
+    fn tracks_mut(&mut self, count: usize) -> &mut [TrackInfo] {
+        let len = self.tracks.len().max(count).max(self.total_songs as usize);
+        self.tracks.resize(len, TrackInfo::default());
+        &mut self.tracks
+    }
+
+    fn check(&self) -> Result<(), String> {
+        if self.total_songs == 0 {
+            return Err("File has no songs".to_string());
+        }
+        if self.data.is_empty() {
+            return Err("File has no program data".to_string());
+        }
+        if self.load_addr < 0x6000 {
+            return Err(format!(
+                "Load address {:04X} is out of range",
+                self.load_addr
+            ));
+        }
+        Ok(())
+    }
+
+    // The tune switches 4KB banks at $8000-$FFFF through $5FF8-$5FFF
+    pub fn is_bankswitched(&self) -> bool {
+        self.bank_init.iter().any(|&bank| bank != 0)
+    }
+
+    // NSFe track name, or the title with the track number
+    pub fn track_name(&self, track: usize) -> String {
+        match self.tracks.get(track).and_then(|info| info.name.clone()) {
+            Some(name) => name,
+            None => format!("{} #{}", self.title, track + 1),
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    // An NSF header for the given program, loaded at $8000 with INIT at $8000 and
+    // PLAY at $8003
+    pub fn test_nsf(program: &[u8]) -> Vec<u8> {
+        let mut raw = vec![0; HEADER_SIZE];
+        raw[..5].copy_from_slice(NSF_TAG);
+        raw[0x05] = 1;
+        raw[0x06] = 3;
+        raw[0x07] = 2;
+        raw[0x08..0x0E].copy_from_slice(&[0x00, 0x80, 0x00, 0x80, 0x03, 0x80]);
+        raw[0x0E..0x13].copy_from_slice(b"Title");
+        raw[0x2E..0x34].copy_from_slice(b"Artist");
+        raw[0x6E..0x70].copy_from_slice(&16639_u16.to_le_bytes());
+        raw.extend_from_slice(program);
+        raw
+    }
+
+    fn chunk(id: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut chunk = (data.len() as u32).to_le_bytes().to_vec();
+        chunk.extend_from_slice(id);
+        chunk.extend_from_slice(data);
+        chunk
+    }
+
+    #[test]
+    fn test_parse_nsf() {
+        let nsf = NsfFile::new(&test_nsf(&[0x60, 0x60])).unwrap();
+        assert_eq!(nsf.total_songs, 3);
+        assert_eq!(nsf.starting_song, 1);
+        assert_eq!(
+            (nsf.load_addr, nsf.init_addr, nsf.play_addr),
+            (0x8000, 0x8000, 0x8003)
+        );
+        assert_eq!(nsf.title, "Title");
+        assert_eq!(nsf.artist, "Artist");
+        assert_eq!(nsf.play_speed_pal, DEFAULT_PLAY_SPEED_PAL);
+        assert_eq!(nsf.data, vec![0x60, 0x60]);
+        assert!(!nsf.is_bankswitched());
+        assert_eq!(nsf.track_name(2), "Title #3");
+
+        assert!(is_nsf(&test_nsf(&[])));
+        assert!(NsfFile::new(&test_nsf(&[])).is_err());
+        assert!(NsfFile::new(b"NES\x1A").is_err());
+    }
+
+    #[test]
+    fn test_parse_nsfe() {
+        let mut raw = NSFE_TAG.to_vec();
+        raw.extend(chunk(
+            b"INFO",
+            &[0x00, 0x80, 0x00, 0x80, 0x03, 0x80, 0, 0b1_0001, 2, 1],
+        ));
+        raw.extend(chunk(b"DATA", &[0x60]));
+        raw.extend(chunk(b"BANK", &[0, 1, 2, 3, 4, 5, 6, 7]));
+        raw.extend(chunk(b"auth", b"Game\0Composer\0Company\0Ripper\0"));
+        raw.extend(chunk(b"tlbl", b"Intro\0\0"));
+        raw.extend(chunk(
+            b"time",
+            &[&90_000_i32.to_le_bytes()[..], &(-1_i32).to_le_bytes()].concat(),
+        ));
+        raw.extend(chunk(b"plst", &[1, 0]));
+        raw.extend(chunk(b"NEND", &[]));
+
+        let nsf = NsfFile::new(&raw).unwrap();
+        assert_eq!(nsf.total_songs, 2);
+        assert_eq!(nsf.starting_song, 1);
+        assert_eq!(
+            nsf.expansion,
+            ExpansionChips::VRC6 | ExpansionChips::NAMCO163
+        );
+        assert!(nsf.is_bankswitched());
+        assert_eq!(
+            (
+                nsf.title.as_str(),
+                nsf.artist.as_str(),
+                nsf.copyright.as_str()
+            ),
+            ("Game", "Composer", "Company")
+        );
+        assert_eq!(nsf.play_speed_ntsc, DEFAULT_PLAY_SPEED_NTSC);
+        assert_eq!(nsf.track_name(0), "Intro");
+        assert_eq!(nsf.track_name(1), "Game #2");
+        assert_eq!(nsf.tracks[0].length, Some(90_000));
+        assert_eq!(nsf.tracks[1].length, None);
+
+        // Unknown chunks are only fatal when they are required
+        let mut required = raw[..raw.len() - 8].to_vec();
+        required.extend(chunk(b"XTRA", &[]));
+        assert!(NsfFile::new(&required).is_err());
+        assert!(NsfFile::new(&[NSFE_TAG, &chunk(b"DATA", &[0x60])].concat()).is_err());
+    }
+}
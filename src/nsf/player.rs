@@ -0,0 +1,335 @@
+use super::NsfFile;
+use crate::apu::APU;
+use crate::cpu::CpuBus;
+use crate::cpu::Mem;
+use crate::cpu::CPU;
+use crate::mapper::Mapper;
+use crate::mapper::NsfCartridge;
+use crate::region::Region;
+use crate::resampler::Resampler;
+use crate::wav::WavWriter;
+use crate::wav::RECORDING_RATE;
+use std::io;
+use std::io::Seek;
+use std::io::Write;
+
+// INIT and PLAY return here, where the bus answers with BRK to stop the CPU. Nothing
+// is mapped at $4018 on the console
+const RETURN_ADDRESS: u16 = 0x4018;
+const BRK: u8 = 0x00;
+
+const STACK_RESET: u8 = 0xFD;
+
+// A routine still running after a second of CPU time is stopped
+const MAX_ROUTINE_CYCLES: usize = 1_789_773;
+
+// Tracks without an NSFe length play this long before fading out, in milliseconds
+pub const DEFAULT_TRACK_LENGTH: u32 = 150_000;
+pub const DEFAULT_FADE: u32 = 5_000;
+
+// Declare NsfBus struct
+// The minimal console an NSF needs: RAM, the APU and the cartridge, with no PPU
+pub struct NsfBus {
+    ram: [u8; 2048],
+    pub apu: APU,
+    pub cartridge: NsfCartridge,
+    stall_cycles: usize,
+    cycles: u64, // CPU cycles since the track started
+    region: Region,
+}
+
+// Implement functionality of NsfBus
+impl NsfBus {
+    // Create new NsfBus object with the tune inserted, and the APU timing of a region
+    pub fn new(cartridge: NsfCartridge, region: Region) -> Self {
+        NsfBus {
+            ram: [0; 2048],
+            apu: APU::with_region(region),
+            cartridge,
+            stall_cycles: 0,
+            cycles: 0,
+            region,
+        }
+    }
+
+    // Power on state, with the APU set up the way INIT expects it
+    // https://www.nesdev.org/wiki/NSF#Initializing_a_tune
+    fn reset(&mut self) {
+        self.ram = [0; 2048];
+        self.apu = APU::with_region(self.region);
+        self.cartridge.reset();
+        self.stall_cycles = 0;
+        self.cycles = 0;
+        for addr in 0x4000..=0x4013 {
+            self.mem_write(addr, 0);
+        }
+        self.mem_write(0x4015, 0x0F);
+        self.mem_write(0x4017, 0x40);
+    }
+}
+
+// Implement functionality of Mem for NsfBus
+impl Mem for NsfBus {
+    fn mem_read(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x1FFF => self.ram[addr as usize & 0x07FF],
+            0x4015 => self.apu.read_status(),
+            RETURN_ADDRESS => BRK,
+            0x4020..=0xFFFF => self.cartridge.cpu_read(addr).unwrap_or(0),
+            _ => 0,
+        }
+    }
+
+    fn mem_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.ram[addr as usize & 0x07FF] = data,
+            0x4000..=0x4013 | 0x4015 | 0x4017 => self.apu.write_register(addr, data),
+            0x4020..=0xFFFF => self.cartridge.cpu_write(addr, data),
+            _ => { /* ignore writes to unmapped addresses */ }
+        }
+    }
+}
+
+// Implement functionality of CpuBus for NsfBus
+// No interrupts reach the CPU, tunes are driven by calling PLAY
+impl CpuBus for NsfBus {
+    fn tick(&mut self, cycles: u8) {
+        for _ in 0..cycles {
+            self.cycles += 1;
+            self.cartridge.cpu_clock();
+            self.apu.set_expansion_output(self.cartridge.audio_output());
+            self.apu.tick();
+
+            if let Some(addr) = self.apu.dmc_dma_request() {
+                let data = self.mem_read(addr);
+                self.apu.dmc_dma_complete(data);
+                self.stall_cycles += 4;
+            }
+        }
+    }
+
+    fn take_stall_cycles(&mut self) -> usize {
+        std::mem::take(&mut self.stall_cycles)
+    }
+}
+
+// Declare NsfPlayer struct
+// Plays the tracks of an NSF: INIT once with the track number, then PLAY at the rate
+// the header gives for the region of the console
+pub struct NsfPlayer {
+    pub nsf: NsfFile,
+    cpu: CPU<NsfBus>,
+    region: Region,
+    track: usize,
+    play_period: f64, // CPU cycles between PLAY calls
+    next_play: f64,   // bus cycle the next PLAY call is due at
+}
+
+// Implement functionality of NsfPlayer
+impl NsfPlayer {
+    // Create new NsfPlayer object, starting the first track of the file on a console
+    // of the region the tune was made for
+    pub fn new(nsf: NsfFile) -> Self {
+        let region = nsf.region();
+        NsfPlayer::with_region(nsf, region)
+    }
+
+    // Create new NsfPlayer object playing the tune on a console of the given region
+    pub fn with_region(nsf: NsfFile, region: Region) -> Self {
+        let bus = NsfBus::new(NsfCartridge::new(&nsf), region);
+        let play_period = nsf.play_speed(region) as f64 * region.cpu_clock_rate() / 1_000_000.0;
+        let track = nsf.starting_song as usize;
+        let mut player = NsfPlayer {
+            nsf,
+            cpu: CPU::with_bus(bus),
+            region,
+            track,
+            play_period,
+            next_play: 0.0,
+        };
+        player.start_track(track);
+        player
+    }
+
+    pub fn track(&self) -> usize {
+        self.track
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    // Rate of the samples returned by play_frame
+    pub fn sample_rate(&self) -> f64 {
+        self.cpu.bus.apu.sample_rate()
+    }
+
+    pub fn track_count(&self) -> usize {
+        self.nsf.total_songs as usize
+    }
+
+    // Reset the console and run INIT for a track (0-based)
+    pub fn start_track(&mut self, track: usize) {
+        self.track = track;
+        self.cpu.bus.reset();
+        // X selects NTSC (0) or PAL (1)
+        let pal = match self.region {
+            Region::Ntsc => 0,
+            Region::Pal | Region::Dendy => 1,
+        };
+        self.call(self.nsf.init_addr, track as u8, pal);
+        self.next_play = self.cpu.bus.cycles as f64;
+        self.cpu.bus.apu.take_samples();
+    }
+
+    // Run a routine of the tune until it returns
+    fn call(&mut self, addr: u16, register_a: u8, register_x: u8) {
+        let cpu = &mut self.cpu;
+        cpu.register_a = register_a;
+        cpu.register_x = register_x;
+        cpu.register_y = 0;
+        cpu.stack_pointer = STACK_RESET;
+        cpu.call_subroutine(addr, RETURN_ADDRESS);
+        let deadline = cpu.cycles + MAX_ROUTINE_CYCLES;
+        cpu.run_with_callback(|cpu| {
+            if cpu.cycles > deadline {
+                cpu.program_counter = RETURN_ADDRESS;
+            }
+        });
+    }
+
+    // Call PLAY and let the console run until the next call is due, returns the APU
+    // samples of that time
+    pub fn play_frame(&mut self) -> Vec<f32> {
+        self.call(self.nsf.play_addr, 0, 0);
+        // A PLAY running longer than the period delays the next call
+        self.next_play = (self.next_play + self.play_period).max(self.cpu.bus.cycles as f64);
+        while (self.cpu.bus.cycles as f64) < self.next_play {
+            self.cpu.bus.tick(1);
+        }
+        self.cpu.bus.take_stall_cycles();
+        self.cpu.bus.apu.take_samples()
+    }
+
+    // Play time and fade out of a track in milliseconds
+    pub fn track_length(&self, track: usize) -> (u32, u32) {
+        let info = self.nsf.tracks.get(track);
+        (
+            info.and_then(|info| info.length)
+                .unwrap_or(DEFAULT_TRACK_LENGTH),
+            info.and_then(|info| info.fade).unwrap_or(DEFAULT_FADE),
+        )
+    }
+
+    // Play a track from the start for its length, fading it out at the end
+    pub fn render_track<W: Write + Seek>(
+        &mut self,
+        track: usize,
+        wav: &mut WavWriter<W>,
+    ) -> io::Result<()> {
+        let (length, fade) = self.track_length(track);
+        let rate = RECORDING_RATE as u64;
+        let fade_start = length as u64 * rate / 1000;
+        let total = (length as u64 + fade as u64) * rate / 1000;
+
+        let mut resampler = Resampler::new(self.sample_rate(), RECORDING_RATE as f64);
+        let mut buffer = Vec::new();
+        let mut written = 0;
+        self.start_track(track);
+        while written < total {
+            buffer.clear();
+            resampler.process(&self.play_frame(), &mut buffer);
+            buffer.truncate((total - written) as usize);
+            for (index, sample) in buffer.iter_mut().enumerate() {
+                let position = written + index as u64;
+                if position >= fade_start {
+                    *sample *= 1.0 - (position - fade_start) as f32 / (total - fade_start) as f32;
+                }
+            }
+            wav.write_samples(&buffer)?;
+            written += buffer.len() as u64;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::apu;
+    use crate::nsf::test::test_nsf;
+    use std::io::Cursor;
+
+    // INIT stores the track number in $00, PLAY counts its calls in $01
+    fn counting_player() -> NsfPlayer {
+        let program = [0x85, 0x00, 0x60, 0xE6, 0x01, 0x60];
+        NsfPlayer::new(NsfFile::new(&test_nsf(&program)).unwrap())
+    }
+
+    #[test]
+    fn test_init_and_play_rate() {
+        let mut player = counting_player();
+        assert_eq!(player.track(), 1);
+        assert_eq!(player.cpu.bus.mem_read(0x00), 1);
+
+        let mut samples = 0;
+        for _ in 0..60 {
+            samples += player.play_frame().len();
+        }
+        assert_eq!(player.cpu.bus.mem_read(0x01), 60);
+        // 60 calls 16639 microseconds apart
+        let expected = 60.0 * 16639.0 * apu::SAMPLE_RATE / 1_000_000.0;
+        assert!((samples as f64 - expected).abs() < 2.0);
+
+        player.start_track(2);
+        assert_eq!(player.cpu.bus.mem_read(0x00), 2);
+        assert_eq!(player.cpu.bus.mem_read(0x01), 0);
+    }
+
+    #[test]
+    fn test_pal_tune() {
+        // INIT stores X in $00, PLAY counts its calls in $01
+        let mut raw = test_nsf(&[0x86, 0x00, 0x60, 0xE6, 0x01, 0x60]);
+        raw[0x78..0x7A].copy_from_slice(&20000_u16.to_le_bytes());
+        raw[0x7A] = 0b01;
+        let mut player = NsfPlayer::new(NsfFile::new(&raw).unwrap());
+        assert_eq!(player.region(), Region::Pal);
+        assert_eq!(player.cpu.bus.mem_read(0x00), 1);
+
+        let mut samples = 0;
+        for _ in 0..50 {
+            samples += player.play_frame().len();
+        }
+        // 50 calls 20000 microseconds apart, at the PAL clock rate
+        let expected = 50.0 * 20000.0 * Region::Pal.cpu_clock_rate() / 1_000_000.0;
+        assert!((samples as f64 - expected).abs() < 2.0);
+        assert_eq!(player.sample_rate(), Region::Pal.cpu_clock_rate());
+
+        // The region can be overridden, dual-region tunes start as NTSC
+        let player = NsfPlayer::with_region(NsfFile::new(&raw).unwrap(), Region::Ntsc);
+        assert_eq!(player.region(), Region::Ntsc);
+        raw[0x7A] = 0b10;
+        assert_eq!(NsfFile::new(&raw).unwrap().region(), Region::Ntsc);
+    }
+
+    #[test]
+    fn test_runaway_routine_is_stopped() {
+        // INIT loops forever
+        let nsf = NsfFile::new(&test_nsf(&[0x4C, 0x00, 0x80, 0x60])).unwrap();
+        let mut player = NsfPlayer::new(nsf);
+        assert!(player.cpu.cycles >= MAX_ROUTINE_CYCLES);
+        player.play_frame();
+    }
+
+    #[test]
+    fn test_render_track() {
+        let mut player = counting_player();
+        player.nsf.tracks[0].length = Some(100);
+        player.nsf.tracks[0].fade = Some(50);
+        let mut wav = WavWriter::new(Cursor::new(Vec::new()), RECORDING_RATE).unwrap();
+        player.render_track(0, &mut wav).unwrap();
+        let bytes = wav.finish().unwrap().into_inner();
+        assert_eq!(bytes.len(), 44 + 2 * RECORDING_RATE as usize * 150 / 1000);
+        assert_eq!(player.track_length(1), (DEFAULT_TRACK_LENGTH, DEFAULT_FADE));
+    }
+}
@@ -0,0 +1,181 @@
+use serde::Deserialize;
+use serde::Serialize;
+use std::time::Duration;
+use std::time::Instant;
+
+// Sleeping is only accurate to around a millisecond, the rest of the wait is spun
+const SPIN_TIME: Duration = Duration::from_millis(1);
+
+// Declare SyncMode enum
+// What holds the frontend to the speed of the console
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncMode {
+    #[default]
+    Timer, // wait for the time of each frame
+    Vsync, // present on the display refresh, for displays running at the region's rate
+    Audio, // wait for the audio queue to drain
+}
+
+// Declare Speed enum
+// Emulation speed relative to the console
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Speed {
+    #[default]
+    Normal,
+    SlowMotion,
+    FastForward,
+    Uncapped, // as fast as the host runs
+}
+
+// Implement functionality of Speed
+impl Speed {
+    pub fn name(self) -> &'static str {
+        match self {
+            Speed::Normal => "normal speed",
+            Speed::SlowMotion => "slow motion",
+            Speed::FastForward => "fast-forward",
+            Speed::Uncapped => "uncapped",
+        }
+    }
+
+    // Frames emulated per frame of the console, None when uncapped
+    pub fn multiplier(self) -> Option<f64> {
+        match self {
+            Speed::Normal => Some(1.0),
+            Speed::SlowMotion => Some(0.5),
+            Speed::FastForward => Some(3.0),
+            Speed::Uncapped => None,
+        }
+    }
+}
+
+// Declare FramePacer struct
+// Spaces frames out to the refresh rate of the console, scaled by the speed
+pub struct FramePacer {
+    frame_duration: Duration, // one frame of the console
+    speed: Speed,
+    next_frame: Instant,   // when the next frame is due
+    last_present: Instant, // when a frame was last shown
+}
+
+// Implement functionality of FramePacer
+impl FramePacer {
+    // Create new FramePacer object for frames at frame_rate Hz
+    pub fn new(frame_rate: f64) -> Self {
+        let now = Instant::now();
+        FramePacer {
+            frame_duration: Duration::from_secs_f64(1.0 / frame_rate),
+            speed: Speed::Normal,
+            next_frame: now,
+            last_present: now,
+        }
+    }
+
+    pub fn speed(&self) -> Speed {
+        self.speed
+    }
+
+    // Change the speed, pacing starts over from now
+    pub fn set_speed(&mut self, speed: Speed) {
+        self.speed = speed;
+        self.next_frame = Instant::now();
+    }
+
+    // Switch to a speed, or back to normal when it is already the speed
+    pub fn toggle_speed(&mut self, speed: Speed) {
+        if self.speed == speed {
+            self.set_speed(Speed::Normal);
+        } else {
+            self.set_speed(speed);
+        }
+    }
+
+    // Faster than normal only every frame a display refresh apart is shown, the
+    // others are emulated without being drawn
+    pub fn present_due(&mut self, now: Instant) -> bool {
+        let fast = match self.speed.multiplier() {
+            Some(multiplier) => multiplier > 1.0,
+            None => true,
+        };
+        if fast && now < self.last_present + self.frame_duration {
+            return false;
+        }
+        self.last_present = now;
+        true
+    }
+
+    // Time of the frame just finished, None when it should not be waited for. A
+    // frontend more than a frame behind (e.g. after the window was dragged) starts
+    // over instead of running fast to catch up
+    fn frame_deadline(&mut self, now: Instant) -> Option<Instant> {
+        let multiplier = self.speed.multiplier()?;
+        let duration = self.frame_duration.div_f64(multiplier);
+        if now > self.next_frame + duration {
+            self.next_frame = now;
+        }
+        self.next_frame += duration;
+        Some(self.next_frame)
+    }
+
+    // Wait for the end of the frame just finished
+    pub fn wait(&mut self) {
+        if let Some(deadline) = self.frame_deadline(Instant::now()) {
+            sleep_until(deadline);
+        }
+    }
+}
+
+// Sleep until shortly before the deadline and spin for the rest
+pub fn sleep_until(deadline: Instant) {
+    let now = Instant::now();
+    if deadline > now + SPIN_TIME {
+        std::thread::sleep(deadline - now - SPIN_TIME);
+    }
+    while Instant::now() < deadline {
+        std::hint::spin_loop();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_frame_deadlines() {
+        let mut pacer = FramePacer::new(50.0);
+        let start = pacer.next_frame;
+        let frame = Duration::from_millis(20);
+        assert_eq!(pacer.frame_deadline(start), Some(start + frame));
+        assert_eq!(pacer.frame_deadline(start), Some(start + 2 * frame));
+
+        // Falling behind by more than a frame starts over
+        let late = start + 10 * frame;
+        assert_eq!(pacer.frame_deadline(late), Some(late + frame));
+
+        pacer.speed = Speed::FastForward;
+        let deadline = pacer.frame_deadline(late).unwrap();
+        assert!(((deadline - late).as_secs_f64() - 0.020 * 4.0 / 3.0).abs() < 1e-6);
+        pacer.speed = Speed::SlowMotion;
+        let next = pacer.frame_deadline(late).unwrap();
+        assert_eq!(next - deadline, 2 * frame);
+        pacer.speed = Speed::Uncapped;
+        assert_eq!(pacer.frame_deadline(late), None);
+    }
+
+    #[test]
+    fn test_present_due() {
+        let mut pacer = FramePacer::new(50.0);
+        let start = pacer.last_present;
+        let half_frame = Duration::from_millis(10);
+        assert!(pacer.present_due(start + half_frame));
+        assert!(pacer.present_due(start + 2 * half_frame));
+
+        pacer.toggle_speed(Speed::Uncapped);
+        assert!(!pacer.present_due(start + 3 * half_frame));
+        assert!(pacer.present_due(start + 4 * half_frame));
+        pacer.toggle_speed(Speed::Uncapped);
+        assert_eq!(pacer.speed(), Speed::Normal);
+        assert!(pacer.present_due(start + 5 * half_frame));
+    }
+}
@@ -0,0 +1,819 @@
+use crate::cartridge::Mirroring;
+use crate::frame::Frame;
+use crate::mapper::Mapper;
+use crate::mapper::PpuFetch;
+use crate::region::Region;
+use palette::SYSTEM_PALETTE;
+use registers::ControlRegister;
+use registers::MaskRegister;
+use registers::StatusRegister;
+use serde::Deserialize;
+use serde::Serialize;
+
+pub mod palette;
+pub mod registers;
+
+// Frame timing https://www.nesdev.org/wiki/PPU_rendering, the vblank and pre-render
+// lines depend on the region
+const DOTS_PER_SCANLINE: u16 = 341;
+const VISIBLE_SCANLINES: u16 = 240;
+
+const OAM_SIZE: usize = 256;
+const MAX_SPRITES_PER_LINE: usize = 8;
+
+// Sprite attribute bits (byte 2 of an OAM entry)
+const SPRITE_PALETTE: u8 = 0b0000_0011;
+const SPRITE_BEHIND_BACKGROUND: u8 = 0b0010_0000;
+const SPRITE_FLIP_HORIZONTAL: u8 = 0b0100_0000;
+const SPRITE_FLIP_VERTICAL: u8 = 0b1000_0000;
+
+// A sprite selected for the next scanline, with its pattern row
+#[derive(Clone, Copy, Serialize, Deserialize)]
+struct Sprite {
+    y: u8,
+    tile: u8,
+    attributes: u8,
+    x: u8,
+    pattern_lo: u8,
+    pattern_hi: u8,
+    sprite_zero: bool,
+}
+
+// Empty slots still fetch tile $FF, which mappers watching the address bus rely on
+const EMPTY_SPRITE: Sprite = Sprite {
+    y: 0xFF,
+    tile: 0xFF,
+    attributes: 0,
+    x: 0xFF,
+    pattern_lo: 0,
+    pattern_hi: 0,
+    sprite_zero: false,
+};
+
+// Declare PPU struct
+// The 2C02 picture processing unit, stepped one dot at a time. Scrolling follows the
+// "loopy" model, v and t are the current and temporary VRAM addresses
+// https://www.nesdev.org/wiki/PPU_scrolling
+#[derive(Serialize, Deserialize)]
+pub struct PPU {
+    pub ctrl: ControlRegister,
+    pub mask: MaskRegister,
+    pub status: StatusRegister,
+    pub oam_addr: u8,
+    #[serde(with = "crate::state::byte_array")]
+    pub oam_data: [u8; OAM_SIZE],
+    pub palette_table: [u8; 32],
+    #[serde(with = "crate::state::byte_array")]
+    vram: [u8; 4096], // 2KB CIRAM, plus the cartridge VRAM of four-screen boards
+    v: u16,
+    t: u16,
+    fine_x: u8,
+    write_toggle: bool,
+    read_buffer: u8, // PPUDATA reads return the previous value
+    io_latch: u8,    // last value written to a register, returned by write-only registers
+
+    pub scanline: u16,
+    pub dot: u16,
+    odd_frame: bool,
+    vblank_scanline: u16,
+    pre_render_scanline: u16, // last line of the frame
+    skip_odd_frame_dot: bool,
+    nmi_interrupt: bool,
+    frame_complete: bool,
+
+    // Background pipeline
+    next_tile_id: u8,
+    next_tile_attribute: u8,
+    next_tile_lo: u8,
+    next_tile_hi: u8,
+    pattern_shifter_lo: u16,
+    pattern_shifter_hi: u16,
+    attribute_shifter_lo: u16,
+    attribute_shifter_hi: u16,
+
+    // Sprites of the current scanline
+    sprites: [Sprite; MAX_SPRITES_PER_LINE],
+    sprite_count: usize,
+
+    pub frame: Frame,
+}
+
+// Implement functionality of PPU
+impl PPU {
+    // Create new PPU object with NTSC timing
+    pub fn new() -> Self {
+        PPU::with_region(Region::Ntsc)
+    }
+
+    // Create new PPU object with the frame timing of a region
+    pub fn with_region(region: Region) -> Self {
+        PPU {
+            ctrl: ControlRegister::from_bits_truncate(0),
+            mask: MaskRegister::from_bits_truncate(0),
+            status: StatusRegister::from_bits_truncate(0),
+            oam_addr: 0,
+            oam_data: [0; OAM_SIZE],
+            palette_table: [0; 32],
+            vram: [0; 4096],
+            v: 0,
+            t: 0,
+            fine_x: 0,
+            write_toggle: false,
+            read_buffer: 0,
+            io_latch: 0,
+            scanline: 0,
+            dot: 0,
+            odd_frame: false,
+            vblank_scanline: region.vblank_scanline(),
+            pre_render_scanline: region.scanlines() - 1,
+            skip_odd_frame_dot: region.skips_odd_frame_dot(),
+            nmi_interrupt: false,
+            frame_complete: false,
+            next_tile_id: 0,
+            next_tile_attribute: 0,
+            next_tile_lo: 0,
+            next_tile_hi: 0,
+            pattern_shifter_lo: 0,
+            pattern_shifter_hi: 0,
+            attribute_shifter_lo: 0,
+            attribute_shifter_hi: 0,
+            sprites: [EMPTY_SPRITE; MAX_SPRITES_PER_LINE],
+            sprite_count: 0,
+            frame: Frame::new(Frame::NES_WIDTH, Frame::NES_HEIGHT),
+        }
+    }
+
+    // NMI raised at the start of vblank, cleared once the CPU has seen it
+    pub fn poll_nmi(&mut self) -> bool {
+        std::mem::take(&mut self.nmi_interrupt)
+    }
+
+    // True once per frame, when the picture is complete and vblank starts
+    pub fn take_frame_complete(&mut self) -> bool {
+        std::mem::take(&mut self.frame_complete)
+    }
+
+    // REGISTERS ($2000-$2007, mirrored up to $3FFF)
+
+    pub fn read_register(&mut self, addr: u16, mapper: &mut dyn Mapper) -> u8 {
+        let data = match addr & 0b111 {
+            2 => {
+                let data = (self.status.bits() & 0xE0) | (self.io_latch & 0x1F);
+                self.status.remove(StatusRegister::VBLANK_STARTED);
+                self.write_toggle = false;
+                data
+            }
+            4 => self.oam_data[self.oam_addr as usize],
+            7 => self.read_data(mapper),
+            _ => self.io_latch,
+        };
+        self.io_latch = data;
+        data
+    }
+
+    pub fn write_register(&mut self, addr: u16, data: u8, mapper: &mut dyn Mapper) {
+        self.io_latch = data;
+        match addr & 0b111 {
+            0 => self.write_to_ctrl(data),
+            1 => self.mask = MaskRegister::from_bits_truncate(data),
+            3 => self.oam_addr = data,
+            4 => self.write_to_oam_data(data),
+            5 => self.write_to_scroll(data),
+            6 => {
+                self.write_to_ppu_addr(data);
+                // The second write puts the new address on the bus
+                if !self.write_toggle {
+                    mapper.ppu_address(self.v & 0x3FFF);
+                }
+            }
+            7 => {
+                self.write_vram(self.v, data, mapper);
+                self.increment_vram_addr();
+            }
+            _ => { /* PPUSTATUS is read-only */ }
+        }
+    }
+
+    // OAM DMA ($4014) writes through OAMDATA
+    pub fn write_to_oam_data(&mut self, data: u8) {
+        self.oam_data[self.oam_addr as usize] = data;
+        self.oam_addr = self.oam_addr.wrapping_add(1);
+    }
+
+    fn write_to_ctrl(&mut self, data: u8) {
+        let nmi_was_enabled = self.ctrl.generate_vblank_nmi();
+        self.ctrl = ControlRegister::from_bits_truncate(data);
+        self.t = (self.t & 0xF3FF) | (((data & 0b11) as u16) << 10);
+
+        // Enabling NMI during vblank raises it straight away
+        if !nmi_was_enabled
+            && self.ctrl.generate_vblank_nmi()
+            && self.status.contains(StatusRegister::VBLANK_STARTED)
+        {
+            self.nmi_interrupt = true;
+        }
+    }
+
+    fn write_to_scroll(&mut self, data: u8) {
+        if !self.write_toggle {
+            self.t = (self.t & 0xFFE0) | (data >> 3) as u16;
+            self.fine_x = data & 0b111;
+        } else {
+            self.t =
+                (self.t & 0x8C1F) | (((data & 0b111) as u16) << 12) | (((data & 0xF8) as u16) << 2);
+        }
+        self.write_toggle = !self.write_toggle;
+    }
+
+    fn write_to_ppu_addr(&mut self, data: u8) {
+        if !self.write_toggle {
+            self.t = (self.t & 0x00FF) | (((data & 0x3F) as u16) << 8);
+        } else {
+            self.t = (self.t & 0xFF00) | data as u16;
+            self.v = self.t;
+        }
+        self.write_toggle = !self.write_toggle;
+    }
+
+    fn read_data(&mut self, mapper: &mut dyn Mapper) -> u8 {
+        let addr = self.v & 0x3FFF;
+        let data = if addr >= 0x3F00 {
+            // Palette reads are not buffered, the buffer gets the nametable byte underneath
+            self.read_buffer = self.read_vram(addr - 0x1000, mapper);
+            (self.read_vram(addr, mapper) & 0x3F) | (self.io_latch & 0xC0)
+        } else {
+            let result = self.read_buffer;
+            self.read_buffer = self.read_vram(addr, mapper);
+            result
+        };
+        self.increment_vram_addr();
+        data
+    }
+
+    fn increment_vram_addr(&mut self) {
+        self.v = self.v.wrapping_add(self.ctrl.vram_addr_increment()) & 0x7FFF;
+    }
+
+    // PPU MEMORY MAP
+
+    fn read_vram(&self, addr: u16, mapper: &mut dyn Mapper) -> u8 {
+        let addr = addr & 0x3FFF;
+        mapper.ppu_address(addr);
+        match addr {
+            0x0000..=0x1FFF => mapper.ppu_read(addr),
+            0x2000..=0x3EFF => match mapper.nametable_read(addr) {
+                Some(data) => data,
+                None => self.vram[mirror_vram_addr(addr, mapper.mirroring())],
+            },
+            _ => self.palette_table[palette_index(addr)],
+        }
+    }
+
+    // Rendering fetches tell the mapper what they are for before the read
+    fn fetch_vram(&self, fetch: PpuFetch, addr: u16, mapper: &mut dyn Mapper) -> u8 {
+        mapper.ppu_fetch(fetch, addr & 0x3FFF);
+        self.read_vram(addr, mapper)
+    }
+
+    fn write_vram(&mut self, addr: u16, data: u8, mapper: &mut dyn Mapper) {
+        let addr = addr & 0x3FFF;
+        mapper.ppu_address(addr);
+        match addr {
+            0x0000..=0x1FFF => mapper.ppu_write(addr, data),
+            0x2000..=0x3EFF => {
+                if !mapper.nametable_write(addr, data) {
+                    self.vram[mirror_vram_addr(addr, mapper.mirroring())] = data;
+                }
+            }
+            _ => self.palette_table[palette_index(addr)] = data & 0x3F,
+        }
+    }
+
+    // RENDERING
+
+    // Advance by one dot
+    pub fn tick(&mut self, mapper: &mut dyn Mapper) {
+        let rendering = self.mask.rendering_enabled();
+        let visible_line = self.scanline < VISIBLE_SCANLINES;
+        let pre_render = self.scanline == self.pre_render_scanline;
+
+        if pre_render && self.dot == 1 {
+            self.status.remove(
+                StatusRegister::VBLANK_STARTED
+                    | StatusRegister::SPRITE_ZERO_HIT
+                    | StatusRegister::SPRITE_OVERFLOW,
+            );
+        }
+
+        if (visible_line || pre_render) && rendering {
+            self.fetch(mapper, pre_render);
+        }
+
+        if visible_line && (1..=256).contains(&self.dot) {
+            self.render_pixel();
+        }
+
+        if self.scanline == self.vblank_scanline && self.dot == 1 {
+            self.status.insert(StatusRegister::VBLANK_STARTED);
+            if self.ctrl.generate_vblank_nmi() {
+                self.nmi_interrupt = true;
+            }
+            self.frame_complete = true;
+        }
+
+        if self.dot == 260 {
+            mapper.ppu_scanline(self.scanline, rendering);
+        }
+
+        self.dot += 1;
+        // The last dot of the pre-render line is skipped on odd frames
+        if pre_render
+            && rendering
+            && self.odd_frame
+            && self.skip_odd_frame_dot
+            && self.dot == DOTS_PER_SCANLINE - 1
+        {
+            self.dot = DOTS_PER_SCANLINE;
+        }
+        if self.dot >= DOTS_PER_SCANLINE {
+            self.dot = 0;
+            self.scanline += 1;
+            if self.scanline > self.pre_render_scanline {
+                self.scanline = 0;
+                self.odd_frame = !self.odd_frame;
+            }
+        }
+    }
+
+    // Memory fetches and scroll updates of the visible and pre-render lines, at the
+    // same dots as the hardware so mappers can follow them
+    fn fetch(&mut self, mapper: &mut dyn Mapper, pre_render: bool) {
+        let dot = self.dot;
+
+        if (2..=257).contains(&dot) || (321..=337).contains(&dot) {
+            self.update_shifters();
+            if dot % 8 == 1 && dot != 321 {
+                self.load_background_shifters();
+            }
+        }
+
+        // Background tiles: nametable, attribute, then the two pattern planes
+        if (1..=256).contains(&dot) || (321..=336).contains(&dot) {
+            match dot % 8 {
+                1 => {
+                    let addr = 0x2000 | (self.v & 0x0FFF);
+                    self.next_tile_id = self.fetch_vram(PpuFetch::Nametable, addr, mapper);
+                }
+                3 => {
+                    let addr = 0x23C0
+                        | (self.v & 0x0C00)
+                        | ((self.v >> 4) & 0x38)
+                        | ((self.v >> 2) & 0x07);
+                    let mut attribute = self.fetch_vram(PpuFetch::Attribute, addr, mapper);
+                    if self.v & 0x0040 != 0 {
+                        attribute >>= 4;
+                    }
+                    if self.v & 0x0002 != 0 {
+                        attribute >>= 2;
+                    }
+                    self.next_tile_attribute = attribute & 0b11;
+                }
+                5 => {
+                    let addr = self.background_pattern_addr();
+                    self.next_tile_lo = self.fetch_vram(PpuFetch::BackgroundPattern, addr, mapper);
+                }
+                7 => {
+                    let addr = self.background_pattern_addr() + 8;
+                    self.next_tile_hi = self.fetch_vram(PpuFetch::BackgroundPattern, addr, mapper);
+                }
+                0 => self.increment_x(),
+                _ => {}
+            }
+        }
+
+        match dot {
+            256 => self.increment_y(),
+            257 => {
+                self.v = (self.v & !0x041F) | (self.t & 0x041F);
+                if pre_render {
+                    self.sprite_count = 0;
+                    self.sprites = [EMPTY_SPRITE; MAX_SPRITES_PER_LINE];
+                } else {
+                    self.evaluate_sprites();
+                }
+            }
+            // Unused nametable fetches at the end of the line
+            337 | 339 => {
+                self.fetch_vram(PpuFetch::Unused, 0x2000 | (self.v & 0x0FFF), mapper);
+            }
+            _ => {}
+        }
+
+        if (257..=320).contains(&dot) {
+            self.oam_addr = 0;
+            let slot = ((dot - 257) / 8) as usize;
+            match (dot - 257) % 8 {
+                // Unused nametable fetches
+                0 | 2 => {
+                    self.fetch_vram(PpuFetch::Unused, 0x2000 | (self.v & 0x0FFF), mapper);
+                }
+                4 => {
+                    let addr = self.sprite_pattern_addr(&self.sprites[slot]);
+                    self.sprites[slot].pattern_lo =
+                        self.fetch_vram(PpuFetch::SpritePattern, addr, mapper);
+                }
+                6 => {
+                    let addr = self.sprite_pattern_addr(&self.sprites[slot]) + 8;
+                    self.sprites[slot].pattern_hi =
+                        self.fetch_vram(PpuFetch::SpritePattern, addr, mapper);
+                }
+                _ => {}
+            }
+        }
+
+        if pre_render && (280..=304).contains(&dot) {
+            self.v = (self.v & !0x7BE0) | (self.t & 0x7BE0);
+        }
+    }
+
+    fn background_pattern_addr(&self) -> u16 {
+        self.ctrl.background_pattern_addr()
+            + self.next_tile_id as u16 * 16
+            + ((self.v >> 12) & 0b111)
+    }
+
+    fn sprite_pattern_addr(&self, sprite: &Sprite) -> u16 {
+        let height = self.ctrl.sprite_size() as i32;
+        let mut row = (self.scanline as i32 - sprite.y as i32).clamp(0, height - 1) as u16;
+        if sprite.attributes & SPRITE_FLIP_VERTICAL != 0 {
+            row = height as u16 - 1 - row;
+        }
+        if height == 8 {
+            self.ctrl.sprite_pattern_addr() + sprite.tile as u16 * 16 + row
+        } else {
+            // 8x16 sprites take the pattern table from bit 0 of the tile number
+            let table = (sprite.tile as u16 & 1) * 0x1000;
+            let mut tile = sprite.tile as u16 & 0xFE;
+            if row >= 8 {
+                tile += 1;
+                row -= 8;
+            }
+            table + tile * 16 + row
+        }
+    }
+
+    // Pick the first 8 sprites on this line, to be drawn on the next one
+    fn evaluate_sprites(&mut self) {
+        let height = self.ctrl.sprite_size() as i32;
+        self.sprite_count = 0;
+        self.sprites = [EMPTY_SPRITE; MAX_SPRITES_PER_LINE];
+        for index in 0..OAM_SIZE / 4 {
+            let entry = &self.oam_data[index * 4..index * 4 + 4];
+            let row = self.scanline as i32 - entry[0] as i32;
+            if !(0..height).contains(&row) {
+                continue;
+            }
+            if self.sprite_count == MAX_SPRITES_PER_LINE {
+                self.status.insert(StatusRegister::SPRITE_OVERFLOW);
+                break;
+            }
+            self.sprites[self.sprite_count] = Sprite {
+                y: entry[0],
+                tile: entry[1],
+                attributes: entry[2],
+                x: entry[3],
+                pattern_lo: 0,
+                pattern_hi: 0,
+                sprite_zero: index == 0,
+            };
+            self.sprite_count += 1;
+        }
+    }
+
+    fn update_shifters(&mut self) {
+        self.pattern_shifter_lo <<= 1;
+        self.pattern_shifter_hi <<= 1;
+        self.attribute_shifter_lo <<= 1;
+        self.attribute_shifter_hi <<= 1;
+    }
+
+    fn load_background_shifters(&mut self) {
+        self.pattern_shifter_lo = (self.pattern_shifter_lo & 0xFF00) | self.next_tile_lo as u16;
+        self.pattern_shifter_hi = (self.pattern_shifter_hi & 0xFF00) | self.next_tile_hi as u16;
+        let attribute_lo = if self.next_tile_attribute & 0b01 != 0 {
+            0xFF
+        } else {
+            0
+        };
+        let attribute_hi = if self.next_tile_attribute & 0b10 != 0 {
+            0xFF
+        } else {
+            0
+        };
+        self.attribute_shifter_lo = (self.attribute_shifter_lo & 0xFF00) | attribute_lo;
+        self.attribute_shifter_hi = (self.attribute_shifter_hi & 0xFF00) | attribute_hi;
+    }
+
+    // Move to the next tile, switching horizontal nametable at the edge
+    fn increment_x(&mut self) {
+        if self.v & 0x001F == 31 {
+            self.v &= !0x001F;
+            self.v ^= 0x0400;
+        } else {
+            self.v += 1;
+        }
+    }
+
+    // Move to the next pixel row, switching vertical nametable after row 29
+    fn increment_y(&mut self) {
+        if self.v & 0x7000 != 0x7000 {
+            self.v += 0x1000;
+            return;
+        }
+        self.v &= !0x7000;
+        let mut coarse_y = (self.v & 0x03E0) >> 5;
+        if coarse_y == 29 {
+            coarse_y = 0;
+            self.v ^= 0x0800;
+        } else if coarse_y == 31 {
+            coarse_y = 0;
+        } else {
+            coarse_y += 1;
+        }
+        self.v = (self.v & !0x03E0) | (coarse_y << 5);
+    }
+
+    // Combine the background and sprite pixels at the current dot
+    fn render_pixel(&mut self) {
+        let x = (self.dot - 1) as usize;
+
+        let mut background = (0, 0);
+        if self.mask.contains(MaskRegister::SHOW_BACKGROUND)
+            && (x >= 8 || self.mask.contains(MaskRegister::LEFTMOST_8PXL_BACKGROUND))
+        {
+            let mux = 0x8000 >> self.fine_x;
+            let pixel = ((self.pattern_shifter_hi & mux != 0) as u8) << 1
+                | (self.pattern_shifter_lo & mux != 0) as u8;
+            let palette = ((self.attribute_shifter_hi & mux != 0) as u8) << 1
+                | (self.attribute_shifter_lo & mux != 0) as u8;
+            background = (pixel, palette);
+        }
+
+        let mut foreground = (0, 0);
+        let mut behind_background = false;
+        let mut sprite_zero = false;
+        if self.mask.contains(MaskRegister::SHOW_SPRITES)
+            && (x >= 8 || self.mask.contains(MaskRegister::LEFTMOST_8PXL_SPRITE))
+        {
+            for sprite in self.sprites[..self.sprite_count].iter() {
+                let offset = x as i32 - sprite.x as i32;
+                if !(0..8).contains(&offset) {
+                    continue;
+                }
+                let bit = if sprite.attributes & SPRITE_FLIP_HORIZONTAL != 0 {
+                    offset
+                } else {
+                    7 - offset
+                };
+                let pixel =
+                    ((sprite.pattern_hi >> bit) & 1) << 1 | ((sprite.pattern_lo >> bit) & 1);
+                if pixel != 0 {
+                    foreground = (pixel, (sprite.attributes & SPRITE_PALETTE) + 4);
+                    behind_background = sprite.attributes & SPRITE_BEHIND_BACKGROUND != 0;
+                    sprite_zero = sprite.sprite_zero;
+                    break;
+                }
+            }
+        }
+
+        let (pixel, palette) = match (background.0, foreground.0) {
+            (0, 0) => (0, 0),
+            (0, _) => foreground,
+            (_, 0) => background,
+            _ => {
+                if sprite_zero && x != 255 {
+                    self.status.insert(StatusRegister::SPRITE_ZERO_HIT);
+                }
+                if behind_background {
+                    background
+                } else {
+                    foreground
+                }
+            }
+        };
+
+        // With rendering off the backdrop comes from the palette entry v points at
+        let palette_addr = if !self.mask.rendering_enabled() && self.v & 0x3F00 == 0x3F00 {
+            self.v
+        } else {
+            0x3F00 + (palette as u16) * 4 + pixel as u16
+        };
+        let mut color = self.palette_table[palette_index(palette_addr)];
+        if self.mask.contains(MaskRegister::GREYSCALE) {
+            color &= 0x30;
+        }
+        self.frame.set_pixel(
+            x,
+            self.scanline as usize,
+            SYSTEM_PALETTE[(color & 0x3F) as usize],
+        );
+    }
+}
+
+impl Default for PPU {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Map a nametable address to the VRAM, following the cartridge mirroring
+//   Horizontal:         Vertical:
+//     [ A ] [ a ]         [ A ] [ B ]
+//     [ B ] [ b ]         [ a ] [ b ]
+fn mirror_vram_addr(addr: u16, mirroring: Mirroring) -> usize {
+    let vram_index = (addr - 0x2000) as usize & 0x0FFF;
+    let nametable = vram_index / 0x400;
+    let physical = match mirroring {
+        Mirroring::Vertical => nametable % 2,
+        Mirroring::Horizontal => nametable / 2,
+        Mirroring::SingleScreenLower => 0,
+        Mirroring::SingleScreenUpper => 1,
+        Mirroring::FourScreen => nametable,
+        Mirroring::Mapped(pages) => pages[nametable] as usize & 0b11,
+    };
+    physical * 0x400 + vram_index % 0x400
+}
+
+// $3F10/$3F14/$3F18/$3F1C mirror the backdrop entries $3F00/$3F04/$3F08/$3F0C
+fn palette_index(addr: u16) -> usize {
+    let index = (addr & 0x1F) as usize;
+    if index >= 0x10 && index.is_multiple_of(4) {
+        index - 0x10
+    } else {
+        index
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::test_rom;
+    use crate::mapper::NROM;
+
+    fn chr_ram_mapper(mirroring: Mirroring) -> NROM {
+        let mut rom = test_rom(vec![]);
+        rom.chr_rom.clear();
+        rom.screen_mirroring = mirroring;
+        NROM::new(rom)
+    }
+
+    fn set_addr(ppu: &mut PPU, addr: u16, mapper: &mut dyn Mapper) {
+        ppu.write_register(0x2006, (addr >> 8) as u8, mapper);
+        ppu.write_register(0x2006, (addr & 0xFF) as u8, mapper);
+    }
+
+    #[test]
+    fn test_vram_reads_are_buffered() {
+        let mut mapper = chr_ram_mapper(Mirroring::Horizontal);
+        let mut ppu = PPU::new();
+        set_addr(&mut ppu, 0x2305, &mut mapper);
+        ppu.write_register(0x2007, 0x66, &mut mapper);
+        ppu.write_register(0x2007, 0x77, &mut mapper);
+
+        set_addr(&mut ppu, 0x2305, &mut mapper);
+        ppu.read_register(0x2007, &mut mapper);
+        assert_eq!(ppu.read_register(0x2007, &mut mapper), 0x66);
+        assert_eq!(ppu.read_register(0x2007, &mut mapper), 0x77);
+    }
+
+    #[test]
+    fn test_vram_increment_by_32() {
+        let mut mapper = chr_ram_mapper(Mirroring::Horizontal);
+        let mut ppu = PPU::new();
+        ppu.write_register(0x2000, 0b100, &mut mapper);
+        set_addr(&mut ppu, 0x21FF, &mut mapper);
+        ppu.write_register(0x2007, 0x66, &mut mapper);
+        ppu.write_register(0x2007, 0x77, &mut mapper);
+        assert_eq!(ppu.vram[0x01FF], 0x66);
+        assert_eq!(ppu.vram[0x021F], 0x77);
+    }
+
+    #[test]
+    fn test_nametable_mirroring() {
+        let mut mapper = chr_ram_mapper(Mirroring::Horizontal);
+        let mut ppu = PPU::new();
+        set_addr(&mut ppu, 0x2405, &mut mapper);
+        ppu.write_register(0x2007, 0x66, &mut mapper);
+        set_addr(&mut ppu, 0x2C05, &mut mapper);
+        ppu.write_register(0x2007, 0x77, &mut mapper);
+
+        set_addr(&mut ppu, 0x2005, &mut mapper);
+        ppu.read_register(0x2007, &mut mapper);
+        assert_eq!(ppu.read_register(0x2007, &mut mapper), 0x66);
+        set_addr(&mut ppu, 0x2805, &mut mapper);
+        ppu.read_register(0x2007, &mut mapper);
+        assert_eq!(ppu.read_register(0x2007, &mut mapper), 0x77);
+
+        assert_eq!(mirror_vram_addr(0x2805, Mirroring::Vertical), 0x005);
+        assert_eq!(mirror_vram_addr(0x2C05, Mirroring::FourScreen), 0xC05);
+    }
+
+    #[test]
+    fn test_palette_mirrors_and_unbuffered_read() {
+        let mut mapper = chr_ram_mapper(Mirroring::Horizontal);
+        let mut ppu = PPU::new();
+        set_addr(&mut ppu, 0x3F10, &mut mapper);
+        ppu.write_register(0x2007, 0x21, &mut mapper);
+        set_addr(&mut ppu, 0x3F00, &mut mapper);
+        assert_eq!(ppu.read_register(0x2007, &mut mapper), 0x21);
+    }
+
+    #[test]
+    fn test_status_read_clears_vblank_and_write_toggle() {
+        let mut mapper = chr_ram_mapper(Mirroring::Horizontal);
+        let mut ppu = PPU::new();
+        ppu.status.insert(StatusRegister::VBLANK_STARTED);
+        ppu.write_register(0x2006, 0x21, &mut mapper);
+
+        assert_eq!(ppu.read_register(0x2002, &mut mapper) & 0x80, 0x80);
+        assert_eq!(ppu.read_register(0x2002, &mut mapper) & 0x80, 0);
+
+        // The next write is the high byte again
+        set_addr(&mut ppu, 0x2305, &mut mapper);
+        assert_eq!(ppu.v, 0x2305);
+    }
+
+    #[test]
+    fn test_vblank_nmi() {
+        let mut mapper = chr_ram_mapper(Mirroring::Horizontal);
+        let mut ppu = PPU::new();
+        ppu.write_register(0x2000, 0x80, &mut mapper);
+        for _ in 0..(241 * DOTS_PER_SCANLINE as usize + 1) {
+            ppu.tick(&mut mapper);
+        }
+        assert!(!ppu.poll_nmi());
+        ppu.tick(&mut mapper);
+        assert!(ppu.status.contains(StatusRegister::VBLANK_STARTED));
+        assert!(ppu.poll_nmi());
+        assert!(!ppu.poll_nmi());
+        assert!(ppu.take_frame_complete());
+    }
+
+    // Dots from power on until vblank starts, and in a whole frame
+    fn frame_timing(region: Region) -> (usize, usize) {
+        let mut mapper = chr_ram_mapper(Mirroring::Horizontal);
+        let mut ppu = PPU::with_region(region);
+        let mut dots = 0;
+        while !ppu.take_frame_complete() {
+            ppu.tick(&mut mapper);
+            dots += 1;
+        }
+        let vblank = dots;
+        while !ppu.take_frame_complete() {
+            ppu.tick(&mut mapper);
+            dots += 1;
+        }
+        (vblank, dots - vblank)
+    }
+
+    #[test]
+    fn test_region_timing() {
+        assert_eq!(frame_timing(Region::Ntsc), (241 * 341 + 2, 262 * 341));
+        assert_eq!(frame_timing(Region::Pal), (241 * 341 + 2, 312 * 341));
+        assert_eq!(frame_timing(Region::Dendy), (291 * 341 + 2, 312 * 341));
+    }
+
+    #[test]
+    fn test_background_rendering() {
+        let mut mapper = chr_ram_mapper(Mirroring::Horizontal);
+        let mut ppu = PPU::new();
+
+        // Tile 1: leftmost pixel of every row uses colour 3, the rest colour 1
+        set_addr(&mut ppu, 0x0010, &mut mapper);
+        for _ in 0..8 {
+            ppu.write_register(0x2007, 0xFF, &mut mapper);
+        }
+        for _ in 0..8 {
+            ppu.write_register(0x2007, 0x80, &mut mapper);
+        }
+        set_addr(&mut ppu, 0x2000, &mut mapper);
+        ppu.write_register(0x2007, 0x01, &mut mapper);
+        set_addr(&mut ppu, 0x3F00, &mut mapper);
+        for color in [0x0F, 0x21, 0x00, 0x16] {
+            ppu.write_register(0x2007, color, &mut mapper);
+        }
+        ppu.write_register(0x2000, 0, &mut mapper);
+        ppu.write_register(0x2005, 0, &mut mapper);
+        ppu.write_register(0x2005, 0, &mut mapper);
+        ppu.write_register(0x2001, 0b0000_1010, &mut mapper);
+
+        // Rendering starts from the pre-render line
+        ppu.scanline = 261;
+        for _ in 0..2 * DOTS_PER_SCANLINE as usize {
+            ppu.tick(&mut mapper);
+        }
+        assert_eq!(ppu.frame.pixel(0, 0), SYSTEM_PALETTE[0x16]);
+        assert_eq!(ppu.frame.pixel(1, 0), SYSTEM_PALETTE[0x21]);
+        assert_eq!(ppu.frame.pixel(8, 0), SYSTEM_PALETTE[0x0F]);
+    }
+}
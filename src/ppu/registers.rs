@@ -0,0 +1,123 @@
+use serde::Deserialize;
+use serde::Serialize;
+
+// PPUCTRL ($2000)
+bitflags! {
+    /// # Controller Register https://www.nesdev.org/wiki/PPU_registers#PPUCTRL
+    ///
+    ///  7 6 5 4 3 2 1 0
+    ///  V P H B S I N N
+    ///  | | | | | | +-+--- Base nametable address (0 = $2000; 1 = $2400; 2 = $2800; 3 = $2C00)
+    ///  | | | | | +------- VRAM address increment per CPU read/write of PPUDATA (0: add 1; 1: add 32)
+    ///  | | | | +--------- Sprite pattern table address for 8x8 sprites (0: $0000; 1: $1000)
+    ///  | | | +----------- Background pattern table address (0: $0000; 1: $1000)
+    ///  | | +------------- Sprite size (0: 8x8 pixels; 1: 8x16 pixels)
+    ///  | +--------------- PPU master/slave select
+    ///  +----------------- Generate an NMI at the start of vertical blanking
+    ///
+    #[derive(Serialize, Deserialize)]
+    pub struct ControlRegister: u8 {
+        const NAMETABLE1             = 0b00000001;
+        const NAMETABLE2             = 0b00000010;
+        const VRAM_ADD_INCREMENT     = 0b00000100;
+        const SPRITE_PATTERN_ADDR    = 0b00001000;
+        const BACKGROUND_PATTERN_ADDR = 0b00010000;
+        const SPRITE_SIZE            = 0b00100000;
+        const MASTER_SLAVE_SELECT    = 0b01000000;
+        const GENERATE_NMI           = 0b10000000;
+    }
+}
+
+// Implement functionality of ControlRegister
+impl ControlRegister {
+    pub fn vram_addr_increment(&self) -> u16 {
+        if self.contains(ControlRegister::VRAM_ADD_INCREMENT) {
+            32
+        } else {
+            1
+        }
+    }
+
+    pub fn sprite_pattern_addr(&self) -> u16 {
+        if self.contains(ControlRegister::SPRITE_PATTERN_ADDR) {
+            0x1000
+        } else {
+            0
+        }
+    }
+
+    pub fn background_pattern_addr(&self) -> u16 {
+        if self.contains(ControlRegister::BACKGROUND_PATTERN_ADDR) {
+            0x1000
+        } else {
+            0
+        }
+    }
+
+    pub fn sprite_size(&self) -> u8 {
+        if self.contains(ControlRegister::SPRITE_SIZE) {
+            16
+        } else {
+            8
+        }
+    }
+
+    pub fn generate_vblank_nmi(&self) -> bool {
+        self.contains(ControlRegister::GENERATE_NMI)
+    }
+}
+
+// PPUMASK ($2001)
+bitflags! {
+    /// # Mask Register https://www.nesdev.org/wiki/PPU_registers#PPUMASK
+    ///
+    ///  7 6 5 4 3 2 1 0
+    ///  B G R s b M m G
+    ///  | | | | | | | +--- Greyscale
+    ///  | | | | | | +----- Show background in leftmost 8 pixels of screen
+    ///  | | | | | +------- Show sprites in leftmost 8 pixels of screen
+    ///  | | | | +--------- Show background
+    ///  | | | +----------- Show sprites
+    ///  | | +------------- Emphasize red
+    ///  | +--------------- Emphasize green
+    ///  +----------------- Emphasize blue
+    ///
+    #[derive(Serialize, Deserialize)]
+    pub struct MaskRegister: u8 {
+        const GREYSCALE               = 0b00000001;
+        const LEFTMOST_8PXL_BACKGROUND = 0b00000010;
+        const LEFTMOST_8PXL_SPRITE    = 0b00000100;
+        const SHOW_BACKGROUND         = 0b00001000;
+        const SHOW_SPRITES            = 0b00010000;
+        const EMPHASISE_RED           = 0b00100000;
+        const EMPHASISE_GREEN         = 0b01000000;
+        const EMPHASISE_BLUE          = 0b10000000;
+    }
+}
+
+// Implement functionality of MaskRegister
+impl MaskRegister {
+    // The PPU only fetches and updates its address while something is shown
+    pub fn rendering_enabled(&self) -> bool {
+        self.intersects(MaskRegister::SHOW_BACKGROUND | MaskRegister::SHOW_SPRITES)
+    }
+}
+
+// PPUSTATUS ($2002)
+bitflags! {
+    /// # Status Register https://www.nesdev.org/wiki/PPU_registers#PPUSTATUS
+    ///
+    ///  7 6 5 4 3 2 1 0
+    ///  V S O . . . . .
+    ///  | | | +-+-+-+-+--- Open bus
+    ///  | | +------------- Sprite overflow
+    ///  | +--------------- Sprite 0 hit
+    ///  +----------------- Vertical blank has started
+    ///
+    #[derive(Serialize, Deserialize)]
+    pub struct StatusRegister: u8 {
+        const SPRITE_OVERFLOW = 0b00100000;
+        const SPRITE_ZERO_HIT = 0b01000000;
+        const VBLANK_STARTED  = 0b10000000;
+    }
+}
@@ -0,0 +1,158 @@
+use serde::Deserialize;
+use serde::Serialize;
+use std::path::Path;
+
+// Master clocks of the NTSC and PAL consoles in Hz, Dendy clones use the PAL one
+const NTSC_MASTER_CLOCK: f64 = 21_477_272.0;
+const PAL_MASTER_CLOCK: f64 = 26_601_712.0;
+
+const DOTS_PER_SCANLINE: f64 = 341.0;
+
+// Declare Region enum
+// TV system of the console, which sets the clock rates and the frame timing
+// https://www.nesdev.org/wiki/Cycle_reference_chart
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum Region {
+    #[default]
+    Ntsc,
+    Pal,
+    Dendy, // PAL frame timing with an NTSC-like CPU and APU, used by famiclones
+}
+
+// Implement functionality of Region
+impl Region {
+    pub fn name(self) -> &'static str {
+        match self {
+            Region::Ntsc => "NTSC",
+            Region::Pal => "PAL",
+            Region::Dendy => "Dendy",
+        }
+    }
+
+    // Region from its name, in any case: ntsc, pal or dendy
+    pub fn from_name(name: &str) -> Option<Region> {
+        match name.to_ascii_lowercase().as_str() {
+            "ntsc" => Some(Region::Ntsc),
+            "pal" => Some(Region::Pal),
+            "dendy" => Some(Region::Dendy),
+            _ => None,
+        }
+    }
+
+    // CPU clock in Hz: the master clock divided by 12 (NTSC), 16 (PAL) or 15 (Dendy)
+    pub fn cpu_clock_rate(self) -> f64 {
+        match self {
+            Region::Ntsc => NTSC_MASTER_CLOCK / 12.0,
+            Region::Pal => PAL_MASTER_CLOCK / 16.0,
+            Region::Dendy => PAL_MASTER_CLOCK / 15.0,
+        }
+    }
+
+    // PPU dots per 5 CPU cycles: 3 dots per cycle, or 3.2 on PAL
+    pub fn ppu_dots_per_5_cpu_cycles(self) -> u8 {
+        match self {
+            Region::Pal => 16,
+            Region::Ntsc | Region::Dendy => 15,
+        }
+    }
+
+    // Scanlines per frame, counting the pre-render line
+    pub fn scanlines(self) -> u16 {
+        match self {
+            Region::Ntsc => 262,
+            Region::Pal | Region::Dendy => 312,
+        }
+    }
+
+    // Scanline vblank starts on. Dendy keeps the 20 vblank lines of NTSC and idles
+    // for 51 lines after the picture instead
+    pub fn vblank_scanline(self) -> u16 {
+        match self {
+            Region::Ntsc | Region::Pal => 241,
+            Region::Dendy => 291,
+        }
+    }
+
+    // Only the NTSC PPU shortens odd frames by a dot when rendering
+    pub fn skips_odd_frame_dot(self) -> bool {
+        self == Region::Ntsc
+    }
+
+    // Screen refresh rate in Hz: 60.0988 on NTSC, 50.0070 on PAL and Dendy
+    pub fn frame_rate(self) -> f64 {
+        let mut dots = DOTS_PER_SCANLINE * self.scanlines() as f64;
+        if self.skips_odd_frame_dot() {
+            dots -= 0.5;
+        }
+        let dots_per_cpu_cycle = self.ppu_dots_per_5_cpu_cycles() as f64 / 5.0;
+        self.cpu_clock_rate() * dots_per_cpu_cycle / dots
+    }
+
+    // Region from the tags dumps are named with in the No-Intro and GoodNES sets, e.g.
+    // "Game (Europe).nes" or "Game (E) [!].nes". Releases for several regions play
+    // as NTSC
+    pub fn from_file_name(path: &Path) -> Option<Region> {
+        let name = path.file_stem()?.to_string_lossy();
+        let mut tags = Vec::new();
+        for group in name.split('(').skip(1) {
+            let group = group.split(')').next().unwrap_or_default();
+            tags.extend(group.split(',').map(|tag| tag.trim().to_ascii_lowercase()));
+        }
+        let has_tag = |list: &[&str]| tags.iter().any(|tag| list.contains(&tag.as_str()));
+
+        if has_tag(&[
+            "usa", "u", "japan", "j", "ju", "world", "w", "ntsc", "canada",
+        ]) {
+            Some(Region::Ntsc)
+        } else if has_tag(&[
+            "europe",
+            "e",
+            "pal",
+            "australia",
+            "a",
+            "germany",
+            "g",
+            "france",
+            "f",
+            "spain",
+            "s",
+            "italy",
+            "i",
+            "sweden",
+            "sw",
+            "netherlands",
+            "uk",
+        ]) {
+            Some(Region::Pal)
+        } else if has_tag(&["russia", "r", "dendy"]) {
+            Some(Region::Dendy)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_frame_rates() {
+        assert!((Region::Ntsc.frame_rate() - 60.0988).abs() < 0.0001);
+        assert!((Region::Pal.frame_rate() - 50.0070).abs() < 0.0001);
+        assert!((Region::Dendy.frame_rate() - 50.0070).abs() < 0.0001);
+        assert!((Region::Pal.cpu_clock_rate() - 1_662_607.0).abs() < 1.0);
+    }
+
+    #[test]
+    fn test_from_file_name() {
+        let region = |name: &str| Region::from_file_name(Path::new(name));
+        assert_eq!(region("roms/Elite (Europe).nes"), Some(Region::Pal));
+        assert_eq!(region("Elite (E) [!].nes"), Some(Region::Pal));
+        assert_eq!(region("Tetris (USA, Europe).nes"), Some(Region::Ntsc));
+        assert_eq!(region("Zelda (J).nes"), Some(Region::Ntsc));
+        assert_eq!(region("Game (Russia) (Unl).nes"), Some(Region::Dendy));
+        assert_eq!(region("Homebrew (PD).nes"), None);
+        assert_eq!(region("mario.nes"), None);
+    }
+}
@@ -0,0 +1,198 @@
+use std::f64::consts::PI;
+
+// Band-limited resampler from the APU rate (one sample per CPU cycle) down to the audio
+// device rate. The APU output is a step function, so instead of filtering every input
+// sample each change in level is drawn into the output as a band-limited step
+// (windowed sinc impulse, integrated), in the same way as blip_buf
+
+// Length of the impulse in output samples, and number of fractional positions
+const KERNEL_TAPS: usize = 32;
+const KERNEL_PHASES: usize = 64;
+
+// Cutoff frequency as a fraction of the output rate
+const CUTOFF: f64 = 0.45;
+
+// DC blocking high-pass, close to the first filter stage of the NES audio output
+const HIGH_PASS_HZ: f64 = 37.0;
+
+// Largest speed change dynamic rate control may apply (0.5% is inaudible)
+const MAX_RATE_DELTA: f64 = 0.005;
+
+// Declare Resampler struct
+pub struct Resampler {
+    kernel: Vec<[f32; KERNEL_TAPS]>, // one impulse per phase, each summing to 1
+    base_step: f64,                  // output samples per input sample
+    step: f64,                       // base_step after dynamic rate control
+    time: f64,                       // position of the next input sample in output samples
+    last_input: f32,
+    deltas: Vec<f32>, // band-limited level changes waiting to be integrated
+    integrator: f32,
+    high_pass_factor: f32,
+    high_pass_input: f32,
+    high_pass_output: f32,
+}
+
+// Implement functionality of Resampler
+impl Resampler {
+    // Create new Resampler object converting input_rate Hz to output_rate Hz
+    pub fn new(input_rate: f64, output_rate: f64) -> Self {
+        let base_step = output_rate / input_rate;
+        Resampler {
+            kernel: build_kernel(),
+            base_step,
+            step: base_step,
+            time: 0.0,
+            last_input: 0.0,
+            deltas: vec![0.0; KERNEL_TAPS],
+            integrator: 0.0,
+            high_pass_factor: (-2.0 * PI * HIGH_PASS_HZ / output_rate).exp() as f32,
+            high_pass_input: 0.0,
+            high_pass_output: 0.0,
+        }
+    }
+
+    // Speed up or slow down the output slightly, see rate_adjustment
+    pub fn set_rate_adjustment(&mut self, adjustment: f64) {
+        self.step = self.base_step * adjustment;
+    }
+
+    // Resample the input and append every completed output sample
+    pub fn process(&mut self, input: &[f32], output: &mut Vec<f32>) {
+        for &sample in input {
+            if sample != self.last_input {
+                self.add_delta(sample - self.last_input);
+                self.last_input = sample;
+            }
+            self.time += self.step;
+        }
+
+        // Output samples before the current time can no longer receive new deltas
+        let ready = self.time as usize;
+        if self.deltas.len() < ready {
+            self.deltas.resize(ready, 0.0);
+        }
+        for index in 0..ready {
+            self.integrator += self.deltas[index];
+            let sample = self.high_pass(self.integrator);
+            output.push(sample);
+        }
+        self.deltas.drain(..ready);
+        self.time -= ready as f64;
+    }
+
+    // Draw a level change at the current time as a band-limited impulse
+    fn add_delta(&mut self, delta: f32) {
+        let index = self.time as usize;
+        let phase = ((self.time - index as f64) * KERNEL_PHASES as f64) as usize;
+        if self.deltas.len() < index + KERNEL_TAPS {
+            self.deltas.resize(index + KERNEL_TAPS, 0.0);
+        }
+        for (slot, tap) in self.deltas[index..index + KERNEL_TAPS]
+            .iter_mut()
+            .zip(self.kernel[phase].iter())
+        {
+            *slot += delta * tap;
+        }
+    }
+
+    // First-order high-pass removing the DC offset of the APU output
+    fn high_pass(&mut self, input: f32) -> f32 {
+        let output = input - self.high_pass_input + self.high_pass_factor * self.high_pass_output;
+        self.high_pass_input = input;
+        self.high_pass_output = output;
+        output
+    }
+}
+
+// Blackman windowed sinc impulses for every fractional phase
+fn build_kernel() -> Vec<[f32; KERNEL_TAPS]> {
+    let center = KERNEL_TAPS as f64 / 2.0;
+    (0..KERNEL_PHASES)
+        .map(|phase| {
+            let offset = phase as f64 / KERNEL_PHASES as f64;
+            let mut taps = [0.0_f64; KERNEL_TAPS];
+            for (k, tap) in taps.iter_mut().enumerate() {
+                let x = k as f64 - offset - center;
+                let sinc = if x == 0.0 {
+                    1.0
+                } else {
+                    (2.0 * PI * CUTOFF * x).sin() / (2.0 * PI * CUTOFF * x)
+                };
+                let u = (k as f64 - offset + 1.0) / KERNEL_TAPS as f64;
+                let window = 0.42 - 0.5 * (2.0 * PI * u).cos() + 0.08 * (4.0 * PI * u).cos();
+                *tap = sinc * window.max(0.0);
+            }
+            // Normalise so a step settles exactly on the new level
+            let sum: f64 = taps.iter().sum();
+            let mut kernel = [0.0_f32; KERNEL_TAPS];
+            for (out, tap) in kernel.iter_mut().zip(taps.iter()) {
+                *out = (tap / sum) as f32;
+            }
+            kernel
+        })
+        .collect()
+}
+
+// Dynamic rate control https://docs.libretro.com/development/cores/dynamic-rate-control/
+// Keeps the audio queue around its target size: a fuller queue slows the output down,
+// an emptier one speeds it up, so audio never underruns or drifts away from video
+pub fn rate_adjustment(queued_samples: usize, target_samples: usize) -> f64 {
+    let fill = (queued_samples as f64 / (2 * target_samples) as f64).min(1.0);
+    1.0 + MAX_RATE_DELTA * (1.0 - 2.0 * fill)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_output_rate() {
+        let mut resampler = Resampler::new(1_789_773.0, 48_000.0);
+        let mut output = Vec::new();
+        let input = vec![0.5; 178_977];
+        for _ in 0..10 {
+            resampler.process(&input, &mut output);
+        }
+        assert!((output.len() as i64 - 48_000).abs() <= 1);
+
+        output.clear();
+        resampler.set_rate_adjustment(rate_adjustment(0, 1000));
+        resampler.process(&vec![0.5; 1_789_773], &mut output);
+        assert!((output.len() as i64 - 48_240).abs() <= 1);
+    }
+
+    #[test]
+    fn test_high_frequencies_are_removed() {
+        let mut resampler = Resampler::new(1_789_773.0, 48_000.0);
+        let mut output = Vec::new();
+
+        // A square wave at ~55.9 kHz is far above the cutoff
+        let ultrasonic: Vec<f32> = (0..200_000)
+            .map(|n| if (n / 16) % 2 == 0 { 0.5 } else { 0.0 })
+            .collect();
+        resampler.process(&ultrasonic, &mut output);
+        let peak = output[1000..]
+            .iter()
+            .fold(0.0_f32, |peak, s| peak.max(s.abs()));
+        assert!(peak < 0.02, "peak {}", peak);
+
+        // A square wave at ~440 Hz passes through
+        output.clear();
+        let audible: Vec<f32> = (0..200_000)
+            .map(|n| if (n / 2034) % 2 == 0 { 0.5 } else { 0.0 })
+            .collect();
+        resampler.process(&audible, &mut output);
+        let peak = output[1000..]
+            .iter()
+            .fold(0.0_f32, |peak, s| peak.max(s.abs()));
+        assert!(peak > 0.2, "peak {}", peak);
+    }
+
+    #[test]
+    fn test_rate_adjustment() {
+        assert!((rate_adjustment(1000, 1000) - 1.0).abs() < 1e-9);
+        assert!(rate_adjustment(0, 1000) > 1.0);
+        assert!(rate_adjustment(2000, 1000) < 1.0);
+        assert!((rate_adjustment(10_000, 1000) - (1.0 - MAX_RATE_DELTA)).abs() < 1e-9);
+    }
+}
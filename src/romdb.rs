@@ -0,0 +1,173 @@
+use crate::cartridge::Rom;
+use crate::config::Config;
+use crate::region::Region;
+use std::collections::HashMap;
+use std::path::Path;
+use std::path::PathBuf;
+
+const DATABASE_FILE: &str = "romdb.txt";
+
+// The list shipped with the emulator
+const BUNDLED_DATABASE: &str = include_str!("romdb.txt");
+
+// CRC-32 as used by zip and the No-Intro and NesCartDB listings (reflected, polynomial
+// 0xEDB88320). Continue a checksum by passing the one so far
+pub fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    crc32_update(0, data)
+}
+
+// Checksum of a dump without its header: the PRG-ROM followed by the CHR-ROM, which
+// is the CRC the ROM listings give
+pub fn rom_crc32(rom: &Rom) -> u32 {
+    crc32_update(crc32(&rom.prg_rom), &rom.chr_rom)
+}
+
+// Declare RomDatabase struct
+// Regions of known dumps, looked up by their checksum. Each line of the file is a
+// CRC-32 in hex and a region, then anything, e.g. the name of the game:
+//     01234567 pal Some Game (Europe)
+// Empty lines and lines starting with # are skipped. The bundled list comes first,
+// a romdb.txt in the config directory can add dumps or change their region
+#[derive(Debug, Default, Clone)]
+pub struct RomDatabase {
+    regions: HashMap<u32, Region>,
+}
+
+// Implement functionality of RomDatabase
+impl RomDatabase {
+    // Where the database is looked for: next to config.toml
+    pub fn user_path() -> Option<PathBuf> {
+        Some(Config::user_path()?.with_file_name(DATABASE_FILE))
+    }
+
+    pub fn parse(text: &str) -> Result<RomDatabase, String> {
+        let mut regions = HashMap::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error = |what: &str| format!("Line {}: {}", number + 1, what);
+            let mut fields = line.split_whitespace();
+            let crc = fields.next().unwrap_or_default();
+            let crc = u32::from_str_radix(crc, 16)
+                .map_err(|_| error(&format!("invalid CRC-32 {}", crc)))?;
+            let region = fields.next().ok_or_else(|| error("no region"))?;
+            let region = Region::from_name(region).ok_or_else(|| {
+                error(&format!(
+                    "unknown region {}: expected ntsc, pal or dendy",
+                    region
+                ))
+            })?;
+            regions.insert(crc, region);
+        }
+        Ok(RomDatabase { regions })
+    }
+
+    // The list shipped with the emulator, which is checked by the tests
+    pub fn bundled() -> RomDatabase {
+        RomDatabase::parse(BUNDLED_DATABASE).unwrap_or_default()
+    }
+
+    // Add the entries of another database, which win over the ones already there
+    pub fn extend(&mut self, other: RomDatabase) {
+        self.regions.extend(other.regions);
+    }
+
+    // Read the file on top of the database, nothing is read when the file does not exist
+    pub fn load(&mut self, path: &Path) -> Result<(), String> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(format!("Cannot read {}: {}", path.display(), err)),
+        };
+        let database =
+            RomDatabase::parse(&text).map_err(|err| format!("{}: {}", path.display(), err))?;
+        self.extend(database);
+        Ok(())
+    }
+
+    // The bundled list with the database in the user's config directory on top
+    pub fn load_user() -> Result<RomDatabase, String> {
+        let mut database = RomDatabase::bundled();
+        if let Some(path) = RomDatabase::user_path() {
+            database.load(&path)?;
+        }
+        Ok(database)
+    }
+
+    pub fn len(&self) -> usize {
+        self.regions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.regions.is_empty()
+    }
+
+    pub fn lookup(&self, rom: &Rom) -> Option<Region> {
+        self.regions.get(&rom_crc32(rom)).copied()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32_update(crc32(b"1234"), b"56789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn test_bundled_database_parses() {
+        let database = RomDatabase::parse(BUNDLED_DATABASE).unwrap();
+        assert_eq!(database.len(), RomDatabase::bundled().len());
+    }
+
+    #[test]
+    fn test_lookup() {
+        let mut raw = vec![0x4E, 0x45, 0x53, 0x1A, 1, 1, 0, 0];
+        raw.resize(16, 0);
+        raw.extend(vec![0xEA; 0x4000]);
+        raw.extend(vec![0x55; 0x2000]);
+        let rom = Rom::new(&raw).unwrap();
+        let crc = crc32(&raw[16..]);
+        assert_eq!(rom_crc32(&rom), crc);
+
+        let text = format!(
+            "# Test dumps\n\n{:08X} Dendy Test (Russia)\n12345678 pal\n",
+            crc
+        );
+        let database = RomDatabase::parse(&text).unwrap();
+        assert_eq!(database.len(), 2);
+        assert_eq!(database.lookup(&rom), Some(Region::Dendy));
+        raw[16] = 0;
+        assert_eq!(database.lookup(&Rom::new(&raw).unwrap()), None);
+
+        // The user file wins over the bundled list
+        let mut bundled = RomDatabase::parse(&format!("{:08X} pal", crc)).unwrap();
+        bundled.extend(database);
+        assert_eq!(bundled.lookup(&rom), Some(Region::Dendy));
+
+        assert!(RomDatabase::parse("xyz pal").is_err());
+        assert!(RomDatabase::parse("12345678").is_err());
+        assert!(RomDatabase::parse("12345678 secam").is_err());
+    }
+}
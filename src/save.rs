@@ -0,0 +1,143 @@
+use crate::mapper::Mapper;
+use std::ffi::OsString;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::ErrorKind;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+// How often the battery-backed RAM is checked for changes while running, in frames
+pub const FLUSH_INTERVAL_FRAMES: u32 = 300;
+
+// Declare SaveFile struct
+// The battery-backed RAM of a cartridge, kept in a .sav file next to the ROM. The file
+// is only rewritten when the RAM changed since the last flush
+pub struct SaveFile {
+    path: PathBuf,
+    saved: Vec<u8>, // RAM contents as last loaded or written
+}
+
+// Implement functionality of SaveFile
+impl SaveFile {
+    // Save file of a ROM: games/zelda.nes -> games/zelda.sav
+    pub fn path_for(rom_path: &Path) -> PathBuf {
+        rom_path.with_extension("sav")
+    }
+
+    // Load the save into the mapper, a missing file leaves the RAM as it is
+    pub fn open(path: PathBuf, mapper: &mut dyn Mapper) -> io::Result<Self> {
+        match fs::read(&path) {
+            Ok(data) => mapper.load_save_ram(&data),
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+        Ok(SaveFile {
+            path,
+            saved: mapper.save_ram(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Write the RAM out when it changed, returns whether the file was written
+    pub fn flush(&mut self, mapper: &dyn Mapper) -> io::Result<bool> {
+        let data = mapper.save_ram();
+        if data == self.saved {
+            return Ok(false);
+        }
+        write_atomic(&self.path, &data)?;
+        self.saved = data;
+        Ok(true)
+    }
+}
+
+// Write a file through a temporary file that is renamed over it, so a crash halfway
+// leaves either the old or the new contents
+pub fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut temp_name = OsString::from(path.as_os_str());
+    temp_name.push(".tmp");
+    let temp_path = PathBuf::from(temp_name);
+
+    let mut file = File::create(&temp_path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&temp_path, path)?;
+
+    // Make the rename itself durable where directories can be synced
+    if let Some(parent) = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        let _ = File::open(parent).and_then(|dir| dir.sync_all());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::test_rom;
+    use crate::mapper::new_mapper;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("save-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn battery_mapper() -> Box<dyn Mapper> {
+        let mut rom = test_rom(vec![]);
+        rom.battery = true;
+        rom.prg_ram_size = 0;
+        rom.prg_nvram_size = 0x2000;
+        new_mapper(rom).unwrap()
+    }
+
+    #[test]
+    fn test_path_for() {
+        assert_eq!(
+            SaveFile::path_for(Path::new("games/zelda.nes")),
+            PathBuf::from("games/zelda.sav")
+        );
+    }
+
+    #[test]
+    fn test_flush_and_load() {
+        let path = temp_path("flush.sav");
+        let mut mapper = battery_mapper();
+        let mut save = SaveFile::open(path.clone(), &mut *mapper).unwrap();
+
+        // Nothing is written until the RAM changes
+        assert!(!save.flush(&*mapper).unwrap());
+        assert!(!path.exists());
+
+        mapper.cpu_write(0x6123, 0x42);
+        assert!(save.flush(&*mapper).unwrap());
+        assert!(!save.flush(&*mapper).unwrap());
+        assert_eq!(fs::read(&path).unwrap().len(), 0x2000);
+
+        // A fresh cartridge picks the save up
+        let mut mapper = battery_mapper();
+        SaveFile::open(path.clone(), &mut *mapper).unwrap();
+        assert_eq!(mapper.cpu_read(0x6123), Some(0x42));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_write_atomic_replaces_file() {
+        let path = temp_path("atomic.sav");
+        fs::write(&path, [1, 2, 3, 4]).unwrap();
+        write_atomic(&path, &[5, 6]).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), vec![5, 6]);
+
+        let mut temp_name = OsString::from(path.as_os_str());
+        temp_name.push(".tmp");
+        assert!(!PathBuf::from(temp_name).exists());
+        fs::remove_file(&path).unwrap();
+    }
+}
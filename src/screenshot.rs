@@ -0,0 +1,59 @@
+use crate::frame::Frame;
+use serde::Deserialize;
+use serde::Serialize;
+use std::fs::File;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::Path;
+
+// Declare ScreenshotSize enum
+// How big screenshots are
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ScreenshotSize {
+    #[default]
+    Native, // a pixel for every pixel of the picture, 256x240 for the NES
+    Display, // scaled like the window
+}
+
+// Encode the frame as a PNG image, every pixel becoming scale x scale pixels
+pub fn write_png<W: Write>(frame: &Frame, scale: usize, out: W) -> Result<(), String> {
+    let frame = frame.scaled(scale.max(1));
+    let mut encoder = png::Encoder::new(out, frame.width as u32, frame.height as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().map_err(|err| err.to_string())?;
+    writer
+        .write_image_data(&frame.data)
+        .map_err(|err| err.to_string())?;
+    writer.finish().map_err(|err| err.to_string())
+}
+
+// Write the frame to path as a PNG image
+pub fn save_png(frame: &Frame, scale: usize, path: &Path) -> Result<(), String> {
+    let write_error = |err: String| format!("Cannot write {}: {}", path.display(), err);
+    let file = File::create(path).map_err(|err| write_error(err.to_string()))?;
+    write_png(frame, scale, BufWriter::new(file)).map_err(write_error)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_write_png() {
+        let mut frame = Frame::new(2, 1);
+        frame.set_pixel(1, 0, (255, 0, 0));
+        let mut out = Vec::new();
+        write_png(&frame, 3, &mut out).unwrap();
+        assert_eq!(&out[..8], b"\x89PNG\r\n\x1a\n");
+
+        let decoder = png::Decoder::new(out.as_slice());
+        let mut reader = decoder.read_info().unwrap();
+        let mut data = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut data).unwrap();
+        assert_eq!((info.width, info.height), (6, 3));
+        assert_eq!(&data[..3], &[0, 0, 0]);
+        assert_eq!(&data[15..18], &[255, 0, 0]);
+    }
+}
@@ -0,0 +1,262 @@
+use crate::bus::Bus;
+use crate::cpu::CPUFlags;
+use crate::cpu::CPU;
+use crate::region::Region;
+use bincode::Options;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::path::Path;
+use std::path::PathBuf;
+
+// Save states start with the tag and the version of their layout, which goes up
+// whenever a saved struct changes so older states are refused instead of misread
+const STATE_TAG: [u8; 4] = *b"NESS";
+const STATE_VERSION: u8 = 1;
+
+// Save state slots, picked with --state
+pub const SLOT_COUNT: u8 = 10;
+
+// Rewinding goes back through a snapshot taken every few frames, for about the last
+// 10 seconds at 60 frames per second
+pub const REWIND_INTERVAL_FRAMES: u32 = 6;
+pub const REWIND_SNAPSHOTS: usize = 100;
+
+// Save state of a ROM in a slot: games/zelda.nes -> games/zelda.st2
+pub fn slot_path(rom_path: &Path, slot: u8) -> PathBuf {
+    rom_path.with_extension(format!("st{}", slot))
+}
+
+// Save states are bincode with variable-length integers. Bytes left over after a part
+// are taken as damage rather than ignored
+fn bincode_options() -> impl Options {
+    bincode::DefaultOptions::new().reject_trailing_bytes()
+}
+
+// Serialise a part of the console for a save state
+pub(crate) fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, String> {
+    bincode_options()
+        .serialize(value)
+        .map_err(|err| format!("Cannot save the state: {}", err))
+}
+
+// Read back a part of the console written by encode
+pub(crate) fn decode<T: DeserializeOwned>(data: &[u8]) -> Result<T, String> {
+    bincode_options()
+        .deserialize(data)
+        .map_err(|err| format!("Save state is damaged: {}", err))
+}
+
+// Byte arrays longer than the 32 elements serde handles, for #[serde(with)]
+pub(crate) mod byte_array {
+    use serde::de;
+    use serde::Deserialize;
+    use serde::Deserializer;
+    use serde::Serializer;
+
+    pub fn serialize<S: Serializer, const N: usize>(
+        array: &[u8; N],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(array)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>, const N: usize>(
+        deserializer: D,
+    ) -> Result<[u8; N], D::Error> {
+        let bytes = Vec::<u8>::deserialize(deserializer)?;
+        let len = bytes.len();
+        bytes
+            .try_into()
+            .map_err(|_| de::Error::invalid_length(len, &"an array of the saved size"))
+    }
+}
+
+// Declare Rewind struct
+// The recent history of a game, as save states
+pub struct Rewind {
+    snapshots: VecDeque<Vec<u8>>,
+    capacity: usize,
+    interval: u32,
+    frames: u32, // frames played since the last snapshot
+}
+
+// Implement functionality of Rewind
+impl Rewind {
+    // Create new Rewind object keeping capacity snapshots, one every interval frames
+    pub fn new(capacity: usize, interval: u32) -> Self {
+        Rewind {
+            snapshots: VecDeque::with_capacity(capacity),
+            capacity,
+            interval,
+            frames: 0,
+        }
+    }
+
+    // Count a frame played, returns true when a snapshot is due
+    pub fn frame_done(&mut self) -> bool {
+        self.frames += 1;
+        if self.frames < self.interval {
+            return false;
+        }
+        self.frames = 0;
+        true
+    }
+
+    // Keep a snapshot, dropping the oldest one when full
+    pub fn push(&mut self, snapshot: Vec<u8>) {
+        if self.snapshots.len() >= self.capacity {
+            self.snapshots.pop_front();
+        }
+        self.snapshots.push_back(snapshot);
+    }
+
+    // Take the latest snapshot, None once the history is used up
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        self.frames = 0;
+        self.snapshots.pop_back()
+    }
+
+    pub fn len(&self) -> usize {
+        self.snapshots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.snapshots.is_empty()
+    }
+}
+
+impl Default for Rewind {
+    fn default() -> Self {
+        Rewind::new(REWIND_SNAPSHOTS, REWIND_INTERVAL_FRAMES)
+    }
+}
+
+// Declare Registers struct
+// The CPU without its bus
+#[derive(Serialize, Deserialize)]
+struct Registers {
+    register_a: u8,
+    register_x: u8,
+    register_y: u8,
+    status: CPUFlags,
+    program_counter: u16,
+    stack_pointer: u8,
+    cycles: usize,
+    illegal_opcode: Option<u8>,
+}
+
+// Declare SaveState struct
+// What follows the tag and version
+#[derive(Serialize, Deserialize)]
+struct SaveState {
+    cartridge: u32, // CRC-32 of the cartridge, a state only loads into the same game
+    region: Region,
+    registers: Registers,
+    bus: Vec<u8>, // see Bus::save_state
+}
+
+// Snapshot of the CPU and everything on its bus, the cartridge included
+pub(crate) fn save(cpu: &CPU<Bus>, cartridge: u32) -> Result<Vec<u8>, String> {
+    let state = SaveState {
+        cartridge,
+        region: cpu.bus.region(),
+        registers: Registers {
+            register_a: cpu.register_a,
+            register_x: cpu.register_x,
+            register_y: cpu.register_y,
+            status: cpu.status,
+            program_counter: cpu.program_counter,
+            stack_pointer: cpu.stack_pointer,
+            cycles: cpu.cycles,
+            illegal_opcode: cpu.illegal_opcode,
+        },
+        bus: cpu.bus.save_state()?,
+    };
+    let mut data = STATE_TAG.to_vec();
+    data.push(STATE_VERSION);
+    data.extend(encode(&state)?);
+    Ok(data)
+}
+
+// Go back to a snapshot taken by save. Nothing changes when the state cannot be loaded
+pub(crate) fn load(cpu: &mut CPU<Bus>, cartridge: u32, data: &[u8]) -> Result<(), String> {
+    let body = data
+        .strip_prefix(&STATE_TAG)
+        .ok_or_else(|| "Not a save state".to_string())?;
+    if body.first() != Some(&STATE_VERSION) {
+        return Err("Save state is from another version of the emulator".to_string());
+    }
+    let state: SaveState = decode(&body[1..])?;
+    if state.cartridge != cartridge {
+        return Err("Save state is of another game".to_string());
+    }
+    if state.region != cpu.bus.region() {
+        return Err(format!(
+            "Save state is of a console with {} timing",
+            state.region.name()
+        ));
+    }
+    cpu.bus.load_state(&state.bus)?;
+
+    let registers = state.registers;
+    cpu.register_a = registers.register_a;
+    cpu.register_x = registers.register_x;
+    cpu.register_y = registers.register_y;
+    cpu.status = registers.status;
+    cpu.program_counter = registers.program_counter;
+    cpu.stack_pointer = registers.stack_pointer;
+    cpu.cycles = registers.cycles;
+    cpu.illegal_opcode = registers.illegal_opcode;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_slot_path() {
+        assert_eq!(
+            slot_path(Path::new("games/zelda.nes"), 2),
+            PathBuf::from("games/zelda.st2")
+        );
+        assert_eq!(
+            slot_path(Path::new("smb2.fds"), 0),
+            PathBuf::from("smb2.st0")
+        );
+    }
+
+    #[test]
+    fn test_rewind() {
+        let mut rewind = Rewind::new(3, 2);
+        for frame in 1..=10_u8 {
+            if rewind.frame_done() {
+                rewind.push(vec![frame]);
+            }
+        }
+        // Only the last 3 snapshots of the 5 taken are kept
+        assert_eq!(rewind.len(), 3);
+        assert_eq!(rewind.pop(), Some(vec![10]));
+        assert_eq!(rewind.pop(), Some(vec![8]));
+        assert_eq!(rewind.pop(), Some(vec![6]));
+        assert_eq!(rewind.pop(), None);
+        assert!(rewind.is_empty());
+    }
+
+    #[test]
+    fn test_byte_array() {
+        #[derive(Serialize, Deserialize)]
+        struct Memory {
+            #[serde(with = "byte_array")]
+            ram: [u8; 0x800],
+        }
+        let mut ram = [0; 0x800];
+        ram[0x7FF] = 0x42;
+        let data = encode(&Memory { ram }).unwrap();
+        assert_eq!(decode::<Memory>(&data).unwrap().ram, ram);
+        assert!(decode::<Memory>(&data[..data.len() - 1]).is_err());
+        assert!(decode::<[u8; 4]>(&data).is_err());
+    }
+}
@@ -0,0 +1,159 @@
+use crate::cpu::AddressingMode;
+use crate::cpu::CpuBus;
+use crate::cpu::Mem;
+use crate::cpu::CPU;
+use crate::opcodes;
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+// Disassemble the instruction at the program counter, with the registers before it
+// runs, in the style of the nestest log:
+// 0600  A9 05     LDA #$05     A:00 X:00 Y:00 P:24 SP:FD CYC:0
+pub fn trace<M: CpuBus>(cpu: &mut CPU<M>) -> String {
+    let pc = cpu.program_counter;
+    let code = cpu.mem_read(pc);
+    let (mnemonic, len, mode) = match opcodes::OPCODES_MAP.get(&code) {
+        Some(opcode) => (opcode.mnemonic, opcode.len, &opcode.mode),
+        None => ("???", 1, &AddressingMode::NoneAddressing),
+    };
+    let bytes: Vec<u8> = (0..len as u16)
+        .map(|offset| cpu.mem_read(pc.wrapping_add(offset)))
+        .collect();
+    let byte = bytes.get(1).copied().unwrap_or_default();
+    let word = u16::from_le_bytes([byte, bytes.get(2).copied().unwrap_or_default()]);
+
+    let operand = match (mode, len) {
+        (AddressingMode::Immediate, _) => format!("#${:02X}", byte),
+        (AddressingMode::ZeroPage, _) => format!("${:02X}", byte),
+        (AddressingMode::ZeroPage_X, _) => format!("${:02X},X", byte),
+        (AddressingMode::ZeroPage_Y, _) => format!("${:02X},Y", byte),
+        (AddressingMode::Absolute, _) => format!("${:04X}", word),
+        (AddressingMode::Absolute_X, _) => format!("${:04X},X", word),
+        (AddressingMode::Absolute_Y, _) => format!("${:04X},Y", word),
+        (AddressingMode::Indirect_X, _) => format!("(${:02X},X)", byte),
+        (AddressingMode::Indirect_Y, _) => format!("(${:02X}),Y", byte),
+        // Branches, relative to the next instruction
+        (AddressingMode::NoneAddressing, 2) => {
+            let target = pc.wrapping_add(2).wrapping_add(byte as i8 as u16);
+            format!("${:04X}", target)
+        }
+        (AddressingMode::NoneAddressing, 3) if code == 0x6C => format!("(${:04X})", word),
+        (AddressingMode::NoneAddressing, 3) => format!("${:04X}", word),
+        (AddressingMode::NoneAddressing, _) => String::new(),
+    };
+
+    let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+    format!(
+        "{:04X}  {:8}  {:12} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} CYC:{}",
+        pc,
+        hex.join(" "),
+        format!("{} {}", mnemonic, operand).trim_end(),
+        cpu.register_a,
+        cpu.register_x,
+        cpu.register_y,
+        cpu.status.bits(),
+        cpu.stack_pointer,
+        cpu.cycles
+    )
+}
+
+// Declare TraceLog struct
+// A file the trace of every instruction run is written to
+pub struct TraceLog {
+    writer: BufWriter<File>,
+    path: PathBuf,
+}
+
+// Implement functionality of TraceLog
+impl TraceLog {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        Ok(TraceLog {
+            writer: BufWriter::new(File::create(path)?),
+            path: path.to_path_buf(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Add the instruction about to run
+    pub fn log<M: CpuBus>(&mut self, cpu: &mut CPU<M>) -> io::Result<()> {
+        writeln!(self.writer, "{}", trace(cpu))
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+// Open the trace file given with --trace
+pub fn open_trace(path: Option<&Path>) -> Result<Option<TraceLog>, String> {
+    match path {
+        Some(path) => TraceLog::create(path)
+            .map(Some)
+            .map_err(|err| format!("Cannot write {}: {}", path.display(), err)),
+        None => Ok(None),
+    }
+}
+
+// Trace the instruction about to run, giving up on the trace when the file fails
+pub fn write_trace<M: CpuBus>(trace_log: &mut Option<TraceLog>, cpu: &mut CPU<M>) {
+    if let Some(log) = trace_log.as_mut() {
+        if let Err(err) = log.log(cpu) {
+            eprintln!(
+                "Trace stopped: cannot write {}: {}",
+                log.path().display(),
+                err
+            );
+            *trace_log = None;
+        }
+    }
+}
+
+// Write out the rest of the trace
+pub fn finish_trace(trace_log: &mut Option<TraceLog>) {
+    if let Some(log) = trace_log.as_mut() {
+        if let Err(err) = log.flush() {
+            eprintln!("Cannot write {}: {}", log.path().display(), err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_trace_format() {
+        let mut cpu = CPU::new();
+        // LDA #$05; STA $0200,X; BNE -2 (to itself); JMP ($1234); TAX
+        let program = vec![
+            0xA9, 0x05, 0x9D, 0x00, 0x02, 0xD0, 0xFE, 0x6C, 0x34, 0x12, 0xAA,
+        ];
+        cpu.load(program);
+        cpu.reset();
+        cpu.register_x = 0x10;
+
+        let mut lines = Vec::new();
+        for addr in [0x0600, 0x0602, 0x0605, 0x0607, 0x060A] {
+            cpu.program_counter = addr;
+            lines.push(trace(&mut cpu));
+        }
+        assert_eq!(
+            lines[0],
+            "0600  A9 05     LDA #$05     A:00 X:10 Y:00 P:24 SP:FD CYC:0"
+        );
+        assert_eq!(
+            lines[1],
+            "0602  9D 00 02  STA $0200,X  A:00 X:10 Y:00 P:24 SP:FD CYC:0"
+        );
+        assert!(lines[2].starts_with("0605  D0 FE     BNE $0605 "));
+        assert!(lines[3].starts_with("0607  6C 34 12  JMP ($1234) "));
+        assert!(lines[4].starts_with("060A  AA        TAX          A:00"));
+    }
+}
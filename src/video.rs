@@ -0,0 +1,181 @@
+use crate::frame::Frame;
+use crate::wav::WavRecorder;
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+// Convert a pixel to limited-range BT.601 Y'CbCr, as most players expect
+fn rgb_to_yuv((r, g, b): (u8, u8, u8)) -> (u8, u8, u8) {
+    let (r, g, b) = (r as f32, g as f32, b as f32);
+    let y = 16.0 + (65.738 * r + 129.057 * g + 25.064 * b) / 256.0;
+    let u = 128.0 + (-37.945 * r - 74.494 * g + 112.439 * b) / 256.0;
+    let v = 128.0 + (112.439 * r - 94.154 * g - 18.285 * b) / 256.0;
+    (y.round() as u8, u.round() as u8, v.round() as u8)
+}
+
+// Declare Y4mWriter struct
+// Writes uncompressed YUV4MPEG2 video with full-resolution 4:4:4 colour, which
+// ffmpeg, mpv and VLC all read without an encoder being involved
+pub struct Y4mWriter<W: Write> {
+    writer: W,
+    width: usize,
+    height: usize,
+    planes: Vec<u8>,
+}
+
+impl Y4mWriter<BufWriter<File>> {
+    // Create the file and write the stream header
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        width: usize,
+        height: usize,
+        frame_rate: f64,
+    ) -> io::Result<Self> {
+        Y4mWriter::new(
+            BufWriter::new(File::create(path)?),
+            width,
+            height,
+            frame_rate,
+        )
+    }
+}
+
+// Implement functionality of Y4mWriter
+impl<W: Write> Y4mWriter<W> {
+    // Create new Y4mWriter object writing to any output. The frame rate is kept to
+    // a thousandth of a frame per second
+    pub fn new(mut writer: W, width: usize, height: usize, frame_rate: f64) -> io::Result<Self> {
+        let rate = (frame_rate * 1000.0).round() as u64;
+        writeln!(
+            writer,
+            "YUV4MPEG2 W{} H{} F{}:1000 Ip A1:1 C444",
+            width, height, rate
+        )?;
+        Ok(Y4mWriter {
+            writer,
+            width,
+            height,
+            planes: vec![0; width * height * 3],
+        })
+    }
+
+    // Append a frame, which must have the size given when the stream was started
+    pub fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
+        if frame.width != self.width || frame.height != self.height {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Frame is {}x{}, the video {}x{}",
+                    frame.width, frame.height, self.width, self.height
+                ),
+            ));
+        }
+        let size = self.width * self.height;
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let (luma, cb, cr) = rgb_to_yuv(frame.pixel(x, y));
+                let index = y * self.width + x;
+                self.planes[index] = luma;
+                self.planes[size + index] = cb;
+                self.planes[2 * size + index] = cr;
+            }
+        }
+        self.writer.write_all(b"FRAME\n")?;
+        self.writer.write_all(&self.planes)
+    }
+
+    // Flush, returning the output
+    pub fn finish(mut self) -> io::Result<W> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+// Declare VideoRecorder struct
+// Records emulated frames to a .y4m file and the APU output to a .wav file next to
+// it. Every frame given is recorded whatever the speed the game runs at, so the
+// recording plays back in real time
+pub struct VideoRecorder {
+    video: Y4mWriter<BufWriter<File>>,
+    audio: WavRecorder,
+    path: PathBuf,
+    frames: u64,
+}
+
+// Implement functionality of VideoRecorder
+impl VideoRecorder {
+    // Start recording to path, with the sound going to the same name ending in .wav.
+    // sample_rate is the rate of the samples given to record
+    pub fn start(
+        path: &Path,
+        width: usize,
+        height: usize,
+        frame_rate: f64,
+        sample_rate: f64,
+    ) -> io::Result<Self> {
+        Ok(VideoRecorder {
+            video: Y4mWriter::create(path, width, height, frame_rate)?,
+            audio: WavRecorder::start(&path.with_extension("wav"), sample_rate, false)?,
+            path: path.to_path_buf(),
+            frames: 0,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Number of frames recorded so far
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    // Record one emulated frame and the sound made during it
+    pub fn record(&mut self, frame: &Frame, samples: &[f32]) -> io::Result<()> {
+        self.video.write_frame(frame)?;
+        self.audio.record(samples, &[])?;
+        self.frames += 1;
+        Ok(())
+    }
+
+    // Finalise both files
+    pub fn finish(self) -> io::Result<()> {
+        self.video.finish()?;
+        self.audio.finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rgb_to_yuv() {
+        assert_eq!(rgb_to_yuv((0, 0, 0)), (16, 128, 128));
+        assert_eq!(rgb_to_yuv((255, 255, 255)), (235, 128, 128));
+        let (_, u, v) = rgb_to_yuv((255, 0, 0));
+        assert!(u < 128 && v == 240);
+    }
+
+    #[test]
+    fn test_y4m_frames() {
+        let mut frame = Frame::new(2, 1);
+        frame.set_pixel(1, 0, (255, 255, 255));
+        let mut y4m = Y4mWriter::new(Vec::new(), 2, 1, 60.0988).unwrap();
+        y4m.write_frame(&frame).unwrap();
+        y4m.write_frame(&frame).unwrap();
+        assert!(y4m.write_frame(&Frame::new(1, 2)).is_err());
+        let bytes = y4m.finish().unwrap();
+
+        let header = b"YUV4MPEG2 W2 H1 F60099:1000 Ip A1:1 C444\n";
+        assert_eq!(&bytes[..header.len()], header);
+        let frame_bytes = [b"FRAME\n".as_slice(), &[16, 235, 128, 128, 128, 128]].concat();
+        assert_eq!(
+            &bytes[header.len()..],
+            [&frame_bytes[..], &frame_bytes[..]].concat()
+        );
+    }
+}
@@ -0,0 +1,202 @@
+use crate::apu::Channel;
+use crate::resampler::Resampler;
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+// Recordings are written as 16-bit mono PCM at this rate
+pub const RECORDING_RATE: u32 = 48_000;
+
+// Size of the RIFF/WAVE header written before the samples
+const HEADER_SIZE: u32 = 44;
+
+// Declare WavWriter struct
+// Writes 16-bit mono PCM, the chunk sizes are filled in by finish()
+pub struct WavWriter<W: Write + Seek> {
+    writer: W,
+    samples_written: u32,
+}
+
+impl WavWriter<BufWriter<File>> {
+    // Create the file and write a placeholder header
+    pub fn create<P: AsRef<Path>>(path: P, sample_rate: u32) -> io::Result<Self> {
+        WavWriter::new(BufWriter::new(File::create(path)?), sample_rate)
+    }
+}
+
+// Implement functionality of WavWriter
+impl<W: Write + Seek> WavWriter<W> {
+    // Create new WavWriter object writing to any seekable output
+    pub fn new(mut writer: W, sample_rate: u32) -> io::Result<Self> {
+        let channels: u16 = 1;
+        let bits_per_sample: u16 = 16;
+        let block_align = channels * bits_per_sample / 8;
+
+        writer.write_all(b"RIFF")?;
+        writer.write_all(&(HEADER_SIZE - 8).to_le_bytes())?;
+        writer.write_all(b"WAVE")?;
+        writer.write_all(b"fmt ")?;
+        writer.write_all(&16_u32.to_le_bytes())?;
+        writer.write_all(&1_u16.to_le_bytes())?; // PCM
+        writer.write_all(&channels.to_le_bytes())?;
+        writer.write_all(&sample_rate.to_le_bytes())?;
+        writer.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+        writer.write_all(&block_align.to_le_bytes())?;
+        writer.write_all(&bits_per_sample.to_le_bytes())?;
+        writer.write_all(b"data")?;
+        writer.write_all(&0_u32.to_le_bytes())?;
+
+        Ok(WavWriter {
+            writer,
+            samples_written: 0,
+        })
+    }
+
+    // Append samples in the range -1.0 to 1.0
+    pub fn write_samples(&mut self, samples: &[f32]) -> io::Result<()> {
+        for &sample in samples {
+            let value = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            self.writer.write_all(&value.to_le_bytes())?;
+        }
+        self.samples_written += samples.len() as u32;
+        Ok(())
+    }
+
+    // Fill in the chunk sizes and flush, returning the output
+    pub fn finish(mut self) -> io::Result<W> {
+        let data_size = self.samples_written * 2;
+        self.writer.seek(SeekFrom::Start(4))?;
+        self.writer
+            .write_all(&(HEADER_SIZE - 8 + data_size).to_le_bytes())?;
+        self.writer.seek(SeekFrom::Start(40))?;
+        self.writer.write_all(&data_size.to_le_bytes())?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+// One output file fed by its own resampler
+struct Track {
+    resampler: Resampler,
+    writer: WavWriter<BufWriter<File>>,
+    buffer: Vec<f32>,
+}
+
+impl Track {
+    fn create(path: &Path, input_rate: f64) -> io::Result<Self> {
+        Ok(Track {
+            resampler: Resampler::new(input_rate, RECORDING_RATE as f64),
+            writer: WavWriter::create(path, RECORDING_RATE)?,
+            buffer: Vec::new(),
+        })
+    }
+
+    fn write(&mut self, samples: &[f32]) -> io::Result<()> {
+        self.buffer.clear();
+        self.resampler.process(samples, &mut self.buffer);
+        self.writer.write_samples(&self.buffer)
+    }
+}
+
+// Declare WavRecorder struct
+// Records the mixed APU output, and optionally every channel on its own, to WAV files.
+// Works on the raw APU samples, so no audio device is needed
+pub struct WavRecorder {
+    mix: Track,
+    channels: Vec<Track>, // indexed by Channel, empty unless recording split channels
+}
+
+// Implement functionality of WavRecorder
+impl WavRecorder {
+    // Start recording to path; split channels go next to it as <name>.<channel>.wav
+    // The APU must have channel capture enabled when split_channels is set
+    pub fn start(path: &Path, input_rate: f64, split_channels: bool) -> io::Result<Self> {
+        let mut channels = Vec::new();
+        if split_channels {
+            for channel in Channel::ALL.iter() {
+                channels.push(Track::create(&channel_path(path, *channel), input_rate)?);
+            }
+        }
+        Ok(WavRecorder {
+            mix: Track::create(path, input_rate)?,
+            channels,
+        })
+    }
+
+    // Record the samples of one frame, as returned by APU::take_samples and
+    // APU::take_channel_samples
+    pub fn record(&mut self, mix: &[f32], channels: &[Vec<f32>]) -> io::Result<()> {
+        self.mix.write(mix)?;
+        for (track, samples) in self.channels.iter_mut().zip(channels.iter()) {
+            track.write(samples)?;
+        }
+        Ok(())
+    }
+
+    pub fn is_recording_channels(&self) -> bool {
+        !self.channels.is_empty()
+    }
+
+    // Finalise every file
+    pub fn finish(self) -> io::Result<()> {
+        self.mix.writer.finish()?;
+        for track in self.channels {
+            track.writer.finish()?;
+        }
+        Ok(())
+    }
+}
+
+// music.wav -> music.pulse1.wav
+fn channel_path(path: &Path, channel: Channel) -> PathBuf {
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    path.with_file_name(format!("{}.{}.wav", stem, channel.name()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_wav_header() {
+        let mut wav = WavWriter::new(Cursor::new(Vec::new()), 48_000).unwrap();
+        wav.write_samples(&[0.0, 1.0, -1.0, 2.0]).unwrap();
+        let bytes = wav.finish().unwrap().into_inner();
+
+        assert_eq!(bytes.len(), 44 + 8);
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes(bytes[4..8].try_into().unwrap()), 36 + 8);
+        assert_eq!(&bytes[8..16], b"WAVEfmt ");
+        assert_eq!(
+            u32::from_le_bytes(bytes[24..28].try_into().unwrap()),
+            48_000
+        );
+        assert_eq!(&bytes[36..40], b"data");
+        assert_eq!(u32::from_le_bytes(bytes[40..44].try_into().unwrap()), 8);
+
+        // Samples are clamped to the 16-bit range
+        let samples: Vec<i16> = bytes[44..]
+            .chunks(2)
+            .map(|pair| i16::from_le_bytes([pair[0], pair[1]]))
+            .collect();
+        assert_eq!(samples, vec![0, 32767, -32767, 32767]);
+    }
+
+    #[test]
+    fn test_channel_path() {
+        assert_eq!(
+            channel_path(Path::new("out/music.wav"), Channel::Triangle),
+            PathBuf::from("out/music.triangle.wav")
+        );
+    }
+}